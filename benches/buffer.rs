@@ -0,0 +1,52 @@
+//! Times buffer edits against files of increasing size. Run with `cargo bench`.
+//!
+//! Per-edit cost should stay roughly flat as the file grows; a linear-time buffer would get about
+//! 256 times slower between the smallest and largest sizes.
+
+extern crate ted;
+
+use std::time::Instant;
+
+use ted::buffer::Buffer;
+
+const EDITS: usize = 10000;
+
+fn test_text(size: usize) -> String {
+    let mut text = String::with_capacity(size + 64);
+    let mut i = 0;
+    while text.len() < size {
+        text.push_str(&format!("{:08} the quick brown fox jumps over the lazy dog\n", i));
+        i += 1;
+    }
+    text
+}
+
+/// Average nanoseconds taken by `edit`, run EDITS times at pseudo-random positions
+fn time_edits<F: FnMut(&mut Buffer, usize)>(buffer: &mut Buffer, mut edit: F) -> u64 {
+    let mut index = 0;
+    let start = Instant::now();
+    for _ in 0..EDITS {
+        // Stay on ASCII text, so any index is on a char boundary
+        index = (index + 104729) % (buffer.len() - 1);
+        edit(buffer, index);
+    }
+    let elapsed = start.elapsed();
+    (elapsed.as_secs() * 1000000000 + elapsed.subsec_nanos() as u64) / EDITS as u64
+}
+
+fn main() {
+    println!("{:>10} {:>14} {:>14} {:>14}", "size", "insert_char", "remove_char", "line_info");
+
+    for &size in &[64 * 1024, 1024 * 1024, 16 * 1024 * 1024] {
+        let mut buffer = Buffer::from_string(test_text(size));
+
+        let insert = time_edits(&mut buffer, |buffer, index| { buffer.insert_char(index, 'x'); });
+        let remove = time_edits(&mut buffer, |buffer, index| { buffer.remove_char(index); });
+        let line_info = time_edits(&mut buffer, |buffer, index| {
+            let line = buffer.line_at_index(index);
+            buffer.line_info(line);
+        });
+
+        println!("{:>10} {:>11} ns {:>11} ns {:>11} ns", size, insert, remove, line_info);
+    }
+}
@@ -1,37 +1,35 @@
+use std::borrow::Cow;
+use std::cmp;
+use std::fmt;
+
+use rope::{Chunks, Rope};
+use unicode;
+
 #[derive(Clone)]
 pub struct Buffer {
-    buf: String,
-    line_info: Vec<LineInfo>, // [(index, line size)], note '\n' not included in line size
+    rope: Rope,
 }
 
 impl Buffer {
     pub fn new() -> Buffer {
         Buffer {
-            buf: "".to_string(),
-            line_info: build_line_info(""),
+            rope: Rope::new(),
         }
     }
 
     pub fn from_string(text: String) -> Buffer {
-        let line_info = build_line_info(text.as_str());
         Buffer {
-            buf: text,
-            line_info: line_info,
+            rope: Rope::from_string(text),
         }
     }
 
     pub fn insert(&mut self, buf_index: usize, text: &str) {
-        self.buf = self.buf[..buf_index].to_string() + text + &self.buf[buf_index..].to_string();
-
-        // TODO: optimize, we don't have to rebuild whole line_info array
-        self.line_info = build_line_info(self.buf.as_str());
+        self.rope.insert(buf_index, text);
     }
 
     pub fn insert_char(&mut self, buf_index: usize, c: char) {
-        self.buf.insert(buf_index, c);
-
-        // TODO: optimize, we don't have to rebuild whole line_info array
-        self.line_info = build_line_info(self.buf.as_str());
+        let mut bytes = [0u8; 4];
+        self.rope.insert(buf_index, c.encode_utf8(&mut bytes));
     }
 
     /// Remove all characters between the from and to cursors inclusively
@@ -41,49 +39,118 @@ impl Buffer {
         
         let (from, to) = (min(from, to), max(from, to));
         
-        let removed = self.buf[from..to+1].to_string();
-        self.buf = self.buf[..from].to_string() + &self.buf[to+1..].to_string();
-
-        // TODO: optimize, we don't have to rebuild whole line_info array
-        self.line_info = build_line_info(self.buf.as_str());
-
-        removed
+        self.rope.remove(from, to+1)
     }
 
     /// Remove a single character from the buffer and return it
     pub fn remove_char(&mut self, index: usize) -> char {
-        let c = self.buf.remove(index);
-
-        // TODO: optimize, we don't have to rebuild whole line_info array
-        self.line_info = build_line_info(self.buf.as_str());
-
+        let c = self.rope.char_at(index);
+        self.rope.remove(index, index + c.len_utf8());
         c
     }
 
-    /// Read-only access to line_info
-    pub fn line_info(&self) -> &Vec<LineInfo> {
-        &self.line_info
+    /// Position and length of the specified line
+    pub fn line_info(&self, line: usize) -> LineInfo {
+        let buf_index = self.rope.line_start(line);
+        let end =
+            if line + 1 < self.line_count() {
+                // Note '\n' not included in line size
+                self.rope.line_start(line + 1) - 1
+            } else {
+                self.rope.len()
+            };
+        LineInfo { buf_index: buf_index, length: end - buf_index }
+    }
+
+    pub fn line(&self, index: usize) -> Cow<str> {
+        let LineInfo { buf_index, length } = self.line_info(index);
+        self.rope.slice(buf_index, buf_index+length)
     }
 
-    pub fn line(&self, index: usize) -> &str {
-        let LineInfo { buf_index, length } = self.line_info[index];
-        &self.buf[buf_index..buf_index+length]
+    /// Line that the specified buffer index falls on
+    pub fn line_at_index(&self, buf_index: usize) -> usize {
+        self.rope.line_at(buf_index)
     }
 
     pub fn line_count(&self) -> usize {
-        self.line_info.len()
+        self.rope.newlines() + 1
     }
 
     pub fn len(&self) -> usize {
-        self.buf.len()
+        self.rope.len()
+    }
+
+    pub fn byte(&self, buf_index: usize) -> u8 {
+        self.rope.byte(buf_index)
+    }
+
+    /// Character starting at the specified buffer index
+    pub fn char_at(&self, buf_index: usize) -> char {
+        self.rope.char_at(buf_index)
     }
 
-    pub fn buffer(&self) -> &String {
-        &self.buf
+    /// Start of the grapheme cluster at or around buf_index
+    pub fn grapheme_start(&self, buf_index: usize) -> usize {
+        let line = self.line_at_index(buf_index);
+        let info = self.line_info(line);
+        info.buf_index + unicode::boundary_at(&self.line(line), buf_index - info.buf_index)
+    }
+
+    /// Start of the grapheme cluster after the one at buf_index. A line break is a cluster of
+    /// its own.
+    pub fn next_grapheme(&self, buf_index: usize) -> usize {
+        let line = self.line_at_index(buf_index);
+        let info = self.line_info(line);
+        if buf_index >= info.buf_index + info.length {
+            cmp::min(buf_index + 1, self.len())
+        } else {
+            info.buf_index + unicode::next_boundary(&self.line(line), buf_index - info.buf_index)
+        }
+    }
+
+    /// Start of the grapheme cluster before buf_index
+    pub fn prev_grapheme(&self, buf_index: usize) -> usize {
+        if buf_index == 0 {
+            return 0;
+        }
+        let line = self.line_at_index(buf_index);
+        let info = self.line_info(line);
+        if buf_index <= info.buf_index {
+            buf_index - 1
+        } else {
+            info.buf_index + unicode::prev_boundary(&self.line(line), buf_index - info.buf_index)
+        }
+    }
+
+    pub fn slice(&self, from: usize, to: usize) -> Cow<str> {
+        self.rope.slice(from, to)
+    }
+
+    /// Iterates over the buffer's contents in pieces, in order
+    pub fn chunks(&self) -> Chunks {
+        self.rope.chunks()
+    }
+
+    /// FNV-1a hash of the buffer's contents, stable across runs
+    pub fn hash(&self) -> u64 {
+        let mut hash = 0xcbf29ce484222325u64;
+        for chunk in self.chunks() {
+            for b in chunk.bytes() {
+                hash ^= b as u64;
+                hash = hash.wrapping_mul(0x100000001b3);
+            }
+        }
+        hash
     }
 
     pub fn clear(&mut self) {
-        self.buf.clear();
+        self.rope = Rope::new();
+    }
+}
+
+impl fmt::Display for Buffer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.rope.fmt(f)
     }
 }
 
@@ -93,30 +160,9 @@ pub struct LineInfo {
     pub length: usize,
 }
 
-impl LineInfo {
-    pub fn last_column(&self) -> usize {
-        if self.length > 0 {
-            self.length - 1
-        } else {
-            0
-        }
-    }
-}
-
-fn build_line_info(text: &str) -> Vec<LineInfo> {
-    let mut line_info = Vec::new();
-    let mut next_line_start = 0;
-    for (i, c) in text.chars().enumerate() {
-        if c == '\n' {
-            // Note '\n' not included in line_size
-            let length = i - next_line_start;
-            line_info.push(LineInfo { buf_index: next_line_start, length: length });
-            next_line_start = i+1;
-        }
-    }
-    let length = text.len() - next_line_start;
-    line_info.push(LineInfo { buf_index: next_line_start, length: length });
-    line_info
+#[cfg(test)]
+fn line_infos(buf: &Buffer) -> Vec<LineInfo> {
+    (0..buf.line_count()).map(|i| buf.line_info(i)).collect()
 }
 
 #[test]
@@ -124,8 +170,8 @@ fn buffer_insert_no_lines() {
     let mut buf = Buffer::from_string("helloworld!\nbye".to_string());
     buf.insert(5, ", ");
 
-    assert!(buf.buf.as_str() == "hello, world!\nbye");
-    assert!(buf.line_info == vec![LineInfo { buf_index: 0, length: 13 },
+    assert!(buf.to_string() == "hello, world!\nbye");
+    assert!(line_infos(&buf) == vec![LineInfo { buf_index: 0, length: 13 },
                                   LineInfo { buf_index: 14, length: 3 }]);
 }
 
@@ -134,8 +180,8 @@ fn buffer_insert_two_lines() {
     let mut buf = Buffer::from_string("hello\nbye".to_string());
     buf.insert(5, "s\nworld");
 
-    assert!(buf.buf.as_str() == "hellos\nworld\nbye");
-    assert!(buf.line_info == vec![LineInfo { buf_index: 0, length: 6 },
+    assert!(buf.to_string() == "hellos\nworld\nbye");
+    assert!(line_infos(&buf) == vec![LineInfo { buf_index: 0, length: 6 },
                                   LineInfo { buf_index: 7, length: 5 },
                                   LineInfo { buf_index: 13, length: 3 }]);
 }
@@ -145,9 +191,9 @@ fn buffer_remove_same_line() {
     let mut buf = Buffer::from_string("hello, world!\nbye".to_string());
     let removed = buf.remove(5, 6);
 
-    assert!(buf.buf.as_str() == "helloworld!\nbye");
+    assert!(buf.to_string() == "helloworld!\nbye");
     assert!(removed.as_str() == ", ");
-    assert!(buf.line_info == vec![LineInfo { buf_index: 0, length: 11 },
+    assert!(line_infos(&buf) == vec![LineInfo { buf_index: 0, length: 11 },
                                   LineInfo { buf_index: 12, length: 3 }]);
 }
 
@@ -156,9 +202,9 @@ fn buffer_remove_two_lines() {
     let mut buf = Buffer::from_string("hello, world!\nbye".to_string());
     let removed = buf.remove(5, 15);
 
-    assert!(buf.buf.as_str() == "helloe");
+    assert!(buf.to_string() == "helloe");
     assert!(removed.as_str() == ", world!\nby");
-    assert!(buf.line_info == vec![LineInfo { buf_index: 0, length: 6 }]);
+    assert!(line_infos(&buf) == vec![LineInfo { buf_index: 0, length: 6 }]);
 }
 
 #[test]
@@ -166,29 +212,103 @@ fn buffer_remove_multi_line() {
     let mut buf = Buffer::from_string("hello, world!\nbye\nhola".to_string());
     let removed = buf.remove(5, 19);
 
-    assert!(buf.buf.as_str() == "hellola");
+    assert!(buf.to_string() == "hellola");
     assert!(removed.as_str() == ", world!\nbye\nho");
-    assert!(buf.line_info == vec![LineInfo { buf_index: 0, length: 7 }]);
+    assert!(line_infos(&buf) == vec![LineInfo { buf_index: 0, length: 7 }]);
 }
 
 #[test]
-fn build_line_info_empty() {
-    let line_info = build_line_info("");
+fn buffer_line_info_empty() {
+    let line_info = line_infos(&Buffer::new());
 
     assert!(line_info == vec![LineInfo { buf_index: 0, length: 0 }]);
 }
 
 #[test]
-fn build_line_info_one() {
-    let line_info = build_line_info("hello");
+fn buffer_line_info_one() {
+    let line_info = line_infos(&Buffer::from_string("hello".to_string()));
 
     assert!(line_info == vec![LineInfo { buf_index: 0, length: 5 }]);
 }
 
 #[test]
-fn build_line_info_two() {
-    let line_info = build_line_info("hello\nfoo");
+fn buffer_line_info_two() {
+    let line_info = line_infos(&Buffer::from_string("hello\nfoo".to_string()));
 
     assert!(line_info == vec![LineInfo { buf_index: 0, length: 5 },
                               LineInfo { buf_index: 6, length: 3 }]);
 }
+
+#[test]
+fn buffer_hash_ignores_chunking() {
+    let mut buf = Buffer::from_string("hello world".to_string());
+    buf.remove(5, 10);
+    buf.insert(5, " world");
+
+    assert!(buf.hash() == Buffer::from_string("hello world".to_string()).hash());
+    assert!(buf.hash() != Buffer::from_string("hello worle".to_string()).hash());
+}
+
+#[test]
+fn buffer_line_spanning_chunks() {
+    let long_line: String = (0..5000).map(|_| 'a').collect();
+    let buf = Buffer::from_string(format!("hi\n{}\nbye", long_line));
+
+    assert!(buf.line_count() == 3);
+    assert!(buf.line(1) == long_line.as_str());
+    assert!(buf.line(2) == "bye");
+    assert!(buf.line_at_index(5003) == 1);
+    assert!(buf.line_at_index(5004) == 2);
+}
+
+#[test]
+fn buffer_random_unicode_edits() {
+    use unicode::Random;
+    use unicode_segmentation::UnicodeSegmentation;
+
+    let mut random = Random(0x2545f4914f6cdd1d);
+    let mut buf = Buffer::new();
+    let mut text = String::new();
+    for _ in 0..2000 {
+        // Edits go on char boundaries, even where that splits a grapheme cluster
+        let boundaries: Vec<usize> = text.char_indices().map(|(i, _)| i).chain(Some(text.len())).collect();
+        let from = boundaries[random.below(boundaries.len())];
+        let to = boundaries[random.below(boundaries.len())];
+        if random.below(3) > 0 || from == to {
+            let piece = random.sample();
+            buf.insert(from, piece);
+            text = format!("{}{}{}", &text[..from], piece, &text[from..]);
+        } else {
+            let (from, to) = (cmp::min(from, to), cmp::max(from, to));
+            assert!(buf.remove(from, to - 1) == &text[from..to]);
+            text = format!("{}{}", &text[..from], &text[to..]);
+        }
+        assert!(buf.to_string() == text);
+
+        let lines: Vec<&str> = text.split('\n').collect();
+        assert!(buf.line_count() == lines.len());
+        let line = random.below(lines.len());
+        let info = buf.line_info(line);
+        assert!(buf.line(line) == lines[line]);
+        assert!(info.length == lines[line].len());
+
+        // Stepping through a line either way lands on each grapheme cluster in turn
+        let (start, end) = (info.buf_index, info.buf_index + info.length);
+        let graphemes: Vec<usize> = lines[line].grapheme_indices(true).map(|(i, _)| start + i).collect();
+        let mut forward = Vec::new();
+        let mut i = start;
+        while i < end {
+            forward.push(i);
+            assert!(buf.grapheme_start(i) == i);
+            i = buf.next_grapheme(i);
+        }
+        assert!(forward == graphemes && i == end);
+        let mut backward = Vec::new();
+        while i > start {
+            i = buf.prev_grapheme(i);
+            backward.push(i);
+        }
+        backward.reverse();
+        assert!(backward == graphemes);
+    }
+}
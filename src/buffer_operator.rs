@@ -1,113 +1,523 @@
-use std::fs::File;
+use std::cmp;
+use std::fs::{self, File};
 use std::io;
 use std::io::{
     BufReader,
+    BufWriter,
     Read,
     Write,
 };
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
 use buffer::Buffer;
+use file_format::{self, FileFormat};
+use hex;
+use highlight::{self, Highlighter, Span};
 use operation::Operation;
+use pager::Pager;
+
+/// Files bigger than this are paged instead of read all at once, half of it at a time
+pub const LARGE_FILE_SIZE: u64 = 64 * 1024 * 1024;
+
+/// How much of the start of a file is looked at to tell whether it's binary
+const BINARY_CHECK_SIZE: u64 = 8000;
+
+/// The file as it was when it was last read or written, to notice when something else changes it
+#[derive(Clone, Debug, PartialEq)]
+pub struct DiskFile {
+    pub modified: Option<SystemTime>,
+    pub len: u64,
+    pub text: String, // The base when merging the file's changes with the buffer's
+    pub format: FileFormat,
+}
+
+impl DiskFile {
+    fn new(path: &Path, text: String, format: FileFormat) -> io::Result<DiskFile> {
+        let metadata = try!(fs::metadata(path));
+        Ok(DiskFile {
+            modified: metadata.modified().ok(),
+            len: metadata.len(),
+            text: text,
+            format: format,
+        })
+    }
+
+    /// Reads path again, or returns None if its modification time and size haven't changed
+    fn reread(&self, path: &Path) -> io::Result<Option<DiskFile>> {
+        let metadata = try!(fs::metadata(path));
+        if metadata.modified().ok() == self.modified && metadata.len() == self.len {
+            return Ok(None);
+        }
+
+        let mut bytes = Vec::new();
+        try!(File::open(path).and_then(|mut f| f.read_to_end(&mut bytes)));
+        let (text, format) = FileFormat::decode(&bytes);
+        Ok(Some(DiskFile {
+            modified: metadata.modified().ok(),
+            len: metadata.len(),
+            text: text,
+            format: format,
+        }))
+    }
+}
+
+/// How a file is shown in the buffer
+pub enum View {
+    Text,
+    Hex,          // The file's bytes as a hex dump, written back as the bytes
+    Paged(Pager), // A file too big to read at once, shown read-only a page at a time
+}
 
 pub struct BufferOperator {
     buffer: Buffer,
+    pub view: View,
+    highlighter: Highlighter,
 
     pub dirty: bool,
+    pub modified: bool, // Whether the buffer changed since it was last read or written
     pub file_path: Option<String>,
+    pub disk_file: Option<DiskFile>,
+    pub format: FileFormat, // How the text is written to the file
 }
 
 impl BufferOperator {
     pub fn new() -> BufferOperator {
         BufferOperator {
             buffer: Buffer::new(),
+            view: View::Text,
+            highlighter: Highlighter::new(None),
 
             dirty: true,
+            modified: false,
             file_path: None,
+            disk_file: None,
+            format: FileFormat::new(),
         }
     }
 
     pub fn from_string(text: String) -> BufferOperator {
         BufferOperator {
             buffer: Buffer::from_string(text),
+            view: View::Text,
+            highlighter: Highlighter::new(None),
 
             dirty: true,
+            modified: false,
             file_path: None,
+            disk_file: None,
+            format: FileFormat::new(),
         }
     }
 
     pub fn from_file(path: String) -> io::Result<BufferOperator> {
-        // Read the file into file_contents
+        BufferOperator::open(path, LARGE_FILE_SIZE)
+    }
+
+    /// Reads a file, as text unless it's binary or bigger than large_file_size. A file that's
+    /// both can't be opened.
+    pub fn open(path: String, large_file_size: u64) -> io::Result<BufferOperator> {
+        let len = try!(fs::metadata(&path)).len();
         let mut file = BufReader::new(try!(File::open(path.as_str())));
-        let mut file_contents = String::new();
-        try!(file.read_to_string(&mut file_contents));
+        let mut bytes = Vec::new();
+        try!((&mut file).take(BINARY_CHECK_SIZE).read_to_end(&mut bytes));
+        let binary = file_format::is_binary(&bytes);
+
+        if len > large_file_size {
+            if binary {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "binary file too big to open"));
+            }
+            let mut pager = try!(Pager::open(Path::new(&path), large_file_size / 2));
+            let text = try!(pager.read_around(0));
+            return Ok(BufferOperator {
+                buffer: Buffer::from_string(text),
+                view: View::Paged(pager),
+                highlighter: Highlighter::new(highlight::grammar_for_path(Path::new(&path))),
+
+                dirty: true,
+                modified: false,
+                file_path: Some(path),
+                disk_file: None,
+                format: FileFormat::new(),
+            });
+        }
+
+        // Read the rest of the file into file_contents, in whatever encoding and line endings it has
+        try!(file.read_to_end(&mut bytes));
+        let (file_contents, format) = FileFormat::decode(&bytes);
+        let disk_file = try!(DiskFile::new(Path::new(&path), file_contents.clone(), format));
+        if binary {
+            return Ok(BufferOperator {
+                buffer: Buffer::from_string(hex::dump(&bytes)),
+                view: View::Hex,
+                highlighter: Highlighter::new(None),
+
+                dirty: true,
+                modified: false,
+                file_path: Some(path),
+                disk_file: Some(disk_file),
+                format: FileFormat::new(),
+            });
+        }
 
         Ok(BufferOperator {
             buffer: Buffer::from_string(file_contents),
+            view: View::Text,
+            highlighter: Highlighter::new(highlight::grammar_for_path(Path::new(&path))),
 
             dirty: true,
+            modified: false,
             file_path: Some(path),
+            disk_file: Some(disk_file),
+            format: format,
         })
     }
 
-    pub fn write_file(&self) -> Result<(), String> {
-        match self.file_path {
-            Some(ref path) => {
-                let mut file =
-                    try!(File::open(path.as_str())
-                            .map_err(|e| format!("Failed to open buffer's file: {}", e)));
-                file.write_all(self.buffer.buffer().as_bytes());
-                Ok(())
+    /// Whether the buffer is only for looking at, because it's only part of the file
+    pub fn is_read_only(&self) -> bool {
+        match self.view {
+            View::Text | View::Hex => false,
+            View::Paged(_) => true,
+        }
+    }
+
+    pub fn is_hex(&self) -> bool {
+        match self.view {
+            View::Hex => true,
+            View::Text | View::Paged(_) => false,
+        }
+    }
+
+    pub fn is_paged(&self) -> bool {
+        match self.view {
+            View::Paged(_) => true,
+            View::Text | View::Hex => false,
+        }
+    }
+
+    /// Switches between showing the file's bytes as text and as a hex dump. The bytes are what
+    /// the buffer would be written as, and are read back like a file's.
+    pub fn toggle_hex(&mut self) -> io::Result<()> {
+        let text =
+            match self.view {
+                View::Text => {
+                    let mut bytes = self.format.bom_bytes().to_vec();
+                    for chunk in self.buffer.chunks() {
+                        bytes.extend(try!(self.format.encode(chunk)));
+                    }
+                    self.view = View::Hex;
+                    hex::dump(&bytes)
+                },
+                View::Hex => {
+                    let bytes = try!(self.hex_bytes());
+                    let (text, format) = FileFormat::decode(&bytes);
+                    self.format = format;
+                    self.view = View::Text;
+                    text
+                },
+                View::Paged(_) => {
+                    return Err(io::Error::new(io::ErrorKind::Other, "the buffer is read-only"));
+                },
+            };
+        self.buffer = Buffer::from_string(text);
+        self.highlighter.edited(0);
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Reads the page around pos, a place in the whole file, into the buffer of a paged file.
+    /// Returns where the page starts in the file.
+    pub fn read_page(&mut self, pos: u64) -> io::Result<u64> {
+        let (text, start) =
+            match self.view {
+                View::Paged(ref mut pager) => (try!(pager.read_around(pos)), pager.start),
+                _ => { return Ok(0); },
+            };
+        self.buffer = Buffer::from_string(text);
+        self.highlighter.edited(0);
+        self.dirty = true;
+        Ok(start)
+    }
+
+    /// Writes the buffer to path without ever leaving a half written file behind: the text goes
+    /// to a temporary file next to it first, which then replaces the original. The original's
+    /// permissions are kept, and with backup set it's copied to `path~` beforehand. The text is
+    /// written in the buffer's format.
+    pub fn write_file(&mut self, path: &Path, backup: bool) -> io::Result<()> {
+        if self.is_read_only() {
+            return Err(io::Error::new(io::ErrorKind::Other, "the buffer is read-only"));
+        }
+
+        // Write through symlinks instead of replacing them
+        let path = fs::canonicalize(path).unwrap_or(path.to_path_buf());
+        let metadata = fs::metadata(&path).ok();
+
+        if backup && metadata.is_some() {
+            try!(fs::copy(&path, backup_file_path(&path)));
+        }
+
+        let temp_path = temp_file_path(&path);
+        let result = self.write_temp_file(&temp_path, metadata.as_ref())
+                         .and_then(|_| fs::rename(&temp_path, &path));
+        if result.is_err() {
+            let _ = fs::remove_file(&temp_path);
+        }
+        try!(result);
+
+        // The file's text is what's compared to notice changes, not the dump
+        let (text, format) =
+            if self.is_hex() {
+                FileFormat::decode(&try!(self.hex_bytes()))
+            } else {
+                (self.buffer.to_string(), self.format)
+            };
+        self.disk_file = Some(try!(DiskFile::new(&path, text, format)));
+        self.modified = false;
+        Ok(())
+    }
+
+    /// Checks whether something else changed the file since it was last read or written. Returns
+    /// the file as it is now if its text is different.
+    pub fn check_disk_file(&mut self, path: &Path) -> io::Result<Option<DiskFile>> {
+        let changed =
+            match self.disk_file {
+                Some(ref disk_file) => try!(disk_file.reread(path)),
+                None => None,
+            };
+        match changed {
+            Some(ref disk_file) if self.disk_file.as_ref().map_or(false, |f| f.text == disk_file.text &&
+                                                                          f.format == disk_file.format) => {
+                // Only touched, remember the new time so the file isn't read every time
+                self.disk_file = changed.clone();
+                Ok(None)
             },
-            None => Err("Buffer file_path is None".to_string()),
+            _ => Ok(changed),
         }
     }
 
+    /// A disk file's text the way the buffer shows it, which in hex view is a dump of its bytes
+    pub fn shown_text(&self, disk_file: &DiskFile) -> io::Result<String> {
+        if !self.is_hex() {
+            return Ok(disk_file.text.clone());
+        }
+        let mut bytes = disk_file.format.bom_bytes().to_vec();
+        bytes.extend(try!(disk_file.format.encode(&disk_file.text)));
+        Ok(hex::dump(&bytes))
+    }
+
+    /// The bytes a hex dump buffer stands for
+    fn hex_bytes(&self) -> io::Result<Vec<u8>> {
+        hex::parse(&self.buffer.to_string()).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn write_temp_file(&self, temp_path: &Path, metadata: Option<&fs::Metadata>) -> io::Result<()> {
+        let file = try!(File::create(temp_path));
+        if let Some(metadata) = metadata {
+            try!(fs::set_permissions(temp_path, metadata.permissions()));
+        }
+
+        let mut writer = BufWriter::new(file);
+        if self.is_hex() {
+            let bytes = try!(self.hex_bytes());
+            try!(writer.write_all(&bytes));
+        } else {
+            try!(writer.write_all(self.format.bom_bytes()));
+            for chunk in self.buffer.chunks() {
+                try!(writer.write_all(&try!(self.format.encode(chunk))));
+            }
+        }
+        try!(writer.flush());
+        writer.get_ref().sync_all()
+    }
+
     pub fn buffer(&self) -> &Buffer {
         &self.buffer
     }
 
-    pub fn buffer_bytes(&self) -> &[u8] {
-        &self.buffer.buffer().as_bytes()
-    }
-    
+    /// The buffer can be changed anywhere through this, so it's highlighted again from the top
     pub fn buffer_mut(&mut self) -> &mut Buffer {
+        self.highlighter.edited(0);
         &mut self.buffer
     }
 
+    ////////////////////////////////////////////////////////////////////////////////////////////////
+    // Highlighting stuff
+
+    /// The highlighted spans of a line. Hex dumps aren't highlighted.
+    pub fn highlight(&self, line: usize) -> Vec<Span> {
+        if self.is_hex() {
+            return Vec::new();
+        }
+        self.highlighter.line(&self.buffer, line)
+    }
+
+    pub fn grammar_name(&self) -> Option<&'static str> {
+        self.highlighter.grammar_name()
+    }
+
+    /// Highlights the buffer with the grammar called name, or not at all with None. Returns false
+    /// if there's no such grammar.
+    pub fn set_grammar(&mut self, name: Option<&str>) -> bool {
+        let grammar =
+            match name {
+                Some(name) => match highlight::grammar(name) { Some(g) => Some(g), None => { return false; } },
+                None => None,
+            };
+        self.highlighter.set_grammar(grammar);
+        true
+    }
+
+    /// Forgets the highlighting from the line with index in it on, before the text changes there
+    fn edited(&mut self, index: u64) {
+        let line = self.buffer.line_at_index(index as usize);
+        self.highlighter.edited(line);
+    }
+
     ////////////////////////////////////////////////////////////////////////////////////////////////
     // Operation stuff
 
     pub fn do_operation(&mut self, operation: &Operation) {
         match *operation {
+            Operation::InsertChar(index, _) | Operation::Insert(index, _) |
+            Operation::RemoveChar(index, _) => { self.edited(index); },
+            Operation::Remove(start, end, _) => { self.edited(cmp::min(start, end)); },
+        }
+        match *operation {
+            Operation::Insert(_, ref text) | Operation::Remove(_, _, ref text) if text.is_empty() => { },
             Operation::InsertChar(index, c) => { self.buffer.insert_char(index as usize, c); },
             Operation::Insert(index, ref text) => { self.buffer.insert(index as usize, text.as_str()); },
             Operation::RemoveChar(index, _) => { self.buffer.remove_char(index as usize); },
             Operation::Remove(start, end, _) => { self.buffer.remove(start as usize, end as usize); },
         }
         self.dirty = true;
+        self.modified = true;
     }
 
     pub fn insert_char(&mut self, index: u64, c: char) -> Operation {
         self.dirty = true;
+        self.modified = true;
+        self.edited(index);
         self.buffer.insert_char(index as usize, c);
         Operation::InsertChar(index, c)
     }
 
     pub fn insert(&mut self, index: u64, text: String) -> Operation {
         self.dirty = true;
+        self.modified = true;
+        self.edited(index);
         self.buffer.insert(index as usize, text.as_str());
         Operation::Insert(index, text)
     }
 
     pub fn remove_char(&mut self, index: u64) -> Operation {
         self.dirty = true;
+        self.modified = true;
+        self.edited(index);
         let c = self.buffer.remove_char(index as usize);
         Operation::RemoveChar(index, c)
     }
 
     pub fn remove(&mut self, from: u64, to: u64) -> Operation {
         self.dirty = true;
+        self.modified = true;
+        self.edited(cmp::min(from, to));
         let text = self.buffer.remove(from as usize, to as usize);
         Operation::Remove(from, to, text)
     }
 }
+
+/// Where the previous version of a file is kept when backups are on, e.g. `dir/foo.rs~`
+pub fn backup_file_path(file_path: &Path) -> PathBuf {
+    let file_name = file_path.file_name().map_or("".into(), |n| n.to_string_lossy());
+    file_path.with_file_name(format!("{}~", file_name))
+}
+
+/// Where a file is written before it replaces the original, e.g. `dir/.foo.rs.ted-save`
+fn temp_file_path(file_path: &Path) -> PathBuf {
+    let file_name = file_path.file_name().map_or("".into(), |n| n.to_string_lossy());
+    file_path.with_file_name(format!(".{}.ted-save", file_name))
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+
+#[test]
+fn buffer_operator_write_file() {
+    use std::env;
+
+    let path = env::temp_dir().join("ted_write_file_test.txt");
+    let backup_path = backup_file_path(&path);
+    let _ = fs::remove_file(&backup_path);
+    {
+        let mut file = File::create(&path).unwrap();
+        file.write_all(b"old").unwrap();
+    }
+    let mut permissions = fs::metadata(&path).unwrap().permissions();
+    permissions.set_readonly(true);
+    fs::set_permissions(&path, permissions).unwrap();
+
+    let mut buf_op = BufferOperator::from_string("new\ntext".to_string());
+    buf_op.insert(0, "a ".to_string());
+    assert!(buf_op.modified);
+    buf_op.write_file(&path, true).unwrap();
+    assert!(!buf_op.modified);
+
+    let mut text = String::new();
+    File::open(&path).unwrap().read_to_string(&mut text).unwrap();
+    assert!(text == "a new\ntext");
+    assert!(fs::metadata(&path).unwrap().permissions().readonly());
+    assert!(!temp_file_path(&path).exists());
+
+    text.clear();
+    File::open(&backup_path).unwrap().read_to_string(&mut text).unwrap();
+    assert!(text == "old");
+
+    // Let the next run overwrite the files
+    for path in &[&path, &backup_path] {
+        let mut permissions = fs::metadata(path).unwrap().permissions();
+        permissions.set_readonly(false);
+        fs::set_permissions(path, permissions).unwrap();
+    }
+}
+
+#[test]
+fn buffer_operator_keeps_file_format() {
+    use std::env;
+
+    let path = env::temp_dir().join("ted_file_format_test.txt");
+    File::create(&path).unwrap().write_all(b"caf\xE9\r\nend\r\n").unwrap();
+
+    let mut buf_op = BufferOperator::from_file(path.display().to_string()).unwrap();
+    assert!(buf_op.buffer().to_string() == "café\nend\n");
+    buf_op.insert(0, "à ".to_string());
+    buf_op.write_file(&path, false).unwrap();
+
+    let mut bytes = Vec::new();
+    File::open(&path).unwrap().read_to_end(&mut bytes).unwrap();
+    assert!(bytes == b"\xE0 caf\xE9\r\nend\r\n");
+
+    // Text the encoding can't hold leaves the file alone
+    buf_op.insert(0, "日".to_string());
+    assert!(buf_op.write_file(&path, false).is_err());
+    assert!(buf_op.modified);
+    assert!(!temp_file_path(&path).exists());
+}
+
+#[test]
+fn buffer_operator_keeps_undecodable_file() {
+    use std::env;
+
+    // A UTF-8 byte order mark with Latin-1 after it is written back as it was, not with
+    // replacement characters
+    let path = env::temp_dir().join("ted_undecodable_file_test.txt");
+    let bytes = b"\xEF\xBB\xBFcaf\xE9\r\n";
+    File::create(&path).unwrap().write_all(bytes).unwrap();
+
+    let mut buf_op = BufferOperator::from_file(path.display().to_string()).unwrap();
+    assert!(!buf_op.buffer().to_string().contains('\u{FFFD}'));
+    buf_op.write_file(&path, false).unwrap();
+
+    let mut written = Vec::new();
+    File::open(&path).unwrap().read_to_end(&mut written).unwrap();
+    assert!(written == bytes);
+}
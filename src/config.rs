@@ -0,0 +1,336 @@
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use toml;
+
+use highlight::Kind;
+use keymap;
+
+/// User settings, read from a TOML file like:
+///
+/// ```toml
+/// left_column = 3
+/// tab_width = 4
+/// expand_tab = true
+/// backup = false
+/// wrap = false
+///
+/// [colors]
+/// normal = "blue"
+/// insert = 9
+///
+/// [theme]
+/// comment = "cyan"
+/// keyword = 208
+///
+/// [keys.insert]
+/// jk = "<Esc>"
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    pub left_column: usize,  // Columns left of the text
+    pub right_column: usize, // Columns right of the text
+    pub tab_width: usize,
+    pub expand_tab: bool,    // Whether tab and > insert spaces instead of tabs
+    pub backup: bool,        // Whether saving keeps the previous version of the file as file~
+    pub wrap: bool,          // Whether long lines continue on the next rows instead of scrolling sideways
+    pub colors: Colors,
+    pub theme: Theme,
+    pub mappings: Vec<Mapping>,
+}
+
+/// ANSI color numbers for each part of the screen
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Colors {
+    pub text: u8,
+    pub message: u8,
+    pub error: u8,
+    pub normal: u8,  // Mode name in the status line for each mode
+    pub insert: u8,
+    pub command: u8,
+    pub visual: u8,
+    pub search: u8, // Background of search matches
+}
+
+/// ANSI color numbers for each kind of text syntax highlighting picks out
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Theme {
+    pub comment: u8,
+    pub string: u8,
+    pub number: u8,
+    pub keyword: u8,
+    pub type_name: u8, // type in the [theme] table
+    pub variable: u8,
+    pub heading: u8,
+    pub emphasis: u8,
+    pub code: u8,
+}
+
+impl Theme {
+    pub fn color(&self, kind: Kind) -> u8 {
+        match kind {
+            Kind::Comment => self.comment,
+            Kind::String => self.string,
+            Kind::Number => self.number,
+            Kind::Keyword => self.keyword,
+            Kind::Type => self.type_name,
+            Kind::Variable => self.variable,
+            Kind::Heading => self.heading,
+            Kind::Emphasis => self.emphasis,
+            Kind::Code => self.code,
+        }
+    }
+}
+
+/// A key mapping from one of the [keys.<mode>] tables, like a :map command
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mapping {
+    pub command: &'static str, // The :map command that makes the mapping
+    pub keys: String,
+    pub mapped: String,
+}
+
+const KEY_TABLES: &'static [(&'static str, &'static str)] = &[
+    ("all", "map"),
+    ("normal", "nmap"),
+    ("operator_pending", "omap"),
+    ("insert", "imap"),
+    ("visual", "vmap"),
+];
+
+const COLOR_NAMES: &'static [&'static str] = &[
+    "black", "red", "green", "yellow", "blue", "magenta", "cyan", "white",
+];
+
+impl Config {
+    pub fn new() -> Config {
+        Config {
+            left_column: 3,
+            right_column: 3,
+            tab_width: 4,
+            expand_tab: true,
+            backup: false,
+            wrap: false,
+            colors: Colors {
+                text: 7,
+                message: 7,
+                error: 1,
+                normal: 4,
+                insert: 1,
+                command: 2,
+                visual: 3,
+                search: 3,
+            },
+            theme: Theme {
+                comment: 4,
+                string: 2,
+                number: 5,
+                keyword: 3,
+                type_name: 6,
+                variable: 1,
+                heading: 3,
+                emphasis: 5,
+                code: 2,
+            },
+            mappings: Vec::new(),
+        }
+    }
+
+    pub fn from_file(path: &Path) -> Result<Config, String> {
+        let mut text = String::new();
+        try!(File::open(path).and_then(|mut f| f.read_to_string(&mut text))
+                             .map_err(|e| format!("Failed to read {}: {}", path.display(), e)));
+        Config::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Reads settings from TOML text. Settings that aren't in the text keep their defaults.
+    pub fn parse(text: &str) -> Result<Config, String> {
+        let mut parser = toml::Parser::new(text);
+        let table =
+            match parser.parse() {
+                Some(table) => table,
+                None => {
+                    let error = &parser.errors[0];
+                    let (line, _) = parser.to_linecol(error.lo);
+                    return Err(format!("line {}: {}", line + 1, error.desc));
+                },
+            };
+
+        let mut config = Config::new();
+        for (key, value) in &table {
+            match key.as_str() {
+                "left_column" => { config.left_column = try!(get_usize(key, value)); },
+                "right_column" => { config.right_column = try!(get_usize(key, value)); },
+                "tab_width" => {
+                    config.tab_width = try!(get_usize(key, value));
+                    if config.tab_width == 0 {
+                        return Err("tab_width must be at least 1".to_string());
+                    }
+                },
+                "expand_tab" => {
+                    config.expand_tab = try!(value.as_bool().ok_or(format!("{} must be true or false", key)));
+                },
+                "backup" => {
+                    config.backup = try!(value.as_bool().ok_or(format!("{} must be true or false", key)));
+                },
+                "wrap" => {
+                    config.wrap = try!(value.as_bool().ok_or(format!("{} must be true or false", key)));
+                },
+                "colors" => { try!(config.parse_colors(try!(get_table(key, value)))); },
+                "theme" => { try!(config.parse_theme(try!(get_table(key, value)))); },
+                "keys" => { try!(config.parse_keys(try!(get_table(key, value)))); },
+                _ => { return Err(format!("Unknown setting: {}", key)); },
+            }
+        }
+        Ok(config)
+    }
+
+    /// Text inserted for one level of indentation
+    pub fn indent(&self) -> String {
+        if self.expand_tab {
+            (0..self.tab_width).map(|_| ' ').collect()
+        } else {
+            "\t".to_string()
+        }
+    }
+
+    fn parse_colors(&mut self, table: &toml::Table) -> Result<(), String> {
+        for (key, value) in table {
+            let color = try!(parse_color(&format!("colors.{}", key), value));
+            match key.as_str() {
+                "text" => { self.colors.text = color; },
+                "message" => { self.colors.message = color; },
+                "error" => { self.colors.error = color; },
+                "normal" => { self.colors.normal = color; },
+                "insert" => { self.colors.insert = color; },
+                "command" => { self.colors.command = color; },
+                "visual" => { self.colors.visual = color; },
+                "search" => { self.colors.search = color; },
+                _ => { return Err(format!("Unknown color: colors.{}", key)); },
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_theme(&mut self, table: &toml::Table) -> Result<(), String> {
+        for (key, value) in table {
+            let color = try!(parse_color(&format!("theme.{}", key), value));
+            match key.as_str() {
+                "comment" => { self.theme.comment = color; },
+                "string" => { self.theme.string = color; },
+                "number" => { self.theme.number = color; },
+                "keyword" => { self.theme.keyword = color; },
+                "type" => { self.theme.type_name = color; },
+                "variable" => { self.theme.variable = color; },
+                "heading" => { self.theme.heading = color; },
+                "emphasis" => { self.theme.emphasis = color; },
+                "code" => { self.theme.code = color; },
+                _ => { return Err(format!("Unknown color: theme.{}", key)); },
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_keys(&mut self, table: &toml::Table) -> Result<(), String> {
+        for (mode, value) in table {
+            let command =
+                match KEY_TABLES.iter().find(|&&(name, _)| name == mode.as_str()) {
+                    Some(&(_, command)) => command,
+                    None => { return Err(format!("Unknown mode: keys.{}", mode)); },
+                };
+
+            let full_name = format!("keys.{}", mode);
+            for (keys, mapped) in try!(get_table(&full_name, value)) {
+                let mapped =
+                    try!(mapped.as_str().ok_or(format!("{}.{} must be a string of keys", full_name, keys)));
+
+                // Catch bad key names now rather than when the mapping is made
+                try!(keymap::parse_keys(keys).map_err(|e| format!("{}: {}", full_name, e)));
+                try!(keymap::parse_keys(mapped).map_err(|e| format!("{}.{}: {}", full_name, keys, e)));
+
+                self.mappings.push(Mapping {
+                    command: command,
+                    keys: keys.clone(),
+                    mapped: mapped.to_string(),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Where the config file is looked for at startup
+pub fn default_path() -> Option<PathBuf> {
+    match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => Some(PathBuf::from(dir)),
+        None => env::home_dir().map(|home| home.join(".config")),
+    }.map(|dir| dir.join("ted").join("config.toml"))
+}
+
+fn get_usize(key: &str, value: &toml::Value) -> Result<usize, String> {
+    match value.as_integer() {
+        Some(n) if n >= 0 => Ok(n as usize),
+        _ => Err(format!("{} must be a positive number", key)),
+    }
+}
+
+fn get_table<'a>(key: &str, value: &'a toml::Value) -> Result<&'a toml::Table, String> {
+    value.as_table().ok_or(format!("{} must be a table", key))
+}
+
+/// A color name like "red", or an ANSI color number from 0 to 255
+fn parse_color(key: &str, value: &toml::Value) -> Result<u8, String> {
+    match *value {
+        toml::Value::Integer(n) if n >= 0 && n <= 255 => Ok(n as u8),
+        toml::Value::String(ref name) => {
+            COLOR_NAMES.iter().position(|&c| c == name.as_str())
+                              .map(|c| c as u8)
+                              .ok_or(format!("Unknown color for {}: {}", key, name))
+        },
+        _ => Err(format!("{} must be a color name or a number from 0 to 255", key)),
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+
+#[test]
+fn config_parse() {
+    let config = Config::parse("left_column = 5\n\
+                                expand_tab = false\n\
+                                backup = true\n\
+                                wrap = true\n\
+                                [colors]\n\
+                                normal = \"green\"\n\
+                                insert = 208\n\
+                                [theme]\n\
+                                type = \"red\"\n\
+                                [keys.insert]\n\
+                                jk = \"<Esc>\"\n").unwrap();
+
+    assert!(config.left_column == 5);
+    assert!(config.right_column == 3);
+    assert!(config.indent() == "\t");
+    assert!(config.backup);
+    assert!(config.wrap);
+    assert!(config.colors.normal == 2);
+    assert!(config.colors.insert == 208);
+    assert!(config.theme.color(Kind::Type) == 1);
+    assert!(config.theme.comment == 4);
+    assert!(config.mappings == vec![Mapping { command: "imap", keys: "jk".to_string(),
+                                              mapped: "<Esc>".to_string() }]);
+}
+
+#[test]
+fn config_parse_errors() {
+    assert!(Config::parse("left_column = ").unwrap_err().starts_with("line 1:"));
+    assert!(Config::parse("tab_width = \"4\"") == Err("tab_width must be a positive number".to_string()));
+    assert!(Config::parse("foo = 1") == Err("Unknown setting: foo".to_string()));
+    assert!(Config::parse("[colors]\ntext = \"mauve\"") ==
+            Err("Unknown color for colors.text: mauve".to_string()));
+    assert!(Config::parse("[keys.replace]\nx = \"y\"") == Err("Unknown mode: keys.replace".to_string()));
+    assert!(Config::parse("[keys.normal]\nx = \"<Nope>\"") ==
+            Err("keys.normal.x: Unknown key: <Nope>".to_string()));
+}
@@ -1,6 +1,12 @@
+use std::cmp;
+
 use buffer::Buffer;
 use operation::Operation;
+use unicode;
 
+/// A position in a buffer. buf_index is in bytes, always at the start of a grapheme cluster.
+/// column is the display column the cursor would like to be at, kept while moving through shorter
+/// lines or lines with wide characters.
 #[derive(Copy, Clone, PartialEq, Eq)]
 pub struct Cursor {
     pub line: u64,
@@ -29,29 +35,15 @@ impl Cursor {
 
     /// Moves the cursor left and returns the new index within the buffer
     pub fn move_left(&mut self, buffer: &Buffer) {
-        if self.column > 0 {
-            // Cursor can move to the left, need to determine if it's past the current line, though.
-            if self.column <= buffer.line_info()[self.line as usize].length as u64 {
-                self.column -= 1;
-            } else if buffer.line_info()[self.line as usize].length >= 1 {
-                self.column = (buffer.line_info()[self.line as usize].length - 1) as u64;
-            } else if self.line > 0 {
-                // column = 0, so cursor is at the beginning of the line, move to previous line
-                self.line -= 1;
-                if buffer.line_info()[self.line as usize].length > 0 {
-                    self.column = buffer.line_info()[self.line as usize].length as u64;
-                } else {
-                    self.column = 0;
-                }
-            }
+        let line = buffer.line(self.line as usize);
+        let offset = self.line_offset(buffer);
+        if offset > 0 {
+            // Onto the grapheme before the cursor
+            self.column = unicode::width(&line[..unicode::prev_boundary(&line, offset)]) as u64;
         } else if self.line > 0 {
             // Cursor is at the beginning of the line, move to previous line
             self.line -= 1;
-            if buffer.line_info()[self.line as usize].length > 0 {
-                self.column = buffer.line_info()[self.line as usize].length as u64;
-            } else {
-                self.column = 0;
-            }
+            self.column = unicode::width(&buffer.line(self.line as usize)) as u64;
         }
 
         self.calculate_index(buffer);
@@ -59,10 +51,11 @@ impl Cursor {
 
     /// Moves the cursor right and returns the new index within the buffer
     pub fn move_right(&mut self, buffer: &Buffer) {
-        if buffer.line_info()[self.line as usize].length > 0 &&
-           self.column < buffer.line_info()[self.line as usize].length as u64 {
+        let line = buffer.line(self.line as usize);
+        let offset = self.line_offset(buffer);
+        if offset < line.len() {
             // Cursor can move to the right
-            self.column += 1;
+            self.column = unicode::width(&line[..unicode::next_boundary(&line, offset)]) as u64;
         } else if self.line < (buffer.line_count() - 1) as u64 {
             // Cursor can't move right, move to next line
             self.line += 1;
@@ -72,11 +65,73 @@ impl Cursor {
         self.calculate_index(buffer);
     }
 
-    /// Calculates the position to display the cursor at
-    pub fn get_display_xy(&self, buffer: &Buffer) -> (u64, u64) {
-        let line_info = buffer.line_info()[self.line as usize];
+    /// Moves the cursor down a row of the screen, where lines are wrapped at width columns
+    pub fn move_display_down(&mut self, buffer: &Buffer, width: u64) {
+        let line = buffer.line(self.line as usize);
+        let rows = unicode::wrap_rows(&line, width as usize);
+        let (row, x) = self.display_row(&line, &rows, self.line_offset(buffer));
+        if row + 1 < rows.len() {
+            // The line goes on in the next row
+            self.column = unicode::width(&line[..column_in_row(&line, rows[row + 1], x)]) as u64;
+        } else if self.line < (buffer.line_count() - 1) as u64 {
+            self.line += 1;
+            let line = buffer.line(self.line as usize);
+            let rows = unicode::wrap_rows(&line, width as usize);
+            self.column = unicode::width(&line[..column_in_row(&line, rows[0], x)]) as u64;
+        }
+
+        self.calculate_index(buffer);
+    }
+
+    /// Moves the cursor up a row of the screen, where lines are wrapped at width columns
+    pub fn move_display_up(&mut self, buffer: &Buffer, width: u64) {
+        let line = buffer.line(self.line as usize);
+        let rows = unicode::wrap_rows(&line, width as usize);
+        let (row, x) = self.display_row(&line, &rows, self.line_offset(buffer));
+        if row > 0 {
+            self.column = unicode::width(&line[..column_in_row(&line, rows[row - 1], x)]) as u64;
+        } else if self.line > 0 {
+            // Onto the last row of the line above
+            self.line -= 1;
+            let line = buffer.line(self.line as usize);
+            let rows = unicode::wrap_rows(&line, width as usize);
+            self.column = unicode::width(&line[..column_in_row(&line, rows[rows.len() - 1], x)]) as u64;
+        }
 
-        (self.buf_index - (line_info.buf_index as u64), self.line)
+        self.calculate_index(buffer);
+    }
+
+    /// Which of a line's wrapped rows the byte offset is on, and the display column within it
+    fn display_row(&self, line: &str, rows: &[(usize, usize)], offset: usize) -> (usize, usize) {
+        let row = rows.iter().rposition(|&(start, _)| start <= offset).unwrap_or(0);
+        (row, unicode::width(&line[rows[row].0..offset]))
+    }
+
+    /// Where the cursor is in its line, in bytes
+    fn line_offset(&self, buffer: &Buffer) -> usize {
+        let line_info = buffer.line_info(self.line as usize);
+        cmp::min((self.buf_index as usize).saturating_sub(line_info.buf_index), line_info.length)
+    }
+
+    /// Calculates the position to display the cursor at, from the top left of the text shown.
+    /// scroll is the first line shown. Lines are either scrolled left columns sideways, or with
+    /// a wrap width, go on in the rows below. Wide characters take two columns.
+    pub fn get_display_xy(&self, buffer: &Buffer, scroll: u64, left: u64, wrap: Option<u64>) -> (u64, u64) {
+        let line_info = buffer.line_info(self.line as usize);
+        let line = buffer.line(self.line as usize);
+        let column = cmp::min((self.buf_index - line_info.buf_index as u64) as usize, line.len());
+
+        match wrap {
+            None => ((unicode::width(&line[..column]) as u64).saturating_sub(left), self.line.saturating_sub(scroll)),
+            Some(width) => {
+                let rows_above: usize =
+                    (scroll..self.line).map(|l| unicode::wrap_rows(&buffer.line(l as usize), width as usize).len())
+                                       .sum();
+                let rows = unicode::wrap_rows(&line, width as usize);
+                let row = rows.iter().rposition(|&(start, _)| start <= column).unwrap_or(0);
+                (unicode::width(&line[rows[row].0..column]) as u64, (rows_above + row) as u64)
+            },
+        }
     }
 
     /// Adjusts cursor according to operation
@@ -88,7 +143,7 @@ impl Cursor {
                     if c == '\n' {
                         self.line += 1;
                     }
-                    self.buf_index += 1;
+                    self.buf_index += c.len_utf8() as u64;
                     self.calculate_column(buffer);
                 }
             },
@@ -108,7 +163,7 @@ impl Cursor {
                         // Handle special newline case
                         self.line -= 1;
                     }
-                    self.buf_index -= 1;
+                    self.buf_index = self.buf_index.saturating_sub(c.len_utf8() as u64);
                     self.calculate_column(buffer);
                 }
             },
@@ -136,55 +191,32 @@ impl Cursor {
 
     /// Calculates the cursor's index within the specified buffer based on line and column
     pub fn calculate_index(&mut self, buffer: &Buffer) {
-        use std::cmp;
-
-        let line_info = buffer.line_info()[self.line as usize];
-        self.buf_index =
-            if line_info.length > 0 {
-                line_info.buf_index + cmp::min(line_info.length, self.column as usize)
-            } else {
-                line_info.buf_index
-            } as u64;
+        let line_info = buffer.line_info(self.line as usize);
+        let line = buffer.line(self.line as usize);
+        self.buf_index = (line_info.buf_index + unicode::index_at_column(&line, self.column as usize)) as u64;
     }
 
     /// Calculates the cursor's column within the specified buffer based on line and buf_index
     pub fn calculate_column(&mut self, buffer: &Buffer) {
-        let line_info = buffer.line_info()[self.line as usize];
-        self.column = self.buf_index - line_info.buf_index as u64;
+        let line = buffer.line(self.line as usize);
+        self.column = unicode::width(&line[..self.line_offset(buffer)]) as u64;
     }
 
     /// Calculates the cursor's line and column within the specified buffer based on buf_index
     pub fn calculate_pos(&mut self, buffer: &Buffer) {
-        let mut start = 0;
-        let mut stop = buffer.line_info().len() - 1;
-        // Binary search for correct line
-        while start != stop {
-            let mid_line_index = (start + stop) / 2;
-            let mid_buf_index = buffer.line_info()[mid_line_index].buf_index;
-            if mid_buf_index > self.buf_index as usize {
-                stop = mid_line_index - 1;
-            } else if mid_buf_index < self.buf_index as usize {
-                if buffer.line_info()[mid_line_index + 1].buf_index > self.buf_index as usize {
-                    // line at mid_line_index is less than buf_index, line at mid_line_index+1 is
-                    // more than buf_index. It's the line at mid_line_index.
-                    start = mid_line_index;
-                    stop = mid_line_index;
-                } else if buffer.line_info()[mid_line_index + 1].buf_index < self.buf_index as usize {
-                    // Line after mid_line_index has buf_index < self.buf_index also, so we can
-                    // eliminate line at mid_line_index.
-                    start = mid_line_index + 1;
-                } else {
-                    // buf_index at mid_line_index + 1 == self.buf_index
-                    start = mid_line_index + 1;
-                    stop = mid_line_index + 1;
-                }
-            } else {
-                // If mid_buf_index == self.buf_index, it's the line at mid_line_index
-                start = mid_line_index;
-                stop = mid_line_index;
-            }
-        }
-        self.line = start as u64;
+        self.line = buffer.line_at_index(self.buf_index as usize) as u64;
         self.calculate_column(buffer);
     }
 }
+
+/// Start of the grapheme cluster of a wrapped row that covers display column x. Past the end of
+/// a row that the line goes on from, that's the row's last grapheme cluster.
+fn column_in_row(line: &str, row: (usize, usize), x: usize) -> usize {
+    let (start, end) = row;
+    let index = start + unicode::index_at_column(&line[start..end], x);
+    if index == end && end < line.len() {
+        unicode::prev_boundary(line, end)
+    } else {
+        index
+    }
+}
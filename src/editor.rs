@@ -1,97 +1,123 @@
+use std::cmp;
 use std::error::Error;
 use std::default::Default;
 use std::io;
-use std::io::{Write, Stdout, Stdin, stdout};
+use std::io::{Stdout, Stdin, stdout};
+#[cfg(test)]
+use std::io::Write;
 
-use termion::{self, AsyncReader, async_stdin, color, cursor, style};
+use termion::{self, AsyncReader, async_stdin};
 use termion::event::Key;
 use termion::input::TermRead;
 use termion::raw::{IntoRawMode, RawTerminal};
 
-use time::Duration;
+use time::{Duration, SteadyTime};
 
+use hex;
 use net;
-use ted::{Event, Mode, Ted};
+use screen::{Screen, Style};
+use unicode;
+use ted::{Event, MessageKind, Mode, Ted};
+use window::Window;
 use ted_client::TedClient;
 
+/// How often the terminal's size is checked for a resize
+const RESIZE_CHECK_MS: i64 = 100;
+
 pub struct Editor {
     ted: Ted,
     ted_client: Option<TedClient>,
     stdin: AsyncReader,
     stdout: RawTerminal<Stdout>,
-    left_column: usize,
-    right_column: usize,
+    terminal_size: (u16, u16), // Size of the terminal when ted was last fit to it
+    size_checked_at: Option<SteadyTime>,
+    screen: Screen,
 }
 
 impl Editor {
     pub fn new() -> Editor {
         let (_, terminal_height) = termion::terminal_size().unwrap();
+        let mut ted = Ted::new(terminal_height.saturating_sub(2) as u64);
+        ted.load_config();
 
         Editor {
-            ted: Ted::new((terminal_height-2) as u64),
+            ted: ted,
             ted_client: None,
             stdin: async_stdin(),
             stdout: stdout().into_raw_mode().unwrap(),
-            left_column: 3,
-            right_column: 3,
+            terminal_size: (0, 0),
+            size_checked_at: None,
+            screen: Screen::new(0, 0),
         }
     }
 
     pub fn from_string(text: String) -> Editor {
         let (_, terminal_height) = termion::terminal_size().unwrap();
+        let mut ted = Ted::from_string(terminal_height.saturating_sub(2) as u64, text);
+        ted.load_config();
 
         Editor {
-            ted: Ted::from_string((terminal_height-2) as u64, text),
+            ted: ted,
             ted_client: None,
             stdin: async_stdin(),
             stdout: stdout().into_raw_mode().unwrap(),
-            left_column: 3,
-            right_column: 3,
+            terminal_size: (0, 0),
+            size_checked_at: None,
+            screen: Screen::new(0, 0),
         }
     }
 
     pub fn from_file(path: String) -> io::Result<Editor> {
         let (_, terminal_height) = termion::terminal_size().unwrap();
-        let ted = try!(Ted::from_file((terminal_height-2) as u64, path));
+        let mut ted = try!(Ted::from_file(terminal_height.saturating_sub(2) as u64, path));
+        ted.load_config();
 
         Ok(Editor {
             ted: ted,
             ted_client: None,
             stdin: async_stdin(),
             stdout: stdout().into_raw_mode().unwrap(),
-            left_column: 3,
-            right_column: 3,
+            terminal_size: (0, 0),
+            size_checked_at: None,
+            screen: Screen::new(0, 0),
         })
     }
 
     pub fn from_server(address: &str) -> Result<Editor, String> {
-        let (_, terminal_height) = termion::terminal_size().unwrap();
-        let client = net::Client::new(address);
+        let client =
+            try!(net::Client::new(address).map_err(|e| format!("Failed to connect to {}: {}", address, e)));
         let mut ted_client = TedClient::new(client);
         let mut ted =
             try!(ted_client.download_buffer()
                            .map_err(|e| format!("Failed to download buffer from server: {}", e)));
-        ted.height = (terminal_height-2) as u64;
+        ted.single_buffer = true;
+        ted.load_config();
 
         Ok(Editor {
             ted: ted,
             ted_client: Some(ted_client),
             stdin: async_stdin(),
             stdout: stdout().into_raw_mode().unwrap(),
-            left_column: 3,
-            right_column: 3,
+            terminal_size: (0, 0),
+            size_checked_at: None,
+            screen: Screen::new(0, 0),
         })
     }
 
+    pub fn set_persist_undo(&mut self, persist: bool) {
+        self.ted.set_persist_undo(persist);
+    }
+
     pub fn run(&mut self) {
         use termion::input::TermRead;
 
         while self.ted.running() {
+            self.fit_to_terminal();
             self.handle_events();
-            if let Some(ref mut ted_client) = self.ted_client {
-                while let Ok(mut packet) = ted_client.client.try_receive() {
-                    ted_client.handle_packet(&mut self.ted, &mut packet);
-                }
+            self.ted.check_key_timeout();
+            self.ted.check_file();
+            if let Err(e) = self.receive_from_server() {
+                self.disconnect(e);
             }
             if self.ted.is_dirty() {
                 // Redraw screen if ted is dirty
@@ -100,108 +126,443 @@ impl Editor {
         }
     }
 
-    fn present(&mut self) {
-        use std::cmp;
+    /// Lays ted out again if the terminal's size changed. The size is checked every so often
+    /// rather than every frame, so there's no need for a SIGWINCH handler.
+    fn fit_to_terminal(&mut self) {
+        let now = SteadyTime::now();
+        if let Some(checked_at) = self.size_checked_at {
+            if now - checked_at < Duration::milliseconds(RESIZE_CHECK_MS) {
+                return;
+            }
+        }
+        self.size_checked_at = Some(now);
+
+        let size = match termion::terminal_size() { Ok(size) => size, Err(_) => { return; } };
+        if size != self.terminal_size {
+            self.terminal_size = size;
+            let (width, height) = size;
+            // The last two rows are for the status line and the command line
+            self.ted.resize(width as u64, height.saturating_sub(2) as u64);
+            // What the terminal shows after a resize is unknown, so the next frame is drawn whole
+            self.screen.resize(width as usize, height as usize);
+        }
+    }
 
+    fn present(&mut self) {
         // Clear dirty flag
         self.ted.clean();
 
-        // Clear the screen
-        write!(self.stdout, "{}", termion::clear::All);
-
-        // Draw main text
-        let text = self.ted.buffer();
-        for i in self.ted.scroll..cmp::min(text.line_count() as u64, self.ted.scroll+self.ted.height) {
-            write!(self.stdout, "{}{}{}{}{}",
-                   cursor::Goto(self.left_column as u16 + 1, (i - self.ted.scroll) as u16 + 1),
-                   style::Bold, color::Fg(color::White),
-                   color::Bg(color::Reset), text.line(i as usize));
-        }
-
-        // Draw command
-        if self.ted.mode() == Mode::Command {
-            write!(self.stdout, "{}{}{}{}{}",
-                   cursor::Goto(1, (self.ted.height + 1) as u16 + 1),
-                   style::Bold, color::Fg(color::White),
-                   color::Bg(color::Reset), ":");
-            write!(self.stdout, "{}{}{}{}{}",
-                   cursor::Goto(2, (self.ted.height + 1) as u16 + 1),
-                   style::Bold, color::Fg(color::White),
-                   color::Bg(color::Reset), self.ted.command_buffer().buffer().as_str());
-        } 
-
-        // Draw editor status 
-        match self.ted.mode() {
-            Mode::Normal => {
-                write!(self.stdout, "{}{}{}{}{}",
-                       cursor::Goto(1, self.ted.height as u16 + 1),
-                       style::Bold, color::Fg(color::Blue),
-                       color::Bg(color::Reset), "--NORMAL--");
-            },
-            Mode::Insert => {
-                write!(self.stdout, "{}{}{}{}{}",
-                       cursor::Goto(1, self.ted.height as u16 + 1),
-                       style::Bold, color::Fg(color::Red),
-                       color::Bg(color::Reset), "--INSERT--");
-            },
-            Mode::Command => {
-                write!(self.stdout, "{}{}{}{}{}",
-                       cursor::Goto(1, self.ted.height as u16 + 1),
-                       style::Bold, color::Fg(color::Green),
-                       color::Bg(color::Reset), "--COMMAND--");
-            },
-            Mode::VisualChar { start: _ } => {
-                write!(self.stdout, "{}{}{}{}{}",
-                       cursor::Goto(1, self.ted.height as u16 + 1),
-                       style::Bold, color::Fg(color::Yellow),
-                       color::Bg(color::Reset), "--VISUAL CHARACTER--");
-            },
-            Mode::VisualLine { start: _ } => {
-                write!(self.stdout, "{}{}{}{}{}",
-                       cursor::Goto(1, self.ted.height as u16 + 1),
-                       style::Bold, color::Fg(color::Yellow),
-                       color::Bg(color::Reset), "--VISUAL LINE--");
-            },
-            Mode::VisualBlock { start: _ } => {
-                write!(self.stdout, "{}{}{}{}{}",
-                       cursor::Goto(1, self.ted.height as u16 + 1),
-                       style::Bold, color::Fg(color::Yellow),
-                       color::Bg(color::Reset), "--VISUAL BLOCK--");
-            },
-        }
-
-        // Draw the cursor
-        let (cursor_x, cursor_y) = self.ted.cursor.get_display_xy(self.ted.buffer());
-        write!(self.stdout, "{}{}",
-               cursor::Goto((cursor_x as usize + self.left_column) as u16 + 1,
-                            (cursor_y - self.ted.scroll) as u16 + 1),
-               cursor::Show);
-        self.stdout.flush().unwrap();
+        draw(&self.ted, &mut self.screen);
+        self.screen.render(&mut self.stdout).unwrap();
     }
 
     fn handle_events(&mut self) {
         use std::io::Read;
-        use termion::event;
 
         let mut bytes = [0u8; 64];
         let bytes_read = self.stdin.read(&mut bytes).unwrap();
-        let ref mut bytes = bytes[..bytes_read].iter().map(|b| Ok(*b));
-        while let Some(b) = bytes.next() {
-            let e = event::parse_event(b, bytes).unwrap();
-            let k = if let event::Event::Key(k) = e { k } else { continue; };
-            let e = match k {
-                Key::Char('\n') => { Event::Enter },
-                Key::Char('~') => { Event::Esc },
-                Key::Char(c) => { Event::Char(c) },
-                Key::Backspace => { Event::Backspace },
-                //Key::Escape => { Event::Esc },
-                _ => { continue; },
-            };
+        for e in key_events(&bytes[..bytes_read]) {
             self.ted.handle_event(e);
-            if let Some(ref mut ted_client) = self.ted_client {
-                ted_client.send_commands(&mut self.ted);
-                ted_client.send_operations(&mut self.ted);
+            if let Err(e) = self.send_to_server() {
+                self.disconnect(e);
+            }
+        }
+    }
+
+    /// Sends new commands and operations to the server, if there is one
+    fn send_to_server(&mut self) -> Result<(), String> {
+        if let Some(ref mut ted_client) = self.ted_client {
+            try!(ted_client.send_commands(&mut self.ted).map_err(|e| e.to_string()));
+            try!(ted_client.send_operations(&mut self.ted).map_err(|e| e.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Handles the packets that have arrived from the server, if there is one
+    fn receive_from_server(&mut self) -> Result<(), String> {
+        if let Some(ref mut ted_client) = self.ted_client {
+            loop {
+                match ted_client.client.try_receive() {
+                    Ok(mut packet) => { try!(ted_client.handle_packet(&mut self.ted, &mut packet)); },
+                    Err(ref e) if e.kind() == io::ErrorKind::TimedOut => { break; },
+                    Err(e) => { return Err(e.to_string()); },
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops a broken connection to the server. The buffer stays open for editing and saving.
+    fn disconnect(&mut self, error: String) {
+        if self.ted_client.take().is_some() {
+            self.ted.notify_error(format!("Disconnected from server: {}", error));
+        }
+    }
+}
+
+/// Draws ted's whole screen into a frame
+/// The keys in some bytes read from the terminal. termion only knows the escape byte as the start
+/// of a key sequence, so one on its own is Esc, and one read along with the next key, like Alt
+/// with that key, is Esc and then the key.
+fn key_events(bytes: &[u8]) -> Vec<Event> {
+    use termion::event;
+
+    let mut events = Vec::new();
+    let ref mut bytes = bytes.iter().map(|b| Ok(*b)).peekable();
+    while let Some(b) = bytes.next() {
+        if b.as_ref().ok() == Some(&b'\x1B') && bytes.peek().is_none() {
+            events.push(Event::Esc);
+            continue;
+        }
+        let e = match event::parse_event(b, bytes) { Ok(e) => e, Err(_) => { continue; } };
+        let k = if let event::Event::Key(k) = e { k } else { continue; };
+        match k {
+            Key::Char('\n') => { events.push(Event::Enter); },
+            Key::Char(c) => { events.push(Event::Char(c)); },
+            Key::Alt(c) => {
+                events.push(Event::Esc);
+                events.push(Event::Char(c));
+            },
+            Key::Ctrl(c) => { events.push(Event::Ctrl(c)); },
+            Key::Backspace => { events.push(Event::Backspace); },
+            Key::Up => { events.push(Event::Up); },
+            Key::Down => { events.push(Event::Down); },
+            _ => { },
+        }
+    }
+    events
+}
+
+pub fn draw(ted: &Ted, screen: &mut Screen) {
+    let colors = ted.config().colors;
+    let left_column = ted.config().left_column;
+    let text_style = Style { fg: Some(colors.text), ..Style::default() };
+
+    screen.clear();
+
+    // Draw the tab line when there's more than one tab page
+    let (tab_titles, current_tab) = ted.tab_titles();
+    if tab_titles.len() > 1 {
+        let mut x = 0;
+        for (i, title) in tab_titles.iter().enumerate() {
+            let style =
+                if i == current_tab {
+                    Style { bold: true, ..text_style }
+                } else {
+                    Style { invert: true, ..text_style }
+                };
+            x = screen.put_str(x, 0, &format!(" {} ", title), style);
+        }
+    }
+
+    // Draw each window, with a status line under it if there's more than one
+    let windows = ted.windows();
+    for window in &windows {
+        let active = window.id == ted.active_window();
+        draw_window(ted, screen, window, active);
+        if windows.len() > 1 {
+            let title = ted.window_title(window);
+            let title: String = format!(" {:1$}", title, window.rect.width).chars().take(window.rect.width).collect();
+            screen.put_str(window.rect.x, window.rect.y + window.rect.height, &title,
+                           Style { bold: active, invert: true, ..text_style });
+        }
+    }
+    for separator in ted.separators() {
+        for row in separator.y..separator.y + separator.height {
+            screen.put_str(separator.x, row, "|", text_style);
+        }
+    }
+
+    // Draw command
+    let (_, screen_height) = ted.screen_size();
+    let screen_height = screen_height as usize;
+    let prompt =
+        match ted.mode() {
+            Mode::Command => Some(':'),
+            Mode::Search { forward: true } => Some('/'),
+            Mode::Search { forward: false } => Some('?'),
+            _ => None,
+        };
+    if let Some(prompt) = prompt {
+        screen.put_str(0, screen_height + 1, &format!("{}{}", prompt, ted.command_buffer().to_string()),
+                       Style { bold: true, ..text_style });
+    } else {
+        // Draw the notification area: the newest message in the command line, older ones on
+        // the lines above the status line
+        let messages = ted.messages();
+        let shown = cmp::min(messages.len(), screen_height + 1);
+        for (i, message) in messages.iter().rev().take(shown).enumerate() {
+            let row = if i == 0 { screen_height + 1 } else { screen_height - i };
+            let color =
+                match message.kind {
+                    MessageKind::Info => colors.message,
+                    MessageKind::Error => colors.error,
+                };
+            let width = screen.width();
+            screen.fill(0, row, width, ' ', Style::default());
+            screen.put_str(0, row, &message.text, Style { fg: Some(color), ..Style::default() });
+        }
+    }
+
+    // Draw editor status
+    let (label, color) =
+        match ted.mode() {
+            Mode::Normal => ("--NORMAL--", colors.normal),
+            Mode::Insert => ("--INSERT--", colors.insert),
+            Mode::Command => ("--COMMAND--", colors.command),
+            Mode::Search { forward: _ } => ("--SEARCH--", colors.command),
+            Mode::Confirm => ("--CONFIRM--", colors.command),
+            Mode::FileChanged => ("--FILE CHANGED--", colors.command),
+            Mode::VisualChar { start: _ } => ("--VISUAL CHARACTER--", colors.visual),
+            Mode::VisualLine { start: _ } => ("--VISUAL LINE--", colors.visual),
+            Mode::VisualBlock { start: _ } => ("--VISUAL BLOCK--", colors.visual),
+        };
+    let x = screen.put_str(0, screen_height, label, Style { fg: Some(color), bold: true, ..Style::default() });
+    if ted.is_modified() {
+        screen.put_str(x, screen_height, " [+]", text_style);
+    } else if ted.is_read_only() {
+        screen.put_str(x, screen_height, " [RO]", text_style);
+    }
+
+    // Draw the cursor
+    if let Some(window) = windows.iter().find(|w| w.id == ted.active_window()) {
+        let wrap = if ted.config().wrap { Some(ted.text_width(window)) } else { None };
+        let (cursor_x, cursor_y) = ted.cursor.get_display_xy(ted.buffer(), ted.scroll, ted.left, wrap);
+        screen.set_cursor(window.rect.x + left_column + cursor_x as usize, window.rect.y + cursor_y as usize);
+    }
+}
+
+/// Draws the lines of a window that are in view. The selection and the current search's
+/// matches are only shown in the active window.
+fn draw_window(ted: &Ted, screen: &mut Screen, window: &Window, active: bool) {
+    let colors = ted.config().colors;
+    let theme = ted.config().theme;
+    let left_column = ted.config().left_column;
+    let text_width = ted.text_width(window) as usize;
+    let buf_op = ted.window_buf_op(window);
+    let text = buf_op.buffer();
+    let text_style = Style { fg: Some(colors.text), bold: true, ..Style::default() };
+    let hex = ted.window_is_hex(window);
+
+    let bottom = window.rect.y + window.rect.height;
+    let mut y = window.rect.y;
+    let mut i = window.scroll as usize;
+    while y < bottom && i < text.line_count() {
+        let line = text.line(i);
+        let selection = if active { ted.line_selection(i) } else { None };
+        let matches = if active { ted.line_matches(i) } else { ted.text_matches(&line) };
+        let spans = buf_op.highlight(i);
+
+        // A hex dump has its bytes in bold and the offsets and text plainly. The byte the cursor
+        // is on is marked in the column the cursor isn't in.
+        let mirror =
+            if hex && active && i == ted.cursor.line as usize {
+                match hex::byte_at(&line, ted.cursor.column as usize) {
+                    Some((b, true)) => Some((hex::text_column(b), hex::text_column(b) + 1)),
+                    Some((b, false)) => Some((hex::hex_column(b), hex::hex_column(b) + 2)),
+                    None => None,
+                }
+            } else {
+                None
+            };
+
+        // The parts of the line shown on each row, and the column each starts at: every row it
+        // wraps onto, or the one row scrolled sideways. A wide character cut by the left edge of
+        // the window isn't shown.
+        let rows: Vec<(usize, usize, usize)> =
+            if ted.config().wrap {
+                unicode::wrap_rows(&line, text_width).into_iter().map(|(start, end)| (start, end, 0)).collect()
+            } else {
+                let left = window.left as usize;
+                let mut start = unicode::index_at_column(&line, left);
+                if unicode::width(&line[..start]) < left {
+                    start = unicode::next_boundary(&line, start);
+                }
+                let offset = unicode::width(&line[..start]).saturating_sub(left);
+                vec![(start, unicode::fit(&line, start, text_width - cmp::min(offset, text_width)), offset)]
+            };
+        for (start, end, offset) in rows {
+            if y >= bottom {
+                break;
+            }
+            let clip = |index: usize| cmp::min(cmp::max(unicode::boundary_at(&line, index), start), end);
+
+            // Split the row wherever the selection, a search match or a highlighted span starts
+            // or ends. Selected text is drawn with inverted colors, matches on the search color and
+            // spans in their theme color.
+            let mut bounds = vec![start, end];
+            if let Some((from, to)) = selection {
+                bounds.push(clip(from));
+                bounds.push(clip(to));
+            }
+            for &(from, to) in matches.iter().chain(mirror.iter()) {
+                bounds.push(clip(from));
+                bounds.push(clip(to));
+            }
+            for span in &spans {
+                bounds.push(clip(span.start));
+                bounds.push(clip(span.end));
+            }
+            if hex {
+                bounds.push(clip(hex::HEX_START));
+                bounds.push(clip(hex::HEX_END));
+            }
+            bounds.sort();
+            bounds.dedup();
+            let mut x = window.rect.x + left_column + offset;
+            for segment in bounds.windows(2) {
+                let (from, to) = (segment[0], segment[1]);
+                let selected = selection.map_or(false, |(start, end)| from >= clip(start) && to <= clip(end));
+                let matched = matches.iter().any(|&(start, end)| from >= clip(start) && to <= clip(end));
+                let mirrored = mirror.map_or(false, |(start, end)| from >= clip(start) && to <= clip(end));
+                let plain = hex && (to <= clip(hex::HEX_START) || from >= clip(hex::HEX_END));
+                let kind = spans.iter().find(|span| from >= clip(span.start) && to <= clip(span.end))
+                                       .map(|span| span.kind);
+                let style =
+                    Style {
+                        fg: Some(kind.map_or(colors.text, |kind| theme.color(kind))),
+                        invert: selected || mirrored,
+                        bg: if matched { Some(colors.search) } else { None },
+                        bold: !plain,
+                        ..text_style
+                    };
+                x = screen.put_str(x, y, &line[from..to], style);
+            }
+            if let Some((_, to)) = selection {
+                if to > line.len() && end == line.len() && x < window.rect.x + left_column + text_width {
+                    // A selected line break is drawn as an inverted space
+                    screen.put_str(x, y, " ", Style { invert: true, ..text_style });
+                }
             }
+            y += 1;
         }
+        i += 1;
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+
+#[test]
+fn editor_draw() {
+    use screen::MemoryTerminal;
+
+    let mut ted = Ted::from_string(4, "hello\nworld".to_string());
+    ted.resize(20, 4);
+    let mut screen = Screen::new(20, 6);
+    let mut terminal = MemoryTerminal::new(20, 6);
+
+    draw(&ted, &mut screen);
+    screen.render(&mut terminal).unwrap();
+    assert!(terminal.row(0) == "   hello");
+    assert!(terminal.row(1) == "   world");
+    assert!(terminal.row(4) == "--NORMAL--");
+    assert!(terminal.cell(11, 4).text == " ");
+    assert!(terminal.cursor == (3, 0));
+
+    // Deleting a character only redraws the rest of its line and the status line
+    for c in "dl".chars() {
+        ted.handle_event(Event::Char(c));
+    }
+    draw(&ted, &mut screen);
+    let mut output = Vec::new();
+    screen.render(&mut output).unwrap();
+    terminal.write_all(&output).unwrap();
+    assert!(terminal.row(0) == "   ello");
+    assert!(terminal.row(1) == "   world");
+    assert!(terminal.row(4) == "--NORMAL-- [+]");
+    let output = String::from_utf8(output).unwrap();
+    assert!(!output.contains("world") && !output.contains("NORMAL"));
+
+    // Side by side windows
+    for c in ":vsplit".chars() {
+        ted.handle_event(Event::Char(c));
+    }
+    ted.handle_event(Event::Enter);
+    draw(&ted, &mut screen);
+    screen.render(&mut terminal).unwrap();
+    assert!(terminal.row(0) == "   ello   |   ello");
+    assert!(terminal.row(3) == " [No Name]| [No Name");
+    assert!(terminal.cell(1, 3).style.invert);
+}
+
+#[test]
+fn editor_key_events() {
+    assert!(key_events(b"~") == vec![Event::Char('~')]);
+    assert!(key_events(b"\x1B") == vec![Event::Esc]);
+    assert!(key_events(b"ab\x1B") == vec![Event::Char('a'), Event::Char('b'), Event::Esc]);
+    assert!(key_events(b"\x1Bj") == vec![Event::Esc, Event::Char('j')]);
+    assert!(key_events(b"\x1B[A\r\x7f") == vec![Event::Up, Event::Enter, Event::Backspace]);
+}
+
+#[test]
+fn editor_draw_wrapped() {
+    use std::env;
+    use std::fs::File;
+    use screen::MemoryTerminal;
+
+    let path = env::temp_dir().join("ted_editor_draw_wrapped.toml");
+    File::create(&path).unwrap().write_all(b"left_column = 0\nwrap = true\n").unwrap();
+    let mut ted = Ted::from_string(3, "0123456789abcdefghij\nend".to_string());
+    ted.resize(8, 3);
+    ted.source(&path).unwrap();
+    let mut screen = Screen::new(8, 5);
+    let mut terminal = MemoryTerminal::new(8, 5);
+
+    ted.handle_event(Event::Char('$'));
+    draw(&ted, &mut screen);
+    screen.render(&mut terminal).unwrap();
+    assert!(terminal.row(0) == "01234567");
+    assert!(terminal.row(1) == "89abcdef");
+    assert!(terminal.row(2) == "ghij");
+    assert!(terminal.cursor == (3, 2));
+}
+
+#[test]
+fn editor_draw_hex() {
+    use std::env;
+    use std::fs::File;
+    use screen::MemoryTerminal;
+
+    let path = env::temp_dir().join("ted_editor_draw_hex.bin");
+    File::create(&path).unwrap().write_all(b"AB\x00").unwrap();
+    let mut ted = Ted::from_file(3, path.display().to_string()).unwrap();
+    ted.resize(80, 3);
+    let mut screen = Screen::new(80, 5);
+    let mut terminal = MemoryTerminal::new(80, 5);
+
+    for c in "12l".chars() {
+        ted.handle_event(Event::Char(c));
     }
+    draw(&ted, &mut screen);
+    screen.render(&mut terminal).unwrap();
+    assert!(terminal.row(0) == format!("   {}", hex::dump(b"AB\x00").trim_right()));
+    assert!(!terminal.cell(3, 0).style.bold && terminal.cell(13, 0).style.bold);
+    assert!(!terminal.cell(54, 0).style.bold);
+
+    // The cursor is on the second byte's digits, so its text is marked
+    assert!(terminal.cursor == (15, 0));
+    assert!(terminal.cell(55, 0).style.invert && !terminal.cell(54, 0).style.invert);
+}
+
+#[test]
+fn editor_draw_highlighted() {
+    use std::env;
+    use std::fs::File;
+    use screen::MemoryTerminal;
+
+    let path = env::temp_dir().join("ted_editor_draw_highlighted.rs");
+    File::create(&path).unwrap().write_all(b"fn main() {} // hi").unwrap();
+    let mut ted = Ted::from_file(3, path.display().to_string()).unwrap();
+    ted.resize(30, 3);
+    let mut screen = Screen::new(30, 5);
+    let mut terminal = MemoryTerminal::new(30, 5);
+
+    draw(&ted, &mut screen);
+    screen.render(&mut terminal).unwrap();
+    let theme = ted.config().theme;
+    assert!(terminal.cell(3, 0).style.fg == Some(theme.keyword));
+    assert!(terminal.cell(6, 0).style.fg == Some(ted.config().colors.text));
+    assert!(terminal.cell(16, 0).style.fg == Some(theme.comment));
+    assert!(terminal.cell(20, 0).style.fg == Some(theme.comment));
 }
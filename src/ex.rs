@@ -0,0 +1,333 @@
+use std::cmp;
+use std::collections::HashMap;
+
+use ted::Ted;
+
+/// Runs a command. Errors are shown in the message line.
+pub type Handler = fn(&mut Ted, &CommandLine) -> Result<(), String>;
+
+/// A command that can be typed after :
+pub struct Command {
+    pub name: &'static str,
+    pub abbreviation: &'static str, // The shortest prefix of the name that runs the command
+    pub range: bool, // Whether the command takes a line range
+    pub zero: bool,  // Whether line 0, before the first line, is a valid address for it
+    pub bang: bool,  // Whether the command has a ! variant
+    pub count: bool, // Whether a trailing number is a count of lines from the end of the range
+    pub handler: Handler,
+}
+
+/// A parsed command line
+pub struct CommandLine {
+    pub command: Option<&'static Command>, // None for a lone range, like :10
+    pub range: Option<(usize, usize)>,     // Line numbers as typed, starting at 1
+    pub bang: bool,
+    pub arg_text: String, // Everything after the command name, ! and count
+}
+
+/// What addresses in a range are relative to
+pub struct Context<'a> {
+    pub current: usize, // Cursor line, starting at 0
+    pub last: usize,    // Last line, starting at 0
+    pub marks: &'a HashMap<char, usize>,
+}
+
+impl CommandLine {
+    /// The range as lines starting at 0
+    pub fn lines(&self) -> Option<(usize, usize)> {
+        self.range.map(|(first, last)| (first.saturating_sub(1), last.saturating_sub(1)))
+    }
+
+    /// The range as lines starting at 0, or just the given line without one
+    pub fn lines_or(&self, line: usize) -> (usize, usize) {
+        self.lines().unwrap_or((line, line))
+    }
+
+    /// The arguments, split on whitespace. Quotes group words, and a backslash escapes the next
+    /// character outside single quotes.
+    pub fn args(&self) -> Result<Vec<String>, String> {
+        split_args(&self.arg_text)
+    }
+}
+
+/// Finds the command a name or abbreviation refers to
+pub fn find(commands: &'static [Command], name: &str) -> Option<&'static Command> {
+    commands.iter().find(|c| name.starts_with(c.abbreviation) && c.name.starts_with(name))
+}
+
+/// Parses a command line like `:'<,'>s/a/b/g`, `:w! foo` or `:d 3`
+pub fn parse(text: &str, commands: &'static [Command], context: &Context) -> Result<CommandLine, String> {
+    let text = text.trim_left_matches(|c| c == ':' || c == ' ');
+    let (mut range, len) = try!(parse_range(text, context));
+    let rest = text[len..].trim_left();
+
+    let name_len = rest.find(|c: char| !c.is_alphabetic()).unwrap_or(rest.len());
+    let name = &rest[..name_len];
+    let mut rest = &rest[name_len..];
+    if name.is_empty() {
+        if !rest.is_empty() {
+            return Err(format!("Not an editor command: {}", text));
+        }
+        if let Some((first, last)) = range {
+            try!(check_range(first, last, context, false));
+        }
+        return Ok(CommandLine { command: None, range: range, bang: false, arg_text: String::new() });
+    }
+
+    let command = try!(find(commands, name).ok_or(format!("Not an editor command: {}", text)));
+    if range.is_some() && !command.range {
+        return Err(format!("No range allowed: {}", command.name));
+    }
+
+    let bang = command.bang && rest.starts_with('!');
+    if bang {
+        rest = &rest[1..];
+    }
+    let mut arg_text = rest.trim_left();
+
+    if command.count {
+        arg_text = arg_text.trim_right();
+        let start = arg_text.rfind(' ').map_or(0, |i| i + 1);
+        let last_arg = &arg_text[start..];
+        if !last_arg.is_empty() && last_arg.chars().all(|c| c.is_digit(10)) {
+            let count: usize = try!(last_arg.parse().map_err(|_| format!("Invalid count: {}", last_arg)));
+            if count == 0 {
+                return Err("Positive count required".to_string());
+            }
+            // The count starts at the end of the range
+            let (_, end) = range.unwrap_or((context.current + 1, context.current + 1));
+            range = Some((end, cmp::min(end + count - 1, context.last + 1)));
+            arg_text = arg_text[..start].trim_right();
+        }
+    }
+
+    if let Some((first, last)) = range {
+        try!(check_range(first, last, context, command.zero));
+    }
+
+    Ok(CommandLine {
+        command: Some(command),
+        range: range,
+        bang: bang,
+        arg_text: arg_text.to_string(),
+    })
+}
+
+/// Parses the line range at the start of a command, like `%`, `'<,'>`, `10,20` or `.,$-1`.
+/// Returns the range, if any, and the length of the text it took up.
+pub fn parse_range(text: &str, context: &Context) -> Result<(Option<(usize, usize)>, usize), String> {
+    if text.starts_with('%') {
+        return Ok((Some((1, context.last + 1)), 1));
+    }
+
+    let mut i = 0;
+    let first = match try!(parse_address(text, &mut i, context)) {
+        Some(first) => first,
+        None => { return Ok((None, 0)); },
+    };
+    let second =
+        if text[i..].starts_with(',') {
+            i += 1;
+            match try!(parse_address(text, &mut i, context)) {
+                Some(second) => second,
+                None => { return Err("Invalid range".to_string()); },
+            }
+        } else {
+            first
+        };
+
+    if first < 0 || second < 0 {
+        return Err("Invalid range".to_string());
+    }
+    let (first, second) = (first as usize, second as usize);
+    if first <= second {
+        Ok((Some((first, second)), i))
+    } else {
+        Ok((Some((second, first)), i))
+    }
+}
+
+/// Parses a single address, like the destination of :m and :t
+pub fn parse_line(text: &str, context: &Context) -> Result<usize, String> {
+    let mut i = 0;
+    match try!(parse_address(text.trim(), &mut i, context)) {
+        Some(line) if i == text.trim().len() && line >= 0 && line as usize <= context.last + 1 => Ok(line as usize),
+        _ => Err(format!("Invalid address: {}", text)),
+    }
+}
+
+fn check_range(first: usize, last: usize, context: &Context, zero: bool) -> Result<(), String> {
+    if (first == 0 && !zero) || last > context.last + 1 {
+        Err("Invalid range".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+/// A line number, `.`, `$` or a mark, followed by any number of +n and -n offsets
+fn parse_address(text: &str, i: &mut usize, context: &Context) -> Result<Option<i64>, String> {
+    let rest = &text[*i..];
+    let digits = rest.chars().take_while(|c| c.is_digit(10)).count();
+    let mut line =
+        if digits > 0 {
+            *i += digits;
+            try!(rest[..digits].parse::<i64>().map_err(|_| "Invalid range".to_string()))
+        } else if rest.starts_with('.') {
+            *i += 1;
+            context.current as i64 + 1
+        } else if rest.starts_with('$') {
+            *i += 1;
+            context.last as i64 + 1
+        } else if rest.starts_with('\'') {
+            let mark = try!(rest[1..].chars().next().ok_or("Invalid range".to_string()));
+            *i += 1 + mark.len_utf8();
+            match context.marks.get(&mark) {
+                Some(&line) => line as i64 + 1,
+                None => { return Err(format!("Mark not set: {}", mark)); },
+            }
+        } else if rest.starts_with('+') || rest.starts_with('-') {
+            // An offset on its own is from the current line
+            context.current as i64 + 1
+        } else {
+            return Ok(None);
+        };
+
+    loop {
+        let rest = &text[*i..];
+        let sign =
+            if rest.starts_with('+') { 1 } else if rest.starts_with('-') { -1 } else { break; };
+        let digits = rest[1..].chars().take_while(|c| c.is_digit(10)).count();
+        let offset =
+            if digits > 0 {
+                try!(rest[1..digits+1].parse::<i64>().map_err(|_| "Invalid range".to_string()))
+            } else {
+                1
+            };
+        line += sign * offset;
+        *i += 1 + digits;
+    }
+    Ok(Some(line))
+}
+
+fn split_args(text: &str) -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    let mut arg = String::new();
+    let mut in_arg = false; // Whether arg has started, it can be an empty quoted argument
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' | '\t' => {
+                if in_arg {
+                    args.push(arg.clone());
+                    arg.clear();
+                    in_arg = false;
+                }
+                continue;
+            },
+            '"' => {
+                loop {
+                    match chars.next() {
+                        Some('"') => { break; },
+                        Some('\\') => {
+                            match chars.next() {
+                                Some(escaped) => { arg.push(escaped); },
+                                None => { return Err("Unterminated quote".to_string()); },
+                            }
+                        },
+                        Some(c) => { arg.push(c); },
+                        None => { return Err("Unterminated quote".to_string()); },
+                    }
+                }
+            },
+            '\'' => {
+                loop {
+                    match chars.next() {
+                        Some('\'') => { break; },
+                        Some(c) => { arg.push(c); },
+                        None => { return Err("Unterminated quote".to_string()); },
+                    }
+                }
+            },
+            '\\' => { arg.push(chars.next().unwrap_or('\\')); },
+            c => { arg.push(c); },
+        }
+        in_arg = true;
+    }
+    if in_arg {
+        args.push(arg);
+    }
+    Ok(args)
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+
+#[cfg(test)]
+fn nop(_: &mut Ted, _: &CommandLine) -> Result<(), String> { Ok(()) }
+
+#[cfg(test)]
+const TEST_COMMANDS: &'static [Command] = &[
+    Command { name: "write", abbreviation: "w", range: false, zero: false, bang: true, count: false, handler: nop },
+    Command { name: "wq", abbreviation: "wq", range: false, zero: false, bang: true, count: false, handler: nop },
+    Command { name: "delete", abbreviation: "d", range: true, zero: false, bang: false, count: true, handler: nop },
+    Command { name: "read", abbreviation: "r", range: true, zero: true, bang: false, count: false, handler: nop },
+];
+
+#[test]
+fn ex_parse_commands() {
+    let marks = HashMap::new();
+    let context = Context { current: 2, last: 9, marks: &marks };
+
+    let line = parse("w! \"my file\" 'x y'", TEST_COMMANDS, &context).unwrap();
+    assert!(line.command.unwrap().name == "write");
+    assert!(line.bang);
+    assert!(line.args() == Ok(vec!["my file".to_string(), "x y".to_string()]));
+
+    assert!(parse(":wq", TEST_COMMANDS, &context).unwrap().command.unwrap().name == "wq");
+    assert!(parse("wr", TEST_COMMANDS, &context).unwrap().command.unwrap().name == "write");
+    assert!(parse("wx", TEST_COMMANDS, &context).err() == Some("Not an editor command: wx".to_string()));
+    assert!(parse("é", TEST_COMMANDS, &context).err() == Some("Not an editor command: é".to_string()));
+    assert!(parse("wé!", TEST_COMMANDS, &context).err() == Some("Not an editor command: wé!".to_string()));
+    assert!(parse("2w", TEST_COMMANDS, &context).err() == Some("No range allowed: write".to_string()));
+    assert!(parse("w \"foo", TEST_COMMANDS, &context).unwrap().args().is_err());
+
+    let line = parse("10", TEST_COMMANDS, &context).unwrap();
+    assert!(line.command.is_none() && line.lines() == Some((9, 9)));
+    assert!(parse("11", TEST_COMMANDS, &context).is_err());
+}
+
+#[test]
+fn ex_parse_counts() {
+    let marks = HashMap::new();
+    let context = Context { current: 2, last: 9, marks: &marks };
+
+    let line = parse("d a 3", TEST_COMMANDS, &context).unwrap();
+    assert!(line.range == Some((3, 5)));
+    assert!(line.arg_text == "a");
+    assert!(parse("2,4d 20", TEST_COMMANDS, &context).unwrap().range == Some((4, 10)));
+    assert!(parse("d 0", TEST_COMMANDS, &context).is_err());
+
+    // Only some commands take line 0
+    assert!(parse("0r foo", TEST_COMMANDS, &context).unwrap().range == Some((0, 0)));
+    assert!(parse("0d", TEST_COMMANDS, &context).is_err());
+}
+
+#[test]
+fn ex_parse_range() {
+    let mut marks = HashMap::new();
+    marks.insert('<', 4);
+    marks.insert('>', 6);
+    let context = Context { current: 3, last: 9, marks: &marks };
+
+    assert!(parse_range("%s/a/b/", &context) == Ok((Some((1, 10)), 1)));
+    assert!(parse_range("s/a/b/", &context) == Ok((None, 0)));
+    assert!(parse_range("2,5s", &context) == Ok((Some((2, 5)), 3)));
+    assert!(parse_range(".,$-1s", &context) == Ok((Some((4, 9)), 5)));
+    assert!(parse_range("'<,'>s", &context) == Ok((Some((5, 7)), 5)));
+    assert!(parse_range("+2", &context) == Ok((Some((6, 6)), 2)));
+    assert!(parse_range("5,2", &context) == Ok((Some((2, 5)), 3)));
+    assert!(parse_range("'as", &context).is_err());
+    assert!(parse_line("$", &context) == Ok(10));
+    assert!(parse_line("0", &context) == Ok(0));
+    assert!(parse_line("x", &context).is_err());
+}
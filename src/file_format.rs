@@ -0,0 +1,288 @@
+use std::io;
+use std::str;
+
+/// How lines end in a file. The buffer always uses \n, other endings are converted when the file
+/// is read and written.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LineEnding {
+    Unix, // \n
+    Dos,  // \r\n
+    Mac,  // \r
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Encoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    Latin1,
+}
+
+/// How a buffer's text is stored on disk, so a file is written back the way it was read
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct FileFormat {
+    pub line_ending: LineEnding,
+    pub encoding: Encoding,
+    pub bom: bool, // Whether the file starts with a byte order mark. UTF-16 always has one.
+}
+
+const LINE_ENDING_NAMES: &'static [(&'static str, LineEnding)] = &[
+    ("unix", LineEnding::Unix),
+    ("dos", LineEnding::Dos),
+    ("mac", LineEnding::Mac),
+];
+
+/// Names :set fileencoding takes, the first one for each encoding is the one shown
+const ENCODING_NAMES: &'static [(&'static str, Encoding)] = &[
+    ("utf-8", Encoding::Utf8),
+    ("utf8", Encoding::Utf8),
+    ("utf-16le", Encoding::Utf16Le),
+    ("utf-16be", Encoding::Utf16Be),
+    ("utf-16", Encoding::Utf16Be),
+    ("latin1", Encoding::Latin1),
+    ("iso-8859-1", Encoding::Latin1),
+];
+
+const UTF8_BOM: &'static [u8] = &[0xEF, 0xBB, 0xBF];
+const UTF16LE_BOM: &'static [u8] = &[0xFF, 0xFE];
+const UTF16BE_BOM: &'static [u8] = &[0xFE, 0xFF];
+
+impl LineEnding {
+    pub fn name(&self) -> &'static str {
+        LINE_ENDING_NAMES.iter().find(|&&(_, e)| e == *self).unwrap().0
+    }
+
+    pub fn from_name(name: &str) -> Option<LineEnding> {
+        LINE_ENDING_NAMES.iter().find(|&&(n, _)| n == name).map(|&(_, e)| e)
+    }
+}
+
+impl Encoding {
+    pub fn name(&self) -> &'static str {
+        ENCODING_NAMES.iter().find(|&&(_, e)| e == *self).unwrap().0
+    }
+
+    pub fn from_name(name: &str) -> Option<Encoding> {
+        let name = name.to_lowercase();
+        ENCODING_NAMES.iter().find(|&&(n, _)| n == name).map(|&(_, e)| e)
+    }
+}
+
+impl FileFormat {
+    /// The format of new files
+    pub fn new() -> FileFormat {
+        FileFormat {
+            line_ending: LineEnding::Unix,
+            encoding: Encoding::Utf8,
+            bom: false,
+        }
+    }
+
+    /// Works out a file's format and turns its bytes into text with \n line endings. UTF-16 is
+    /// recognized by its byte order mark. A byte order mark is only believed if the rest of the
+    /// file decodes cleanly, and anything that isn't valid UTF-8 is read as Latin-1, which every
+    /// byte is valid in, so the text is always written back as the same bytes.
+    pub fn decode(bytes: &[u8]) -> (String, FileFormat) {
+        let (text, encoding, bom) =
+            if let Some(text) = after_bom(bytes, Encoding::Utf8).and_then(|b| str::from_utf8(b).ok()) {
+                (text.to_string(), Encoding::Utf8, true)
+            } else if let Some(text) = after_bom(bytes, Encoding::Utf16Le)
+                                           .and_then(|b| decode_utf16(b, Encoding::Utf16Le)) {
+                (text, Encoding::Utf16Le, true)
+            } else if let Some(text) = after_bom(bytes, Encoding::Utf16Be)
+                                           .and_then(|b| decode_utf16(b, Encoding::Utf16Be)) {
+                (text, Encoding::Utf16Be, true)
+            } else if let Ok(text) = str::from_utf8(bytes) {
+                (text.to_string(), Encoding::Utf8, false)
+            } else {
+                (bytes.iter().map(|&b| b as char).collect(), Encoding::Latin1, false)
+            };
+
+        // A file is only dos if every line ends that way, so mixed endings are kept as they are
+        let line_ending =
+            if text.contains('\n') {
+                if text.matches("\r\n").count() == text.matches('\n').count() {
+                    LineEnding::Dos
+                } else {
+                    LineEnding::Unix
+                }
+            } else if text.contains('\r') {
+                LineEnding::Mac
+            } else {
+                LineEnding::Unix
+            };
+        let text =
+            match line_ending {
+                LineEnding::Unix => text,
+                LineEnding::Dos => text.replace("\r\n", "\n"),
+                LineEnding::Mac => text.replace('\r', "\n"),
+            };
+
+        let format = FileFormat {
+            line_ending: line_ending,
+            encoding: encoding,
+            bom: bom,
+        };
+        (text, format)
+    }
+
+    /// What goes at the start of the file
+    pub fn bom_bytes(&self) -> &'static [u8] {
+        match self.encoding {
+            Encoding::Utf8 if !self.bom => &[],
+            Encoding::Latin1 => &[],
+            encoding => bom_bytes(encoding),
+        }
+    }
+
+    /// Turns a piece of buffer text into the file's bytes. Fails on characters the encoding can't
+    /// hold.
+    pub fn encode(&self, text: &str) -> io::Result<Vec<u8>> {
+        let text =
+            match self.line_ending {
+                LineEnding::Unix => text.to_string(),
+                LineEnding::Dos => text.replace('\n', "\r\n"),
+                LineEnding::Mac => text.replace('\n', "\r"),
+            };
+
+        match self.encoding {
+            Encoding::Utf8 => Ok(text.into_bytes()),
+            Encoding::Utf16Le => Ok(text.encode_utf16().flat_map(|u| vec![u as u8, (u >> 8) as u8]).collect()),
+            Encoding::Utf16Be => Ok(text.encode_utf16().flat_map(|u| vec![(u >> 8) as u8, u as u8]).collect()),
+            Encoding::Latin1 => {
+                let mut bytes = Vec::with_capacity(text.len());
+                for c in text.chars() {
+                    if c as u32 > 0xFF {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                  format!("'{}' can't be written as latin1", c)));
+                    }
+                    bytes.push(c as u8);
+                }
+                Ok(bytes)
+            },
+        }
+    }
+
+    /// Notes for messages about the file, e.g. " [dos] [latin1]", or nothing for the usual format
+    pub fn notes(&self) -> String {
+        let mut notes = String::new();
+        if self.line_ending != LineEnding::Unix {
+            notes.push_str(&format!(" [{}]", self.line_ending.name()));
+        }
+        if self.encoding != Encoding::Utf8 {
+            notes.push_str(&format!(" [{}]", self.encoding.name()));
+        } else if self.bom {
+            notes.push_str(" [BOM]");
+        }
+        notes
+    }
+}
+
+/// Whether the start of a file looks like it's not text: it has a NUL byte, which text files
+/// don't apart from UTF-16 ones
+pub fn is_binary(head: &[u8]) -> bool {
+    !head.starts_with(UTF16LE_BOM) && !head.starts_with(UTF16BE_BOM) && head.contains(&0)
+}
+
+/// The bytes after an encoding's byte order mark, if bytes start with it
+fn after_bom(bytes: &[u8], encoding: Encoding) -> Option<&[u8]> {
+    let bom = bom_bytes(encoding);
+    if bytes.starts_with(bom) { Some(&bytes[bom.len()..]) } else { None }
+}
+
+/// UTF-16 text, or None if there's an odd byte at the end or a surrogate without its pair
+fn decode_utf16(bytes: &[u8], encoding: Encoding) -> Option<String> {
+    if bytes.len() % 2 != 0 {
+        return None;
+    }
+    let units: Vec<u16> =
+        bytes.chunks(2).map(|pair| {
+            if encoding == Encoding::Utf16Le {
+                pair[0] as u16 | (pair[1] as u16) << 8
+            } else {
+                (pair[0] as u16) << 8 | pair[1] as u16
+            }
+        }).collect();
+    String::from_utf16(&units).ok()
+}
+
+fn bom_bytes(encoding: Encoding) -> &'static [u8] {
+    match encoding {
+        Encoding::Utf8 => UTF8_BOM,
+        Encoding::Utf16Le => UTF16LE_BOM,
+        Encoding::Utf16Be => UTF16BE_BOM,
+        Encoding::Latin1 => &[],
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+
+#[test]
+fn file_format_decode() {
+    let (text, format) = FileFormat::decode(b"one\r\ntwo\r\n");
+    assert!(text == "one\ntwo\n");
+    assert!(format == FileFormat { line_ending: LineEnding::Dos, encoding: Encoding::Utf8, bom: false });
+
+    let (text, format) = FileFormat::decode(b"one\rtwo");
+    assert!(text == "one\ntwo" && format.line_ending == LineEnding::Mac);
+
+    // Mixed endings stay as they are
+    let (text, format) = FileFormat::decode(b"one\r\ntwo\n");
+    assert!(text == "one\r\ntwo\n" && format.line_ending == LineEnding::Unix);
+
+    let (text, format) = FileFormat::decode(b"\xEF\xBB\xBFcaf\xC3\xA9");
+    assert!(text == "café" && format.encoding == Encoding::Utf8 && format.bom);
+
+    let (text, format) = FileFormat::decode(b"caf\xE9\n");
+    assert!(text == "café\n" && format.encoding == Encoding::Latin1);
+
+    let (text, format) = FileFormat::decode(b"\xFF\xFEa\x00\r\x00\n\x00\xE9\x00");
+    assert!(text == "a\né" && format.encoding == Encoding::Utf16Le && format.line_ending == LineEnding::Dos);
+
+    let (text, format) = FileFormat::decode(b"\xFE\xFF\x00a\xD8\x3D\xDE\x00");
+    assert!(text == "a😀" && format.encoding == Encoding::Utf16Be);
+
+    // A byte order mark followed by something else isn't believed
+    let (text, format) = FileFormat::decode(b"\xEF\xBB\xBFcaf\xE9");
+    assert!(text == "\u{EF}\u{BB}\u{BF}caf\u{E9}" && format.encoding == Encoding::Latin1 && !format.bom);
+    let (_, format) = FileFormat::decode(b"\xFF\xFEa\x00\x00\xD8"); // A surrogate without its pair
+    assert!(format.encoding == Encoding::Latin1);
+    let (_, format) = FileFormat::decode(b"\xFE\xFF\x00a\x00"); // An odd byte
+    assert!(format.encoding == Encoding::Latin1);
+}
+
+#[test]
+fn file_format_round_trip() {
+    let files: &[&[u8]] = &[
+        b"one\ntwo\n",
+        b"one\r\ntwo\r\n",
+        b"one\rtwo\r",
+        b"one\r\ntwo\nthree\r",
+        b"\xEF\xBB\xBFone\r\n",
+        b"caf\xE9\r\n\xFF",
+        b"\xFF\xFEa\x00\r\x00\n\x00\xE9\x00",
+        b"\xFE\xFF\x00a\xD8\x3D\xDE\x00\x00\n",
+        b"\xEF\xBB\xBFcaf\xE9\r\n",
+        b"\xFF\xFEa\x00\x00\xD8",
+        b"\xFE\xFF\x00a\x00",
+    ];
+    for &bytes in files {
+        let (text, format) = FileFormat::decode(bytes);
+        let mut written = format.bom_bytes().to_vec();
+        written.extend(format.encode(&text).unwrap());
+        assert!(written == bytes);
+    }
+
+    let latin1 = FileFormat { line_ending: LineEnding::Unix, encoding: Encoding::Latin1, bom: false };
+    assert!(latin1.encode("日本").is_err());
+    assert!(Encoding::from_name("UTF-16") == Some(Encoding::Utf16Be));
+    assert!(LineEnding::from_name("dos") == Some(LineEnding::Dos));
+}
+
+#[test]
+fn file_format_is_binary() {
+    assert!(!is_binary(b"text\r\n\xE9"));
+    assert!(is_binary(b"\x7fELF\x02\x01\x01\x00"));
+    assert!(!is_binary(b"\xFF\xFEa\x00"));
+}
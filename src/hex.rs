@@ -0,0 +1,132 @@
+use std::cmp;
+
+/// Bytes shown on each line of a hex dump
+pub const BYTES_PER_LINE: usize = 16;
+
+/// Where the columns of a dump line are: the offset, the bytes in hex, then the bytes as text
+pub const HEX_START: usize = 10;
+pub const HEX_END: usize = 49;
+pub const TEXT_START: usize = 51;
+
+/// Shows bytes like xxd does, a line for every 16 bytes with their offset, the bytes in hex in
+/// groups of two, and the bytes as text with a . for anything that isn't printable ASCII:
+///
+/// ```text
+/// 00000000: 4865 6c6c 6f0a 00ff                      Hello...
+/// ```
+pub fn dump(bytes: &[u8]) -> String {
+    let mut text = String::with_capacity(bytes.len() / BYTES_PER_LINE * 68 + 68);
+    for (i, line) in bytes.chunks(BYTES_PER_LINE).enumerate() {
+        text.push_str(&format!("{:08x}:", i * BYTES_PER_LINE));
+        for column in 0..BYTES_PER_LINE {
+            if column % 2 == 0 {
+                text.push(' ');
+            }
+            match line.get(column) {
+                Some(b) => { text.push_str(&format!("{:02x}", b)); },
+                None => { text.push_str("  "); },
+            }
+        }
+        text.push_str("  ");
+        for &b in line {
+            text.push(text_char(b));
+        }
+        text.push('\n');
+    }
+    text
+}
+
+/// How a byte is shown in the text column
+pub fn text_char(b: u8) -> char {
+    if b >= 0x20 && b < 0x7f { b as char } else { '.' }
+}
+
+/// Column of the first hex digit of the bth byte of a line
+pub fn hex_column(b: usize) -> usize {
+    HEX_START + b / 2 * 5 + b % 2 * 2
+}
+
+/// Column of the bth byte of a line in the text column
+pub fn text_column(b: usize) -> usize {
+    TEXT_START + b
+}
+
+/// Which byte of a dump line a column is on, and whether it's on its hex digits rather than its
+/// text. The offset and the spaces between columns aren't on a byte.
+pub fn byte_at(line: &str, column: usize) -> Option<(usize, bool)> {
+    let count = line.len().saturating_sub(TEXT_START);
+    let (b, in_hex) =
+        if column >= HEX_START && column < HEX_END && (column - HEX_START) % 5 != 4 {
+            let group = (column - HEX_START) / 5;
+            (group * 2 + (column - HEX_START) % 5 / 2, true)
+        } else if column >= TEXT_START {
+            (column - TEXT_START, false)
+        } else {
+            return None;
+        };
+    if b < count { Some((b, in_hex)) } else { None }
+}
+
+/// Reads the bytes back out of a dump. Only the hex column counts, the offsets and the text column
+/// are only there to look at.
+pub fn parse(text: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let end = cmp::min(line.len(), HEX_END);
+        if line.is_empty() {
+            continue;
+        } else if end < HEX_START || !line.is_char_boundary(HEX_START) || !line.is_char_boundary(end) {
+            return Err(format!("Invalid hex dump on line {}", i + 1));
+        }
+
+        let digits: Vec<char> = line[HEX_START..end].chars().filter(|&c| c != ' ').collect();
+        if digits.len() % 2 != 0 {
+            return Err(format!("Invalid hex dump on line {}", i + 1));
+        }
+        for pair in digits.chunks(2) {
+            match (pair[0].to_digit(16), pair[1].to_digit(16)) {
+                (Some(high), Some(low)) => { bytes.push((high << 4 | low) as u8); },
+                _ => { return Err(format!("Invalid hex dump on line {}", i + 1)); },
+            }
+        }
+    }
+    Ok(bytes)
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+
+#[test]
+fn hex_dump() {
+    assert!(dump(b"") == "");
+    assert!(dump(b"Hello\n\x00\xff") ==
+            "00000000: 4865 6c6c 6f0a 00ff                      Hello...\n");
+    let bytes: Vec<u8> = (0..18).collect();
+    assert!(dump(&bytes) ==
+            "00000000: 0001 0203 0405 0607 0809 0a0b 0c0d 0e0f  ................\n\
+             00000010: 1011                                     ..\n");
+}
+
+#[test]
+fn hex_parse() {
+    let bytes: Vec<u8> = (0..259).map(|b| b as u8).collect();
+    assert!(parse(&dump(&bytes)) == Ok(bytes));
+    assert!(parse("00000000: 4865 6c6c 6f                             Hello") == Ok(b"Hello".to_vec()));
+    assert!(parse("00000000: 486x  H.").is_err());
+    assert!(parse("oops").is_err());
+}
+
+#[test]
+fn hex_byte_at() {
+    let line = "00000000: 4865 6c6c 6f                             Hello";
+    assert!(byte_at(line, 9) == None);
+    assert!(byte_at(line, 10) == Some((0, true)));
+    assert!(byte_at(line, 13) == Some((1, true)));
+    assert!(byte_at(line, 14) == None);
+    assert!(byte_at(line, 20) == Some((4, true)));
+    assert!(byte_at(line, 22) == None); // Past the last byte
+    assert!(byte_at(line, 51) == Some((0, false)));
+    assert!(byte_at(line, 55) == Some((4, false)));
+    assert!(byte_at(line, 56) == None);
+    assert!(hex_column(4) == 20 && text_column(4) == 55);
+}
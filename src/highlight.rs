@@ -0,0 +1,652 @@
+use std::cell::RefCell;
+use std::iter;
+use std::path::Path;
+
+use buffer::Buffer;
+
+/// What a piece of text is, which picks its color from the theme
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Kind {
+    Comment,
+    String,
+    Number,
+    Keyword,
+    Type,
+    Variable,
+    Heading,
+    Emphasis,
+    Code,
+}
+
+/// A highlighted part of a line, [start, end) in bytes
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub kind: Kind,
+}
+
+/// What a grammar carries from the end of one line to the start of the next, like being inside a
+/// block comment. Each grammar decides what the numbers mean, the first line starts at 0.
+pub type State = u32;
+
+/// A language's rules for highlighting text a line at a time
+pub trait Grammar {
+    fn name(&self) -> &'static str;
+
+    /// Highlights a line that starts in state, and returns the state it ends in
+    fn highlight(&self, line: &str, state: State) -> (Vec<Span>, State);
+}
+
+/// The grammars ted knows, by name, with the file extensions they're for
+const GRAMMARS: &'static [(&'static str, &'static [&'static str])] = &[
+    ("rust", &["rs"]),
+    ("toml", &["toml"]),
+    ("markdown", &["md", "markdown"]),
+    ("sh", &["sh", "bash", "zsh"]),
+];
+
+/// The grammar called name, as :set filetype takes it
+pub fn grammar(name: &str) -> Option<Box<Grammar>> {
+    match name {
+        "rust" => Some(Box::new(Rust)),
+        "toml" => Some(Box::new(Toml)),
+        "markdown" => Some(Box::new(Markdown)),
+        "sh" => Some(Box::new(Shell)),
+        _ => None,
+    }
+}
+
+/// The grammar for a file, going by its extension
+pub fn grammar_for_path(path: &Path) -> Option<Box<Grammar>> {
+    let extension = match path.extension().and_then(|e| e.to_str()) { Some(e) => e, None => { return None; } };
+    GRAMMARS.iter().find(|&&(_, extensions)| extensions.contains(&extension))
+                   .and_then(|&(name, _)| grammar(name))
+}
+
+/// Highlights a buffer's lines with a grammar. Lines are remembered along with the state they end
+/// in, so after an edit only the lines from the edited one on are highlighted again, and only as
+/// far down as they're looked at.
+pub struct Highlighter {
+    grammar: Option<Box<Grammar>>,
+    lines: RefCell<Vec<(Vec<Span>, State)>>, // From the first line on
+}
+
+impl Highlighter {
+    pub fn new(grammar: Option<Box<Grammar>>) -> Highlighter {
+        Highlighter {
+            grammar: grammar,
+            lines: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub fn grammar_name(&self) -> Option<&'static str> {
+        self.grammar.as_ref().map(|g| g.name())
+    }
+
+    pub fn set_grammar(&mut self, grammar: Option<Box<Grammar>>) {
+        self.grammar = grammar;
+        self.edited(0);
+    }
+
+    /// Forgets the lines from line on, after the text changed there
+    pub fn edited(&mut self, line: usize) {
+        self.lines.get_mut().truncate(line);
+    }
+
+    /// The spans of one of buffer's lines, highlighting the lines before it first if need be
+    pub fn line(&self, buffer: &Buffer, line: usize) -> Vec<Span> {
+        let grammar = match self.grammar { Some(ref grammar) => grammar, None => { return Vec::new(); } };
+        if line >= buffer.line_count() {
+            return Vec::new();
+        }
+
+        let mut lines = self.lines.borrow_mut();
+        while lines.len() <= line {
+            let state = lines.last().map_or(0, |&(_, state)| state);
+            let highlighted = grammar.highlight(&buffer.line(lines.len()), state);
+            lines.push(highlighted);
+        }
+        lines[line].0.clone()
+    }
+
+    /// How many lines are highlighted and remembered
+    #[cfg(test)]
+    pub fn highlighted(&self) -> usize {
+        self.lines.borrow().len()
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+// Scanning lines
+
+/// Goes through a line a character at a time, collecting spans
+struct Scanner<'a> {
+    line: &'a str,
+    pos: usize,
+    spans: Vec<Span>,
+}
+
+impl<'a> Scanner<'a> {
+    fn new(line: &'a str) -> Scanner<'a> {
+        Scanner {
+            line: line,
+            pos: 0,
+            spans: Vec::new(),
+        }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.line[self.pos..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    /// The character before the current one
+    fn prev(&self) -> Option<char> {
+        self.line[..self.pos].chars().next_back()
+    }
+
+    fn done(&self) -> bool {
+        self.pos >= self.line.len()
+    }
+
+    fn bump(&mut self) {
+        if let Some(c) = self.peek() {
+            self.pos += c.len_utf8();
+        }
+    }
+
+    fn eat(&mut self, text: &str) -> bool {
+        if self.rest().starts_with(text) {
+            self.pos += text.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn eat_while<F: Fn(char) -> bool>(&mut self, f: F) -> &'a str {
+        let start = self.pos;
+        while self.peek().map_or(false, |c| f(c)) {
+            self.bump();
+        }
+        &self.line[start..self.pos]
+    }
+
+    fn skip_to_end(&mut self) {
+        self.pos = self.line.len();
+    }
+
+    /// Goes past the closing quote of a string whose opening quote is behind. Returns whether the
+    /// string ends on this line.
+    fn quoted(&mut self, quote: char, escapes: bool) -> bool {
+        while let Some(c) = self.peek() {
+            self.bump();
+            if c == quote {
+                return true;
+            } else if c == '\\' && escapes {
+                self.bump();
+            }
+        }
+        false
+    }
+
+    /// Goes past text up to and including close. Returns whether it was found on this line.
+    fn until(&mut self, close: &str) -> bool {
+        match self.rest().find(close) {
+            Some(i) => {
+                self.pos += i + close.len();
+                true
+            },
+            None => {
+                self.skip_to_end();
+                false
+            },
+        }
+    }
+
+    /// Adds a span from start to where the scanner is
+    fn span(&mut self, start: usize, kind: Kind) {
+        if self.pos > start {
+            self.spans.push(Span { start: start, end: self.pos, kind: kind });
+        }
+    }
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+// Grammars
+
+const RUST_KEYWORDS: &'static [&'static str] = &[
+    "as", "break", "const", "continue", "crate", "dyn", "else", "enum", "extern", "false", "fn", "for",
+    "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub", "ref", "return", "self",
+    "Self", "static", "struct", "super", "trait", "true", "type", "unsafe", "use", "where", "while",
+];
+
+const RUST_TYPES: &'static [&'static str] = &[
+    "bool", "char", "str", "u8", "u16", "u32", "u64", "usize", "i8", "i16", "i32", "i64", "isize",
+    "f32", "f64",
+];
+
+/// Rust. The state is how deep in nested block comments the line ends, RUST_STRING in a string that
+/// goes on to the next line, or RUST_RAW_STRING plus the number of #s in a raw string that does.
+struct Rust;
+
+const RUST_STRING: State = 1 << 16;
+const RUST_RAW_STRING: State = 2 << 16;
+
+impl Grammar for Rust {
+    fn name(&self) -> &'static str { "rust" }
+
+    fn highlight(&self, line: &str, mut state: State) -> (Vec<Span>, State) {
+        let mut s = Scanner::new(line);
+
+        // Finish what the last line started
+        if state >= RUST_RAW_STRING {
+            let close: String = iter::once('"').chain(iter::repeat('#').take((state - RUST_RAW_STRING) as usize)).collect();
+            if s.until(&close) {
+                state = 0;
+            }
+            s.span(0, Kind::String);
+        } else if state == RUST_STRING {
+            if s.quoted('"', true) {
+                state = 0;
+            }
+            s.span(0, Kind::String);
+        } else if state > 0 {
+            state = block_comment(&mut s, state);
+            s.span(0, Kind::Comment);
+        }
+
+        while state == 0 && !s.done() {
+            let start = s.pos;
+            let rest = s.rest();
+            let c = s.peek().unwrap();
+            if s.eat("//") {
+                s.skip_to_end();
+                s.span(start, Kind::Comment);
+            } else if s.eat("/*") {
+                state = block_comment(&mut s, 1);
+                s.span(start, Kind::Comment);
+            } else if let Some(hashes) = raw_string_start(rest) {
+                s.pos += rest.find('"').unwrap() + 1;
+                let close: String = iter::once('"').chain(iter::repeat('#').take(hashes)).collect();
+                if !s.until(&close) {
+                    state = RUST_RAW_STRING + hashes as State;
+                }
+                s.span(start, Kind::String);
+            } else if s.eat("\"") || s.eat("b\"") {
+                if !s.quoted('"', true) {
+                    state = RUST_STRING;
+                }
+                s.span(start, Kind::String);
+            } else if c == '\'' || rest.starts_with("b'") {
+                // A character, or a lifetime if there's no closing quote right after one
+                let quote = rest.find('\'').unwrap();
+                let after: Vec<char> = rest[quote+1..].chars().take(3).collect();
+                s.pos += quote + 1;
+                if after.get(0) == Some(&'\\') || after.get(1) == Some(&'\'') {
+                    s.quoted('\'', true);
+                    s.span(start, Kind::String);
+                } else {
+                    s.eat_while(is_word_char);
+                    s.span(start, Kind::Type);
+                }
+            } else if c.is_digit(10) {
+                s.eat_while(is_word_char);
+                while s.rest().starts_with('.') && s.rest()[1..].chars().next().map_or(false, |c| c.is_digit(10)) {
+                    s.bump();
+                    s.eat_while(is_word_char);
+                }
+                s.span(start, Kind::Number);
+            } else if is_word_char(c) {
+                let word = s.eat_while(is_word_char);
+                if RUST_KEYWORDS.contains(&word) {
+                    s.span(start, Kind::Keyword);
+                } else if RUST_TYPES.contains(&word) || c.is_uppercase() {
+                    s.span(start, Kind::Type);
+                }
+            } else {
+                s.bump();
+            }
+        }
+        (s.spans, state)
+    }
+}
+
+/// Goes through a block comment depth comments deep, returning how deep it is at the end of the
+/// line
+fn block_comment(s: &mut Scanner, mut depth: State) -> State {
+    while !s.done() {
+        if s.eat("*/") {
+            depth -= 1;
+            if depth == 0 {
+                break;
+            }
+        } else if s.eat("/*") {
+            depth += 1;
+        } else {
+            s.bump();
+        }
+    }
+    depth
+}
+
+/// The number of #s if text starts with a raw string like r"..." or br#"..."#
+fn raw_string_start(text: &str) -> Option<usize> {
+    let text = if text.starts_with("br") { &text[2..] } else if text.starts_with('r') { &text[1..] } else { return None; };
+    let hashes = text.chars().take_while(|&c| c == '#').count();
+    if text[hashes..].starts_with('"') { Some(hashes) } else { None }
+}
+
+/// TOML. The state is TOML_BASIC or TOML_LITERAL in a """ or ''' string that goes on to the next
+/// line.
+struct Toml;
+
+const TOML_BASIC: State = 1;
+const TOML_LITERAL: State = 2;
+
+impl Grammar for Toml {
+    fn name(&self) -> &'static str { "toml" }
+
+    fn highlight(&self, line: &str, mut state: State) -> (Vec<Span>, State) {
+        let mut s = Scanner::new(line);
+        if state != 0 {
+            if s.until(if state == TOML_BASIC { "\"\"\"" } else { "'''" }) {
+                state = 0;
+            }
+            s.span(0, Kind::String);
+        }
+
+        while state == 0 && !s.done() {
+            let start = s.pos;
+            let c = s.peek().unwrap();
+            if c == '#' {
+                s.skip_to_end();
+                s.span(start, Kind::Comment);
+            } else if c == '[' && line[..start].trim().is_empty() {
+                // A table header
+                s.until("]");
+                s.eat("]");
+                s.span(start, Kind::Heading);
+            } else if s.eat("\"\"\"") || s.eat("'''") {
+                let close = &line[start..s.pos];
+                if !s.until(close) {
+                    state = if close == "\"\"\"" { TOML_BASIC } else { TOML_LITERAL };
+                }
+                s.span(start, Kind::String);
+            } else if c == '"' || c == '\'' {
+                s.bump();
+                s.quoted(c, c == '"');
+                let key = s.rest().trim_left().starts_with('=') || s.rest().trim_left().starts_with('.');
+                s.span(start, if key { Kind::Variable } else { Kind::String });
+            } else if is_word_char(c) || c == '-' || c == '+' {
+                let word = s.eat_while(|c| is_word_char(c) || "-+.:".contains(c));
+                let first = word.trim_left_matches(|c: char| c == '+' || c == '-');
+                if s.rest().trim_left().starts_with('=') {
+                    s.span(start, Kind::Variable);
+                } else if word == "true" || word == "false" {
+                    s.span(start, Kind::Keyword);
+                } else if first.starts_with(|c: char| c.is_digit(10)) || first == "inf" || first == "nan" {
+                    s.span(start, Kind::Number);
+                }
+            } else {
+                s.bump();
+            }
+        }
+        (s.spans, state)
+    }
+}
+
+/// Markdown. The state is MARKDOWN_FENCE inside a ``` code block.
+struct Markdown;
+
+const MARKDOWN_FENCE: State = 1;
+
+impl Grammar for Markdown {
+    fn name(&self) -> &'static str { "markdown" }
+
+    fn highlight(&self, line: &str, state: State) -> (Vec<Span>, State) {
+        let mut s = Scanner::new(line);
+        let trimmed = line.trim_left();
+        let whole = |kind| vec![Span { start: 0, end: line.len(), kind: kind }];
+        if trimmed.starts_with("```") {
+            return (whole(Kind::Code), if state == MARKDOWN_FENCE { 0 } else { MARKDOWN_FENCE });
+        } else if state == MARKDOWN_FENCE {
+            return (if line.is_empty() { Vec::new() } else { whole(Kind::Code) }, state);
+        }
+
+        let hashes = trimmed.chars().take_while(|&c| c == '#').count();
+        if hashes >= 1 && hashes <= 6 && trimmed[hashes..].chars().next().map_or(true, |c| c == ' ') {
+            return (whole(Kind::Heading), 0);
+        } else if trimmed.starts_with('>') {
+            return (whole(Kind::Comment), 0);
+        }
+
+        // A list item's marker
+        s.eat_while(|c| c == ' ');
+        let start = s.pos;
+        if s.eat("- ") || s.eat("* ") || s.eat("+ ") {
+            s.span(start, Kind::Keyword);
+        } else if !s.eat_while(|c| c.is_digit(10)).is_empty() && (s.eat(". ") || s.eat(") ")) {
+            s.span(start, Kind::Keyword);
+        } else {
+            s.pos = start;
+        }
+
+        while !s.done() {
+            let start = s.pos;
+            let c = s.peek().unwrap();
+            if c == '\\' {
+                s.bump();
+                s.bump();
+            } else if c == '`' {
+                let ticks = s.eat_while(|c| c == '`');
+                if s.until(ticks) {
+                    s.span(start, Kind::Code);
+                }
+            } else if (c == '*' || c == '_') && !(c == '_' && s.prev().map_or(false, is_word_char)) {
+                // Emphasis needs a closing marker, and no space right inside the opening one
+                let marker = if s.rest()[1..].starts_with(c) { &s.rest()[..2] } else { &s.rest()[..1] };
+                s.pos += marker.len();
+                if s.peek().map_or(false, |c| !c.is_whitespace()) && s.until(marker) {
+                    s.span(start, Kind::Emphasis);
+                }
+            } else if c == '[' {
+                // The target of a link, [text](target)
+                s.bump();
+                if let Some(i) = s.rest().find("](") {
+                    s.pos += i + 1;
+                    let target = s.pos;
+                    if s.until(")") {
+                        s.span(target, Kind::String);
+                    }
+                }
+            } else {
+                s.bump();
+            }
+        }
+        (s.spans, 0)
+    }
+}
+
+const SHELL_KEYWORDS: &'static [&'static str] = &[
+    "if", "then", "else", "elif", "fi", "for", "while", "until", "do", "done", "case", "esac", "in",
+    "function", "select", "return", "break", "continue", "local", "export", "readonly", "declare",
+    "unset", "exit",
+];
+
+/// Shell scripts. The state is SHELL_DOUBLE or SHELL_SINGLE in a quoted string that goes on to the
+/// next line.
+struct Shell;
+
+const SHELL_DOUBLE: State = 1;
+const SHELL_SINGLE: State = 2;
+
+impl Grammar for Shell {
+    fn name(&self) -> &'static str { "sh" }
+
+    fn highlight(&self, line: &str, mut state: State) -> (Vec<Span>, State) {
+        let mut s = Scanner::new(line);
+        if state != 0 {
+            if s.quoted(if state == SHELL_DOUBLE { '"' } else { '\'' }, state == SHELL_DOUBLE) {
+                state = 0;
+            }
+            s.span(0, Kind::String);
+        }
+
+        // Words are only special on their own, not as part of things like paths or options
+        let separator = |c: Option<char>| c.map_or(true, |c| c.is_whitespace() || ";&|(){}!".contains(c));
+        while state == 0 && !s.done() {
+            let start = s.pos;
+            let c = s.peek().unwrap();
+            if c == '#' && separator(s.prev()) {
+                s.skip_to_end();
+                s.span(start, Kind::Comment);
+            } else if c == '"' || c == '\'' {
+                s.bump();
+                if !s.quoted(c, c == '"') {
+                    state = if c == '"' { SHELL_DOUBLE } else { SHELL_SINGLE };
+                }
+                s.span(start, Kind::String);
+            } else if c == '\\' {
+                s.bump();
+                s.bump();
+            } else if c == '$' {
+                s.bump();
+                if s.eat("{") {
+                    s.until("}");
+                } else if s.peek().map_or(false, |c| "?#@*!$-".contains(c) || c.is_digit(10)) {
+                    s.bump();
+                } else {
+                    s.eat_while(is_word_char);
+                }
+                s.span(start, Kind::Variable);
+            } else if is_word_char(c) {
+                let before = s.prev();
+                let word = s.eat_while(is_word_char);
+                if s.peek() == Some('=') {
+                    s.span(start, Kind::Variable);
+                } else if separator(before) && separator(s.peek()) {
+                    if SHELL_KEYWORDS.contains(&word) {
+                        s.span(start, Kind::Keyword);
+                    } else if word.chars().all(|c| c.is_digit(10)) {
+                        s.span(start, Kind::Number);
+                    }
+                }
+            } else {
+                s.bump();
+            }
+        }
+        (s.spans, state)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+
+/// The highlighted parts of each line, as (text, kind)
+#[cfg(test)]
+fn highlight_text(grammar: &Grammar, text: &str) -> Vec<Vec<(String, Kind)>> {
+    let mut state = 0;
+    text.split('\n').map(|line| {
+        let (spans, end) = grammar.highlight(line, state);
+        state = end;
+        spans.iter().map(|span| (line[span.start..span.end].to_string(), span.kind)).collect()
+    }).collect()
+}
+
+#[cfg(test)]
+fn spans(parts: &[(&str, Kind)]) -> Vec<(String, Kind)> {
+    parts.iter().map(|&(text, kind)| (text.to_string(), kind)).collect()
+}
+
+#[test]
+fn highlight_rust() {
+    use self::Kind::*;
+
+    let lines = highlight_text(&Rust, "fn main() -> u8 { // Hi\n\
+                                       let s = \"a \\\"b\"; /* one /* two */\n\
+                                       still */ 'x' 'a 1.5 0..2 r#\"raw\n\
+                                       \"# Foo");
+    assert!(lines[0] == spans(&[("fn", Keyword), ("u8", Type), ("// Hi", Comment)]));
+    assert!(lines[1] == spans(&[("let", Keyword), ("\"a \\\"b\"", String), ("/* one /* two */", Comment)]));
+    assert!(lines[2] == spans(&[("still */", Comment), ("'x'", String), ("'a", Type), ("1.5", Number),
+                                ("0", Number), ("2", Number), ("r#\"raw", String)]));
+    assert!(lines[3] == spans(&[("\"#", String), ("Foo", Type)]));
+}
+
+#[test]
+fn highlight_toml() {
+    use self::Kind::*;
+
+    let lines = highlight_text(&Toml, "[table] # Comment\n\
+                                       a.b = \"x\" # y\n\
+                                       \"key\" = [1, -2.5, true, 1979-05-27]\n\
+                                       text = '''one\n\
+                                       two'''");
+    assert!(lines[0] == spans(&[("[table]", Heading), ("# Comment", Comment)]));
+    assert!(lines[1] == spans(&[("a.b", Variable), ("\"x\"", String), ("# y", Comment)]));
+    assert!(lines[2] == spans(&[("\"key\"", Variable), ("1", Number), ("-2.5", Number), ("true", Keyword),
+                                ("1979-05-27", Number)]));
+    assert!(lines[3] == spans(&[("text", Variable), ("'''one", String)]));
+    assert!(lines[4] == spans(&[("two'''", String)]));
+}
+
+#[test]
+fn highlight_markdown() {
+    use self::Kind::*;
+
+    let lines = highlight_text(&Markdown, "# Title\n\
+                                           - a *b* `c` [d](e) snake_case\n\
+                                           ```\n\
+                                           # code\n\
+                                           ```\n\
+                                           > quote");
+    assert!(lines[0] == spans(&[("# Title", Heading)]));
+    assert!(lines[1] == spans(&[("- ", Keyword), ("*b*", Emphasis), ("`c`", Code), ("(e)", String)]));
+    assert!(lines[2] == spans(&[("```", Code)]));
+    assert!(lines[3] == spans(&[("# code", Code)]));
+    assert!(lines[4] == spans(&[("```", Code)]));
+    assert!(lines[5] == spans(&[("> quote", Comment)]));
+}
+
+#[test]
+fn highlight_shell() {
+    use self::Kind::*;
+
+    let lines = highlight_text(&Shell, "#!/bin/sh\n\
+                                        if [ \"$1\" = x ]; then NAME=${2} # c\n\
+                                        echo 'a\n\
+                                        b' $HOME do-thing 42 a#b");
+    assert!(lines[0] == spans(&[("#!/bin/sh", Comment)]));
+    assert!(lines[1] == spans(&[("if", Keyword), ("\"$1\"", String), ("then", Keyword), ("NAME", Variable),
+                                ("${2}", Variable), ("# c", Comment)]));
+    assert!(lines[2] == spans(&[("'a", String)]));
+    assert!(lines[3] == spans(&[("b'", String), ("$HOME", Variable), ("42", Number)]));
+}
+
+#[test]
+fn highlight_incrementally() {
+    let mut buffer = Buffer::from_string("/*\n1\n2\n*/\n3".to_string());
+    let mut highlighter = Highlighter::new(grammar_for_path(Path::new("main.rs")));
+    assert!(highlighter.grammar_name() == Some("rust"));
+    assert!(highlighter.line(&buffer, 1) == vec![Span { start: 0, end: 1, kind: Kind::Comment }]);
+    assert!(highlighter.highlighted() == 2);
+
+    // Only the lines from the edit on are done again
+    assert!(highlighter.line(&buffer, 4) == vec![Span { start: 0, end: 1, kind: Kind::Number }]);
+    buffer.remove(0, 1);
+    highlighter.edited(0);
+    assert!(highlighter.line(&buffer, 1) == vec![Span { start: 0, end: 1, kind: Kind::Number }]);
+    assert!(highlighter.highlighted() == 2);
+
+    highlighter.set_grammar(None);
+    assert!(highlighter.line(&buffer, 1).is_empty());
+}
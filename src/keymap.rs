@@ -0,0 +1,314 @@
+use motion::{CharMotion, Motion, TextObject};
+use normal_command::Operator;
+use ted::Event;
+use trie::Trie;
+use window::Direction;
+
+/// Keys that resolve to nothing wait this long for more keys when they could still become a longer
+/// mapping
+pub const KEY_TIMEOUT_MS: i64 = 1000;
+
+/// What a sequence of keys does
+#[derive(Clone, Debug, PartialEq)]
+pub enum Action {
+    Motion(Motion),
+    CharMotion(CharMotion),
+    Operator(Operator),
+    OperateLines(Operator), // dd, cc, yy, >>, <<
+    TextObject(TextObject),
+    InsertMode,
+    CommandMode,
+    VisualCharMode,
+    VisualLineMode,
+    VisualBlockMode,
+    NormalMode,
+    SwapSelectionEnds, // o in visual mode
+    SearchForward,
+    SearchBackward,
+    SearchNext,
+    SearchPrevious,
+    Undo,
+    Redo,
+    Register, // "<register>, picks the register for the next command
+    PutAfter,
+    PutBefore,
+    FocusWindow(Direction), // <C-w> h, j, k and l
+    NextWindow,
+    SplitWindow { vertical: bool },
+    CloseWindow,
+    OnlyWindow,
+    NextTab,
+    PreviousTab,
+    Keys(Vec<Event>), // Mapped by the user, the keys are typed in place of the mapping
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Resolve {
+    Wait,                  // The keys could still become a longer mapping
+    Action(Action, usize), // The longest mapped prefix of the keys, and its length
+    Unmapped,              // No prefix of the keys is mapped
+}
+
+pub struct Keymap {
+    trie: Trie<Event, Action>,
+}
+
+impl Keymap {
+    pub fn new() -> Keymap {
+        Keymap {
+            trie: Trie::new(),
+        }
+    }
+
+    /// Default normal mode keys
+    pub fn normal() -> Keymap {
+        let mut keymap = Keymap::new();
+        keymap.bind_motions();
+        keymap.bind_str("i", Action::InsertMode);
+        keymap.bind_str(":", Action::CommandMode);
+        keymap.bind_str("v", Action::VisualCharMode);
+        keymap.bind_str("V", Action::VisualLineMode);
+        keymap.bind(&[Event::Ctrl('v')], Action::VisualBlockMode);
+        keymap.bind_str("u", Action::Undo);
+        keymap.bind_str("/", Action::SearchForward);
+        keymap.bind_str("?", Action::SearchBackward);
+        keymap.bind_str("n", Action::SearchNext);
+        keymap.bind_str("N", Action::SearchPrevious);
+        keymap.bind(&[Event::Ctrl('r')], Action::Redo);
+        keymap.bind_str("\"", Action::Register);
+        keymap.bind_str("p", Action::PutAfter);
+        keymap.bind_str("P", Action::PutBefore);
+
+        for &(key, operator) in OPERATORS {
+            keymap.bind_str(key, Action::Operator(operator));
+            keymap.bind_str(&format!("{}{}", key, key), Action::OperateLines(operator));
+        }
+        keymap.bind_str("g~~", Action::OperateLines(Operator::ToggleCase));
+
+        for &(key, ref action) in &[('h', Action::FocusWindow(Direction::Left)),
+                                ('j', Action::FocusWindow(Direction::Down)),
+                                ('k', Action::FocusWindow(Direction::Up)),
+                                ('l', Action::FocusWindow(Direction::Right)),
+                                ('w', Action::NextWindow),
+                                ('s', Action::SplitWindow { vertical: false }),
+                                ('v', Action::SplitWindow { vertical: true }),
+                                ('c', Action::CloseWindow),
+                                ('q', Action::CloseWindow),
+                                ('o', Action::OnlyWindow)] {
+            keymap.bind(&[Event::Ctrl('w'), Event::Char(key)], action.clone());
+        }
+        keymap.bind_str("gt", Action::NextTab);
+        keymap.bind_str("gT", Action::PreviousTab);
+        keymap
+    }
+
+    /// Default visual mode keys. Operators work on the selection instead of waiting for a motion.
+    pub fn visual() -> Keymap {
+        let mut keymap = Keymap::new();
+        keymap.bind_motions();
+        keymap.bind_str("v", Action::VisualCharMode);
+        keymap.bind_str("V", Action::VisualLineMode);
+        keymap.bind(&[Event::Ctrl('v')], Action::VisualBlockMode);
+        keymap.bind(&[Event::Esc], Action::NormalMode);
+        keymap.bind_str("o", Action::SwapSelectionEnds);
+        keymap.bind_str(":", Action::CommandMode);
+        keymap.bind_str("n", Action::SearchNext);
+        keymap.bind_str("N", Action::SearchPrevious);
+        keymap.bind_str("\"", Action::Register);
+
+        for &(key, operator) in OPERATORS {
+            keymap.bind_str(key, Action::Operator(operator));
+        }
+        keymap.bind_str("~", Action::Operator(Operator::ToggleCase));
+        keymap.bind_str("x", Action::Operator(Operator::Delete));
+        keymap.bind_str("s", Action::Operator(Operator::Change));
+        keymap
+    }
+
+    /// Default keys for after an operator has been typed
+    pub fn operator_pending() -> Keymap {
+        let mut keymap = Keymap::new();
+        keymap.bind_motions();
+
+        keymap.bind_str("iw", Action::TextObject(TextObject::Word { around: false }));
+        keymap.bind_str("aw", Action::TextObject(TextObject::Word { around: true }));
+        for &quote in &['"', '\'', '`'] {
+            keymap.bind_str(&format!("i{}", quote), Action::TextObject(TextObject::Quote { quote: quote, around: false }));
+            keymap.bind_str(&format!("a{}", quote), Action::TextObject(TextObject::Quote { quote: quote, around: true }));
+        }
+        for &(open, close, alias) in &[('(', ')', Some('b')), ('{', '}', Some('B')), ('[', ']', None), ('<', '>', None)] {
+            let keys = [Some(open), Some(close), alias];
+            for key in keys.iter().filter_map(|k| *k) {
+                keymap.bind_str(&format!("i{}", key),
+                                Action::TextObject(TextObject::Block { open: open, close: close, around: false }));
+                keymap.bind_str(&format!("a{}", key),
+                                Action::TextObject(TextObject::Block { open: open, close: close, around: true }));
+            }
+        }
+        keymap
+    }
+
+    pub fn bind(&mut self, keys: &[Event], action: Action) {
+        self.trie.set(keys, action);
+    }
+
+    fn bind_str(&mut self, keys: &str, action: Action) {
+        let keys: Vec<Event> = keys.chars().map(Event::Char).collect();
+        self.bind(&keys, action);
+    }
+
+    fn bind_motions(&mut self) {
+        for &(key, motion) in MOTIONS {
+            self.bind_str(key, Action::Motion(motion));
+        }
+        self.bind_str("f", Action::CharMotion(CharMotion::Find));
+        self.bind_str("t", Action::CharMotion(CharMotion::Till));
+        self.bind_str("F", Action::CharMotion(CharMotion::FindBack));
+        self.bind_str("T", Action::CharMotion(CharMotion::TillBack));
+    }
+
+    /// Finds the action for the start of keys. Unless timed_out is set, keys that could still
+    /// become a longer mapping wait for more keys.
+    pub fn resolve(&self, keys: &[Event], timed_out: bool) -> Resolve {
+        if !timed_out && self.trie.has_children(keys) {
+            return Resolve::Wait;
+        }
+
+        for len in (1..keys.len()+1).rev() {
+            if let Some(action) = self.trie.get(&keys[..len]) {
+                return Resolve::Action(action.clone(), len);
+            }
+        }
+        Resolve::Unmapped
+    }
+}
+
+/// The keymaps for each mode that maps keys
+pub struct Keymaps {
+    pub normal: Keymap,
+    pub operator_pending: Keymap,
+    pub insert: Keymap,
+    pub visual: Keymap,
+}
+
+impl Keymaps {
+    pub fn new() -> Keymaps {
+        Keymaps {
+            normal: Keymap::normal(),
+            operator_pending: Keymap::operator_pending(),
+            insert: Keymap::new(),
+            visual: Keymap::visual(),
+        }
+    }
+}
+
+const MOTIONS: &'static [(&'static str, Motion)] = &[
+    ("h", Motion::Left),
+    ("l", Motion::Right),
+    ("k", Motion::Up),
+    ("j", Motion::Down),
+    ("gk", Motion::DisplayUp),
+    ("gj", Motion::DisplayDown),
+    ("w", Motion::WordStart),
+    ("e", Motion::WordEnd),
+    ("b", Motion::WordBack),
+    ("0", Motion::LineStart),
+    ("$", Motion::LineEnd),
+    ("gg", Motion::FirstLine),
+    ("G", Motion::LastLine),
+];
+
+const OPERATORS: &'static [(&'static str, Operator)] = &[
+    ("d", Operator::Delete),
+    ("c", Operator::Change),
+    ("y", Operator::Yank),
+    (">", Operator::Indent),
+    ("<", Operator::Unindent),
+    ("g~", Operator::ToggleCase),
+];
+
+/// Parses keys written like vim's :map arguments, e.g. `<C-r>`, `<Esc>` or `jk`
+pub fn parse_keys(text: &str) -> Result<Vec<Event>, String> {
+    let mut keys = Vec::new();
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        if c == '<' {
+            if let Some(end) = rest.find('>') {
+                let name = &rest[1..end];
+                if let Some(key) = parse_key_name(name) {
+                    keys.push(key);
+                    rest = &rest[end+1..];
+                    continue;
+                } else if name.len() > 1 {
+                    return Err(format!("Unknown key: <{}>", name));
+                }
+            }
+        }
+        keys.push(Event::Char(c));
+        rest = &rest[c.len_utf8()..];
+    }
+
+    if keys.is_empty() {
+        Err("No keys given".to_string())
+    } else {
+        Ok(keys)
+    }
+}
+
+fn parse_key_name(name: &str) -> Option<Event> {
+    let lower = name.to_lowercase();
+    match lower.as_str() {
+        "esc" => Some(Event::Esc),
+        "cr" | "enter" | "return" => Some(Event::Enter),
+        "bs" | "backspace" => Some(Event::Backspace),
+        "space" => Some(Event::Char(' ')),
+        "lt" => Some(Event::Char('<')),
+        "bar" => Some(Event::Char('|')),
+        "up" => Some(Event::Up),
+        "down" => Some(Event::Down),
+        _ => {
+            if lower.starts_with("c-") && lower.chars().count() == 3 {
+                lower.chars().nth(2).map(Event::Ctrl)
+            } else {
+                None
+            }
+        },
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+
+#[test]
+fn keymap_resolve() {
+    let keymap = Keymap::normal();
+
+    assert!(keymap.resolve(&[Event::Char('w')], false) ==
+            Resolve::Action(Action::Motion(Motion::WordStart), 1));
+    assert!(keymap.resolve(&[Event::Char('g')], false) == Resolve::Wait);
+    assert!(keymap.resolve(&[Event::Char('g')], true) == Resolve::Unmapped);
+    assert!(keymap.resolve(&[Event::Char('d'), Event::Char('d')], false) ==
+            Resolve::Action(Action::OperateLines(Operator::Delete), 2));
+    assert!(keymap.resolve(&[Event::Char('d'), Event::Char('w')], false) ==
+            Resolve::Action(Action::Operator(Operator::Delete), 1));
+}
+
+#[test]
+fn keymap_ambiguous_prefix_waits() {
+    let mut keymap = Keymap::normal();
+    keymap.bind(&[Event::Char('j'), Event::Char('k')], Action::Undo);
+
+    assert!(keymap.resolve(&[Event::Char('j')], false) == Resolve::Wait);
+    assert!(keymap.resolve(&[Event::Char('j')], true) == Resolve::Action(Action::Motion(Motion::Down), 1));
+    assert!(keymap.resolve(&[Event::Char('j'), Event::Char('k')], false) == Resolve::Action(Action::Undo, 2));
+}
+
+#[test]
+fn parse_keys_notation() {
+    assert!(parse_keys("jk") == Ok(vec![Event::Char('j'), Event::Char('k')]));
+    assert!(parse_keys("<Esc>") == Ok(vec![Event::Esc]));
+    assert!(parse_keys("<C-r>x") == Ok(vec![Event::Ctrl('r'), Event::Char('x')]));
+    assert!(parse_keys("<lt>>") == Ok(vec![Event::Char('<'), Event::Char('>')]));
+    assert!(parse_keys("<") == Ok(vec![Event::Char('<')]));
+    assert!(parse_keys("<foo>").is_err());
+}
@@ -1,16 +1,40 @@
 extern crate bincode;
+extern crate regex;
 extern crate rustc_serialize;
 extern crate termion;
 extern crate time;
+extern crate toml;
+extern crate unicode_segmentation;
+extern crate unicode_width;
 
 pub use ted::*;
 
 pub mod buffer;
 pub mod buffer_operator;
+pub mod config;
 pub mod cursor;
 pub mod editor;
+pub mod ex;
+pub mod file_format;
+pub mod hex;
+pub mod highlight;
+pub mod keymap;
+pub mod merge;
+pub mod motion;
 pub mod net;
+pub mod normal_command;
 pub mod operation;
+pub mod pager;
+pub mod register;
+pub mod rope;
+pub mod screen;
+pub mod search;
+pub mod substitute;
 pub mod ted;
 pub mod ted_client;
 pub mod ted_server;
+pub mod trie;
+pub mod undo_file;
+pub mod undo_tree;
+pub mod unicode;
+pub mod window;
@@ -2,10 +2,16 @@
 extern crate clap;
 
 extern crate bincode;
+extern crate regex;
 extern crate rustc_serialize;
 extern crate termion;
 extern crate time;
+extern crate toml;
+extern crate unicode_segmentation;
+extern crate unicode_width;
 
+use std::io::{self, Write};
+use std::process;
 use std::thread::Builder;
 
 use buffer_operator::BufferOperator;
@@ -15,13 +21,32 @@ use ted_server::TedServer;
 
 mod buffer;
 mod buffer_operator;
+mod config;
 mod cursor;
 mod editor;
+mod ex;
+mod file_format;
+mod hex;
+mod highlight;
+mod keymap;
+mod merge;
+mod motion;
 mod net;
+mod normal_command;
 mod operation;
+mod pager;
+mod register;
+mod rope;
+mod screen;
+mod search;
+mod substitute;
 mod ted;
 mod ted_client;
 mod ted_server;
+mod undo_file;
+mod undo_tree;
+mod unicode;
+mod window;
 mod trie;
 
 fn main() {
@@ -54,11 +79,20 @@ fn main() {
     } else if let Some(ref matches) = m.subcommand_matches("connect") {
         // Run our client editor
         // address is required
-        Editor::from_server(matches.value_of("address").unwrap()).unwrap().run();
+        match Editor::from_server(matches.value_of("address").unwrap()) {
+            Ok(mut editor) => { editor.run(); },
+            Err(e) => { exit_with_error(&e); },
+        }
     } else {
         match m.value_of("file") {
             Some(file_path) => {
-                Editor::from_file(file_path.to_string()).unwrap().run();
+                match Editor::from_file(file_path.to_string()) {
+                    Ok(mut editor) => {
+                        editor.set_persist_undo(m.is_present("undofile"));
+                        editor.run();
+                    },
+                    Err(e) => { exit_with_error(&format!("Failed to open {}: {}", file_path, e)); },
+                }
             },
             None => {
                 let mut editor = Editor::new();
@@ -67,3 +101,8 @@ fn main() {
         }
     }
 }
+
+fn exit_with_error(message: &str) -> ! {
+    let _ = writeln!(io::stderr(), "ted: {}", message);
+    process::exit(1);
+}
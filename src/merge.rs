@@ -0,0 +1,239 @@
+use std::cmp;
+
+/// Diffs bigger than this many line pairs aren't worth the memory, their middle is treated as one
+/// big change instead
+const MAX_DIFF_CELLS: usize = 4_000_000;
+
+/// Lines [start, end) of the old text, replaced by lines of the new text
+#[derive(Clone, Debug, PartialEq)]
+pub struct Hunk {
+    pub start: usize,
+    pub end: usize,
+    pub lines: Vec<String>,
+}
+
+/// The result of a three-way merge
+#[derive(Clone, Debug, PartialEq)]
+pub struct Merged {
+    pub text: String,
+    pub conflicts: usize, // Places both sides changed differently, marked like git does
+}
+
+/// An edit to text: bytes [from, to) replaced by text
+#[derive(Clone, Debug, PartialEq)]
+pub struct Change {
+    pub from: usize,
+    pub to: usize,
+    pub text: String,
+}
+
+/// The hunks that turn old into new, in order
+pub fn diff(old: &[&str], new: &[&str]) -> Vec<Hunk> {
+    let prefix = old.iter().zip(new).take_while(|&(a, b)| a == b).count();
+    let suffix = old[prefix..].iter().rev().zip(new[prefix..].iter().rev())
+                              .take_while(|&(a, b)| a == b).count();
+    let old = &old[prefix..old.len() - suffix];
+    let new = &new[prefix..new.len() - suffix];
+    let (n, m) = (old.len(), new.len());
+
+    if n * m > MAX_DIFF_CELLS {
+        return vec![Hunk {
+            start: prefix,
+            end: prefix + n,
+            lines: new.iter().map(|l| l.to_string()).collect(),
+        }];
+    }
+
+    // lcs[i][j] is the length of the longest common subsequence of old[i..] and new[j..]
+    let mut lcs = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] =
+                if old[i] == new[j] {
+                    lcs[i + 1][j + 1] + 1
+                } else {
+                    cmp::max(lcs[i + 1][j], lcs[i][j + 1])
+                };
+        }
+    }
+
+    let mut hunks = Vec::new();
+    let mut hunk: Option<Hunk> = None;
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && old[i] == new[j] {
+            if let Some(hunk) = hunk.take() {
+                hunks.push(hunk);
+            }
+            i += 1;
+            j += 1;
+            continue;
+        }
+
+        if hunk.is_none() {
+            hunk = Some(Hunk { start: prefix + i, end: prefix + i, lines: Vec::new() });
+        }
+        let hunk = hunk.as_mut().unwrap();
+        if j < m && (i == n || lcs[i][j + 1] >= lcs[i + 1][j]) {
+            hunk.lines.push(new[j].to_string());
+            j += 1;
+        } else {
+            hunk.end += 1;
+            i += 1;
+        }
+    }
+    if let Some(hunk) = hunk {
+        hunks.push(hunk);
+    }
+    hunks
+}
+
+/// Merges the changes ours and theirs each made to base. Where both changed the same lines
+/// differently, both versions are kept between conflict markers.
+pub fn merge3(base: &str, ours: &str, theirs: &str) -> Merged {
+    let base: Vec<&str> = base.split('\n').collect();
+    let ours: Vec<&str> = ours.split('\n').collect();
+    let theirs: Vec<&str> = theirs.split('\n').collect();
+
+    let mut hunks: Vec<(Hunk, bool)> = // The bool is whether the hunk is ours
+        diff(&base, &ours).into_iter().map(|h| (h, true))
+            .chain(diff(&base, &theirs).into_iter().map(|h| (h, false)))
+            .collect();
+    hunks.sort_by(|a, b| (a.0.start, a.0.end).cmp(&(b.0.start, b.0.end)));
+
+    let mut lines: Vec<String> = Vec::new();
+    let mut conflicts = 0;
+    let mut pos = 0; // Base lines before pos are merged
+    let mut k = 0;
+    while k < hunks.len() {
+        // Hunks that touch each other are merged as one region of base
+        let start = hunks[k].0.start;
+        let mut end = hunks[k].0.end;
+        let mut group_end = k + 1;
+        while group_end < hunks.len() && hunks[group_end].0.start <= end {
+            end = cmp::max(end, hunks[group_end].0.end);
+            group_end += 1;
+        }
+        let group = &hunks[k..group_end];
+
+        lines.extend(base[pos..start].iter().map(|l| l.to_string()));
+        let ours_version = apply_hunks(&base, start, end, group.iter().filter(|h| h.1).map(|h| &h.0));
+        let theirs_version = apply_hunks(&base, start, end, group.iter().filter(|h| !h.1).map(|h| &h.0));
+        if !group.iter().any(|h| !h.1) || ours_version == theirs_version {
+            lines.extend(ours_version);
+        } else if !group.iter().any(|h| h.1) {
+            lines.extend(theirs_version);
+        } else {
+            conflicts += 1;
+            lines.push("<<<<<<< buffer".to_string());
+            lines.extend(ours_version);
+            lines.push("=======".to_string());
+            lines.extend(theirs_version);
+            lines.push(">>>>>>> file".to_string());
+        }
+
+        pos = end;
+        k = group_end;
+    }
+    lines.extend(base[pos..].iter().map(|l| l.to_string()));
+
+    Merged {
+        text: lines.join("\n"),
+        conflicts: conflicts,
+    }
+}
+
+/// Base lines [start, end) with hunks from inside them applied
+fn apply_hunks<'a, I>(base: &[&str], start: usize, end: usize, hunks: I) -> Vec<String>
+    where I: Iterator<Item=&'a Hunk>
+{
+    let mut lines = Vec::new();
+    let mut pos = start;
+    for hunk in hunks {
+        lines.extend(base[pos..hunk.start].iter().map(|l| l.to_string()));
+        lines.extend(hunk.lines.iter().cloned());
+        pos = hunk.end;
+    }
+    lines.extend(base[pos..end].iter().map(|l| l.to_string()));
+    lines
+}
+
+/// The edits that turn old into new, in order. Only the lines that differ are touched, so they
+/// can be applied as operations without disturbing the rest of the buffer.
+pub fn changes(old: &str, new: &str) -> Vec<Change> {
+    let old_lines: Vec<&str> = old.split('\n').collect();
+    let new_lines: Vec<&str> = new.split('\n').collect();
+    let line_count = old_lines.len();
+
+    // Byte index of the start of each line
+    let mut starts = Vec::with_capacity(line_count);
+    let mut index = 0;
+    for line in &old_lines {
+        starts.push(index);
+        index += line.len() + 1;
+    }
+
+    diff(&old_lines, &new_lines).into_iter().map(|hunk| {
+        let text = hunk.lines.join("\n");
+        if hunk.end > hunk.start && !hunk.lines.is_empty() {
+            // Replace the lines, but not the line break after them
+            Change { from: starts[hunk.start], to: starts[hunk.end - 1] + old_lines[hunk.end - 1].len(), text: text }
+        } else if hunk.end > hunk.start {
+            // Remove the lines along with one line break
+            if hunk.end < line_count {
+                Change { from: starts[hunk.start], to: starts[hunk.end], text: text }
+            } else {
+                Change { from: starts[hunk.start].saturating_sub(1), to: old.len(), text: text }
+            }
+        } else if hunk.start < line_count {
+            Change { from: starts[hunk.start], to: starts[hunk.start], text: text + "\n" }
+        } else {
+            Change { from: old.len(), to: old.len(), text: format!("\n{}", text) }
+        }
+    }).collect()
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+
+#[cfg(test)]
+fn apply_changes(text: &str, changes: &[Change]) -> String {
+    let mut text = text.to_string();
+    for change in changes.iter().rev() {
+        text = format!("{}{}{}", &text[..change.from], change.text, &text[change.to..]);
+    }
+    text
+}
+
+#[test]
+fn merge_diff() {
+    assert!(diff(&["a", "b", "c"], &["a", "x", "c", "d"]) ==
+            vec![Hunk { start: 1, end: 2, lines: vec!["x".to_string()] },
+                 Hunk { start: 3, end: 3, lines: vec!["d".to_string()] }]);
+    assert!(diff(&["a", "b"], &["a", "b"]) == vec![]);
+    assert!(diff(&["a", "b", "c"], &["c"]) == vec![Hunk { start: 0, end: 2, lines: vec![] }]);
+}
+
+#[test]
+fn merge_changes() {
+    for &(old, new) in &[("a\nb\nc", "a\nx\nc"), ("a\nb", "a\nx\nb"), ("a\nb", "a\nb\nc"), ("a\nb\nc", "a\nc"),
+                         ("a\nb", "a"), ("a\nb", "b"), ("a", ""), ("", "a\nb"), ("a\nb\n", "x\nb\ny\n")] {
+        assert!(apply_changes(old, &changes(old, new)) == new);
+    }
+    assert!(changes("a\nb\nc", "a\nx\nc") == vec![Change { from: 2, to: 3, text: "x".to_string() }]);
+}
+
+#[test]
+fn merge_three_way() {
+    let base = "one\ntwo\nthree\nfour\n";
+    let merged = merge3(base, "one\n2\nthree\nfour\n", "one\ntwo\nthree\n4\n");
+    assert!(merged == Merged { text: "one\n2\nthree\n4\n".to_string(), conflicts: 0 });
+
+    let merged = merge3(base, "one\n2\nthree\nfour\n", "one\nTWO\nthree\nfour\n");
+    assert!(merged.conflicts == 1);
+    assert!(merged.text == "one\n<<<<<<< buffer\n2\n=======\nTWO\n>>>>>>> file\nthree\nfour\n");
+
+    // The same change on both sides isn't a conflict
+    let merged = merge3(base, "zero\none\ntwo\nthree\nfour\n", "zero\none\ntwo\nthree\nfour\n");
+    assert!(merged == Merged { text: "zero\none\ntwo\nthree\nfour\n".to_string(), conflicts: 0 });
+}
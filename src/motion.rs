@@ -0,0 +1,382 @@
+use std::cmp;
+
+use buffer::Buffer;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Motion {
+    Left,              // h
+    Right,             // l
+    Up,                // k
+    Down,              // j
+    DisplayUp,         // gk, up a row of the screen when lines wrap
+    DisplayDown,       // gj
+    WordStart,         // w
+    WordEnd,           // e
+    WordBack,          // b
+    FindForward(char), // f<char>
+    TillForward(char), // t<char>
+    FindBack(char),    // F<char>
+    TillBack(char),    // T<char>
+    LineStart,         // 0
+    LineEnd,           // $
+    FirstLine,         // gg, or the count'th line
+    LastLine,          // G, or the count'th line
+}
+
+/// How the text between the cursor and a motion's target is treated by an operator
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MotionKind {
+    Exclusive, // The character at the target isn't included
+    Inclusive, // The character at the target is included
+    Linewise,  // Every line between the cursor and the target is included
+}
+
+impl Motion {
+    pub fn kind(&self) -> MotionKind {
+        match *self {
+            Motion::Left | Motion::Right | Motion::DisplayUp | Motion::DisplayDown | Motion::WordStart | Motion::WordBack |
+            Motion::FindBack(_) | Motion::TillBack(_) | Motion::LineStart => MotionKind::Exclusive,
+            Motion::WordEnd | Motion::FindForward(_) | Motion::TillForward(_) |
+            Motion::LineEnd => MotionKind::Inclusive,
+            Motion::Up | Motion::Down | Motion::FirstLine | Motion::LastLine => MotionKind::Linewise,
+        }
+    }
+
+    /// Buffer index the motion goes to from index, or None if the motion fails. Without a wrap
+    /// width to go by, gk and gj move a whole line, keeping the column.
+    pub fn target(&self, buffer: &Buffer, index: usize, count: Option<usize>) -> Option<usize> {
+        let n = count.unwrap_or(1);
+        let line = buffer.line_at_index(index);
+        let line_info = buffer.line_info(line);
+        let line_end = line_info.buf_index + line_info.length;
+        let last_line = buffer.line_count() - 1;
+
+        match *self {
+            Motion::Left => {
+                Some(repeat(n, index, |i| if i > line_info.buf_index { buffer.prev_grapheme(i) } else { i }))
+            },
+            Motion::Right => Some(repeat(n, index, |i| if i < line_end { buffer.next_grapheme(i) } else { i })),
+            Motion::Up => {
+                if line == 0 { return None; }
+                Some(buffer.line_info(line.saturating_sub(n)).buf_index)
+            },
+            Motion::Down => {
+                if line == last_line { return None; }
+                Some(buffer.line_info(cmp::min(line + n, last_line)).buf_index)
+            },
+            Motion::DisplayUp | Motion::DisplayDown => {
+                let target =
+                    if *self == Motion::DisplayUp {
+                        if line == 0 { return None; }
+                        line.saturating_sub(n)
+                    } else {
+                        if line == last_line { return None; }
+                        cmp::min(line + n, last_line)
+                    };
+                let info = buffer.line_info(target);
+                Some(buffer.grapheme_start(info.buf_index + cmp::min(index - line_info.buf_index, info.length)))
+            },
+            Motion::WordStart => Some(repeat(n, index, |i| word_start(buffer, i))),
+            Motion::WordEnd => Some(repeat(n, index, |i| word_end(buffer, i))),
+            Motion::WordBack => Some(repeat(n, index, |i| word_back(buffer, i))),
+            Motion::FindForward(c) => find_forward(buffer, index, line_end, c, n),
+            Motion::TillForward(c) => {
+                find_forward(buffer, index, line_end, c, n).map(|i| buffer.prev_grapheme(i))
+                                                          .and_then(|i| if i > index { Some(i) } else { None })
+            },
+            Motion::FindBack(c) => find_back(buffer, line_info.buf_index, index, c, n),
+            Motion::TillBack(c) => {
+                find_back(buffer, line_info.buf_index, index, c, n).map(|i| buffer.next_grapheme(i))
+                                                                   .and_then(|i| if i < index { Some(i) } else { None })
+            },
+            Motion::LineStart => Some(line_info.buf_index),
+            Motion::LineEnd => {
+                let info = buffer.line_info(cmp::min(line + n - 1, last_line));
+                if info.length > 0 {
+                    Some(buffer.prev_grapheme(info.buf_index + info.length))
+                } else {
+                    Some(info.buf_index)
+                }
+            },
+            Motion::FirstLine => {
+                let target = cmp::min(count.unwrap_or(1).saturating_sub(1), last_line);
+                Some(buffer.line_info(target).buf_index)
+            },
+            Motion::LastLine => {
+                let target = cmp::min(count.map_or(last_line, |c| c.saturating_sub(1)), last_line);
+                Some(buffer.line_info(target).buf_index)
+            },
+        }
+    }
+}
+
+/// A motion that takes the character typed after it, like f<char>
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CharMotion {
+    Find,
+    Till,
+    FindBack,
+    TillBack,
+}
+
+impl CharMotion {
+    pub fn motion(&self, c: char) -> Motion {
+        match *self {
+            CharMotion::Find => Motion::FindForward(c),
+            CharMotion::Till => Motion::TillForward(c),
+            CharMotion::FindBack => Motion::FindBack(c),
+            CharMotion::TillBack => Motion::TillBack(c),
+        }
+    }
+}
+
+/// Text around the cursor that an operator can work on, like iw or a"
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TextObject {
+    Word { around: bool },
+    Quote { quote: char, around: bool },
+    Block { open: char, close: char, around: bool },
+}
+
+impl TextObject {
+    /// The range [from, to) the text object covers around index
+    pub fn range(&self, buffer: &Buffer, index: usize) -> Option<(usize, usize)> {
+        let len = buffer.len();
+        if index >= len { return None; }
+
+        match *self {
+            TextObject::Word { around } => {
+                let class = char_class(buffer.byte(index));
+                let mut from = index;
+                let mut to = index + 1;
+                while from > 0 && char_class(buffer.byte(from - 1)) == class { from -= 1; }
+                while to < len && char_class(buffer.byte(to)) == class { to += 1; }
+                if around {
+                    while to < len && is_blank(buffer.byte(to)) { to += 1; }
+                }
+                Some((from, to))
+            },
+            TextObject::Quote { quote, around } => {
+                let line_info = buffer.line_info(buffer.line_at_index(index));
+                let line = buffer.line(buffer.line_at_index(index));
+                let quotes: Vec<usize> = line.char_indices().filter(|&(_, c)| c == quote)
+                                                            .map(|(i, _)| line_info.buf_index + i)
+                                                            .collect();
+
+                // Quotes pair up from the start of the line. Use the pair around the cursor, or
+                // the first one after it.
+                let pair = quotes.chunks(2).filter(|pair| pair.len() == 2)
+                                           .find(|pair| index <= pair[1]);
+                pair.map(|pair| {
+                    if around {
+                        let mut to = pair[1] + quote.len_utf8();
+                        while to < line_info.buf_index + line_info.length && is_blank(buffer.byte(to)) {
+                            to += 1;
+                        }
+                        (pair[0], to)
+                    } else {
+                        (pair[0] + quote.len_utf8(), pair[1])
+                    }
+                })
+            },
+            TextObject::Block { open, close, around } => {
+                let (open_byte, close_byte) = (open as u8, close as u8);
+
+                // Find the unmatched open bracket before the cursor...
+                let mut depth = 0;
+                let mut start = None;
+                let mut i = index + 1;
+                while i > 0 {
+                    i -= 1;
+                    let b = buffer.byte(i);
+                    if b == close_byte && i != index {
+                        depth += 1;
+                    } else if b == open_byte {
+                        if depth == 0 { start = Some(i); break; }
+                        depth -= 1;
+                    }
+                }
+                let start = match start { Some(start) => start, None => { return None; } };
+
+                // ...and the close bracket that matches it
+                let mut depth = 0;
+                for i in start+1..len {
+                    let b = buffer.byte(i);
+                    if b == open_byte {
+                        depth += 1;
+                    } else if b == close_byte {
+                        if depth == 0 {
+                            return Some(if around { (start, i + 1) } else { (start + 1, i) });
+                        }
+                        depth -= 1;
+                    }
+                }
+                None
+            },
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+// Words
+
+#[derive(Copy, Clone, PartialEq)]
+enum CharClass {
+    Whitespace,
+    Word,
+    Punctuation,
+}
+
+fn char_class(b: u8) -> CharClass {
+    if b == b' ' || b == b'\t' || b == b'\n' {
+        CharClass::Whitespace
+    } else if b == b'_' || (b as char).is_alphanumeric() || b >= 0x80 {
+        CharClass::Word
+    } else {
+        CharClass::Punctuation
+    }
+}
+
+fn is_blank(b: u8) -> bool {
+    b == b' ' || b == b'\t'
+}
+
+fn repeat<F: Fn(usize) -> usize>(count: usize, index: usize, f: F) -> usize {
+    (0..count).fold(index, |i, _| f(i))
+}
+
+/// Start of the next word
+fn word_start(buffer: &Buffer, mut i: usize) -> usize {
+    let len = buffer.len();
+    if i >= len { return len; }
+
+    let class = char_class(buffer.byte(i));
+    if class != CharClass::Whitespace {
+        while i < len && char_class(buffer.byte(i)) == class { i += 1; }
+    }
+    while i < len && char_class(buffer.byte(i)) == CharClass::Whitespace { i += 1; }
+    i
+}
+
+/// End of the current word, or the next word if already at the end of one
+fn word_end(buffer: &Buffer, mut i: usize) -> usize {
+    let len = buffer.len();
+    if i + 1 >= len { return i; }
+
+    i += 1;
+    while i + 1 < len && char_class(buffer.byte(i)) == CharClass::Whitespace { i += 1; }
+    let class = char_class(buffer.byte(i));
+    while i + 1 < len && char_class(buffer.byte(i + 1)) == class { i += 1; }
+    // Words are found byte by byte, the cursor goes on the last grapheme cluster
+    buffer.grapheme_start(i)
+}
+
+/// Start of the current word, or the previous word if already at the start of one
+fn word_back(buffer: &Buffer, mut i: usize) -> usize {
+    if i == 0 { return 0; }
+
+    i -= 1;
+    while i > 0 && char_class(buffer.byte(i)) == CharClass::Whitespace { i -= 1; }
+    let class = char_class(buffer.byte(i));
+    while i > 0 && char_class(buffer.byte(i - 1)) == class { i -= 1; }
+    i
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+// Character search within a line
+
+/// Index of the count'th c after index, before end
+fn find_forward(buffer: &Buffer, index: usize, end: usize, c: char, count: usize) -> Option<usize> {
+    if index >= end { return None; }
+
+    let text = buffer.slice(index, end);
+    text.char_indices().skip(1)
+                       .filter(|&(_, ch)| ch == c)
+                       .nth(count - 1)
+                       .map(|(i, _)| index + i)
+}
+
+/// Index of the count'th c before index, at or after start
+fn find_back(buffer: &Buffer, start: usize, index: usize, c: char, count: usize) -> Option<usize> {
+    if index <= start { return None; }
+
+    let text = buffer.slice(start, index);
+    text.char_indices().rev()
+                       .filter(|&(_, ch)| ch == c)
+                       .nth(count - 1)
+                       .map(|(i, _)| start + i)
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+
+#[test]
+fn motion_words() {
+    let buf = Buffer::from_string("foo bar.baz\n  qux".to_string());
+
+    assert!(Motion::WordStart.target(&buf, 0, None) == Some(4));
+    assert!(Motion::WordStart.target(&buf, 4, None) == Some(7));
+    assert!(Motion::WordStart.target(&buf, 0, Some(4)) == Some(14));
+    assert!(Motion::WordEnd.target(&buf, 0, None) == Some(2));
+    assert!(Motion::WordEnd.target(&buf, 2, None) == Some(6));
+    assert!(Motion::WordBack.target(&buf, 14, None) == Some(8));
+    assert!(Motion::WordBack.target(&buf, 8, Some(2)) == Some(4));
+}
+
+#[test]
+fn motion_find_till() {
+    let buf = Buffer::from_string("a,b,c\nd,e".to_string());
+
+    assert!(Motion::FindForward(',').target(&buf, 0, None) == Some(1));
+    assert!(Motion::FindForward(',').target(&buf, 0, Some(2)) == Some(3));
+    assert!(Motion::FindForward(',').target(&buf, 3, None) == None);
+    assert!(Motion::TillForward(',').target(&buf, 0, None) == None);
+    assert!(Motion::TillForward('c').target(&buf, 0, None) == Some(3));
+    assert!(Motion::FindBack('a').target(&buf, 4, None) == Some(0));
+    assert!(Motion::TillBack('a').target(&buf, 4, None) == Some(1));
+
+    let buf = Buffer::from_string("aé,b\u{301}xü".to_string());
+
+    assert!(Motion::FindForward(',').target(&buf, 0, None) == Some(3));
+    assert!(Motion::TillForward(',').target(&buf, 0, None) == Some(1));
+    assert!(Motion::TillBack('b').target(&buf, 8, None) == Some(7));
+}
+
+#[test]
+fn motion_lines() {
+    let buf = Buffer::from_string("one\ntwo\nthree".to_string());
+
+    assert!(Motion::LineEnd.target(&buf, 4, None) == Some(6));
+    assert!(Motion::LineStart.target(&buf, 6, None) == Some(4));
+    assert!(Motion::FirstLine.target(&buf, 6, None) == Some(0));
+    assert!(Motion::LastLine.target(&buf, 0, None) == Some(8));
+    assert!(Motion::LastLine.target(&buf, 0, Some(2)) == Some(4));
+    assert!(Motion::Up.target(&buf, 0, None) == None);
+}
+
+#[test]
+fn text_object_word() {
+    let buf = Buffer::from_string("foo bar  baz".to_string());
+
+    assert!(TextObject::Word { around: false }.range(&buf, 5) == Some((4, 7)));
+    assert!(TextObject::Word { around: true }.range(&buf, 5) == Some((4, 9)));
+}
+
+#[test]
+fn text_object_quote() {
+    let buf = Buffer::from_string("x = \"a\" + \"bc\"".to_string());
+
+    assert!(TextObject::Quote { quote: '"', around: false }.range(&buf, 0) == Some((5, 6)));
+    assert!(TextObject::Quote { quote: '"', around: false }.range(&buf, 11) == Some((11, 13)));
+    assert!(TextObject::Quote { quote: '"', around: true }.range(&buf, 5) == Some((4, 8)));
+}
+
+#[test]
+fn text_object_block() {
+    let buf = Buffer::from_string("f(a, (b), c)".to_string());
+
+    assert!(TextObject::Block { open: '(', close: ')', around: false }.range(&buf, 3) == Some((2, 11)));
+    assert!(TextObject::Block { open: '(', close: ')', around: true }.range(&buf, 6) == Some((5, 8)));
+    assert!(TextObject::Block { open: '(', close: ')', around: false }.range(&buf, 8) == Some((2, 11)));
+    assert!(TextObject::Block { open: '{', close: '}', around: false }.range(&buf, 3) == None);
+}
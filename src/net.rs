@@ -376,51 +376,53 @@ pub struct Client {
 }
 
 impl Client {
-    pub fn new(host: &str) -> Client {
-        let mut stream = 
-            match TcpStream::connect(host) {
-                Ok(stream) => stream,
-                Err(e) => panic!("Failed to connect to server: {}", e),
-            };
+    pub fn new(host: &str) -> io::Result<Client> {
+        use std::io::{Error, ErrorKind};
 
-        let id = 
-            match read_u32(&mut stream) {
-                Ok(id) => id,
-                Err(e) => panic!("Couldn't connect to server because client ID failed to receive: {}", e),
-            };
+        let mut stream = try!(TcpStream::connect(host));
+        let id =
+            try!(read_u32(&mut stream).map_err(|e| {
+                Error::new(ErrorKind::Other, format!("Failed to receive client ID: {}", e))
+            }));
         
         let (packet_sender, packet_receiver) = channel();
         
-        let mut thread_stream = stream.try_clone().ok().expect("Failed to clone client TcpStream");
-        Builder::new().name("client_packet_receiver".to_string()).spawn(move || {
+        let mut thread_stream = try!(stream.try_clone());
+        try!(Builder::new().name("client_packet_receiver".to_string()).spawn(move || {
             loop {
                 let packet = InPacket::try_new_from_reader(&mut thread_stream);
-                packet_sender.send(packet);
+                let failed = packet.is_err();
+                // Stop once the connection fails or the client is gone
+                if packet_sender.send(packet).is_err() || failed {
+                    break;
+                }
             }
-        });
+        }));
     
-        Client{id: id, stream: stream, packet_receiver: packet_receiver}
+        Ok(Client{id: id, stream: stream, packet_receiver: packet_receiver})
     }
     
-    pub fn send(&mut self, packet: &OutPacket) {
+    pub fn send(&mut self, packet: &OutPacket) -> io::Result<()> {
+        use std::io::{Error, ErrorKind};
+
         let data = &packet.buffer.get_ref();
-        if let Err(e) = write_u16(&mut self.stream, data.len() as u16) {
-            panic!("Failed to send packet size to server: {}", e);
-        }
-        match self.stream.write(&(*data)[..]) {
-            Ok(bytes_written) => {
-                if data.len() != bytes_written {
-                    panic!("Failed to send packet data to server: Tried to write {} bytes, only {} bytes written", data.len(), bytes_written);
-                }
-            },
-            Err(e) => { panic!("Failed to send packet data to server: {}", e); },
+        try!(write_u16(&mut self.stream, data.len() as u16));
+        let bytes_written = try!(self.stream.write(&(*data)[..]));
+        if data.len() != bytes_written {
+            return Err(Error::new(ErrorKind::WriteZero,
+                                  format!("Tried to write {} bytes, only {} bytes written",
+                                          data.len(), bytes_written)));
         }
+        Ok(())
     }
     
-    pub fn receive(&mut self) -> InPacket {
-        self.packet_receiver.recv()
-            .ok().expect("Client packet sending channel closed")
-            .ok().expect("Failed to receive packet from server")
+    pub fn receive(&mut self) -> io::Result<InPacket> {
+        use std::io::{Error, ErrorKind};
+
+        match self.packet_receiver.recv() {
+            Ok(packet) => packet,
+            Err(_) => Err(Error::new(ErrorKind::Other, "Client packet sending channel closed")),
+        }
     }
     
     pub fn try_receive(&mut self) -> io::Result<InPacket> {
@@ -506,7 +508,8 @@ impl InPacket {
         let mut data = vec!();
         let bytes_read = try!(reader.take(packet_size).read_to_end(&mut data));
         if bytes_read as u64 != packet_size {
-            panic!("Expected {} bytes, got {} bytes", packet_size, bytes_read);
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                      format!("Expected {} bytes, got {} bytes", packet_size, bytes_read)));
         }
         
         // Build packet
@@ -601,3 +604,15 @@ fn write_u32<T: Write>(writer: &mut T, data: u32) -> io::Result<usize> {
         },
     }
 }
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+
+#[test]
+fn in_packet_truncated() {
+    // A packet that says it's 4 bytes long, but the connection ends after 2
+    let mut data = io::Cursor::new(vec![4u8, 0, 1, 2]);
+    assert!(InPacket::try_new_from_reader(&mut data).is_err());
+    let mut data = io::Cursor::new(vec![]);
+    assert!(InPacket::try_new_from_reader(&mut data).is_err());
+}
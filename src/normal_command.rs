@@ -0,0 +1,267 @@
+use std::cmp;
+
+use keymap::{Action, Keymap, Resolve};
+use motion::{Motion, TextObject};
+use ted::Event;
+
+/// Counts stop growing here, like vim's, so long runs of digits can't overflow
+const MAX_COUNT: usize = 999999999;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Operator {
+    Delete,   // d
+    Change,   // c
+    Yank,     // y
+    Indent,   // >
+    Unindent, // <
+    ToggleCase, // g~, or ~ in visual mode
+}
+
+/// A complete normal mode command: [count] [operator [count]] motion, or [count] operator operator
+#[derive(Clone, Debug, PartialEq)]
+pub enum NormalCommand {
+    Move(Motion, Option<usize>),
+    Operate(Operator, Motion, Option<usize>),
+    OperateObject(Operator, TextObject, Option<usize>),
+    OperateLines(Operator, usize), // dd, cc, yy, >>, <<
+    Action(Action, Option<usize>), // Anything else the keymap binds, like i or u
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Parse {
+    Incomplete,
+    Invalid,
+    Complete(NormalCommand, usize), // The command, and how many keys it used
+    Remap(usize, usize, Vec<Event>), // Keys [from, to) are a user mapping for these keys
+    Register(usize, usize, char),    // Keys [from, to) pick a register for the next command
+}
+
+/// Parses the keys typed in normal mode so far, looking keys up in the normal mode keymap and
+/// the operator pending keymap after an operator
+pub fn parse(keys: &[Event], normal: &Keymap, operator_pending: &Keymap, timed_out: bool) -> Parse {
+    let mut i = 0;
+    let count = parse_count(keys, &mut i);
+    if i == keys.len() { return Parse::Incomplete; }
+
+    let start = i;
+    let action =
+        match normal.resolve(&keys[i..], timed_out) {
+            Resolve::Wait => { return Parse::Incomplete; },
+            Resolve::Unmapped => { return Parse::Invalid; },
+            Resolve::Action(action, len) => { i += len; action },
+        };
+
+    let operator =
+        match action {
+            Action::Operator(operator) => operator,
+            Action::Motion(motion) => {
+                return Parse::Complete(NormalCommand::Move(motion, count), i);
+            },
+            Action::CharMotion(char_motion) => {
+                return match keys.get(i) {
+                    None => Parse::Incomplete,
+                    Some(&Event::Char(c)) => {
+                        Parse::Complete(NormalCommand::Move(char_motion.motion(c), count), i + 1)
+                    },
+                    Some(_) => Parse::Invalid,
+                };
+            },
+            Action::OperateLines(operator) => {
+                return Parse::Complete(NormalCommand::OperateLines(operator, count.unwrap_or(1)), i);
+            },
+            Action::TextObject(_) => { return Parse::Invalid; },
+            Action::Keys(mapped) => { return Parse::Remap(start, i, mapped); },
+            Action::Register => {
+                return match keys.get(i) {
+                    None => Parse::Incomplete,
+                    Some(&Event::Char(c)) => Parse::Register(start, i + 1, c),
+                    Some(_) => Parse::Invalid,
+                };
+            },
+            action => { return Parse::Complete(NormalCommand::Action(action, count), i); },
+        };
+
+    let motion_count = parse_count(keys, &mut i);
+    if i == keys.len() { return Parse::Incomplete; }
+
+    let count =
+        match (count, motion_count) {
+            (Some(a), Some(b)) => Some(a.checked_mul(b).map_or(MAX_COUNT, |n| cmp::min(n, MAX_COUNT))),
+            (a, b) => a.or(b),
+        };
+
+    let start = i;
+    let action =
+        match operator_pending.resolve(&keys[i..], timed_out) {
+            Resolve::Wait => { return Parse::Incomplete; },
+            Resolve::Unmapped => { return Parse::Invalid; },
+            Resolve::Action(action, len) => { i += len; action },
+        };
+
+    match action {
+        Action::Motion(motion) => Parse::Complete(NormalCommand::Operate(operator, motion, count), i),
+        Action::CharMotion(char_motion) => {
+            match keys.get(i) {
+                None => Parse::Incomplete,
+                Some(&Event::Char(c)) => {
+                    Parse::Complete(NormalCommand::Operate(operator, char_motion.motion(c), count), i + 1)
+                },
+                Some(_) => Parse::Invalid,
+            }
+        },
+        Action::TextObject(object) => Parse::Complete(NormalCommand::OperateObject(operator, object, count), i),
+        Action::Keys(mapped) => Parse::Remap(start, i, mapped),
+        _ => Parse::Invalid,
+    }
+}
+
+/// Parses the keys typed in visual mode so far. Operators work on the selection, so they're
+/// complete on their own as an Action::Operator.
+pub fn parse_visual(keys: &[Event], visual: &Keymap, timed_out: bool) -> Parse {
+    let mut i = 0;
+    let count = parse_count(keys, &mut i);
+    if i == keys.len() { return Parse::Incomplete; }
+
+    let start = i;
+    let action =
+        match visual.resolve(&keys[i..], timed_out) {
+            Resolve::Wait => { return Parse::Incomplete; },
+            Resolve::Unmapped => { return Parse::Invalid; },
+            Resolve::Action(action, len) => { i += len; action },
+        };
+
+    match action {
+        Action::Motion(motion) => Parse::Complete(NormalCommand::Move(motion, count), i),
+        Action::CharMotion(char_motion) => {
+            match keys.get(i) {
+                None => Parse::Incomplete,
+                Some(&Event::Char(c)) => {
+                    Parse::Complete(NormalCommand::Move(char_motion.motion(c), count), i + 1)
+                },
+                Some(_) => Parse::Invalid,
+            }
+        },
+        Action::Keys(mapped) => Parse::Remap(start, i, mapped),
+        Action::Register => {
+            match keys.get(i) {
+                None => Parse::Incomplete,
+                Some(&Event::Char(c)) => Parse::Register(start, i + 1, c),
+                Some(_) => Parse::Invalid,
+            }
+        },
+        action => Parse::Complete(NormalCommand::Action(action, count), i),
+    }
+}
+
+fn parse_count(keys: &[Event], i: &mut usize) -> Option<usize> {
+    // A leading 0 is the line start motion, not a count
+    if *i >= keys.len() || keys[*i] == Event::Char('0') {
+        return None;
+    }
+
+    let mut count = None;
+    while *i < keys.len() {
+        match keys[*i] {
+            Event::Char(c) if c.is_digit(10) => {
+                let digit = c.to_digit(10).unwrap() as usize;
+                count = Some(cmp::min(count.unwrap_or(0) * 10 + digit, MAX_COUNT));
+                *i += 1;
+            },
+            _ => { break; },
+        }
+    }
+    count
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+
+#[cfg(test)]
+fn parse_str(keys: &str) -> Parse {
+    let keys: Vec<Event> = keys.chars().map(Event::Char).collect();
+    parse(&keys, &Keymap::normal(), &Keymap::operator_pending(), false)
+}
+
+#[test]
+fn parse_motions() {
+    assert!(parse_str("w") == Parse::Complete(NormalCommand::Move(Motion::WordStart, None), 1));
+    assert!(parse_str("3e") == Parse::Complete(NormalCommand::Move(Motion::WordEnd, Some(3)), 2));
+    assert!(parse_str("0") == Parse::Complete(NormalCommand::Move(Motion::LineStart, None), 1));
+    assert!(parse_str("10G") == Parse::Complete(NormalCommand::Move(Motion::LastLine, Some(10)), 3));
+    assert!(parse_str("123456789012345678901234j") ==
+            Parse::Complete(NormalCommand::Move(Motion::Down, Some(MAX_COUNT)), 25));
+    assert!(parse_str("g") == Parse::Incomplete);
+    assert!(parse_str("gg") == Parse::Complete(NormalCommand::Move(Motion::FirstLine, None), 2));
+    assert!(parse_str("gx") == Parse::Invalid);
+    assert!(parse_str("z") == Parse::Invalid);
+}
+
+#[test]
+fn parse_operators() {
+    assert!(parse_str("d") == Parse::Incomplete);
+    assert!(parse_str("dw") ==
+            Parse::Complete(NormalCommand::Operate(Operator::Delete, Motion::WordStart, None), 2));
+    assert!(parse_str("2d3w") ==
+            Parse::Complete(NormalCommand::Operate(Operator::Delete, Motion::WordStart, Some(6)), 4));
+    assert!(parse_str("99999d99999w") ==
+            Parse::Complete(NormalCommand::Operate(Operator::Delete, Motion::WordStart, Some(MAX_COUNT)), 12));
+    assert!(parse_str("dt") == Parse::Incomplete);
+    assert!(parse_str("ct)") ==
+            Parse::Complete(NormalCommand::Operate(Operator::Change, Motion::TillForward(')'), None), 3));
+    assert!(parse_str("y$") ==
+            Parse::Complete(NormalCommand::Operate(Operator::Yank, Motion::LineEnd, None), 2));
+    assert!(parse_str("d0") ==
+            Parse::Complete(NormalCommand::Operate(Operator::Delete, Motion::LineStart, None), 2));
+    assert!(parse_str("dy") == Parse::Invalid);
+}
+
+#[test]
+fn parse_operate_lines() {
+    assert!(parse_str("dd") == Parse::Complete(NormalCommand::OperateLines(Operator::Delete, 1), 2));
+    assert!(parse_str("3>>") == Parse::Complete(NormalCommand::OperateLines(Operator::Indent, 3), 3));
+    assert!(parse_str(">") == Parse::Incomplete);
+}
+
+#[test]
+fn parse_text_objects() {
+    assert!(parse_str("ci") == Parse::Incomplete);
+    assert!(parse_str("ci\"") ==
+            Parse::Complete(NormalCommand::OperateObject(Operator::Change,
+                                                         TextObject::Quote { quote: '"', around: false },
+                                                         None), 3));
+    assert!(parse_str("dab") ==
+            Parse::Complete(NormalCommand::OperateObject(Operator::Delete,
+                                                         TextObject::Block { open: '(', close: ')', around: true },
+                                                         None), 3));
+    assert!(parse_str("iw") == Parse::Complete(NormalCommand::Action(Action::InsertMode, None), 1));
+}
+
+#[test]
+fn parse_visual_keys() {
+    let keys: Vec<Event> = "2wd".chars().map(Event::Char).collect();
+    let visual = Keymap::visual();
+
+    assert!(parse_visual(&keys[..2], &visual, false) ==
+            Parse::Complete(NormalCommand::Move(Motion::WordStart, Some(2)), 2));
+    assert!(parse_visual(&keys[2..], &visual, false) ==
+            Parse::Complete(NormalCommand::Action(Action::Operator(Operator::Delete), None), 1));
+    assert!(parse_visual(&[Event::Char('g')], &visual, false) == Parse::Incomplete);
+}
+
+#[test]
+fn parse_registers() {
+    assert!(parse_str("\"") == Parse::Incomplete);
+    assert!(parse_str("\"ayy") == Parse::Register(0, 2, 'a'));
+    assert!(parse_str("2\"ap") == Parse::Register(1, 3, 'a'));
+    assert!(parse_str("3p") == Parse::Complete(NormalCommand::Action(Action::PutAfter, Some(3)), 2));
+}
+
+#[test]
+fn parse_remapped_keys() {
+    let mut normal = Keymap::normal();
+    normal.bind(&[Event::Char('Q')], Action::Keys(vec![Event::Char('d'), Event::Char('d')]));
+    let keys = [Event::Char('3'), Event::Char('Q')];
+
+    assert!(parse(&keys, &normal, &Keymap::operator_pending(), false) ==
+            Parse::Remap(1, 2, vec![Event::Char('d'), Event::Char('d')]));
+}
@@ -12,7 +12,9 @@ impl Operation {
     pub fn inverse(self) -> Operation {
         match self {
             InsertChar(index, c) => RemoveChar(index, c),
-            Insert(index, text) => Remove(index, index+(text.len() as u64), text),
+            // Remove's end is inclusive, an empty insert removes nothing at index
+            Insert(index, ref text) if text.is_empty() => Remove(index, index, String::new()),
+            Insert(index, text) => Remove(index, index+(text.len() as u64)-1, text),
             RemoveChar(index, c) => InsertChar(index, c),
             Remove(start, _, text) => Insert(start, text),
         }
@@ -140,3 +142,17 @@ fn insert_char_do_before_insert_char_no_effect() {
     assert!(before.do_before(&mut after) == true);
     assert!(after == InsertChar(2, 'a'));
 }
+
+#[test]
+fn insert_inverse_is_inclusive_remove() {
+    let op = Insert(3, "abc".to_string());
+
+    assert!(op.inverse() == Remove(3, 5, "abc".to_string()));
+}
+
+#[test]
+fn empty_insert_inverse() {
+    let op = Insert(0, String::new());
+
+    assert!(op.inverse() == Remove(0, 0, String::new()));
+}
@@ -0,0 +1,129 @@
+use std::cmp;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// A file too big to read all at once. It's read a page of whole lines at a time, around wherever
+/// the cursor is.
+pub struct Pager {
+    file: File,
+    pub len: u64, // Of the whole file
+    pub page_size: u64,
+    pub start: u64, // The part of the file in the page, [start, end)
+    pub end: u64,
+}
+
+impl Pager {
+    pub fn open(path: &Path, page_size: u64) -> io::Result<Pager> {
+        let len = try!(fs::metadata(path)).len();
+        Ok(Pager {
+            file: try!(File::open(path)),
+            len: len,
+            page_size: page_size,
+            start: 0,
+            end: 0,
+        })
+    }
+
+    /// Reads the page with pos about in the middle of it, or a whole page at the end of the file.
+    /// The page starts and ends at line breaks unless a line is longer than half a page. Text that
+    /// isn't UTF-8 is read lossily.
+    pub fn read_around(&mut self, pos: u64) -> io::Result<String> {
+        let from = cmp::min(pos.saturating_sub(self.page_size / 2), self.len.saturating_sub(self.page_size));
+        let bytes = try!(self.read(from, self.page_size));
+
+        // Start after the line break before the line pos is in
+        let mut start = from;
+        if from > 0 {
+            if let Some(i) = bytes.iter().position(|&b| b == b'\n') {
+                if from + (i as u64) < pos {
+                    start = from + i as u64 + 1;
+                }
+            }
+        }
+        let bytes = &bytes[(start - from) as usize..];
+
+        // End after a line break, unless that's the end of the file anyway
+        let mut end = start + bytes.len() as u64;
+        if end < self.len {
+            if let Some(i) = bytes.iter().rposition(|&b| b == b'\n') {
+                if start + (i as u64) >= pos {
+                    end = start + i as u64 + 1;
+                }
+            }
+        }
+
+        self.start = start;
+        self.end = end;
+        Ok(String::from_utf8_lossy(&bytes[..(end - start) as usize]).into_owned())
+    }
+
+    /// Where the line with the given number starts, counting line breaks from the start of the
+    /// file a page at a time. A line past the end of the file gives the file's length.
+    pub fn line_start(&mut self, line: usize) -> io::Result<u64> {
+        let mut pos = 0;
+        let mut breaks = 0;
+        while breaks < line && pos < self.len {
+            let bytes = try!(self.read(pos, self.page_size));
+            if bytes.is_empty() { break; }
+            for &b in &bytes {
+                pos += 1;
+                if b == b'\n' {
+                    breaks += 1;
+                    if breaks == line { return Ok(pos); }
+                }
+            }
+        }
+        Ok(if breaks < line { self.len } else { pos })
+    }
+
+    fn read(&mut self, from: u64, len: u64) -> io::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        try!(self.file.seek(SeekFrom::Start(from)));
+        try!((&mut self.file).take(len).read_to_end(&mut bytes));
+        Ok(bytes)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+
+#[test]
+fn pager_read_around() {
+    use std::env;
+    use std::io::Write;
+
+    let path = env::temp_dir().join("ted_pager_test.txt");
+    let text: String = (0..100).map(|i| format!("line {:02}\n", i)).collect(); // 8 bytes a line
+    File::create(&path).unwrap().write_all(text.as_bytes()).unwrap();
+
+    let mut pager = Pager::open(&path, 30).unwrap();
+    assert!(pager.len == 800);
+    assert!(pager.read_around(0).unwrap() == "line 00\nline 01\nline 02\n");
+    assert!(pager.start == 0 && pager.end == 24);
+
+    // Whole lines around pos
+    assert!(pager.read_around(403).unwrap() == "line 49\nline 50\nline 51\n");
+    assert!(pager.start == 392 && pager.end == 416);
+
+    assert!(pager.read_around(799).unwrap() == "line 97\nline 98\nline 99\n");
+    assert!(pager.start == 776 && pager.end == 800);
+}
+
+#[test]
+fn pager_line_start() {
+    use std::env;
+    use std::io::Write;
+
+    let path = env::temp_dir().join("ted_pager_line_test.txt");
+    let text: String = (0..100).map(|i| format!("line {:02}\n", i)).collect();
+    File::create(&path).unwrap().write_all(text.as_bytes()).unwrap();
+
+    let mut pager = Pager::open(&path, 30).unwrap();
+    assert!(pager.line_start(0).unwrap() == 0);
+    assert!(pager.line_start(1).unwrap() == 8);
+    assert!(pager.line_start(50).unwrap() == 400);
+    assert!(pager.line_start(99).unwrap() == 792);
+    assert!(pager.line_start(100).unwrap() == 800);
+    assert!(pager.line_start(1000).unwrap() == 800);
+}
@@ -0,0 +1,201 @@
+use std::collections::{HashMap, VecDeque};
+
+/// Deletes of a line or more are kept in registers 1 through 9
+const NUMBERED_REGISTERS: usize = 9;
+
+/// Text stored by a delete, change or yank
+#[derive(Clone, Debug, PartialEq)]
+pub struct Register {
+    pub text: String,
+    pub linewise: bool, // Whole lines, put above or below the cursor line rather than at the cursor
+}
+
+/// Vim style registers: the unnamed register ", named registers a-z, 0 for the last yank, 1-9 for
+/// the last deletes of a line or more, - for the last small delete and the _ black hole
+pub struct Registers {
+    unnamed: Option<Register>,
+    named: HashMap<char, Register>, // a-z, 0 and -
+    numbered: VecDeque<Option<Register>>, // 1-9, newest first. Empty before the ones written to by name.
+}
+
+impl Registers {
+    pub fn new() -> Registers {
+        Registers {
+            unnamed: None,
+            named: HashMap::new(),
+            numbered: VecDeque::new(),
+        }
+    }
+
+    pub fn is_valid(register: char) -> bool {
+        match register {
+            'a'...'z' | 'A'...'Z' | '0'...'9' | '"' | '-' | '_' => true,
+            _ => false,
+        }
+    }
+
+    /// Stores yanked text. Yanks that don't name a register go to register 0.
+    pub fn yank(&mut self, register: Option<char>, text: String, linewise: bool) {
+        let value = Register { text: text, linewise: linewise };
+        match register {
+            Some('_') => { return; },
+            None | Some('"') => { self.named.insert('0', value.clone()); },
+            Some(r) => { self.store(r, value.clone()); },
+        }
+        self.update_unnamed(register, value);
+    }
+
+    /// Stores deleted or changed text. Deletes that don't name a register go to register 1 if
+    /// they're a line or more, or to - if they're within a line.
+    pub fn delete(&mut self, register: Option<char>, text: String, linewise: bool) {
+        let value = Register { text: text, linewise: linewise };
+        match register {
+            Some('_') => { return; },
+            None | Some('"') => {
+                if linewise || value.text.contains('\n') {
+                    self.numbered.push_front(Some(value.clone()));
+                    self.numbered.truncate(NUMBERED_REGISTERS);
+                } else {
+                    self.named.insert('-', value.clone());
+                }
+            },
+            Some(r) => { self.store(r, value.clone()); },
+        }
+        self.update_unnamed(register, value);
+    }
+
+    /// The contents of a register, or of the unnamed register if none is given
+    pub fn get(&self, register: Option<char>) -> Option<&Register> {
+        match register {
+            None | Some('"') => self.unnamed.as_ref(),
+            Some(r @ '1'...'9') => self.numbered.get(r as usize - '1' as usize).and_then(|r| r.as_ref()),
+            Some(r) => self.named.get(&lowercase(r)),
+        }
+    }
+
+    /// Every register that holds something, in the order :registers lists them
+    pub fn list(&self) -> Vec<(char, &Register)> {
+        let mut list = Vec::new();
+        if let Some(ref unnamed) = self.unnamed {
+            list.push(('"', unnamed));
+        }
+        if let Some(yank) = self.named.get(&'0') {
+            list.push(('0', yank));
+        }
+        for (i, register) in self.numbered.iter().enumerate() {
+            if let Some(ref register) = *register {
+                list.push(((b'1' + i as u8) as char, register));
+            }
+        }
+        let mut named: Vec<(char, &Register)> =
+            self.named.iter().filter(|&(&r, _)| r != '0').map(|(&r, v)| (r, v)).collect();
+        named.sort_by(|a, b| a.0.cmp(&b.0));
+        list.extend(named);
+        list
+    }
+
+    fn store(&mut self, register: char, value: Register) {
+        match register {
+            'A'...'Z' => {
+                // Uppercase names append to the register
+                let register = lowercase(register);
+                let appended =
+                    match self.named.remove(&register) {
+                        Some(mut old) => {
+                            if value.linewise && !old.linewise && !old.text.ends_with('\n') {
+                                old.text.push('\n');
+                            }
+                            old.text.push_str(&value.text);
+                            Register { text: old.text, linewise: old.linewise || value.linewise }
+                        },
+                        None => value,
+                    };
+                self.named.insert(register, appended);
+            },
+            '1'...'9' => {
+                let i = register as usize - '1' as usize;
+                while self.numbered.len() <= i {
+                    self.numbered.push_back(None);
+                }
+                self.numbered[i] = Some(value);
+            },
+            _ => { self.named.insert(register, value); },
+        }
+    }
+
+    /// Points the unnamed register at whatever was just written
+    fn update_unnamed(&mut self, register: Option<char>, value: Register) {
+        self.unnamed =
+            match register {
+                None | Some('"') => Some(value),
+                Some(r) => self.get(Some(r)).cloned(), // Appending makes the register hold more
+            };
+    }
+}
+
+fn lowercase(c: char) -> char {
+    c.to_lowercase().next().unwrap_or(c)
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+
+#[test]
+fn registers_yank_and_delete() {
+    let mut registers = Registers::new();
+    registers.yank(None, "foo".to_string(), false);
+    assert!(registers.get(Some('0')) == Some(&Register { text: "foo".to_string(), linewise: false }));
+    assert!(registers.get(None) == registers.get(Some('0')));
+
+    registers.delete(None, "a line\n".to_string(), true);
+    registers.delete(None, "b line\n".to_string(), true);
+    assert!(registers.get(Some('1')).unwrap().text == "b line\n");
+    assert!(registers.get(Some('2')).unwrap().text == "a line\n");
+    assert!(registers.get(None).unwrap().text == "b line\n");
+
+    // Small deletes go to - and leave the numbered registers alone
+    registers.delete(None, "x".to_string(), false);
+    assert!(registers.get(Some('-')).unwrap().text == "x");
+    assert!(registers.get(Some('1')).unwrap().text == "b line\n");
+    assert!(registers.get(None).unwrap().text == "x");
+    assert!(registers.get(Some('0')).unwrap().text == "foo");
+}
+
+#[test]
+fn registers_named_and_black_hole() {
+    let mut registers = Registers::new();
+    registers.yank(Some('a'), "one".to_string(), false);
+    registers.yank(Some('A'), "two\n".to_string(), true);
+    assert!(registers.get(Some('a')) == Some(&Register { text: "one\ntwo\n".to_string(), linewise: true }));
+    assert!(registers.get(None) == registers.get(Some('a')));
+    assert!(registers.get(Some('0')) == None);
+
+    registers.delete(Some('_'), "gone".to_string(), false);
+    assert!(registers.get(None).unwrap().text == "one\ntwo\n");
+    assert!(registers.get(Some('-')) == None);
+}
+
+#[test]
+fn registers_numbered_shift() {
+    let mut registers = Registers::new();
+    for i in 0..12 {
+        registers.delete(None, format!("{}\n", i), true);
+    }
+    assert!(registers.get(Some('1')).unwrap().text == "11\n");
+    assert!(registers.get(Some('9')).unwrap().text == "3\n");
+    assert!(registers.list().len() == 10);
+}
+
+#[test]
+fn registers_store_numbered_by_name() {
+    let mut registers = Registers::new();
+    registers.yank(Some('5'), "five\n".to_string(), true);
+    assert!(registers.get(Some('5')) == Some(&Register { text: "five\n".to_string(), linewise: true }));
+    assert!(registers.get(Some('1')) == None);
+    assert!(registers.list().iter().map(|&(r, _)| r).collect::<String>() == "\"5");
+
+    // Later deletes shift it along with the rest
+    registers.delete(None, "gone\n".to_string(), true);
+    assert!(registers.get(Some('1')).unwrap().text == "gone\n");
+    assert!(registers.get(Some('6')).unwrap().text == "five\n");
+}
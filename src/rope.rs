@@ -0,0 +1,467 @@
+use std::borrow::Cow;
+use std::cmp;
+use std::fmt;
+use std::mem;
+
+/// Leaves are kept at or below this many bytes. Anything smaller gets merged back into a single
+/// leaf when two nodes are joined.
+const MAX_LEAF: usize = 1024;
+
+/// A height-balanced binary tree of text chunks. Every node caches its byte length and newline
+/// count, so edits and line lookups are O(log n) in the size of the text.
+#[derive(Clone)]
+pub struct Rope {
+    root: Node,
+}
+
+impl Rope {
+    pub fn new() -> Rope {
+        Rope {
+            root: Node::empty(),
+        }
+    }
+
+    pub fn from_string(text: String) -> Rope {
+        Rope {
+            root: Node::from_str(text.as_str()),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.root.len()
+    }
+
+    /// Number of '\n' characters in the rope
+    pub fn newlines(&self) -> usize {
+        self.root.newlines()
+    }
+
+    /// Height of the tree, leaves are height 0
+    pub fn height(&self) -> usize {
+        self.root.height()
+    }
+
+    pub fn insert(&mut self, index: usize, text: &str) {
+        if text.is_empty() { return; }
+
+        let root = mem::replace(&mut self.root, Node::empty());
+        let (left, right) = root.split(index);
+        self.root = Node::join(Node::join(left, Node::from_str(text)), right);
+    }
+
+    /// Remove the bytes in the range [from, to) and return them
+    pub fn remove(&mut self, from: usize, to: usize) -> String {
+        let root = mem::replace(&mut self.root, Node::empty());
+        let (left, rest) = root.split(from);
+        let (middle, right) = rest.split(to - from);
+        self.root = Node::join(left, right);
+
+        let mut removed = String::with_capacity(middle.len());
+        middle.push_to(&mut removed);
+        removed
+    }
+
+    pub fn byte(&self, index: usize) -> u8 {
+        self.root.byte(index)
+    }
+
+    /// Returns the character starting at the specified byte index
+    pub fn char_at(&self, index: usize) -> char {
+        self.root.char_at(index)
+    }
+
+    /// Byte index of the first character on the specified line
+    pub fn line_start(&self, line: usize) -> usize {
+        if line == 0 {
+            0
+        } else {
+            self.root.newline_index(line - 1) + 1
+        }
+    }
+
+    /// Line that contains the specified byte index
+    pub fn line_at(&self, index: usize) -> usize {
+        self.root.newlines_before(index)
+    }
+
+    /// Text in the byte range [from, to). Only allocates if the range spans multiple leaves.
+    pub fn slice(&self, from: usize, to: usize) -> Cow<str> {
+        self.root.slice(from, to)
+    }
+
+    /// Iterates over the rope's leaves in order
+    pub fn chunks(&self) -> Chunks {
+        Chunks {
+            stack: vec![&self.root],
+        }
+    }
+}
+
+impl fmt::Display for Rope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for chunk in self.chunks() {
+            try!(f.write_str(chunk));
+        }
+        Ok(())
+    }
+}
+
+pub struct Chunks<'a> {
+    stack: Vec<&'a Node>,
+}
+
+impl<'a> Iterator for Chunks<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        while let Some(mut node) = self.stack.pop() {
+            loop {
+                match *node {
+                    Node::Leaf(ref leaf) => {
+                        if leaf.text.is_empty() { break; }
+                        return Some(leaf.text.as_str());
+                    },
+                    Node::Branch(ref branch) => {
+                        self.stack.push(&branch.right);
+                        node = &branch.left;
+                    },
+                }
+            }
+        }
+        None
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+// Tree nodes
+
+#[derive(Clone)]
+enum Node {
+    Leaf(Leaf),
+    Branch(Box<Branch>),
+}
+
+#[derive(Clone)]
+struct Leaf {
+    text: String,
+    newlines: usize,
+}
+
+#[derive(Clone)]
+struct Branch {
+    left: Node,
+    right: Node,
+    len: usize,
+    newlines: usize,
+    height: usize,
+}
+
+impl Node {
+    fn empty() -> Node {
+        Node::leaf(String::new())
+    }
+
+    fn leaf(text: String) -> Node {
+        let newlines = count_newlines(text.as_str());
+        Node::Leaf(Leaf { text: text, newlines: newlines })
+    }
+
+    /// Builds a perfectly balanced tree out of text
+    fn from_str(text: &str) -> Node {
+        if text.len() <= MAX_LEAF {
+            return Node::leaf(text.to_string());
+        }
+
+        let mut mid = text.len() / 2;
+        while !text.is_char_boundary(mid) {
+            mid += 1;
+        }
+        Node::branch(Node::from_str(&text[..mid]), Node::from_str(&text[mid..]))
+    }
+
+    fn len(&self) -> usize {
+        match *self {
+            Node::Leaf(ref leaf) => leaf.text.len(),
+            Node::Branch(ref branch) => branch.len,
+        }
+    }
+
+    fn newlines(&self) -> usize {
+        match *self {
+            Node::Leaf(ref leaf) => leaf.newlines,
+            Node::Branch(ref branch) => branch.newlines,
+        }
+    }
+
+    fn height(&self) -> usize {
+        match *self {
+            Node::Leaf(_) => 0,
+            Node::Branch(ref branch) => branch.height,
+        }
+    }
+
+    fn into_children(self) -> (Node, Node) {
+        match self {
+            Node::Leaf(_) => (self, Node::empty()),
+            Node::Branch(branch) => {
+                let Branch { left, right, .. } = *branch;
+                (left, right)
+            },
+        }
+    }
+
+    /// Makes a branch out of two nodes, collapsing them into a single leaf if they're small enough
+    fn branch(left: Node, right: Node) -> Node {
+        if left.len() == 0 { return right; }
+        if right.len() == 0 { return left; }
+
+        let len = left.len() + right.len();
+        if len <= MAX_LEAF {
+            let mut text = String::with_capacity(len);
+            left.push_to(&mut text);
+            right.push_to(&mut text);
+            return Node::leaf(text);
+        }
+
+        Node::Branch(Box::new(Branch {
+            len: len,
+            newlines: left.newlines() + right.newlines(),
+            height: cmp::max(left.height(), right.height()) + 1,
+            left: left,
+            right: right,
+        }))
+    }
+
+    /// Makes a branch out of two nodes whose heights differ by at most two, rotating to restore
+    /// balance if necessary
+    fn balance(left: Node, right: Node) -> Node {
+        let (left_height, right_height) = (left.height(), right.height());
+
+        if left_height > right_height + 1 {
+            let (ll, lr) = left.into_children();
+            if ll.height() >= lr.height() {
+                Node::branch(ll, Node::branch(lr, right))
+            } else {
+                let (lrl, lrr) = lr.into_children();
+                Node::branch(Node::branch(ll, lrl), Node::branch(lrr, right))
+            }
+        } else if right_height > left_height + 1 {
+            let (rl, rr) = right.into_children();
+            if rr.height() >= rl.height() {
+                Node::branch(Node::branch(left, rl), rr)
+            } else {
+                let (rll, rlr) = rl.into_children();
+                Node::branch(Node::branch(left, rll), Node::branch(rlr, rr))
+            }
+        } else {
+            Node::branch(left, right)
+        }
+    }
+
+    /// Concatenates two trees of any height
+    fn join(left: Node, right: Node) -> Node {
+        if left.len() == 0 { return right; }
+        if right.len() == 0 { return left; }
+
+        let (left_height, right_height) = (left.height(), right.height());
+        if left_height > right_height + 1 {
+            let (ll, lr) = left.into_children();
+            Node::balance(ll, Node::join(lr, right))
+        } else if right_height > left_height + 1 {
+            let (rl, rr) = right.into_children();
+            Node::balance(Node::join(left, rl), rr)
+        } else {
+            Node::balance(left, right)
+        }
+    }
+
+    /// Splits the tree into [0, index) and [index, len)
+    fn split(self, index: usize) -> (Node, Node) {
+        match self {
+            Node::Leaf(leaf) => {
+                let mut left = leaf.text;
+                let right = left.split_off(index);
+                (Node::leaf(left), Node::leaf(right))
+            },
+            Node::Branch(branch) => {
+                let Branch { left, right, .. } = *branch;
+                let left_len = left.len();
+                if index <= left_len {
+                    let (a, b) = left.split(index);
+                    (a, Node::join(b, right))
+                } else {
+                    let (a, b) = right.split(index - left_len);
+                    (Node::join(left, a), b)
+                }
+            },
+        }
+    }
+
+    fn push_to(&self, out: &mut String) {
+        match *self {
+            Node::Leaf(ref leaf) => { out.push_str(leaf.text.as_str()); },
+            Node::Branch(ref branch) => {
+                branch.left.push_to(out);
+                branch.right.push_to(out);
+            },
+        }
+    }
+
+    fn byte(&self, index: usize) -> u8 {
+        match *self {
+            Node::Leaf(ref leaf) => leaf.text.as_bytes()[index],
+            Node::Branch(ref branch) => {
+                let left_len = branch.left.len();
+                if index < left_len {
+                    branch.left.byte(index)
+                } else {
+                    branch.right.byte(index - left_len)
+                }
+            },
+        }
+    }
+
+    fn char_at(&self, index: usize) -> char {
+        match *self {
+            Node::Leaf(ref leaf) => leaf.text[index..].chars().next().unwrap(),
+            Node::Branch(ref branch) => {
+                let left_len = branch.left.len();
+                if index < left_len {
+                    branch.left.char_at(index)
+                } else {
+                    branch.right.char_at(index - left_len)
+                }
+            },
+        }
+    }
+
+    /// Byte index of the nth (starting at 0) newline
+    fn newline_index(&self, n: usize) -> usize {
+        match *self {
+            Node::Leaf(ref leaf) => {
+                leaf.text.bytes().enumerate()
+                                 .filter(|&(_, b)| b == b'\n')
+                                 .nth(n)
+                                 .map(|(i, _)| i)
+                                 .unwrap()
+            },
+            Node::Branch(ref branch) => {
+                let left_newlines = branch.left.newlines();
+                if n < left_newlines {
+                    branch.left.newline_index(n)
+                } else {
+                    branch.left.len() + branch.right.newline_index(n - left_newlines)
+                }
+            },
+        }
+    }
+
+    /// Number of newlines in [0, index)
+    fn newlines_before(&self, index: usize) -> usize {
+        match *self {
+            // By bytes, so the index can be inside a character
+            Node::Leaf(ref leaf) => leaf.text.as_bytes()[..index].iter().filter(|&&b| b == b'\n').count(),
+            Node::Branch(ref branch) => {
+                let left_len = branch.left.len();
+                if index <= left_len {
+                    branch.left.newlines_before(index)
+                } else {
+                    branch.left.newlines() + branch.right.newlines_before(index - left_len)
+                }
+            },
+        }
+    }
+
+    fn slice(&self, from: usize, to: usize) -> Cow<str> {
+        match *self {
+            Node::Leaf(ref leaf) => Cow::Borrowed(&leaf.text[from..to]),
+            Node::Branch(ref branch) => {
+                let left_len = branch.left.len();
+                if to <= left_len {
+                    branch.left.slice(from, to)
+                } else if from >= left_len {
+                    branch.right.slice(from - left_len, to - left_len)
+                } else {
+                    let mut text = branch.left.slice(from, left_len).into_owned();
+                    text.push_str(&branch.right.slice(0, to - left_len));
+                    Cow::Owned(text)
+                }
+            },
+        }
+    }
+}
+
+fn count_newlines(text: &str) -> usize {
+    text.bytes().filter(|b| *b == b'\n').count()
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+
+#[cfg(test)]
+fn test_text(lines: usize) -> String {
+    (0..lines).map(|i| format!("line number {}\n", i)).collect()
+}
+
+#[test]
+fn rope_from_string_roundtrip() {
+    let text = test_text(1000);
+    let rope = Rope::from_string(text.clone());
+
+    assert!(rope.to_string() == text);
+    assert!(rope.len() == text.len());
+    assert!(rope.newlines() == 1000);
+}
+
+#[test]
+fn rope_insert_remove_matches_string() {
+    let mut text = test_text(500);
+    let mut rope = Rope::from_string(text.clone());
+
+    for i in 0..2000 {
+        let index = (i * 7919) % text.len();
+        if i % 3 == 0 {
+            let removed = rope.remove(index, index + 1);
+            assert!(removed == text[index..index+1]);
+            text.remove(index);
+        } else {
+            rope.insert(index, "ab\n");
+            text.insert_str(index, "ab\n");
+        }
+    }
+
+    assert!(rope.to_string() == text);
+    assert!(rope.newlines() == count_newlines(text.as_str()));
+}
+
+#[test]
+fn rope_stays_balanced() {
+    let mut rope = Rope::new();
+    for i in 0..20000 {
+        rope.insert(i, "x");
+    }
+
+    // A perfectly balanced tree of 20 leaves would be 5 tall, allow some slack
+    assert!(rope.len() == 20000);
+    assert!(rope.height() <= 10);
+}
+
+#[test]
+fn rope_line_start_and_line_at() {
+    let rope = Rope::from_string(test_text(1000));
+
+    let start = rope.line_start(500);
+    assert!(rope.slice(start, start + 15) == "line number 500");
+    assert!(rope.line_at(start) == 500);
+    assert!(rope.line_at(start - 1) == 499);
+    assert!(rope.line_start(1000) == rope.len());
+}
+
+#[test]
+fn rope_slice_across_leaves() {
+    let text = test_text(1000);
+    let rope = Rope::from_string(text.clone());
+
+    assert!(rope.slice(1000, 3000) == text[1000..3000]);
+    assert!(rope.char_at(1000) == text[1000..].chars().next().unwrap());
+}
@@ -0,0 +1,382 @@
+use std::io::{self, Write};
+
+use termion::{clear, color, cursor, style};
+use unicode_segmentation::UnicodeSegmentation;
+
+use unicode;
+
+/// How a cell is drawn. Colors are ANSI color numbers, None is the terminal's default.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Style {
+    pub fg: Option<u8>,
+    pub bg: Option<u8>,
+    pub bold: bool,
+    pub invert: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cell {
+    pub text: String, // A grapheme cluster, or nothing in the second column of a wide one
+    pub style: Style,
+}
+
+fn blank() -> Cell {
+    Cell { text: " ".to_string(), style: Style::default() }
+}
+
+/// A double buffered terminal screen. A frame is drawn into the back grid, then `render` writes
+/// only the cells that differ from what the terminal already shows.
+pub struct Screen {
+    width: usize,
+    height: usize,
+    front: Vec<Cell>, // What the terminal shows
+    back: Vec<Cell>,  // The frame being drawn
+    cursor: Option<(usize, usize)>,
+    full_redraw: bool, // Whether the terminal's contents are unknown, e.g. after a resize
+}
+
+impl Screen {
+    pub fn new(width: usize, height: usize) -> Screen {
+        Screen {
+            width: width,
+            height: height,
+            front: vec![blank(); width * height],
+            back: vec![blank(); width * height],
+            cursor: None,
+            full_redraw: true,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Changes the size of the screen. The next frame is drawn from scratch.
+    pub fn resize(&mut self, width: usize, height: usize) {
+        *self = Screen::new(width, height);
+    }
+
+    /// Makes the next frame redraw every cell, for when something else wrote to the terminal
+    pub fn invalidate(&mut self) {
+        self.full_redraw = true;
+    }
+
+    /// Starts a new frame
+    pub fn clear(&mut self) {
+        for cell in &mut self.back {
+            *cell = blank();
+        }
+        self.cursor = None;
+    }
+
+    /// Writes text from x on row y, clipped to the screen. Returns the column after the text.
+    /// Control characters are drawn as spaces, since they would move the terminal's cursor.
+    pub fn put_str(&mut self, x: usize, y: usize, text: &str, style: Style) -> usize {
+        let mut x = x;
+        if y >= self.height {
+            return x;
+        }
+        for grapheme in text.graphemes(true) {
+            let width = unicode::grapheme_width(grapheme);
+            if x + width > self.width {
+                break;
+            }
+            let grapheme = if grapheme.chars().next().map_or(true, |c| c.is_control()) { " " } else { grapheme };
+            self.set(x, y, Cell { text: grapheme.to_string(), style: style });
+            for i in 1..width {
+                self.set(x + i, y, Cell { text: String::new(), style: style });
+            }
+            x += width;
+        }
+        x
+    }
+
+    /// Sets a cell of the frame. Overwriting half of a wide grapheme cluster blanks the other half.
+    fn set(&mut self, x: usize, y: usize, cell: Cell) {
+        let i = y * self.width + x;
+        if !cell.text.is_empty() && self.back[i].text.is_empty() && x > 0 {
+            self.back[i - 1].text = " ".to_string();
+        }
+        if x + 1 < self.width && self.back[i + 1].text.is_empty() {
+            self.back[i + 1].text = " ".to_string();
+        }
+        self.back[i] = cell;
+    }
+
+    /// Fills part of a row with a character
+    pub fn fill(&mut self, x: usize, y: usize, width: usize, c: char, style: Style) {
+        let text: String = (0..width).map(|_| c).collect();
+        self.put_str(x, y, &text, style);
+    }
+
+    /// Where the terminal's cursor goes after the frame is drawn. Without one it stays hidden.
+    pub fn set_cursor(&mut self, x: usize, y: usize) {
+        self.cursor = Some((x, y));
+    }
+
+    pub fn cell(&self, x: usize, y: usize) -> &Cell {
+        &self.back[y * self.width + x]
+    }
+
+    /// Writes the escape sequences that turn what the terminal shows into the new frame
+    pub fn render<W: Write>(&mut self, out: &mut W) -> io::Result<()> {
+        let mut output = format!("{}", cursor::Hide);
+        if self.full_redraw {
+            output.push_str(&format!("{}{}", style::Reset, clear::All));
+        }
+
+        let mut position = None; // Where the terminal's cursor is, if known
+        let mut current_style = if self.full_redraw { Some(Style::default()) } else { None };
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let cell = &self.back[y * self.width + x];
+                let unchanged =
+                    if self.full_redraw { *cell == blank() } else { *cell == self.front[y * self.width + x] };
+                if unchanged || cell.text.is_empty() {
+                    // The second half of a wide grapheme cluster is drawn with the first
+                    continue;
+                }
+
+                if position != Some((x, y)) {
+                    output.push_str(&format!("{}", cursor::Goto(x as u16 + 1, y as u16 + 1)));
+                }
+                if current_style != Some(cell.style) {
+                    output.push_str(&style_sequence(cell.style));
+                    current_style = Some(cell.style);
+                }
+                output.push_str(&cell.text);
+                // Terminals don't all agree on how wide some graphemes are, so don't count on it
+                position = if unicode::grapheme_width(&cell.text) == 1 { Some((x + 1, y)) } else { None };
+            }
+        }
+
+        if current_style != Some(Style::default()) {
+            output.push_str(&format!("{}", style::Reset));
+        }
+        if let Some((x, y)) = self.cursor {
+            output.push_str(&format!("{}{}", cursor::Goto(x as u16 + 1, y as u16 + 1), cursor::Show));
+        }
+
+        try!(out.write_all(output.as_bytes()));
+        self.front.clone_from(&self.back);
+        self.full_redraw = false;
+        out.flush()
+    }
+}
+
+fn style_sequence(cell_style: Style) -> String {
+    let mut sequence = format!("{}", style::Reset);
+    if cell_style.bold {
+        sequence.push_str(&format!("{}", style::Bold));
+    }
+    if cell_style.invert {
+        sequence.push_str(&format!("{}", style::Invert));
+    }
+    if let Some(fg) = cell_style.fg {
+        sequence.push_str(&format!("{}", color::Fg(color::AnsiValue(fg))));
+    }
+    if let Some(bg) = cell_style.bg {
+        sequence.push_str(&format!("{}", color::Bg(color::AnsiValue(bg))));
+    }
+    sequence
+}
+
+/// A terminal emulator that keeps what's written to it in memory, for testing what gets drawn.
+/// It understands the escape sequences the editor uses.
+#[cfg(test)]
+pub struct MemoryTerminal {
+    pub width: usize,
+    pub height: usize,
+    cells: Vec<Cell>,
+    pub cursor: (usize, usize),
+    pub cursor_visible: bool,
+    style: Style,
+}
+
+#[cfg(test)]
+impl MemoryTerminal {
+    pub fn new(width: usize, height: usize) -> MemoryTerminal {
+        MemoryTerminal {
+            width: width,
+            height: height,
+            cells: vec![blank(); width * height],
+            cursor: (0, 0),
+            cursor_visible: true,
+            style: Style::default(),
+        }
+    }
+
+    /// The text of a row, without trailing spaces
+    pub fn row(&self, y: usize) -> String {
+        let row: String = self.cells[y * self.width..(y + 1) * self.width].iter().map(|c| c.text.as_str()).collect();
+        row.trim_right().to_string()
+    }
+
+    pub fn cell(&self, x: usize, y: usize) -> &Cell {
+        &self.cells[y * self.width + x]
+    }
+
+    /// Prints text at the cursor, a grapheme cluster to each cell or two for wide ones
+    fn print(&mut self, text: &str) {
+        for grapheme in text.graphemes(true) {
+            let (x, y) = self.cursor;
+            let width = unicode::grapheme_width(grapheme);
+            for i in 0..width {
+                if x + i < self.width && y < self.height {
+                    let text = if i == 0 { grapheme.to_string() } else { String::new() };
+                    self.cells[y * self.width + x + i] = Cell { text: text, style: self.style };
+                }
+            }
+            self.cursor = (x + width, y);
+        }
+    }
+
+    fn escape(&mut self, params: &str, command: char) {
+        let numbers: Vec<usize> = params.split(';').map(|p| p.parse().unwrap_or(0)).collect();
+        match command {
+            'H' => {
+                let row = numbers.get(0).cloned().unwrap_or(1);
+                let column = numbers.get(1).cloned().unwrap_or(1);
+                self.cursor = (column.saturating_sub(1), row.saturating_sub(1));
+            },
+            'J' => {
+                for cell in &mut self.cells {
+                    *cell = Cell { text: " ".to_string(), style: self.style };
+                }
+            },
+            'K' => {
+                let y = self.cursor.1;
+                for x in 0..self.width {
+                    self.cells[y * self.width + x] = Cell { text: " ".to_string(), style: self.style };
+                }
+            },
+            'h' if params == "?25" => { self.cursor_visible = true; },
+            'l' if params == "?25" => { self.cursor_visible = false; },
+            'm' => {
+                let mut i = 0;
+                while i < numbers.len() {
+                    match numbers[i] {
+                        0 => { self.style = Style::default(); },
+                        1 => { self.style.bold = true; },
+                        7 => { self.style.invert = true; },
+                        22 => { self.style.bold = false; },
+                        27 => { self.style.invert = false; },
+                        39 => { self.style.fg = None; },
+                        49 => { self.style.bg = None; },
+                        38 if numbers.get(i + 1) == Some(&5) => {
+                            self.style.fg = numbers.get(i + 2).map(|&n| n as u8);
+                            i += 2;
+                        },
+                        48 if numbers.get(i + 1) == Some(&5) => {
+                            self.style.bg = numbers.get(i + 2).map(|&n| n as u8);
+                            i += 2;
+                        },
+                        n => { panic!("Unknown style: {}", n); },
+                    }
+                    i += 1;
+                }
+            },
+            _ => { panic!("Unknown escape sequence: {}{}", params, command); },
+        }
+    }
+}
+
+#[cfg(test)]
+impl Write for MemoryTerminal {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        let text = String::from_utf8_lossy(bytes).into_owned();
+        let mut chars = text.chars();
+        let mut printed = String::new();
+        while let Some(c) = chars.next() {
+            if c == '\x1b' {
+                self.print(&printed);
+                printed.clear();
+                assert!(chars.next() == Some('['));
+                let mut params = String::new();
+                loop {
+                    match chars.next() {
+                        Some(c) if c.is_digit(10) || c == ';' || c == '?' => { params.push(c); },
+                        Some(command) => { self.escape(&params, command); break; },
+                        None => { panic!("Unfinished escape sequence"); },
+                    }
+                }
+            } else {
+                printed.push(c);
+            }
+        }
+        self.print(&printed);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+
+#[test]
+fn screen_renders_frames() {
+    let bold = Style { fg: Some(2), bold: true, ..Style::default() };
+    let mut screen = Screen::new(10, 3);
+    let mut terminal = MemoryTerminal::new(10, 3);
+
+    screen.put_str(0, 0, "hello", Style::default());
+    screen.put_str(2, 1, "world\tand more", bold);
+    screen.set_cursor(1, 2);
+    screen.render(&mut terminal).unwrap();
+    assert!(terminal.row(0) == "hello");
+    assert!(terminal.row(1) == "  world an");
+    assert!(terminal.cell(3, 1).style == bold);
+    assert!(terminal.cursor == (1, 2) && terminal.cursor_visible);
+
+    // Only what changed is written
+    screen.clear();
+    screen.put_str(0, 0, "help", Style::default());
+    screen.put_str(2, 1, "world\tand more", bold);
+    let mut output = Vec::new();
+    screen.render(&mut output).unwrap();
+    terminal.write_all(&output).unwrap();
+    assert!(terminal.row(0) == "help");
+    assert!(terminal.row(1) == "  world an");
+    let output = String::from_utf8(output).unwrap();
+    assert!(!output.contains("world") && !output.contains("hel"));
+    assert!(output.contains(&format!("{}", cursor::Goto(4, 1))));
+}
+
+#[test]
+fn screen_full_redraw_after_resize() {
+    let mut screen = Screen::new(4, 2);
+    screen.put_str(0, 0, "ab", Style::default());
+    screen.render(&mut Vec::new()).unwrap();
+
+    screen.resize(5, 2);
+    screen.put_str(0, 0, "ab", Style::default());
+    let mut output = Vec::new();
+    screen.render(&mut output).unwrap();
+    assert!(String::from_utf8(output).unwrap().contains(&format!("{}", clear::All)));
+}
+
+#[test]
+fn screen_wide_characters() {
+    let mut screen = Screen::new(6, 1);
+    let mut terminal = MemoryTerminal::new(6, 1);
+
+    assert!(screen.put_str(0, 0, "日本e\u{301}x", Style::default()) == 6);
+    screen.render(&mut terminal).unwrap();
+    assert!(terminal.row(0) == "日本e\u{301}x");
+    assert!(terminal.cell(1, 0).text == "" && terminal.cell(4, 0).text == "e\u{301}");
+
+    // Overwriting half of a wide character blanks the other half, and one that doesn't fit isn't drawn
+    screen.clear();
+    screen.put_str(0, 0, "日本e\u{301}x", Style::default());
+    screen.put_str(1, 0, "a", Style::default());
+    screen.put_str(5, 0, "語", Style::default());
+    screen.render(&mut terminal).unwrap();
+    assert!(terminal.row(0) == " a本e\u{301}x");
+}
@@ -0,0 +1,115 @@
+use regex::Regex;
+
+use buffer::Buffer;
+
+/// A compiled / or ? search
+pub struct Search {
+    pub pattern: String,
+    pub forward: bool, // Whether n goes towards the end of the buffer
+    regex: Regex,
+}
+
+/// Where a search found a match
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Match {
+    pub index: usize,  // Buffer index of the start of the match
+    pub wrapped: bool, // Whether the search went past the end of the buffer to find it
+}
+
+impl Search {
+    pub fn new(pattern: &str, forward: bool) -> Result<Search, String> {
+        let regex = try!(Regex::new(pattern).map_err(|e| format!("Invalid pattern: {}", e)));
+        Ok(Search {
+            pattern: pattern.to_string(),
+            forward: forward,
+            regex: regex,
+        })
+    }
+
+    /// Byte ranges [from, to) of the matches in a line
+    pub fn line_matches(&self, line: &str) -> Vec<(usize, usize)> {
+        self.regex.find_iter(line).filter(|&(from, to)| to > from).collect()
+    }
+
+    /// Finds the closest match after index, or before it when not going forward, wrapping around
+    /// the ends of the buffer. Matches never span lines.
+    pub fn find(&self, buffer: &Buffer, index: usize, forward: bool) -> Option<Match> {
+        let line_count = buffer.line_count();
+        let start_line = buffer.line_at_index(index);
+        let start_column = index - buffer.line_info(start_line).buf_index;
+
+        // Every line once, starting and ending with the cursor's line
+        for step in 0..line_count+1 {
+            let line =
+                if forward {
+                    (start_line + step) % line_count
+                } else {
+                    (start_line + line_count * 2 - step) % line_count
+                };
+            let wrapped = if forward { line < start_line } else { line > start_line } ||
+                          (step == line_count && step > 0);
+            let line_start = buffer.line_info(line).buf_index;
+            let matches = self.line_matches(&buffer.line(line));
+
+            let found =
+                if step == 0 {
+                    // Only matches past the cursor on its own line
+                    if forward {
+                        matches.iter().find(|&&(from, _)| from > start_column).cloned()
+                    } else {
+                        matches.iter().rev().find(|&&(from, _)| from < start_column).cloned()
+                    }
+                } else if forward {
+                    matches.first().cloned()
+                } else {
+                    matches.last().cloned()
+                };
+
+            if let Some((from, _)) = found {
+                return Some(Match { index: line_start + from, wrapped: wrapped });
+            }
+        }
+        None
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+
+#[test]
+fn search_forward_wraps() {
+    let buf = Buffer::from_string("foo bar\nbaz foo\nqux".to_string());
+    let search = Search::new("foo", true).unwrap();
+
+    assert!(search.find(&buf, 0, true) == Some(Match { index: 12, wrapped: false }));
+    assert!(search.find(&buf, 12, true) == Some(Match { index: 0, wrapped: true }));
+    assert!(search.find(&buf, 17, true) == Some(Match { index: 0, wrapped: true }));
+}
+
+#[test]
+fn search_backward_wraps() {
+    let buf = Buffer::from_string("foo bar\nbaz foo\nqux".to_string());
+    let search = Search::new("ba.", false).unwrap();
+
+    assert!(search.find(&buf, 9, false) == Some(Match { index: 8, wrapped: false }));
+    assert!(search.find(&buf, 8, false) == Some(Match { index: 4, wrapped: false }));
+    assert!(search.find(&buf, 4, false) == Some(Match { index: 8, wrapped: true }));
+}
+
+#[test]
+fn search_single_match_wraps_to_itself() {
+    let buf = Buffer::from_string("a\nfoo\nb".to_string());
+    let search = Search::new("foo", true).unwrap();
+
+    assert!(search.find(&buf, 2, true) == Some(Match { index: 2, wrapped: true }));
+    assert!(search.find(&buf, 2, false) == Some(Match { index: 2, wrapped: true }));
+    assert!(Search::new("nope", true).unwrap().find(&buf, 0, true) == None);
+    assert!(Search::new("(", true).is_err());
+}
+
+#[test]
+fn search_line_matches() {
+    let search = Search::new("o+", true).unwrap();
+    assert!(search.line_matches("foo boo x") == vec![(1, 3), (5, 7)]);
+    assert!(Search::new("x*", true).unwrap().line_matches("ab") == vec![]);
+}
@@ -0,0 +1,177 @@
+use regex::{Captures, Regex};
+
+/// A parsed :s/pattern/replacement/flags command
+pub struct Substitute {
+    pub pattern: String,
+    pub global: bool,  // g, replace every match in a line instead of the first
+    pub confirm: bool, // c, ask before each replacement
+    regex: Regex,
+    replacement: Vec<Replace>,
+}
+
+/// A piece of the replacement text
+#[derive(Clone, Debug, PartialEq)]
+enum Replace {
+    Text(String),
+    Group(usize), // & or \0 for the whole match, \1 to \9 for capture groups
+}
+
+impl Substitute {
+    /// Parses everything after the :s, starting with the delimiter. An empty pattern reuses
+    /// last_pattern, like vim.
+    pub fn parse(text: &str, last_pattern: Option<&str>) -> Result<Substitute, String> {
+        let delimiter =
+            match text.chars().next() {
+                Some(c) if !c.is_alphanumeric() && !c.is_whitespace() && c != '\\' && c != '"' && c != '|' => c,
+                _ => { return Err("Usage: s/pattern/replacement/flags".to_string()); },
+            };
+        let rest = &text[delimiter.len_utf8()..];
+        let (pattern, rest) = split_at_delimiter(rest, delimiter);
+        let (replacement, flags) = split_at_delimiter(rest.unwrap_or(""), delimiter);
+        let flags = flags.unwrap_or("");
+
+        let pattern =
+            if pattern.is_empty() {
+                match last_pattern {
+                    Some(pattern) => pattern.to_string(),
+                    None => { return Err("No previous search".to_string()); },
+                }
+            } else {
+                pattern
+            };
+
+        let mut global = false;
+        let mut confirm = false;
+        let mut ignore_case = false;
+        for flag in flags.trim_right().chars() {
+            match flag {
+                'g' => { global = true; },
+                'c' => { confirm = true; },
+                'i' => { ignore_case = true; },
+                'I' => { ignore_case = false; },
+                _ => { return Err(format!("Unknown flag: {}", flag)); },
+            }
+        }
+
+        let regex =
+            if ignore_case {
+                Regex::new(&format!("(?i){}", pattern))
+            } else {
+                Regex::new(&pattern)
+            };
+        let regex = try!(regex.map_err(|e| format!("Invalid pattern: {}", e)));
+
+        Ok(Substitute {
+            pattern: pattern,
+            global: global,
+            confirm: confirm,
+            regex: regex,
+            replacement: parse_replacement(&replacement),
+        })
+    }
+
+    /// The matches to replace in a line: byte ranges [from, to) of the line, each with its
+    /// replacement text. Without the g flag that's only the first match.
+    pub fn line_matches(&self, line: &str) -> Vec<(usize, usize, String)> {
+        let matches =
+            self.regex.captures_iter(line).map(|caps| {
+                let (from, to) = caps.pos(0).unwrap();
+                (from, to, self.expand(&caps))
+            });
+        if self.global {
+            matches.collect()
+        } else {
+            matches.take(1).collect()
+        }
+    }
+
+    fn expand(&self, caps: &Captures) -> String {
+        let mut text = String::new();
+        for part in &self.replacement {
+            match *part {
+                Replace::Text(ref s) => { text.push_str(s); },
+                Replace::Group(i) => { text.push_str(caps.at(i).unwrap_or("")); },
+            }
+        }
+        text
+    }
+}
+
+/// Splits text at the first delimiter that isn't escaped with a backslash. Escaped delimiters
+/// become plain delimiters, other escapes are kept. The second part is None when there's no
+/// delimiter.
+fn split_at_delimiter(text: &str, delimiter: char) -> (String, Option<&str>) {
+    let mut part = String::new();
+    let mut chars = text.char_indices();
+    while let Some((i, c)) = chars.next() {
+        if c == delimiter {
+            return (part, Some(&text[i + c.len_utf8()..]));
+        } else if c == '\\' {
+            match chars.next() {
+                Some((_, next)) if next == delimiter => { part.push(next); },
+                Some((_, next)) => { part.push('\\'); part.push(next); },
+                None => { part.push('\\'); },
+            }
+        } else {
+            part.push(c);
+        }
+    }
+    (part, None)
+}
+
+/// Parses vim style replacement text: & and \0 are the whole match, \1 to \9 are capture groups,
+/// \r and \n are line breaks, \t is a tab and \& is a plain &
+fn parse_replacement(text: &str) -> Vec<Replace> {
+    let mut parts = Vec::new();
+    let mut literal = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        let group =
+            match c {
+                '&' => Some(0),
+                '\\' => {
+                    match chars.next() {
+                        Some(d) if d.is_digit(10) => d.to_digit(10).map(|d| d as usize),
+                        Some('r') | Some('n') => { literal.push('\n'); None },
+                        Some('t') => { literal.push('\t'); None },
+                        Some(other) => { literal.push(other); None },
+                        None => { literal.push('\\'); None },
+                    }
+                },
+                _ => { literal.push(c); None },
+            };
+        if let Some(group) = group {
+            if !literal.is_empty() {
+                parts.push(Replace::Text(literal.clone()));
+                literal.clear();
+            }
+            parts.push(Replace::Group(group));
+        }
+    }
+    if !literal.is_empty() {
+        parts.push(Replace::Text(literal));
+    }
+    parts
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+
+#[test]
+fn substitute_parse() {
+    let sub = Substitute::parse("/a(b)/x\\1&/g", None).unwrap();
+    assert!(sub.pattern == "a(b)");
+    assert!(sub.global && !sub.confirm);
+    assert!(sub.line_matches("ab cab") == vec![(0, 2, "xbab".to_string()), (4, 6, "xbab".to_string())]);
+
+    // Other delimiters, escaped delimiters and the last search pattern
+    let sub = Substitute::parse("#\\##-#c", None).unwrap();
+    assert!(sub.pattern == "#" && sub.confirm);
+    assert!(sub.line_matches("a#b#") == vec![(1, 2, "-".to_string())]);
+    assert!(Substitute::parse("//\\r/", Some("x")).unwrap().line_matches("axb") == vec![(1, 2, "\n".to_string())]);
+    assert!(Substitute::parse("/A/b/i", None).unwrap().line_matches("a") == vec![(0, 1, "b".to_string())]);
+
+    assert!(Substitute::parse("//x/", None).is_err());
+    assert!(Substitute::parse("/a/b/z", None).is_err());
+    assert!(Substitute::parse("", None).is_err());
+}
@@ -1,27 +1,221 @@
-use std::io::{self, Write};
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read};
 use std::fs::File;
-use std::path::Path;
+use std::mem;
+use std::path::{Path, PathBuf};
+
+use time;
 
 use buffer::Buffer;
-use buffer_operator::BufferOperator;
+use buffer_operator::{BufferOperator, DiskFile, View};
+use config::{self, Config};
 use cursor::Cursor;
+use ex::{self, CommandLine};
+use file_format::{Encoding, FileFormat, LineEnding};
+use hex;
+use keymap::{self, Action, Keymaps, Resolve};
+use merge;
+use motion::{Motion, MotionKind};
+use normal_command::{self, NormalCommand, Operator, Parse};
 use operation::Operation;
+use register::Registers;
+use search::Search;
+use substitute::Substitute;
+use undo_file::{self, UndoFile};
+use undo_tree::{TimeTravel, UndoTree};
+use unicode;
+use window::{self, Layout, Rect, TabPage, Window};
+
+/// Mappings can expand to other mappings this many times before ted gives up on the keys
+const MAX_REMAPS: usize = 1000;
+
+/// Messages shown at once at the bottom of the screen, older ones are dropped
+const MAX_MESSAGES: usize = 5;
+
+/// Messages kept for :messages
+const MAX_MESSAGE_LOG: usize = 200;
+
+/// How often the file is checked for changes made by other programs
+const FILE_CHECK_MS: i64 = 1000;
+
+/// Ex commands that change the buffer's text, refused in read-only buffers. Hex view only refuses
+/// the ones that edit it, going through the undo history keeps the dump's layout.
+const EDITING_COMMANDS: &'static [&'static str] = &["read", "delete", "move", "t", "copy", "substitute"];
+const HISTORY_COMMANDS: &'static [&'static str] = &["earlier", "later", "undo"];
+
+const HEX_ONLY_TYPE_OVER: &'static str = "Bytes can only be typed over in hex view";
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MessageKind {
+    Info,
+    Error,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message {
+    pub text: String,
+    pub kind: MessageKind,
+}
+
+/// Every command that can be typed after :
+const EX_COMMANDS: &'static [ex::Command] = &[
+    ex::Command { name: "write", abbreviation: "w", range: false, zero: false, bang: true, count: false,
+                  handler: Ted::ex_write },
+    ex::Command { name: "wq", abbreviation: "wq", range: false, zero: false, bang: true, count: false,
+                  handler: Ted::ex_write_quit },
+    ex::Command { name: "quit", abbreviation: "q", range: false, zero: false, bang: true, count: false,
+                  handler: Ted::ex_quit },
+    ex::Command { name: "edit", abbreviation: "e", range: false, zero: false, bang: true, count: false,
+                  handler: Ted::ex_edit },
+    ex::Command { name: "bnext", abbreviation: "bn", range: false, zero: false, bang: false, count: false,
+                  handler: Ted::ex_buffer_next },
+    ex::Command { name: "bprevious", abbreviation: "bp", range: false, zero: false, bang: false, count: false,
+                  handler: Ted::ex_buffer_previous },
+    ex::Command { name: "bdelete", abbreviation: "bd", range: false, zero: false, bang: true, count: false,
+                  handler: Ted::ex_buffer_delete },
+    ex::Command { name: "buffer", abbreviation: "b", range: false, zero: false, bang: false, count: false,
+                  handler: Ted::ex_buffer },
+    ex::Command { name: "buffers", abbreviation: "buffers", range: false, zero: false, bang: false, count: false,
+                  handler: Ted::ex_buffers },
+    ex::Command { name: "ls", abbreviation: "ls", range: false, zero: false, bang: false, count: false,
+                  handler: Ted::ex_buffers },
+    ex::Command { name: "split", abbreviation: "sp", range: false, zero: false, bang: false, count: false,
+                  handler: Ted::ex_split },
+    ex::Command { name: "vsplit", abbreviation: "vs", range: false, zero: false, bang: false, count: false,
+                  handler: Ted::ex_split },
+    ex::Command { name: "close", abbreviation: "clo", range: false, zero: false, bang: false, count: false,
+                  handler: Ted::ex_close },
+    ex::Command { name: "only", abbreviation: "on", range: false, zero: false, bang: false, count: false,
+                  handler: Ted::ex_only },
+    ex::Command { name: "tabnew", abbreviation: "tabnew", range: false, zero: false, bang: false, count: false,
+                  handler: Ted::ex_tab_new },
+    ex::Command { name: "tabedit", abbreviation: "tabe", range: false, zero: false, bang: false, count: false,
+                  handler: Ted::ex_tab_new },
+    ex::Command { name: "tabnext", abbreviation: "tabn", range: false, zero: false, bang: false, count: false,
+                  handler: Ted::ex_tab_next },
+    ex::Command { name: "tabprevious", abbreviation: "tabp", range: false, zero: false, bang: false, count: false,
+                  handler: Ted::ex_tab_previous },
+    ex::Command { name: "tabclose", abbreviation: "tabc", range: false, zero: false, bang: false, count: false,
+                  handler: Ted::ex_tab_close },
+    ex::Command { name: "read", abbreviation: "r", range: true, zero: true, bang: false, count: false,
+                  handler: Ted::ex_read },
+    ex::Command { name: "delete", abbreviation: "d", range: true, zero: false, bang: false, count: true,
+                  handler: Ted::ex_delete },
+    ex::Command { name: "move", abbreviation: "m", range: true, zero: false, bang: false, count: false,
+                  handler: Ted::ex_move },
+    ex::Command { name: "t", abbreviation: "t", range: true, zero: false, bang: false, count: false,
+                  handler: Ted::ex_copy },
+    ex::Command { name: "copy", abbreviation: "co", range: true, zero: false, bang: false, count: false,
+                  handler: Ted::ex_copy },
+    ex::Command { name: "normal", abbreviation: "norm", range: true, zero: false, bang: true, count: false,
+                  handler: Ted::ex_normal },
+    ex::Command { name: "substitute", abbreviation: "s", range: true, zero: false, bang: false, count: false,
+                  handler: Ted::ex_substitute },
+    ex::Command { name: "earlier", abbreviation: "ea", range: false, zero: false, bang: false, count: false,
+                  handler: Ted::ex_time_travel },
+    ex::Command { name: "later", abbreviation: "lat", range: false, zero: false, bang: false, count: false,
+                  handler: Ted::ex_time_travel },
+    ex::Command { name: "undo", abbreviation: "u", range: false, zero: false, bang: false, count: false,
+                  handler: Ted::ex_undo },
+    ex::Command { name: "undolist", abbreviation: "undol", range: false, zero: false, bang: false, count: false,
+                  handler: Ted::ex_undo_list },
+    ex::Command { name: "registers", abbreviation: "reg", range: false, zero: false, bang: false, count: false,
+                  handler: Ted::ex_registers },
+    ex::Command { name: "messages", abbreviation: "mes", range: false, zero: false, bang: false, count: false,
+                  handler: Ted::ex_messages },
+    ex::Command { name: "nohlsearch", abbreviation: "noh", range: false, zero: false, bang: false, count: false,
+                  handler: Ted::ex_no_highlight },
+    ex::Command { name: "hex", abbreviation: "hex", range: false, zero: false, bang: false, count: false,
+                  handler: Ted::ex_hex },
+    ex::Command { name: "set", abbreviation: "se", range: false, zero: false, bang: false, count: false,
+                  handler: Ted::ex_set },
+    ex::Command { name: "source", abbreviation: "so", range: false, zero: false, bang: false, count: false,
+                  handler: Ted::ex_source },
+    ex::Command { name: "map", abbreviation: "map", range: false, zero: false, bang: false, count: false,
+                  handler: Ted::ex_map },
+    ex::Command { name: "nmap", abbreviation: "nm", range: false, zero: false, bang: false, count: false,
+                  handler: Ted::ex_map },
+    ex::Command { name: "omap", abbreviation: "om", range: false, zero: false, bang: false, count: false,
+                  handler: Ted::ex_map },
+    ex::Command { name: "imap", abbreviation: "im", range: false, zero: false, bang: false, count: false,
+                  handler: Ted::ex_map },
+    ex::Command { name: "vmap", abbreviation: "vm", range: false, zero: false, bang: false, count: false,
+                  handler: Ted::ex_map },
+];
 
 #[derive(Copy, Clone, PartialEq)]
 pub enum Mode {
     Normal,
     Insert,
     Command,
+    Search { forward: bool }, // Typing a / or ? pattern
+    Confirm, // Answering y/n/a/q/l for each replacement of :s///c
+    FileChanged, // Answering whether to reload, keep or merge a file something else changed
     VisualChar { start: u64 },
     VisualLine { start: u64 },
     VisualBlock { start: u64 },
 }
 
+/// The text selected in a visual mode
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Selection {
+    Chars(usize, usize), // Buffer range [from, to)
+    Lines(usize, usize), // First and last line
+    Block { first: usize, last: usize, left: usize, right: usize }, // Lines and display columns, inclusive
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
 pub enum Event {
     Backspace,
     Enter,
     Esc,
     Char(char),
+    Ctrl(char),
+    Up,
+    Down,
+}
+
+/// What handling the pending keys did
+enum KeyStep {
+    Wait,     // The keys need more keys
+    Done,     // Some of the keys were used up
+    Remapped, // A mapping in the keys was replaced by the keys it maps to
+}
+
+/// Text typed after changing a visual block is copied to the block's other lines on <Esc>
+struct BlockInsert {
+    lines: Vec<usize>, // Lines other than the first that had part of the block in them
+    column: usize,     // Display column
+    start: usize, // Where typing started on the first line
+}
+
+/// A :s command working through its range. Matches are found in each line as it was before any
+/// replacements, so a replacement is never matched again.
+struct Substitution {
+    substitute: Substitute,
+    line_start: usize,  // Where the line being substituted starts
+    line_length: usize, // Its length before any replacements
+    matches: Vec<(usize, usize, String)>, // Matches left in the line, with their replacements
+    shift: isize,       // How far replacements have moved the rest of the line
+    lines_left: usize,  // Lines after this one in the range
+    replace_all: bool,  // Whether the rest is replaced without asking
+    replaced: usize,
+    lines_changed: usize,
+    line_changed: bool,
+    last_changed: Option<usize>, // Start of the last line that changed
+}
+
+/// A buffer that isn't shown, with everything needed to show it again the way it was left
+struct HiddenBuffer {
+    number: usize,
+    path: Option<String>,
+    buf_op: BufferOperator,
+    cursor: Cursor,
+    scroll: u64,
+    left: u64,
+    undo_tree: UndoTree,
+    visual_lines: Option<(usize, usize)>,
 }
 
 pub struct TedOperation {
@@ -34,19 +228,58 @@ pub enum ParameterType {
 
 pub struct Ted {
     path: Option<String>,
+    buffer_number: usize, // Number of the shown buffer, as :ls lists it
+    hidden_buffers: Vec<HiddenBuffer>, // Every other open buffer, by number
+    next_buffer_number: usize,
+    pub single_buffer: bool, // Whether other buffers can't be opened, e.g. when sharing one with a server
+    windows: Vec<Window>, // Every window of every tab page, the active one's cursor and scroll are ted's
+    window: usize, // Id of the window being typed in
+    next_window_id: usize,
+    tabs: Vec<TabPage>,
+    tab: usize, // Index of the tab page shown
+    separators: Vec<Rect>, // Columns between side by side windows
+    screen_width: usize, // Room for the tab line and the windows
+    screen_height: usize,
     mode: Mode,
     pub scroll: u64,
+    pub left: u64, // Columns scrolled off the left of the window, when lines don't wrap
     pub height: u64,
     pub cursor: Cursor,
 
     buf_op: BufferOperator,
     cmd_buffer: BufferOperator,
+    pending_keys: Vec<Event>, // Keys typed so far that don't make a command or mapping yet
+    pending_since: Option<time::SteadyTime>, // When the oldest pending key was typed
+    keymaps: Keymaps,
+    registers: Registers,
+    pending_register: Option<char>, // Register picked with " for the next command
+    block_insert: Option<BlockInsert>,
+    substitution: Option<Substitution>,
+    visual_lines: Option<(usize, usize)>, // Lines of the last visual selection, for '< and '>
+    file_checked_at: Option<time::SteadyTime>, // When the file was last checked for changes
+    disk_change: Option<DiskFile>, // The file as another program left it, waiting for an answer
 
-    pub log: Vec<Operation>,
-    log_index: usize, // Current position in the log from undoing/redoing
+    search: Option<Search>, // Last search, for n and N
+    incremental_search: Option<Search>, // Pattern being typed in search mode
+    highlight_search: bool, // Whether matches of the last search are highlighted
+    search_origin: usize, // Where the cursor was when the search being typed was started
+    search_history: Vec<String>,
+    history_index: Option<usize>, // Entry of the command or search history being shown
+
+    pub undo_tree: UndoTree,
+    join_undo_step: bool, // Whether newly logged operations extend the last undo step
+
+    pub net_log: Vec<Operation>, // Every operation applied locally, in order, for the server
 
     pub cmd_log: Vec<String>,
 
+    messages: VecDeque<Message>, // Shown at the bottom of the screen until the next key
+    message_log: Vec<Message>,   // Every recent message, for :messages
+
+    persist_undo: bool, // Whether saving also stores the undo history next to the file
+
+    config: Config,
+
     pub dirty: bool,
     running: bool,
 }
@@ -55,20 +288,66 @@ impl Ted {
     pub fn new(height: u64) -> Ted {
         Ted {
             path: None,
+            buffer_number: 1,
+            hidden_buffers: Vec::new(),
+            next_buffer_number: 2,
+            single_buffer: false,
+            windows: vec![Window {
+                id: 1,
+                buffer: 1,
+                cursor: Cursor { line: 0, column: 0, buf_index: 0 },
+                scroll: 0,
+                left: 0,
+                rect: Rect { x: 0, y: 0, width: 80, height: height as usize },
+            }],
+            window: 1,
+            next_window_id: 2,
+            tabs: vec![TabPage { layout: Layout::Window(1), window: 1 }],
+            tab: 0,
+            separators: Vec::new(),
+            screen_width: 80,
+            screen_height: height as usize,
             
             mode: Mode::Normal,
             scroll: 0,
+            left: 0,
             height: height,
             cursor: Cursor { line: 0, column: 0, buf_index: 0 },
 
             buf_op: BufferOperator::new(),
             cmd_buffer: BufferOperator::new(),
+            pending_keys: Vec::new(),
+            pending_since: None,
+            keymaps: Keymaps::new(),
+            registers: Registers::new(),
+            pending_register: None,
+            block_insert: None,
+            substitution: None,
+            visual_lines: None,
+            file_checked_at: None,
+            disk_change: None,
+
+            search: None,
+            incremental_search: None,
+            highlight_search: true,
+            search_origin: 0,
+            search_history: Vec::new(),
+            history_index: None,
             
-            log: Vec::new(),
-            log_index: 0,
+            undo_tree: UndoTree::new(),
+            join_undo_step: false,
+
+            net_log: Vec::new(),
 
             cmd_log: Vec::new(),
 
+            messages: VecDeque::new(),
+            message_log: Vec::new(),
+
+            persist_undo: false,
+
+            config: Config::new(),
+
             dirty: true,
             running: true,
         }
@@ -77,403 +356,3927 @@ impl Ted {
     pub fn from_string(height: u64, text: String) -> Ted {
         Ted {
             path: None,
+            buffer_number: 1,
+            hidden_buffers: Vec::new(),
+            next_buffer_number: 2,
+            single_buffer: false,
+            windows: vec![Window {
+                id: 1,
+                buffer: 1,
+                cursor: Cursor { line: 0, column: 0, buf_index: 0 },
+                scroll: 0,
+                left: 0,
+                rect: Rect { x: 0, y: 0, width: 80, height: height as usize },
+            }],
+            window: 1,
+            next_window_id: 2,
+            tabs: vec![TabPage { layout: Layout::Window(1), window: 1 }],
+            tab: 0,
+            separators: Vec::new(),
+            screen_width: 80,
+            screen_height: height as usize,
 
             mode: Mode::Normal,
             scroll: 0,
+            left: 0,
             height: height,
             cursor: Cursor { line: 0, column: 0, buf_index: 0 },
 
             buf_op: BufferOperator::from_string(text),
             cmd_buffer: BufferOperator::new(),
+            pending_keys: Vec::new(),
+            pending_since: None,
+            keymaps: Keymaps::new(),
+            registers: Registers::new(),
+            pending_register: None,
+            block_insert: None,
+            substitution: None,
+            visual_lines: None,
+            file_checked_at: None,
+            disk_change: None,
+
+            search: None,
+            incremental_search: None,
+            highlight_search: true,
+            search_origin: 0,
+            search_history: Vec::new(),
+            history_index: None,
             
-            log: Vec::new(),
-            log_index: 0,
+            undo_tree: UndoTree::new(),
+            join_undo_step: false,
+
+            net_log: Vec::new(),
 
             cmd_log: Vec::new(),
 
+            messages: VecDeque::new(),
+            message_log: Vec::new(),
+
+            persist_undo: false,
+
+            config: Config::new(),
+
             dirty: true,
             running: true,
         }
     }
 
     pub fn from_file(height: u64, path: String) -> io::Result<Ted> {
-        Ok(Ted {
+        let mut ted = Ted {
             path: Some(path.clone()),
+            buffer_number: 1,
+            hidden_buffers: Vec::new(),
+            next_buffer_number: 2,
+            single_buffer: false,
+            windows: vec![Window {
+                id: 1,
+                buffer: 1,
+                cursor: Cursor { line: 0, column: 0, buf_index: 0 },
+                scroll: 0,
+                left: 0,
+                rect: Rect { x: 0, y: 0, width: 80, height: height as usize },
+            }],
+            window: 1,
+            next_window_id: 2,
+            tabs: vec![TabPage { layout: Layout::Window(1), window: 1 }],
+            tab: 0,
+            separators: Vec::new(),
+            screen_width: 80,
+            screen_height: height as usize,
 
             mode: Mode::Normal,
             scroll: 0,
+            left: 0,
             height: height,
             cursor: Cursor { line: 0, column: 0, buf_index: 0 },
 
-            buf_op: try!(BufferOperator::from_file(path)),
+            buf_op: try!(BufferOperator::from_file(path.clone())),
             cmd_buffer: BufferOperator::new(),
+            pending_keys: Vec::new(),
+            pending_since: None,
+            keymaps: Keymaps::new(),
+            registers: Registers::new(),
+            pending_register: None,
+            block_insert: None,
+            substitution: None,
+            visual_lines: None,
+            file_checked_at: None,
+            disk_change: None,
+
+            search: None,
+            incremental_search: None,
+            highlight_search: true,
+            search_origin: 0,
+            search_history: Vec::new(),
+            history_index: None,
             
-            log: Vec::new(),
-            log_index: 0,
+            undo_tree: UndoTree::new(),
+            join_undo_step: false,
+
+            net_log: Vec::new(),
 
             cmd_log: Vec::new(),
 
+            messages: VecDeque::new(),
+            message_log: Vec::new(),
+
+            persist_undo: false,
+
+            config: Config::new(),
+
             dirty: true,
             running: true,
-        })
+        };
+
+        ted.notify_view();
+
+        Ok(ted)
     }
 
     pub fn handle_event(&mut self, e: Event) {
-        match self.mode {
-            Mode::Normal => { self.normal_handle_event(e); },
-            Mode::Insert => { self.insert_handle_event(e); },
-            Mode::Command => { self.command_handle_event(e); },
-            Mode::VisualChar { start: _ } => { self.visual_char_handle_event(e); },
-            Mode::VisualLine { start: _ } => { self.visual_line_handle_event(e); },
-            Mode::VisualBlock { start: _ } => { self.visual_block_handle_event(e); },
+        if !self.messages.is_empty() {
+            self.messages.clear();
+            self.dirty = true;
         }
-    }
 
-    pub fn execute_command(&mut self, cmd: String) {
-        let cmd_split: Vec<String> = cmd.split(" ").map(|s| s.to_owned()).collect();
-
-        if cmd == "q" {
-            self.running = false;
+        match self.mode {
+            Mode::Command | Mode::Search { forward: _ } => { self.command_handle_event(e); },
+            Mode::Confirm => { self.confirm_handle_event(e); },
+            Mode::FileChanged => { self.file_changed_handle_event(e); },
+            _ => {
+                // Keys go through the keymaps first
+                if self.pending_keys.is_empty() {
+                    self.pending_since = Some(time::SteadyTime::now());
+                }
+                self.pending_keys.push(e);
+                self.handle_pending_keys(false);
+            },
         }
+    }
 
-        if cmd_split[0] == "w" {
-            // Write command
-            if cmd_split.len() >= 2 {
-                // User supplied a file
-                self.path = Some(cmd_split[1].clone());
-                self.save(&cmd_split[1]);
-            } else {
-                // User didn't supply a file to write to
-                if let Some(ref path) = self.path {
-                    // TODO: Display error message rather than panicing if save fails
-                    self.save(path).unwrap();
-                }
+    /// Stops waiting for a longer mapping once the pending keys have been waiting long enough
+    pub fn check_key_timeout(&mut self) {
+        if let Some(since) = self.pending_since {
+            if time::SteadyTime::now() - since >= time::Duration::milliseconds(keymap::KEY_TIMEOUT_MS) {
+                self.handle_pending_keys(true);
+                self.pending_since = None;
             }
         }
-
-        self.cmd_log.push(cmd);
     }
 
-    // Normal mode handle event
-    fn normal_handle_event(&mut self, e: Event) {
-        match e {
-            Event::Backspace => { },
-            Event::Char(c) => {
-                match c {
-                    'i' => {
-                        self.mode = Mode::Insert;
-                        self.dirty = true;
-                    },
-                    ':' => {
-                        self.mode = Mode::Command;
-                        self.dirty = true;
-                    },
-                    'v' => {
-                        self.mode = Mode::VisualChar { start: self.cursor.buf_index };
-                        self.dirty = true;
-                    },
-                    'V' => {
-                        self.mode = Mode::VisualLine { start: self.cursor.buf_index };
-                        self.dirty = true;
-                    },
-                    'h' => {
-                        self.cursor_left();
+    /// Runs the commands the pending keys make up, until the rest of the keys need more keys
+    fn handle_pending_keys(&mut self, timed_out: bool) {
+        let mut remaps = 0;
+        while !self.pending_keys.is_empty() {
+            let step =
+                match self.mode {
+                    Mode::Normal => self.normal_handle_keys(timed_out),
+                    Mode::Insert => self.insert_handle_keys(timed_out),
+                    Mode::Command | Mode::Search { forward: _ } => {
+                        // A mapping switched to command mode, the rest of its keys are the command
+                        let e = self.pending_keys.remove(0);
+                        self.command_handle_event(e);
+                        KeyStep::Done
                     },
-                    'l' => {
-                        self.cursor_right();
+                    Mode::Confirm => {
+                        let e = self.pending_keys.remove(0);
+                        self.confirm_handle_event(e);
+                        KeyStep::Done
                     },
-                    'k' => {
-                        self.cursor_up();
-                    },
-                    'j' => {
-                        self.cursor_down();
-                    },
-                    'b' => {
-                        if self.cursor.buf_index > 0 &&
-                            (self.buf_op.buffer_bytes()[self.cursor.buf_index as usize - 1] == b' ' ||
-                            self.buf_op.buffer_bytes()[self.cursor.buf_index as usize - 1] == b'\n')
-                        {
-                            self.cursor.buf_index -= 1;
-                        }
-                        while self.cursor.buf_index > 0 &&
-                              self.buf_op.buffer_bytes()[self.cursor.buf_index as usize - 1] != b' ' &&
-                              self.buf_op.buffer_bytes()[self.cursor.buf_index as usize - 1] != b'\n' {
-                            self.cursor.buf_index -= 1;
-                        }
-                        self.cursor.calculate_pos(&self.buf_op.buffer());
-                        self.dirty = true;
+                    Mode::FileChanged => {
+                        let e = self.pending_keys.remove(0);
+                        self.file_changed_handle_event(e);
+                        KeyStep::Done
                     },
-                    'w' => {
-                        if (self.cursor.buf_index as usize) < self.buf_op.buffer_bytes().len() - 1 &&
-                            (self.cursor.buf_index == 0 ||
-                             ((self.cursor.buf_index as usize) > 0 &&
-                              (self.buf_op.buffer_bytes()[self.cursor.buf_index as usize - 1] == b' ' ||
-                              self.buf_op.buffer_bytes()[self.cursor.buf_index as usize - 1] == b'\n')))
-                        {
-                            self.cursor.buf_index += 1;
-                        }
-                        while (self.cursor.buf_index as usize) < self.buf_op.buffer_bytes().len() - 1 &&
-                              (self.cursor.buf_index as usize) > 0 &&
-                              self.buf_op.buffer_bytes()[self.cursor.buf_index as usize - 1] != b' ' &&
-                              self.buf_op.buffer_bytes()[self.cursor.buf_index as usize - 1] != b'\n' {
-                            self.cursor.buf_index += 1;
-                        }
-                        self.cursor.calculate_pos(&self.buf_op.buffer());
+                    _ => self.visual_handle_keys(timed_out),
+                };
+
+            match step {
+                KeyStep::Wait => { return; },
+                KeyStep::Done => { },
+                KeyStep::Remapped => {
+                    remaps += 1;
+                    if remaps > MAX_REMAPS {
+                        self.pending_keys.clear();
+                        self.notify_error("Recursive mapping".to_string());
                         self.dirty = true;
-                    },
-                    _ => { },
-                }
-            },
-            _ => { },
+                    }
+                },
+            }
         }
+        self.pending_since = None;
     }
 
-    // Insert mode handle event
-    fn insert_handle_event(&mut self, e: Event) {
-        match e {
-            Event::Esc => {
-                self.mode = Mode::Normal;
-                self.dirty = true;
-            },
-            Event::Backspace => {
-                if self.cursor.buf_index > 0 {
-                    if self.cursor.buf_index == self.buffer()
-                                                    .line_info()[self.cursor.line as usize]
-                                                    .buf_index as u64 {
-                        // Handle special newline case
-                        self.cursor.buf_index -= 1;
-                        self.cursor.line -= 1;
-                        self.cursor.column =
-                            self.buffer().line_info()[self.cursor.line as usize].length as u64;
-                    } else {
-                        self.cursor.move_left(self.buf_op.buffer());
-                    }
+    pub fn execute_command(&mut self, cmd: String) {
+        if let Err(e) = self.run_command(&cmd) {
+            self.notify_error(e);
+        }
+        self.cmd_log.push(cmd);
+    }
 
-                    let index = self.cursor.buf_index;
+    fn run_command(&mut self, cmd: &str) -> Result<(), String> {
+        // Ranges in a paged file are lines of the page, but a line number alone is the file's
+        if self.buf_op.is_paged() {
+            let text = cmd.trim_left_matches(|c| c == ':' || c == ' ').trim();
+            if let Ok(number) = text.parse::<usize>() {
+                self.page_to_line(Some(number.saturating_sub(1)));
+                return Ok(());
+            }
+        }
 
-                    let op = self.buf_op.remove_char(index);
-                    self.log(op);
-                }
+        let line = {
+            let marks = self.marks();
+            let context = ex::Context {
+                current: self.cursor.line as usize,
+                last: self.buffer().line_count() - 1,
+                marks: &marks,
+            };
+            try!(ex::parse(cmd, EX_COMMANDS, &context))
+        };
+
+        match line.command {
+            Some(command) if self.buf_op.is_read_only() && (EDITING_COMMANDS.contains(&command.name) ||
+                                                            HISTORY_COMMANDS.contains(&command.name)) => {
+                Err("The buffer is read-only".to_string())
             },
-            Event::Enter => {
-                let index = self.cursor.buf_index;
-                let op = self.buf_op.insert_char(index, '\n');
-                self.log(op);
-                self.cursor.column = 0;
-                self.cursor_down();
+            Some(command) if self.buf_op.is_hex() && EDITING_COMMANDS.contains(&command.name) => {
+                Err(HEX_ONLY_TYPE_OVER.to_string())
             },
-            Event::Char(c) => {
-                let index = self.cursor.buf_index;
-                let op = self.buf_op.insert_char(index, c);
-                self.log(op);
-                self.cursor.column += 1;
-                self.cursor.buf_index += 1;
+            Some(command) => (command.handler)(self, &line),
+            None => {
+                // A range on its own goes to its last line
+                if let Some((_, last)) = line.lines() {
+                    let index = self.buffer().line_info(last).buf_index;
+                    self.move_cursor_to(index);
+                }
+                Ok(())
             },
         }
     }
 
-    // Command mode handle event
-    fn command_handle_event(&mut self, e: Event) {
-        match e {
-            Event::Backspace => {
-                if self.cmd_buffer.buffer().len() > 0 {
-                    let end = self.cmd_buffer.buffer().len()-1;
-                    self.cmd_buffer.buffer_mut().remove(end, end);
-                    self.dirty = true;
-                }
-            },
-            Event::Char(c) => {
-                let end = self.cmd_buffer.buffer().len();
-                self.cmd_buffer.buffer_mut().insert(end, format!("{}", c).as_str());
-                self.dirty = true;
-            },
-            Event::Enter => {
-                let command = self.cmd_buffer.buffer().buffer().clone();
-                self.execute_command(command);
-                self.cmd_buffer.buffer_mut().clear();
-                self.mode = Mode::Normal;
-                self.dirty = true;
-            },
-            Event::Esc => {
-                self.mode = Mode::Normal;
-                self.dirty = true;
-            }
+    /// Lines that ex command ranges can refer to with 'x
+    fn marks(&self) -> HashMap<char, usize> {
+        let mut marks = HashMap::new();
+        if let Some((first, last)) = self.visual_lines {
+            marks.insert('<', first);
+            marks.insert('>', last);
         }
+        marks
     }
 
-    // Visual character mode
-    fn visual_char_handle_event(&mut self, e: Event) {
-        match e {
-            Event::Esc => {
-                self.mode = Mode::Normal;
-                self.dirty = true;
-            },
-            Event::Backspace => { },
-            Event::Char(_) => {
-            },
-            _ => { },
+    fn ex_write(&mut self, line: &CommandLine) -> Result<(), String> {
+        let args = try!(line.args());
+        if let Some(path) = args.get(0) {
+            self.path = Some(path.clone());
         }
+        let path = try!(self.path.clone().ok_or("No file name".to_string()));
+        if !line.bang && args.is_empty() {
+            if let Ok(Some(_)) = self.buf_op.check_disk_file(Path::new(&path)) {
+                return Err("File changed since reading it (add ! to override)".to_string());
+            }
+        }
+        try!(self.save(&path).map_err(|e| format!("Failed to write {}: {}", path, e)));
+        self.notify(format!("\"{}\"{} {}L written", path, self.buf_op.format.notes(), self.buffer().line_count()));
+        Ok(())
     }
 
-    // Visual line mode
-    fn visual_line_handle_event(&mut self, e: Event) {
-        match e {
-            Event::Esc => {
-                self.mode = Mode::Normal;
-                self.dirty = true;
-            },
-            Event::Backspace => { },
-            Event::Char(_) => {
-            },
-            _ => { },
+    fn ex_write_quit(&mut self, line: &CommandLine) -> Result<(), String> {
+        try!(self.ex_write(line));
+        self.ex_quit(line)
+    }
+
+    /// :q[!], closes the window, or quits along with the last one
+    fn ex_quit(&mut self, line: &CommandLine) -> Result<(), String> {
+        if self.windows.len() > 1 {
+            return self.close_window();
+        }
+        if !line.bang {
+            if self.is_modified() {
+                return Err("No write since last change (add ! to override)".to_string());
+            }
+            if let Some(buffer) = self.hidden_buffers.iter().find(|b| b.buf_op.modified) {
+                return Err(format!("No write since last change for buffer {} (add ! to override)", buffer.number));
+            }
         }
+        self.running = false;
+        Ok(())
     }
 
-    // Visual block mode
-    fn visual_block_handle_event(&mut self, e: Event) {
-        match e {
-            Event::Esc => {
-                self.mode = Mode::Normal;
-                self.dirty = true;
-            },
-            Event::Backspace => { },
-            Event::Char(_) => {
-            },
-            _ => { },
+    /// :e[!] [file], opens a file in a buffer of its own, or reloads the current file. The buffer
+    /// being left keeps its changes.
+    fn ex_edit(&mut self, line: &CommandLine) -> Result<(), String> {
+        let args = try!(line.args());
+        if let Some(path) = args.get(0) {
+            if !self.is_current_file(path) {
+                return self.open(path.clone()).map_err(|e| format!("Failed to open {}: {}", path, e));
+            }
+        }
+
+        if !line.bang && self.is_modified() {
+            return Err("No write since last change (add ! to override)".to_string());
         }
+        let path = try!(self.path.clone().ok_or("No file name".to_string()));
+        self.edit(path.clone()).map_err(|e| format!("Failed to open {}: {}", path, e))
     }
 
-    pub fn save<P: AsRef<Path>>(&self, path: &P) -> io::Result<()> {
-        // Open the path in read-only mode, returns `io::Result<File>`
-        let mut file = try!(File::create(path));
+    /// :sp [file] and :vs [file], splits the window, showing a file in the new window if given
+    fn ex_split(&mut self, line: &CommandLine) -> Result<(), String> {
+        let args = try!(line.args());
+        let vertical = line.command.map_or(false, |c| c.name == "vsplit");
+        try!(self.split_window(vertical));
+        if let Some(path) = args.get(0) {
+            if let Err(e) = self.open(path.clone()) {
+                try!(self.close_window());
+                return Err(format!("Failed to open {}: {}", path, e));
+            }
+        }
+        Ok(())
+    }
 
-        file.write_all(&self.buf_op.buffer().buffer().as_bytes())
+    fn ex_close(&mut self, _: &CommandLine) -> Result<(), String> {
+        self.close_window()
     }
 
-    pub fn log(&mut self, operation: Operation) {
-        self.log.truncate(self.log_index+1);
-        self.log.push(operation);
-        self.log_index = self.log.len()-1;
+    fn ex_only(&mut self, _: &CommandLine) -> Result<(), String> {
+        self.only_window();
+        Ok(())
     }
 
-    pub fn log_entry_mut(&mut self, index: usize) -> &mut Operation {
-        &mut self.log[index]
+    /// :tabnew [file] and :tabe [file], opens a tab page with a file or an empty buffer
+    fn ex_tab_new(&mut self, line: &CommandLine) -> Result<(), String> {
+        let args = try!(line.args());
+        if self.single_buffer && args.is_empty() {
+            return Err("Only one buffer can be open while connected to a server".to_string());
+        }
+        self.new_tab();
+        let result =
+            match args.get(0) {
+                Some(path) => self.open(path.clone()).map_err(|e| format!("Failed to open {}: {}", path, e)),
+                None => { self.new_buffer(); Ok(()) },
+            };
+        if result.is_err() {
+            try!(self.close_window());
+        }
+        result
     }
 
-    pub fn mode(&self) -> Mode {
-        self.mode
+    fn ex_tab_next(&mut self, _: &CommandLine) -> Result<(), String> {
+        self.cycle_tab(true);
+        Ok(())
     }
 
-    pub fn buffer(&self) -> &Buffer {
-        self.buf_op.buffer()
+    fn ex_tab_previous(&mut self, _: &CommandLine) -> Result<(), String> {
+        self.cycle_tab(false);
+        Ok(())
     }
 
-    pub fn command_buffer(&self) -> &Buffer {
-        self.cmd_buffer.buffer()
+    fn ex_tab_close(&mut self, _: &CommandLine) -> Result<(), String> {
+        if self.tabs.len() == 1 {
+            return Err("Cannot close last tab page".to_string());
+        }
+        let ids = self.tabs[self.tab].layout.windows();
+        self.windows.retain(|w| !ids.contains(&w.id));
+        self.tabs.remove(self.tab);
+        if self.tab == self.tabs.len() {
+            self.tab -= 1;
+        }
+        let id = self.tabs[self.tab].window;
+        self.enter_window(id);
+        Ok(())
     }
 
-    pub fn running(&self) -> bool {
-        self.running
+    fn ex_buffer_next(&mut self, _: &CommandLine) -> Result<(), String> {
+        self.cycle_buffer(true);
+        Ok(())
     }
 
-    pub fn is_dirty(&self) -> bool {
-        self.dirty || self.buf_op.dirty
+    fn ex_buffer_previous(&mut self, _: &CommandLine) -> Result<(), String> {
+        self.cycle_buffer(false);
+        Ok(())
     }
 
-    pub fn clean(&mut self) {
-        self.dirty = false;
-        self.buf_op.dirty = false;
+    /// :b {number|name}, shows a buffer. Any unique part of a buffer's file name picks it.
+    fn ex_buffer(&mut self, line: &CommandLine) -> Result<(), String> {
+        let name = line.arg_text.trim();
+        if name.is_empty() {
+            return Ok(());
+        }
+
+        let number = try!(self.find_buffer(name));
+        if number != self.buffer_number {
+            let index = self.hidden_buffers.iter().position(|b| b.number == number).unwrap();
+            self.show_buffer(index);
+        }
+        Ok(())
     }
 
-    ////////////////////////////////////////////////////////////////////////////////////////////////
-    // Operation stuff
+    /// :ls and :buffers
+    fn ex_buffers(&mut self, _: &CommandLine) -> Result<(), String> {
+        let mut buffers: Vec<(usize, bool, bool, String, u64)> =
+            self.hidden_buffers.iter().map(|b| {
+                (b.number, false, b.buf_op.modified, buffer_name(&b.path), b.cursor.line)
+            }).collect();
+        buffers.push((self.buffer_number, true, self.is_modified(), buffer_name(&self.path), self.cursor.line));
+        buffers.sort_by_key(|b| b.0);
 
-    pub fn do_operation(&mut self, operation: &Operation) {
-        self.buf_op.do_operation(operation);
-        self.cursor.op_adjust_cursor(self.buf_op.buffer(), operation);
+        self.messages = buffers.into_iter().map(|(number, current, modified, name, line)| {
+            Message {
+                text: format!("{:>3} {}{} \"{}\" line {}", number, if current { '%' } else { 'h' },
+                              if modified { '+' } else { ' ' }, name, line + 1),
+                kind: MessageKind::Info,
+            }
+        }).collect();
+        self.dirty = true;
+        Ok(())
     }
 
+    /// :bd[!] [number|name], closes a buffer. Closing the shown buffer shows the next one.
+    fn ex_buffer_delete(&mut self, line: &CommandLine) -> Result<(), String> {
+        let name = line.arg_text.trim();
+        let number = if name.is_empty() { self.buffer_number } else { try!(self.find_buffer(name)) };
 
-    ////////////////////////////////////////////////////////////////////////////////////////////////
-    // Cursor movement
+        if number == self.buffer_number {
+            if !line.bang && self.is_modified() {
+                return Err(format!("No write since last change for buffer {} (add ! to override)", number));
+            }
+            if self.single_buffer {
+                return Err("Only one buffer can be open while connected to a server".to_string());
+            }
 
-    fn cursor_up(&mut self) {
-        self.cursor.move_up(self.buf_op.buffer());
-        if self.scroll > self.cursor.line {
-            self.scroll = self.cursor.line;
+            if self.hidden_buffers.is_empty() {
+                self.load(None, BufferOperator::new());
+                self.buffer_number = self.next_buffer_number;
+                self.next_buffer_number += 1;
+            } else {
+                let index = self.hidden_buffers.iter().position(|b| b.number > number).unwrap_or(0);
+                let buffer = self.hidden_buffers.remove(index);
+                self.restore_buffer(buffer);
+                self.announce_buffer();
+            }
+        } else {
+            let index = self.hidden_buffers.iter().position(|b| b.number == number).unwrap();
+            if !line.bang && self.hidden_buffers[index].buf_op.modified {
+                return Err(format!("No write since last change for buffer {} (add ! to override)", number));
+            }
+            self.hidden_buffers.remove(index);
         }
-        self.dirty = true;
-    }
 
-    fn cursor_down(&mut self) {
-        self.cursor.move_down(self.buf_op.buffer());
-        if self.scroll+self.height <= self.cursor.line {
-            self.scroll = self.cursor.line - (self.height-1);
+        // Other windows showing the buffer show the current one instead
+        let current = self.buffer_number;
+        for window in self.windows.iter_mut().filter(|w| w.buffer == number) {
+            window.buffer = current;
+            window.cursor = Cursor { line: 0, column: 0, buf_index: 0 };
+            window.scroll = 0;
+            window.left = 0;
         }
         self.dirty = true;
+        Ok(())
     }
 
-    fn cursor_left(&mut self) {
-        self.cursor.move_left(self.buf_op.buffer());
-        if self.scroll > self.cursor.line {
-            self.scroll = self.cursor.line;
+    /// :[line]r file, puts a file's lines below a line
+    fn ex_read(&mut self, line: &CommandLine) -> Result<(), String> {
+        let args = try!(line.args());
+        let path = try!(args.get(0).ok_or("No file name".to_string()));
+        let mut bytes = Vec::new();
+        try!(File::open(path).and_then(|mut f| f.read_to_end(&mut bytes))
+                             .map_err(|e| format!("Failed to read {}: {}", path, e)));
+        let (mut text, _) = FileFormat::decode(&bytes);
+        if text.ends_with('\n') {
+            text.pop();
         }
-        self.dirty = true;
+
+        let after = line.range.map_or(self.cursor.line as usize + 1, |(_, last)| last);
+        self.join_undo_step = false;
+        let index = self.insert_lines_after(after, text);
+        self.move_cursor_to(index);
+        Ok(())
     }
 
-    fn cursor_right(&mut self) {
-        self.cursor.move_right(self.buf_op.buffer());
-        if self.scroll+self.height <= self.cursor.line {
-            self.scroll = self.cursor.line - (self.height-1);
+    /// :[range]d [register] [count]
+    fn ex_delete(&mut self, line: &CommandLine) -> Result<(), String> {
+        let (first, last) = line.lines_or(self.cursor.line as usize);
+        let register = line.arg_text.chars().next();
+        if let Some(register) = register {
+            if !Registers::is_valid(register) || line.arg_text.len() > 1 {
+                return Err(format!("Invalid register: {}", line.arg_text));
+            }
         }
-        self.dirty = true;
+
+        self.join_undo_step = false;
+        self.pending_register = register;
+        self.do_line_operator(Operator::Delete, first, last);
+        Ok(())
     }
-}
 
+    /// :[range]m {address}, moves lines below the line at address
+    fn ex_move(&mut self, line: &CommandLine) -> Result<(), String> {
+        let (first, last) = line.lines_or(self.cursor.line as usize);
+        let dest = try!(self.parse_line(&line.arg_text));
+        if dest > first && dest < last + 1 {
+            return Err("Can't move lines into themselves".to_string());
+        }
 
-#[test]
-fn ted_log_empty() {
-    let mut ted = Ted::new(0);
-    ted.log(Operation::Insert(0, "asdf".to_string()));
+        let count = last - first + 1;
+        if dest != first && dest != last + 1 {
+            let text = self.lines_text(first, last);
+            let (from, to) = self.line_range(first, last);
+            self.join_undo_step = false;
+            let op = self.buf_op.remove(from as u64, (to - 1) as u64);
+            self.log_joined(op);
+            // Lines below the moved lines moved up
+            let dest = if dest > last { dest - count } else { dest };
+            self.insert_lines_after(dest, text);
+            let index = self.buffer().line_info(dest + count - 1).buf_index;
+            self.move_cursor_to(index);
+        }
+        Ok(())
+    }
 
-    assert!(ted.log == vec![Operation::Insert(0, "asdf".to_string())]);
-    assert!(ted.log_index == 0);
-}
+    /// :[range]t {address}, copies lines below the line at address
+    fn ex_copy(&mut self, line: &CommandLine) -> Result<(), String> {
+        let (first, last) = line.lines_or(self.cursor.line as usize);
+        let dest = try!(self.parse_line(&line.arg_text));
+        let text = self.lines_text(first, last);
+        self.join_undo_step = false;
+        self.insert_lines_after(dest, text);
+        let index = self.buffer().line_info(dest + last - first).buf_index;
+        self.move_cursor_to(index);
+        Ok(())
+    }
 
-#[test]
-fn ted_log_beginning() {
-    let mut ted = Ted::new(0);
-    ted.log(Operation::Insert(0, "asdf".to_string()));
-    ted.log_index = 0;
-    ted.log(Operation::Insert(0, "hi".to_string()));
+    /// :[range]norm[!] {keys}, types keys in normal mode on each line. ! ignores mappings.
+    fn ex_normal(&mut self, line: &CommandLine) -> Result<(), String> {
+        if line.arg_text.is_empty() {
+            return Err("Usage: normal <keys>".to_string());
+        }
+        let (first, last) = line.lines_or(self.cursor.line as usize);
+        let keymaps = if line.bang { Some(mem::replace(&mut self.keymaps, Keymaps::new())) } else { None };
 
-    assert!(ted.log == vec![Operation::Insert(0, "asdf".to_string()),
-                            Operation::Insert(0, "hi".to_string())]);
-    assert!(ted.log_index == 1);
-}
+        let mut current = first;
+        for _ in first..last+1 {
+            if current >= self.buffer().line_count() {
+                break;
+            }
+            let line_count = self.buffer().line_count();
+            let index = self.buffer().line_info(current).buf_index;
+            self.mode = Mode::Normal;
+            self.move_cursor_to(index);
 
-#[test]
-fn ted_log_middle() {
-    let mut ted = Ted::new(0);
-    ted.log(Operation::Insert(0, "asdf".to_string()));
-    ted.log(Operation::Insert(0, "asdf".to_string()));
-    ted.log(Operation::Insert(0, "asdf".to_string()));
-    ted.log_index = 1;
-    ted.log(Operation::Insert(0, "hi".to_string()));
+            for c in line.arg_text.chars() {
+                self.handle_event(Event::Char(c));
+            }
+            // Unfinished commands are dropped, like vim
+            self.handle_pending_keys(true);
+            self.pending_keys.clear();
+            match self.mode {
+                Mode::Insert => { self.insert_handle_event(Event::Esc); },
+                Mode::Command | Mode::Search { forward: _ } => { self.cancel_command(); },
+                Mode::Confirm => { self.confirm_handle_event(Event::Esc); },
+                Mode::FileChanged => { self.file_changed_handle_event(Event::Esc); },
+                _ => { self.mode = Mode::Normal; },
+            }
 
-    assert!(ted.log == vec![Operation::Insert(0, "asdf".to_string()),
-                            Operation::Insert(0, "asdf".to_string()),
-                            Operation::Insert(0, "hi".to_string())]);
-    assert!(ted.log_index == 2);
-}
+            // Keep going from the line after this one, wherever the keys left it
+            current = (current + 1 + self.buffer().line_count()).saturating_sub(line_count);
+        }
+
+        if let Some(keymaps) = keymaps {
+            self.keymaps = keymaps;
+        }
+        Ok(())
+    }
+
+    fn ex_substitute(&mut self, line: &CommandLine) -> Result<(), String> {
+        let range = line.lines_or(self.cursor.line as usize);
+        self.substitute(range, &line.arg_text)
+    }
+
+    /// :earlier and :later
+    fn ex_time_travel(&mut self, line: &CommandLine) -> Result<(), String> {
+        let amount = try!(TimeTravel::parse(&line.arg_text).ok_or(format!("Invalid time: {}", line.arg_text)));
+        self.time_travel(amount, line.command.map_or(false, |c| c.name == "later"));
+        Ok(())
+    }
+
+    /// :undo, or :undo N to go to undo tree node N
+    fn ex_undo(&mut self, line: &CommandLine) -> Result<(), String> {
+        if line.arg_text.is_empty() {
+            self.undo();
+            return Ok(());
+        }
+        match line.arg_text.parse::<usize>() {
+            Ok(node) if node < self.undo_tree.len() => {
+                let ops = self.undo_tree.jump(node);
+                self.do_history_operations(ops);
+                Ok(())
+            },
+            _ => Err(format!("Undo number {} not found", line.arg_text)),
+        }
+    }
+
+    fn ex_undo_list(&mut self, _: &CommandLine) -> Result<(), String> {
+        let now = time::get_time().sec;
+        let branches: Vec<String> =
+            self.undo_tree.branches().iter().map(|&node| {
+                format!("{}: {} changes, {}s ago", node, self.undo_tree.depth(node),
+                        now - self.undo_tree.node(node).time)
+            }).collect();
+        if branches.is_empty() {
+            self.notify("Nothing to undo".to_string());
+        } else {
+            self.notify(branches.join(" | "));
+        }
+        Ok(())
+    }
+
+    fn ex_registers(&mut self, _: &CommandLine) -> Result<(), String> {
+        let registers: Vec<String> =
+            self.registers.list().iter().map(|&(name, register)| {
+                format!("\"{} {}", name, register.text.replace('\n', "^J"))
+            }).collect();
+        if registers.is_empty() {
+            self.notify("No registers".to_string());
+        } else {
+            self.notify(registers.join(" | "));
+        }
+        Ok(())
+    }
+
+    /// Shows the recent messages again, as many as fit on the screen
+    fn ex_messages(&mut self, _: &CommandLine) -> Result<(), String> {
+        if self.message_log.is_empty() {
+            return Err("No messages".to_string());
+        }
+        let start = self.message_log.len().saturating_sub(self.height as usize);
+        self.messages = self.message_log[start..].iter().cloned().collect();
+        self.dirty = true;
+        Ok(())
+    }
+
+    fn ex_no_highlight(&mut self, _: &CommandLine) -> Result<(), String> {
+        self.highlight_search = false;
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// :hex, switches between showing the buffer as text and as a hex dump of its bytes. The undo
+    /// history is for the text as it was shown, so it starts over.
+    fn ex_hex(&mut self, _: &CommandLine) -> Result<(), String> {
+        try!(self.buf_op.toggle_hex().map_err(|e| format!("Can't switch hex view: {}", e)));
+        self.undo_tree = UndoTree::new();
+        self.join_undo_step = false;
+        self.visual_lines = None;
+        self.scroll = 0;
+        self.left = 0;
+        self.move_cursor_to(0);
+        Ok(())
+    }
+
+    /// :set option=value, :set option or option? to show it, and :set bomb/nobomb. The options are
+    /// the buffer's fileformat (ff) and fileencoding (fenc), which change how it's written, and
+    /// its filetype (ft), the grammar it's highlighted with. ft= turns highlighting off.
+    fn ex_set(&mut self, line: &CommandLine) -> Result<(), String> {
+        let args = try!(line.args());
+        if args.is_empty() {
+            return Err("Usage: set <option>[=<value>]".to_string());
+        }
+
+        let mut shown = Vec::new();
+        for arg in &args {
+            let (name, value) =
+                match arg.find('=') {
+                    Some(i) => (&arg[..i], Some(&arg[i+1..])),
+                    None => (arg.trim_right_matches('?'), None),
+                };
+            let mut format = self.buf_op.format;
+            match (name, value) {
+                ("fileformat", None) | ("ff", None) => {
+                    shown.push(format!("fileformat={}", format.line_ending.name()));
+                },
+                ("fileencoding", None) | ("fenc", None) => {
+                    shown.push(format!("fileencoding={}", format.encoding.name()));
+                },
+                ("bomb", None) if arg.ends_with('?') => {
+                    shown.push(if format.bom { "bomb" } else { "nobomb" }.to_string());
+                },
+                ("filetype", None) | ("ft", None) => {
+                    shown.push(format!("filetype={}", self.buf_op.grammar_name().unwrap_or("")));
+                },
+                ("filetype", Some(value)) | ("ft", Some(value)) => {
+                    let name = if value.is_empty() { None } else { Some(value) };
+                    if !self.buf_op.set_grammar(name) {
+                        return Err(format!("Invalid filetype: {}", value));
+                    }
+                    self.dirty = true;
+                },
+                ("fileformat", Some(value)) | ("ff", Some(value)) => {
+                    format.line_ending = try!(LineEnding::from_name(value)
+                                                  .ok_or(format!("Invalid fileformat: {}", value)));
+                },
+                ("fileencoding", Some(value)) | ("fenc", Some(value)) => {
+                    format.encoding = try!(Encoding::from_name(value)
+                                               .ok_or(format!("Invalid fileencoding: {}", value)));
+                },
+                ("bomb", None) => { format.bom = true; },
+                ("nobomb", None) => { format.bom = false; },
+                _ => { return Err(format!("Unknown option: {}", arg)); },
+            }
+
+            // The file needs writing to take the new format
+            if format != self.buf_op.format && self.buf_op.is_read_only() {
+                return Err("The buffer is read-only".to_string());
+            }
+            if format != self.buf_op.format {
+                self.buf_op.format = format;
+                self.buf_op.modified = true;
+                self.dirty = true;
+            }
+        }
+        if !shown.is_empty() {
+            self.notify(shown.join(" "));
+        }
+        Ok(())
+    }
+
+    fn ex_source(&mut self, line: &CommandLine) -> Result<(), String> {
+        let args = try!(line.args());
+        let path =
+            match args.get(0) {
+                Some(path) => PathBuf::from(path),
+                None => try!(config::default_path().ok_or("No config file to source".to_string())),
+            };
+        self.source(&path)
+    }
+
+    /// :map, :nmap, :omap, :imap and :vmap. Quotes aren't special in keys.
+    fn ex_map(&mut self, line: &CommandLine) -> Result<(), String> {
+        let args: Vec<String> = line.arg_text.split_whitespace().map(|a| a.to_string()).collect();
+        let command = line.command.map_or("map", |c| c.name);
+        self.map_keys(command, &args)
+    }
+
+    /// Parses the address argument of :m and :t
+    fn parse_line(&self, text: &str) -> Result<usize, String> {
+        let marks = self.marks();
+        let context = ex::Context {
+            current: self.cursor.line as usize,
+            last: self.buffer().line_count() - 1,
+            marks: &marks,
+        };
+        ex::parse_line(text, &context)
+    }
+
+    /// Text of lines [first, last], without the last line break
+    fn lines_text(&self, first: usize, last: usize) -> String {
+        let last_info = self.buffer().line_info(last);
+        let from = self.buffer().line_info(first).buf_index;
+        self.buffer().slice(from, last_info.buf_index + last_info.length).into_owned()
+    }
+
+    /// Inserts text as new lines below a line, counting lines from 1 so that 0 is above the first
+    /// line. Returns where the new lines start.
+    fn insert_lines_after(&mut self, line: usize, text: String) -> usize {
+        if line == 0 {
+            let op = self.buf_op.insert(0, text + "\n");
+            self.log_joined(op);
+            0
+        } else {
+            let line_info = self.buffer().line_info(line - 1);
+            let index = line_info.buf_index + line_info.length;
+            let op = self.buf_op.insert(index as u64, format!("\n{}", text));
+            self.log_joined(op);
+            index + 1
+        }
+    }
+
+    /// Loads the config file from its default place, if there is one
+    pub fn load_config(&mut self) {
+        if let Some(path) = config::default_path() {
+            if path.exists() {
+                if let Err(e) = self.source(&path) {
+                    self.notify_error(e);
+                }
+            }
+        }
+    }
+
+    /// Applies the settings and key mappings in a config file
+    pub fn source(&mut self, path: &Path) -> Result<(), String> {
+        let config = try!(Config::from_file(path));
+        for mapping in &config.mappings {
+            let args = [mapping.keys.clone(), mapping.mapped.clone()];
+            try!(self.map_keys(mapping.command, &args));
+        }
+        self.config = config;
+        // The text may have a different width to keep the cursor in
+        self.arrange_windows();
+        Ok(())
+    }
+
+    /// Binds keys to other keys for :map, :nmap, :omap, :vmap and :imap
+    fn map_keys(&mut self, cmd: &str, args: &[String]) -> Result<(), String> {
+        let args: Vec<&String> = args.iter().filter(|a| !a.is_empty()).collect();
+        if args.len() != 2 {
+            return Err(format!("Usage: {} <keys> <mapped keys>", cmd));
+        }
+        let keys = try!(keymap::parse_keys(args[0]));
+        let mapped = try!(keymap::parse_keys(args[1]));
+
+        // :map works in normal mode, visual mode and after operators, like vim's
+        if cmd == "map" || cmd == "vmap" {
+            self.keymaps.visual.bind(&keys, Action::Keys(mapped.clone()));
+        }
+        if cmd == "map" || cmd == "nmap" {
+            self.keymaps.normal.bind(&keys, Action::Keys(mapped.clone()));
+        }
+        if cmd == "map" || cmd == "omap" {
+            self.keymaps.operator_pending.bind(&keys, Action::Keys(mapped.clone()));
+        }
+        if cmd == "imap" {
+            self.keymaps.insert.bind(&keys, Action::Keys(mapped));
+        }
+        Ok(())
+    }
+
+    // Normal mode keys
+    fn normal_handle_keys(&mut self, timed_out: bool) -> KeyStep {
+        let parse = normal_command::parse(&self.pending_keys, &self.keymaps.normal,
+                                          &self.keymaps.operator_pending, timed_out);
+        self.handle_parse(parse)
+    }
+
+    // Visual mode keys
+    fn visual_handle_keys(&mut self, timed_out: bool) -> KeyStep {
+        self.visual_lines =
+            match self.selection() {
+                Some(Selection::Chars(from, to)) => {
+                    Some((self.buffer().line_at_index(from), self.buffer().line_at_index(cmp::max(from, to - 1))))
+                },
+                Some(Selection::Lines(first, last)) | Some(Selection::Block { first, last, .. }) => Some((first, last)),
+                None => self.visual_lines,
+            };
+        let parse = normal_command::parse_visual(&self.pending_keys, &self.keymaps.visual, timed_out);
+        self.handle_parse(parse)
+    }
+
+    fn handle_parse(&mut self, parse: Parse) -> KeyStep {
+        match parse {
+            Parse::Incomplete => KeyStep::Wait,
+            Parse::Invalid => {
+                self.pending_keys.clear();
+                self.pending_register = None;
+                KeyStep::Done
+            },
+            Parse::Register(from, to, register) => {
+                self.pending_keys.drain(from..to);
+                if Registers::is_valid(register) {
+                    self.pending_register = Some(register);
+                } else {
+                    self.pending_keys.clear();
+                    self.pending_register = None;
+                }
+                KeyStep::Done
+            },
+            Parse::Complete(cmd, len) => {
+                self.pending_keys.drain(..len);
+                self.do_normal_command(cmd);
+                self.pending_register = None;
+                KeyStep::Done
+            },
+            Parse::Remap(from, to, keys) => {
+                let rest = self.pending_keys.split_off(to);
+                self.pending_keys.truncate(from);
+                self.pending_keys.extend(keys);
+                self.pending_keys.extend(rest);
+                KeyStep::Remapped
+            },
+        }
+    }
+
+    fn do_normal_command(&mut self, cmd: NormalCommand) {
+        self.join_undo_step = false;
+
+        if self.buf_op.is_read_only() && changes_text(&cmd) {
+            self.notify_error("The buffer is read-only".to_string());
+            return;
+        }
+        if self.buf_op.is_hex() && changes_text(&cmd) && !keeps_layout(&cmd) {
+            self.notify_error(HEX_ONLY_TYPE_OVER.to_string());
+            return;
+        }
+
+        match cmd {
+            NormalCommand::Move(motion, count) => {
+                self.do_motion(motion, count);
+            },
+            NormalCommand::Operate(operator, motion, count) => {
+                let index = self.cursor.buf_index as usize;
+
+                // cw works like ce unless the cursor is on whitespace
+                let motion =
+                    if operator == Operator::Change && motion == Motion::WordStart &&
+                       index < self.buffer().len() && !(self.buffer().byte(index) as char).is_whitespace() {
+                        Motion::WordEnd
+                    } else {
+                        motion
+                    };
+
+                if let Some(target) = motion.target(self.buffer(), index, count) {
+                    let (from, to) = (cmp::min(index, target), cmp::max(index, target));
+                    match motion.kind() {
+                        MotionKind::Exclusive => {
+                            // dw on the last word of a line doesn't join the next line
+                            let line_info = self.buffer().line_info(self.buffer().line_at_index(from));
+                            let line_end = line_info.buf_index + line_info.length;
+                            let to =
+                                if motion == Motion::WordStart && to > line_end && from < line_end {
+                                    line_end
+                                } else {
+                                    to
+                                };
+                            self.do_operator(operator, from, to, None);
+                        },
+                        MotionKind::Inclusive => {
+                            let to =
+                                if to < self.buffer().len() && self.buffer().byte(to) != b'\n' {
+                                    self.buffer().next_grapheme(to)
+                                } else {
+                                    to
+                                };
+                            self.do_operator(operator, from, to, None);
+                        },
+                        MotionKind::Linewise => {
+                            let first = self.buffer().line_at_index(from);
+                            let last = self.buffer().line_at_index(to);
+                            self.do_line_operator(operator, first, last);
+                        },
+                    }
+                }
+            },
+            NormalCommand::OperateObject(operator, object, _) => {
+                let index = self.cursor.buf_index as usize;
+                if let Some((from, to)) = object.range(self.buffer(), index) {
+                    self.do_operator(operator, from, to, None);
+                }
+            },
+            NormalCommand::OperateLines(operator, count) => {
+                let first = self.cursor.line as usize;
+                let last = cmp::min(first + count - 1, self.buffer().line_count() - 1);
+                self.do_line_operator(operator, first, last);
+            },
+            NormalCommand::Action(action, count) => {
+                self.do_action(action, count);
+            },
+        }
+
+        // A change keeps its undo step open for the text typed after it
+        if self.mode != Mode::Insert {
+            self.join_undo_step = false;
+        }
+        self.scroll_to_cursor();
+        self.page_to_cursor();
+    }
+
+    /// A paged file reads the part around the cursor when the cursor gets to either end of the
+    /// part that's read, keeping the cursor on the same row of the window
+    fn page_to_cursor(&mut self) {
+        let (start, end, len) =
+            match self.buf_op.view {
+                View::Paged(ref pager) => (pager.start, pager.end, pager.len),
+                _ => { return; },
+            };
+        let line = self.cursor.line as usize;
+        if !(line == 0 && start > 0) && !(line + 1 >= self.buffer().line_count() && end < len) {
+            return;
+        }
+
+        let pos = start + self.cursor.buf_index;
+        let row = self.cursor.line - self.scroll;
+        match self.buf_op.read_page(pos) {
+            Ok(start) => {
+                let index = cmp::min(pos.saturating_sub(start) as usize, self.buffer().len());
+                self.cursor.buf_index = self.buffer().grapheme_start(index) as u64;
+                self.cursor.calculate_pos(self.buf_op.buffer());
+                self.scroll = self.cursor.line.saturating_sub(row);
+                self.scroll_to_cursor();
+            },
+            Err(e) => { self.notify_error(format!("Failed to read the file: {}", e)); },
+        }
+    }
+
+    /// Goes to a line of the whole paged file, or its last line for None, reading the page it's in
+    fn page_to_line(&mut self, line: Option<usize>) {
+        let pos =
+            match self.buf_op.view {
+                View::Paged(ref mut pager) => {
+                    let pos = match line {
+                        Some(line) => pager.line_start(line),
+                        None => Ok(pager.len),
+                    };
+                    // The end of the file is in the last line, unless that's empty
+                    pos.map(|pos| cmp::min(pos, pager.len.saturating_sub(1)))
+                },
+                _ => { return; },
+            };
+        match pos.and_then(|pos| self.buf_op.read_page(pos).map(|start| pos - start)) {
+            Ok(index) => {
+                let line = self.buffer().line_at_index(cmp::min(index as usize, self.buffer().len()));
+                let index = self.buffer().line_info(line).buf_index;
+                self.move_cursor_to(index);
+            },
+            Err(e) => { self.notify_error(format!("Failed to read the file: {}", e)); },
+        }
+    }
+
+    fn do_action(&mut self, action: Action, count: Option<usize>) {
+        match action {
+            Action::InsertMode => {
+                self.mode = Mode::Insert;
+                self.dirty = true;
+            },
+            Action::CommandMode => {
+                // : in visual mode starts a command on the selected lines
+                if self.selection().is_some() {
+                    self.cmd_buffer.buffer_mut().insert(0, "'<,'>");
+                }
+                self.mode = Mode::Command;
+                self.history_index = None;
+                self.dirty = true;
+            },
+            Action::VisualCharMode | Action::VisualLineMode | Action::VisualBlockMode => {
+                // Switching to the visual mode that's already on turns it off. Switching between
+                // visual modes keeps the selection's start.
+                let start =
+                    match self.mode {
+                        Mode::VisualChar { start } | Mode::VisualLine { start } |
+                        Mode::VisualBlock { start } => start,
+                        _ => self.cursor.buf_index,
+                    };
+                let mode =
+                    match action {
+                        Action::VisualCharMode => Mode::VisualChar { start: start },
+                        Action::VisualLineMode => Mode::VisualLine { start: start },
+                        _ => Mode::VisualBlock { start: start },
+                    };
+                self.mode = if mode == self.mode { Mode::Normal } else { mode };
+                self.dirty = true;
+            },
+            Action::SearchForward | Action::SearchBackward => {
+                self.mode = Mode::Search { forward: action == Action::SearchForward };
+                self.search_origin = self.cursor.buf_index as usize;
+                self.history_index = None;
+                self.dirty = true;
+            },
+            Action::SearchNext => { self.search_next(count.unwrap_or(1), true); },
+            Action::SearchPrevious => { self.search_next(count.unwrap_or(1), false); },
+            Action::NormalMode => {
+                self.mode = Mode::Normal;
+                self.dirty = true;
+            },
+            Action::SwapSelectionEnds => {
+                let cursor = self.cursor.buf_index;
+                let start =
+                    match self.mode {
+                        Mode::VisualChar { ref mut start } | Mode::VisualLine { ref mut start } |
+                        Mode::VisualBlock { ref mut start } => {
+                            let old_start = *start;
+                            *start = cursor;
+                            old_start
+                        },
+                        _ => { return; },
+                    };
+                self.move_cursor_to(start as usize);
+            },
+            Action::Operator(operator) => {
+                if let Some(selection) = self.selection() {
+                    self.mode = Mode::Normal;
+                    self.do_selection_operator(operator, selection);
+                }
+            },
+            Action::Undo => { for _ in 0..count.unwrap_or(1) { self.undo(); } },
+            Action::Redo => { for _ in 0..count.unwrap_or(1) { self.redo(); } },
+            Action::PutAfter => { self.put(count.unwrap_or(1), false); },
+            Action::PutBefore => { self.put(count.unwrap_or(1), true); },
+            Action::FocusWindow(direction) => {
+                let rects: Vec<(usize, Rect)> = self.windows().iter().map(|w| (w.id, w.rect)).collect();
+                if let Some(id) = window::neighbor(&rects, self.window, direction) {
+                    self.focus_window(id);
+                }
+            },
+            Action::NextWindow => {
+                let ids = self.tabs[self.tab].layout.windows();
+                let i = ids.iter().position(|&id| id == self.window).unwrap();
+                self.focus_window(ids[(i + 1) % ids.len()]);
+            },
+            Action::SplitWindow { vertical } => {
+                if let Err(e) = self.split_window(vertical) {
+                    self.notify_error(e);
+                }
+            },
+            Action::CloseWindow => {
+                if let Err(e) = self.close_window() {
+                    self.notify_error(e);
+                }
+            },
+            Action::OnlyWindow => { self.only_window(); },
+            Action::NextTab => { self.cycle_tab(true); },
+            Action::PreviousTab => { self.cycle_tab(false); },
+            _ => { },
+        }
+    }
+
+    fn do_motion(&mut self, motion: Motion, count: Option<usize>) {
+        let repeat = count.unwrap_or(1);
+        match motion {
+            Motion::Left => { for _ in 0..repeat { self.cursor_left(); } },
+            Motion::Right => { for _ in 0..repeat { self.cursor_right(); } },
+            Motion::Up => { for _ in 0..repeat { self.cursor_up(); } },
+            Motion::Down => { for _ in 0..repeat { self.cursor_down(); } },
+            Motion::DisplayUp => { for _ in 0..repeat { self.cursor_display_up(); } },
+            Motion::DisplayDown => { for _ in 0..repeat { self.cursor_display_down(); } },
+            Motion::FirstLine | Motion::LastLine if self.buf_op.is_paged() => {
+                let line = if motion == Motion::LastLine { count } else { Some(count.unwrap_or(1)) };
+                self.page_to_line(line.map(|l| l.saturating_sub(1)));
+            },
+            _ => {
+                // G and gg go somewhere else when given a count, so don't make one up
+                let index = self.cursor.buf_index as usize;
+                if let Some(target) = motion.target(self.buf_op.buffer(), index, count) {
+                    self.move_cursor_to(target);
+                }
+            },
+        }
+    }
+
+    /// The visual mode selection, between the start of the selection and the cursor
+    pub fn selection(&self) -> Option<Selection> {
+        let cursor = self.cursor.buf_index as usize;
+        match self.mode {
+            Mode::VisualChar { start } => {
+                let (from, to) = (cmp::min(start as usize, cursor), cmp::max(start as usize, cursor));
+                let to = if to < self.buffer().len() { self.buffer().next_grapheme(to) } else { to };
+                Some(Selection::Chars(from, to))
+            },
+            Mode::VisualLine { start } => {
+                let start_line = self.buffer().line_at_index(start as usize);
+                let cursor_line = self.cursor.line as usize;
+                Some(Selection::Lines(cmp::min(start_line, cursor_line), cmp::max(start_line, cursor_line)))
+            },
+            Mode::VisualBlock { start } => {
+                // The block covers all of the characters at both corners, even wide ones
+                let start_line = self.buffer().line_at_index(start as usize);
+                let (start_left, start_right) = self.columns_at(start as usize);
+                let (cursor_left, cursor_right) = self.columns_at(cursor);
+                let cursor_line = self.cursor.line as usize;
+                Some(Selection::Block {
+                    first: cmp::min(start_line, cursor_line),
+                    last: cmp::max(start_line, cursor_line),
+                    left: cmp::min(start_left, cursor_left),
+                    right: cmp::max(start_right, cursor_right),
+                })
+            },
+            _ => None,
+        }
+    }
+
+    /// Columns [from, to) of a line that are selected, for drawing. to is one past the end of the
+    /// line when the line break is selected.
+    pub fn line_selection(&self, line: usize) -> Option<(usize, usize)> {
+        let line_info = self.buffer().line_info(line);
+        let (start, end) = (line_info.buf_index, line_info.buf_index + line_info.length);
+        match self.selection() {
+            Some(Selection::Chars(from, to)) if from <= end && to > start => {
+                Some((cmp::max(from, start) - start, cmp::min(to, end + 1) - start))
+            },
+            Some(Selection::Lines(first, last)) if line >= first && line <= last => {
+                Some((0, line_info.length + 1))
+            },
+            Some(Selection::Block { first, last, left, right }) if line >= first && line <= last => {
+                block_range(&self.buffer().line(line), left, right)
+            },
+            _ => None,
+        }
+    }
+
+    /// The first and last display columns of the character at index
+    fn columns_at(&self, index: usize) -> (usize, usize) {
+        let line = self.buffer().line_at_index(index);
+        let line_info = self.buffer().line_info(line);
+        let text = self.buffer().line(line);
+        let offset = unicode::boundary_at(&text, index - line_info.buf_index);
+        let left = unicode::width(&text[..offset]);
+        let width = unicode::width(&text[offset..unicode::next_boundary(&text, offset)]);
+        (left, left + cmp::max(width, 1) - 1)
+    }
+
+    fn do_selection_operator(&mut self, operator: Operator, selection: Selection) {
+        self.join_undo_step = false;
+        match selection {
+            Selection::Chars(from, to) => { self.do_operator(operator, from, to, None); },
+            Selection::Lines(first, last) => { self.do_line_operator(operator, first, last); },
+            Selection::Block { first, last, left, right } => {
+                self.do_block_operator(operator, first, last, left, right);
+            },
+        }
+        if self.mode != Mode::Insert {
+            self.join_undo_step = false;
+        }
+    }
+
+    /// Applies an operator to a rectangle of text, one line at a time
+    fn do_block_operator(&mut self, operator: Operator, first: usize, last: usize, left: usize, right: usize) {
+        let register = self.pending_register.take();
+
+        // The part of each line inside the block, if the line reaches the block
+        let rows: Vec<(usize, Option<(usize, usize)>)> =
+            (first..last+1).map(|line| {
+                let start = self.buffer().line_info(line).buf_index;
+                let range = block_range(&self.buffer().line(line), left, right);
+                (line, range.map(|(from, to)| (start + from, start + to)))
+            }).collect();
+        let text: Vec<String> =
+            rows.iter().map(|&(_, row)| {
+                row.map_or(String::new(), |(from, to)| self.buffer().slice(from, to).into_owned())
+            }).collect();
+        let text = text.join("\n");
+
+        match operator {
+            Operator::Delete | Operator::Change => {
+                self.registers.delete(register, text, false);
+                for &(_, row) in rows.iter().rev() {
+                    if let Some((from, to)) = row {
+                        let op = self.buf_op.remove(from as u64, (to - 1) as u64);
+                        self.log_joined(op);
+                    }
+                }
+
+                if operator == Operator::Change {
+                    self.block_insert = Some(BlockInsert {
+                        lines: rows.iter().skip(1).filter(|&&(_, row)| row.is_some())
+                                              .map(|&(line, _)| line).collect(),
+                        column: left,
+                        start: 0,
+                    });
+                    self.mode = Mode::Insert;
+                }
+            },
+            Operator::Yank => {
+                self.registers.yank(register, text, false);
+            },
+            Operator::Indent | Operator::Unindent => {
+                self.pending_register = register;
+                self.do_operator(operator, 0, 0, Some((first, last)));
+                return;
+            },
+            Operator::ToggleCase => {
+                for &(_, row) in rows.iter().rev() {
+                    if let Some((from, to)) = row {
+                        self.toggle_case(from, to);
+                    }
+                }
+            },
+        }
+
+        // The cursor goes to the top left of the block
+        let index = self.buffer().line_info(first).buf_index +
+                    unicode::index_at_column(&self.buffer().line(first), left);
+        self.move_cursor_to(index);
+        if let Some(ref mut block_insert) = self.block_insert {
+            block_insert.start = index;
+        }
+        self.dirty = true;
+    }
+
+    /// Copies the text typed into the first line of a changed block onto the block's other lines
+    fn finish_block_insert(&mut self) {
+        let block_insert = match self.block_insert.take() { Some(b) => b, None => { return; } };
+        let cursor = self.cursor.buf_index as usize;
+        if cursor <= block_insert.start {
+            return;
+        }
+        let text = self.buffer().slice(block_insert.start, cursor).into_owned();
+        if text.contains('\n') {
+            return;
+        }
+
+        for &line in block_insert.lines.iter().rev() {
+            let index = self.buffer().line_info(line).buf_index +
+                        unicode::index_at_column(&self.buffer().line(line), block_insert.column);
+            let op = self.buf_op.insert(index as u64, text.clone());
+            self.log_joined(op);
+        }
+        self.move_cursor_to(block_insert.start);
+    }
+
+    /// Swaps upper and lower case in [from, to)
+    fn toggle_case(&mut self, from: usize, to: usize) {
+        let text = self.buffer().slice(from, to).into_owned();
+        // Some characters change into more than one, like ß into SS
+        let mut toggled = String::with_capacity(text.len());
+        for c in text.chars() {
+            if c.is_lowercase() {
+                toggled.extend(c.to_uppercase());
+            } else {
+                toggled.extend(c.to_lowercase());
+            }
+        }
+        if toggled != text {
+            let op = self.buf_op.remove(from as u64, (to - 1) as u64);
+            self.log_joined(op);
+            let op = self.buf_op.insert(from as u64, toggled);
+            self.log_joined(op);
+        }
+    }
+
+    /// Applies an operator to the lines first through last, inclusively
+    fn do_line_operator(&mut self, operator: Operator, first: usize, last: usize) {
+        let (from, to) =
+            if operator == Operator::Change {
+                // cc keeps the line break so there's an empty line left to type on
+                let last_info = self.buffer().line_info(last);
+                (self.buffer().line_info(first).buf_index, last_info.buf_index + last_info.length)
+            } else {
+                self.line_range(first, last)
+            };
+        self.do_operator(operator, from, to, Some((first, last)));
+    }
+
+    /// Applies an operator to the text in [from, to). lines is set for linewise operations.
+    fn do_operator(&mut self, operator: Operator, from: usize, to: usize, lines: Option<(usize, usize)>) {
+        let (first, last) =
+            lines.unwrap_or((self.buffer().line_at_index(from),
+                             self.buffer().line_at_index(cmp::max(from, to.saturating_sub(1)))));
+
+        let register = self.pending_register.take();
+        match operator {
+            Operator::Delete | Operator::Change => {
+                if to > from {
+                    let text = self.register_text(from, to, lines);
+                    self.registers.delete(register, text, lines.is_some());
+                    let op = self.buf_op.remove(from as u64, (to - 1) as u64);
+                    self.log_joined(op);
+                }
+
+                if lines.is_some() && operator == Operator::Delete {
+                    let line = self.buffer().line_at_index(cmp::min(from, self.buffer().len()));
+                    let line_start = self.buffer().line_info(line).buf_index;
+                    self.move_cursor_to(line_start);
+                } else {
+                    self.move_cursor_to(from);
+                }
+
+                if operator == Operator::Change {
+                    self.mode = Mode::Insert;
+                }
+            },
+            Operator::Yank => {
+                let text = self.register_text(from, to, lines);
+                self.registers.yank(register, text, lines.is_some());
+                self.move_cursor_to(from);
+            },
+            Operator::Indent | Operator::Unindent => {
+                // Go from the bottom up so earlier line starts don't move
+                for line in (first..last+1).rev() {
+                    let line_info = self.buffer().line_info(line);
+                    let start = line_info.buf_index;
+                    if operator == Operator::Indent {
+                        if line_info.length > 0 {
+                            let op = self.buf_op.insert(start as u64, self.config.indent());
+                            self.log_joined(op);
+                        }
+                    } else {
+                        let line_text = self.buffer().line(line).into_owned();
+                        let indent =
+                            if line_text.starts_with('\t') {
+                                1
+                            } else {
+                                line_text.bytes().take(self.config.tab_width).take_while(|&b| b == b' ').count()
+                            };
+                        if indent > 0 {
+                            let op = self.buf_op.remove(start as u64, (start + indent - 1) as u64);
+                            self.log_joined(op);
+                        }
+                    }
+                }
+                let first_start = self.buffer().line_info(first).buf_index;
+                self.move_cursor_to(first_start);
+            },
+            Operator::ToggleCase => {
+                if to > from {
+                    self.toggle_case(from, to);
+                }
+                self.move_cursor_to(from);
+            },
+        }
+        self.dirty = true;
+    }
+
+    /// Text that an operator on [from, to) stores in a register. Whole lines always end with a line
+    /// break, whichever line break the operator took.
+    fn register_text(&self, from: usize, to: usize, lines: Option<(usize, usize)>) -> String {
+        match lines {
+            Some((first, last)) => {
+                let last_info = self.buffer().line_info(last);
+                let start = self.buffer().line_info(first).buf_index;
+                let mut text = self.buffer().slice(start, last_info.buf_index + last_info.length).into_owned();
+                text.push('\n');
+                text
+            },
+            None => self.buffer().slice(from, to).into_owned(),
+        }
+    }
+
+    /// Puts the contents of a register count times after or before the cursor. Whole lines go
+    /// below or above the cursor line.
+    fn put(&mut self, count: usize, before: bool) {
+        let register = self.pending_register.take();
+        let value =
+            match self.registers.get(register) {
+                Some(value) => value.clone(),
+                None => {
+                    self.notify_error(format!("Nothing in register {}", register.unwrap_or('"')));
+                    return;
+                },
+            };
+        let text: String = (0..count).map(|_| value.text.as_str()).collect();
+        if text.is_empty() {
+            return;
+        }
+
+        self.join_undo_step = false;
+        if value.linewise {
+            let line = self.cursor.line as usize;
+            let (index, text) =
+                if before {
+                    (self.buffer().line_info(line).buf_index, text)
+                } else if line + 1 < self.buffer().line_count() {
+                    (self.buffer().line_info(line + 1).buf_index, text)
+                } else {
+                    // Below the last line, which has no line break to put the lines after
+                    let mut text = text;
+                    text.pop();
+                    (self.buffer().len(), format!("\n{}", text))
+                };
+            let op = self.buf_op.insert(index as u64, text);
+            self.log(op);
+            let first_line = if before { line } else { line + 1 };
+            let first_start = self.buffer().line_info(first_line).buf_index;
+            self.move_cursor_to(first_start);
+        } else {
+            let mut index = self.cursor.buf_index as usize;
+            if !before && index < self.buffer().len() && self.buffer().byte(index) != b'\n' {
+                index = self.buffer().next_grapheme(index);
+            }
+            let op = self.buf_op.insert(index as u64, text.clone());
+            self.log(op);
+
+            // The cursor ends up on the last grapheme cluster put
+            let end = index + text.len();
+            let last = self.buffer().prev_grapheme(end);
+            self.move_cursor_to(last);
+        }
+        self.join_undo_step = false;
+        self.dirty = true;
+    }
+
+    /// Buffer range covering whole lines, including a line break so no empty line is left behind
+    fn line_range(&self, first: usize, last: usize) -> (usize, usize) {
+        let buffer = self.buffer();
+        let from = buffer.line_info(first).buf_index;
+        if last + 1 < buffer.line_count() {
+            (from, buffer.line_info(last + 1).buf_index)
+        } else if first > 0 {
+            // No line break after the last line, take the one before the first line instead
+            (from - 1, buffer.len())
+        } else {
+            (from, buffer.len())
+        }
+    }
+
+    // Insert mode keys
+    fn insert_handle_keys(&mut self, timed_out: bool) -> KeyStep {
+        match self.keymaps.insert.resolve(&self.pending_keys, timed_out) {
+            Resolve::Wait => KeyStep::Wait,
+            Resolve::Action(Action::Keys(keys), len) => {
+                let rest = self.pending_keys.split_off(len);
+                self.pending_keys = keys;
+                self.pending_keys.extend(rest);
+                KeyStep::Remapped
+            },
+            Resolve::Action(_, len) => {
+                self.pending_keys.drain(..len);
+                KeyStep::Done
+            },
+            Resolve::Unmapped => {
+                let e = self.pending_keys.remove(0);
+                self.insert_handle_event(e);
+                KeyStep::Done
+            },
+        }
+    }
+
+    fn insert_handle_event(&mut self, e: Event) {
+        if self.buf_op.is_hex() {
+            self.hex_insert_handle_event(e);
+            return;
+        }
+
+        match e {
+            Event::Esc => {
+                self.finish_block_insert();
+                let index = self.cursor.buf_index as usize;
+                self.move_cursor_to(index);
+                self.mode = Mode::Normal;
+                self.join_undo_step = false;
+                self.dirty = true;
+            },
+            Event::Backspace => {
+                if self.cursor.buf_index > 0 {
+                    if self.cursor.buf_index == self.buffer()
+                                                    .line_info(self.cursor.line as usize)
+                                                    .buf_index as u64 {
+                        // Handle special newline case
+                        self.cursor.buf_index -= 1;
+                        self.cursor.line -= 1;
+                        self.cursor.calculate_column(self.buf_op.buffer());
+                        let index = self.cursor.buf_index;
+                        let op = self.buf_op.remove_char(index);
+                        self.log(op);
+                    } else {
+                        // The whole grapheme cluster goes, accents and all
+                        let end = self.cursor.buf_index as usize;
+                        let start = self.buffer().prev_grapheme(end);
+                        self.cursor.buf_index = start as u64;
+                        self.cursor.calculate_column(self.buf_op.buffer());
+                        let op = self.buf_op.remove(start as u64, (end - 1) as u64);
+                        self.log(op);
+                    }
+                }
+            },
+            Event::Enter => {
+                let index = self.cursor.buf_index;
+                let op = self.buf_op.insert_char(index, '\n');
+                self.log(op);
+                self.cursor.column = 0;
+                self.cursor_down();
+            },
+            Event::Char('\t') if self.config.expand_tab => {
+                let index = self.cursor.buf_index;
+                let indent = self.config.indent();
+                let op = self.buf_op.insert(index, indent.clone());
+                self.log(op);
+                self.cursor.buf_index += indent.len() as u64;
+                self.cursor.calculate_column(self.buf_op.buffer());
+            },
+            Event::Char(c) => {
+                let index = self.cursor.buf_index;
+                let op = self.buf_op.insert_char(index, c);
+                self.log(op);
+                self.cursor.buf_index += c.len_utf8() as u64;
+                self.cursor.calculate_column(self.buf_op.buffer());
+            },
+            _ => { },
+        }
+        self.scroll_to_cursor();
+    }
+
+    /// Insert mode in hex view types over bytes: hex digits over a byte's digits, or any ASCII
+    /// character over its text. The cursor moves on to the next digit or byte.
+    fn hex_insert_handle_event(&mut self, e: Event) {
+        let line = self.cursor.line as usize;
+        let column = self.cursor.column as usize;
+        let text = self.buffer().line(line).into_owned();
+        match e {
+            Event::Esc => {
+                self.mode = Mode::Normal;
+                self.join_undo_step = false;
+                self.dirty = true;
+            },
+            Event::Backspace => { self.cursor_left(); },
+            Event::Char(c) => {
+                let (b, in_hex) = match hex::byte_at(&text, column) { Some(on) => on, None => { return; } };
+                let digits = hex::hex_column(b);
+                let old = u8::from_str_radix(&text[digits..digits+2], 16).unwrap_or(0);
+                let (byte, next) =
+                    if in_hex {
+                        let digit = match c.to_digit(16) { Some(digit) => digit as u8, None => { return; } };
+                        if column == digits {
+                            (digit << 4 | old & 0x0f, column + 1)
+                        } else {
+                            (old & 0xf0 | digit, hex::hex_column(b + 1))
+                        }
+                    } else if (c as u32) < 0x80 {
+                        (c as u8, hex::text_column(b + 1))
+                    } else {
+                        return;
+                    };
+
+                let line_start = self.buffer().line_info(line).buf_index;
+                if byte != old {
+                    for &(column, ref replacement) in &[(digits, format!("{:02x}", byte)),
+                                                        (hex::text_column(b), hex::text_char(byte).to_string())] {
+                        let index = (line_start + column) as u64;
+                        let op = self.buf_op.remove(index, index + replacement.len() as u64 - 1);
+                        self.log(op);
+                        let op = self.buf_op.insert(index, replacement.clone());
+                        self.log(op);
+                    }
+                }
+
+                // After the last byte of a line comes the first of the next line
+                let next_line = line + 1;
+                if hex::byte_at(&text, next).is_some() {
+                    self.move_cursor_to(line_start + next);
+                } else if next_line < self.buffer().line_count() &&
+                          hex::byte_at(&self.buffer().line(next_line), hex::TEXT_START).is_some() {
+                    let column = if in_hex { hex::hex_column(0) } else { hex::text_column(0) };
+                    let index = self.buffer().line_info(next_line).buf_index + column;
+                    self.move_cursor_to(index);
+                }
+            },
+            _ => { },
+        }
+        self.scroll_to_cursor();
+    }
+
+    // Command and search mode handle event
+    fn command_handle_event(&mut self, e: Event) {
+        match e {
+            Event::Backspace => {
+                if self.cmd_buffer.buffer().len() > 0 {
+                    let end = self.cmd_buffer.buffer().len();
+                    let start = self.cmd_buffer.buffer().prev_grapheme(end);
+                    self.cmd_buffer.buffer_mut().remove(start, end - 1);
+                    self.update_incremental_search();
+                    self.dirty = true;
+                } else {
+                    // Backspacing past the start of the line cancels it
+                    self.cancel_command();
+                }
+            },
+            Event::Char(c) => {
+                let end = self.cmd_buffer.buffer().len();
+                self.cmd_buffer.buffer_mut().insert(end, format!("{}", c).as_str());
+                self.update_incremental_search();
+                self.dirty = true;
+            },
+            Event::Up | Event::Down => {
+                self.browse_history(e == Event::Up);
+                self.update_incremental_search();
+            },
+            Event::Enter => {
+                let command = self.cmd_buffer.buffer().to_string();
+                self.cmd_buffer.buffer_mut().clear();
+                match self.mode {
+                    Mode::Search { forward } => {
+                        self.mode = Mode::Normal;
+                        self.finish_search(command, forward);
+                    },
+                    _ => {
+                        self.mode = Mode::Normal;
+                        self.execute_command(command);
+                    },
+                }
+                self.dirty = true;
+            },
+            Event::Esc => {
+                self.cancel_command();
+            },
+            _ => { },
+        }
+    }
+
+    fn cancel_command(&mut self) {
+        if let Mode::Search { forward: _ } = self.mode {
+            let origin = self.search_origin;
+            self.move_cursor_to(origin);
+        }
+        self.incremental_search = None;
+        self.cmd_buffer.buffer_mut().clear();
+        self.mode = Mode::Normal;
+        self.dirty = true;
+    }
+
+    /// Replaces the command line with an older (or newer) line from the command or search history
+    fn browse_history(&mut self, older: bool) {
+        let entry = {
+            let history =
+                match self.mode {
+                    Mode::Search { forward: _ } => &self.search_history,
+                    _ => &self.cmd_log,
+                };
+            if history.is_empty() {
+                return;
+            }
+            let index =
+                match (self.history_index, older) {
+                    (None, true) => Some(history.len() - 1),
+                    (None, false) => None,
+                    (Some(i), true) => Some(i.saturating_sub(1)),
+                    (Some(i), false) => if i + 1 < history.len() { Some(i + 1) } else { None },
+                };
+            self.history_index = index;
+            index.map_or(String::new(), |i| history[i].clone())
+        };
+
+        self.cmd_buffer.buffer_mut().clear();
+        self.cmd_buffer.buffer_mut().insert(0, &entry);
+        self.dirty = true;
+    }
+
+    /// Moves the cursor to the first match of the pattern typed so far
+    fn update_incremental_search(&mut self) {
+        let forward = match self.mode { Mode::Search { forward } => forward, _ => { return; } };
+        let pattern = self.cmd_buffer.buffer().to_string();
+        self.incremental_search =
+            if pattern.is_empty() { None } else { Search::new(&pattern, forward).ok() };
+
+        let origin = self.search_origin;
+        let target =
+            self.incremental_search.as_ref()
+                                   .and_then(|s| s.find(self.buf_op.buffer(), origin, forward))
+                                   .map_or(origin, |m| m.index);
+        self.move_cursor_to(target);
+    }
+
+    /// Runs the search typed on the command line. An empty pattern repeats the last search.
+    fn finish_search(&mut self, pattern: String, forward: bool) {
+        self.incremental_search = None;
+        let origin = self.search_origin;
+        self.move_cursor_to(origin);
+
+        let pattern =
+            if pattern.is_empty() {
+                match self.search {
+                    Some(ref search) => search.pattern.clone(),
+                    None => {
+                        self.notify_error("No previous search".to_string());
+                        return;
+                    },
+                }
+            } else {
+                self.search_history.retain(|p| *p != pattern);
+                self.search_history.push(pattern.clone());
+                pattern
+            };
+
+        match Search::new(&pattern, forward) {
+            Ok(search) => {
+                self.search = Some(search);
+                self.highlight_search = true;
+                self.search_next(1, true);
+            },
+            Err(e) => { self.notify_error(e); },
+        }
+    }
+
+    /// Goes to the count'th next match of the last search, in the search's direction or against it
+    fn search_next(&mut self, count: usize, same_direction: bool) {
+        let result = {
+            let search = match self.search { Some(ref s) => s, None => {
+                self.notify_error("No previous search".to_string());
+                return;
+            } };
+            let forward = search.forward == same_direction;
+
+            let mut index = self.cursor.buf_index as usize;
+            let mut wrapped = false;
+            let mut found = true;
+            for _ in 0..count {
+                match search.find(self.buf_op.buffer(), index, forward) {
+                    Some(m) => {
+                        index = m.index;
+                        wrapped = wrapped || m.wrapped;
+                    },
+                    None => { found = false; break; },
+                }
+            }
+
+            if !found {
+                Err(format!("Pattern not found: {}", search.pattern))
+            } else if wrapped && forward {
+                Ok((index, "Search hit BOTTOM, continuing at TOP".to_string()))
+            } else if wrapped {
+                Ok((index, "Search hit TOP, continuing at BOTTOM".to_string()))
+            } else {
+                Ok((index, format!("{}{}", if forward { '/' } else { '?' }, search.pattern)))
+            }
+        };
+
+        match result {
+            Ok((index, message)) => {
+                self.move_cursor_to(index);
+                self.notify(message);
+            },
+            Err(message) => { self.notify_error(message); },
+        }
+        self.dirty = true;
+    }
+
+    /// Byte ranges [from, to) of search matches in a line, for highlighting
+    pub fn line_matches(&self, line: usize) -> Vec<(usize, usize)> {
+        if self.mode == Mode::Confirm {
+            // Only the match waiting for an answer
+            let mut matches = Vec::new();
+            if let Some(ref substitution) = self.substitution {
+                if let Some(&(from, to, _)) = substitution.matches.first() {
+                    let index = ((substitution.line_start + from) as isize + substitution.shift) as usize;
+                    let line_start = self.buffer().line_info(line).buf_index;
+                    if self.buffer().line_at_index(index) == line {
+                        matches.push((index - line_start, index - line_start + to - from));
+                    }
+                }
+            }
+            return matches;
+        }
+
+        let search =
+            match self.mode {
+                Mode::Search { forward: _ } => self.incremental_search.as_ref(),
+                _ if self.highlight_search => self.search.as_ref(),
+                _ => None,
+            };
+        search.map_or(Vec::new(), |s| s.line_matches(&self.buffer().line(line)))
+    }
+
+    /// Replaces matches of a :s command in lines [first, last]. With the c flag this stops at the
+    /// first match and waits for an answer in confirm mode.
+    fn substitute(&mut self, (first, last): (usize, usize), args: &str) -> Result<(), String> {
+        let last_pattern = self.search.as_ref().map(|s| s.pattern.clone());
+        let substitute = try!(Substitute::parse(args, last_pattern.as_ref().map(|p| p.as_str())));
+
+        // n and N go on to find the pattern, like in vim
+        if let Ok(search) = Search::new(&substitute.pattern, true) {
+            self.search = Some(search);
+            self.highlight_search = true;
+        }
+
+        let line_start = self.buffer().line_info(first).buf_index;
+        let mut substitution = Substitution {
+            substitute: substitute,
+            line_start: line_start,
+            line_length: 0,
+            matches: Vec::new(),
+            shift: 0,
+            lines_left: last - first,
+            replace_all: false,
+            replaced: 0,
+            lines_changed: 0,
+            line_changed: false,
+            last_changed: None,
+        };
+        self.load_substitution_line(&mut substitution);
+
+        // All the replacements are one undo step
+        self.join_undo_step = false;
+        self.substitution = Some(substitution);
+        self.continue_substitution();
+        Ok(())
+    }
+
+    /// Finds the matches in the line a substitution is at
+    fn load_substitution_line(&self, substitution: &mut Substitution) {
+        let line = self.buffer().line_at_index(substitution.line_start);
+        let text = self.buffer().line(line);
+        substitution.line_length = self.buffer().line_info(line).length;
+        substitution.matches = substitution.substitute.line_matches(&text);
+        substitution.shift = 0;
+        substitution.line_changed = false;
+    }
+
+    /// Replaces matches until one needs confirming or the range is done
+    fn continue_substitution(&mut self) {
+        let mut substitution = match self.substitution.take() { Some(s) => s, None => { return; } };
+        loop {
+            if substitution.matches.is_empty() {
+                if substitution.lines_left == 0 {
+                    break;
+                }
+                if substitution.line_changed {
+                    substitution.lines_changed += 1;
+                }
+                substitution.line_start =
+                    (substitution.line_start as isize + substitution.line_length as isize + substitution.shift) as usize + 1;
+                substitution.lines_left -= 1;
+                self.load_substitution_line(&mut substitution);
+            } else if substitution.substitute.confirm && !substitution.replace_all {
+                let index = ((substitution.line_start + substitution.matches[0].0) as isize + substitution.shift) as usize;
+                self.move_cursor_to(index);
+                self.notify(format!("replace with {} (y/n/a/q/l)?", substitution.matches[0].2.replace('\n', "^J")));
+                self.mode = Mode::Confirm;
+                self.substitution = Some(substitution);
+                return;
+            } else {
+                self.replace_match(&mut substitution);
+            }
+        }
+        self.finish_substitution(substitution);
+    }
+
+    /// Replaces the next match of a substitution
+    fn replace_match(&mut self, substitution: &mut Substitution) {
+        let (from, to, replacement) = substitution.matches.remove(0);
+        let index = ((substitution.line_start + from) as isize + substitution.shift) as usize;
+        if to > from {
+            let op = self.buf_op.remove(index as u64, (index + to - from - 1) as u64);
+            self.log_joined(op);
+        }
+        if !replacement.is_empty() {
+            let op = self.buf_op.insert(index as u64, replacement.clone());
+            self.log_joined(op);
+        }
+        substitution.shift += replacement.len() as isize - (to - from) as isize;
+        substitution.replaced += 1;
+        substitution.line_changed = true;
+        substitution.last_changed = Some(substitution.line_start);
+    }
+
+    /// Leaves the cursor on the last changed line and reports what was done
+    fn finish_substitution(&mut self, substitution: Substitution) {
+        self.mode = Mode::Normal;
+        let lines_changed = substitution.lines_changed + if substitution.line_changed { 1 } else { 0 };
+        match substitution.last_changed {
+            Some(line_start) => {
+                self.move_cursor_to(line_start);
+                self.notify(format!("{} substitution{} on {} line{}",
+                                    substitution.replaced, if substitution.replaced == 1 { "" } else { "s" },
+                                    lines_changed, if lines_changed == 1 { "" } else { "s" }));
+            },
+            None => {
+                if !substitution.substitute.confirm {
+                    self.notify_error(format!("Pattern not found: {}", substitution.substitute.pattern));
+                }
+            },
+        }
+        self.dirty = true;
+    }
+
+    // Confirm mode handle event
+    fn confirm_handle_event(&mut self, e: Event) {
+        let mut substitution = match self.substitution.take() { Some(s) => s, None => { return; } };
+        match e {
+            Event::Char('y') => { self.replace_match(&mut substitution); },
+            Event::Char('n') => { substitution.matches.remove(0); },
+            Event::Char('a') => {
+                substitution.replace_all = true;
+                self.replace_match(&mut substitution);
+            },
+            Event::Char('l') => {
+                self.replace_match(&mut substitution);
+                self.finish_substitution(substitution);
+                return;
+            },
+            Event::Char('q') | Event::Esc => {
+                self.finish_substitution(substitution);
+                return;
+            },
+            _ => {
+                self.substitution = Some(substitution);
+                return;
+            },
+        }
+        self.substitution = Some(substitution);
+        self.continue_substitution();
+    }
+
+    /// Replaces the buffer with a file's text, picking up its undo history like from_file
+    pub fn edit(&mut self, path: String) -> io::Result<()> {
+        let buf_op = try!(BufferOperator::from_file(path.clone()));
+        self.load(Some(path), buf_op);
+        Ok(())
+    }
+
+    fn load(&mut self, path: Option<String>, buf_op: BufferOperator) {
+        self.buf_op = buf_op;
+        self.undo_tree = UndoTree::new();
+        self.path = path;
+        if self.persist_undo {
+            self.read_undo_file();
+        }
+        self.join_undo_step = false;
+        self.visual_lines = None;
+        self.disk_change = None;
+        self.scroll = 0;
+        self.left = 0;
+        self.move_cursor_to(0);
+        self.notify_view();
+    }
+
+    /// Says why a file isn't shown as text, if it isn't
+    fn notify_view(&mut self) {
+        let path = match self.path.clone() { Some(path) => path, None => { return; } };
+        let note =
+            match self.buf_op.view {
+                View::Text => { return; },
+                View::Hex => "binary, shown as hex".to_string(),
+                View::Paged(ref pager) => {
+                    format!("{} bytes, too big to read at once, shown a page at a time (read-only)", pager.len)
+                },
+            };
+        self.notify(format!("\"{}\" is {}", path, note));
+    }
+
+    /// Checks every so often whether another program changed the file, and asks what to do
+    /// about it if so. Only done in normal mode, so nothing being typed is interrupted.
+    pub fn check_file(&mut self) {
+        if self.mode != Mode::Normal || !self.pending_keys.is_empty() {
+            return;
+        }
+        let now = time::SteadyTime::now();
+        if let Some(checked_at) = self.file_checked_at {
+            if now - checked_at < time::Duration::milliseconds(FILE_CHECK_MS) {
+                return;
+            }
+        }
+        self.file_checked_at = Some(now);
+        self.check_file_now();
+    }
+
+    fn check_file_now(&mut self) {
+        let path = match self.path.clone() { Some(path) => path, None => { return; } };
+        // A file that can't be read right now, e.g. while it's being replaced, is tried again later
+        if let Ok(Some(disk_file)) = self.buf_op.check_disk_file(Path::new(&path)) {
+            // A hex dump can't be merged line by line
+            if self.is_modified() && !self.buf_op.is_hex() {
+                self.notify_error(format!("{} changed on disk: (r)eload, (k)eep or (m)erge?", path));
+            } else {
+                self.notify_error(format!("{} changed on disk: (r)eload or (k)eep?", path));
+            }
+            self.disk_change = Some(disk_file);
+            self.mode = Mode::FileChanged;
+        }
+    }
+
+    fn file_changed_handle_event(&mut self, e: Event) {
+        let disk_file = match self.disk_change.take() { Some(f) => f, None => { return; } };
+        let text =
+            match self.buf_op.shown_text(&disk_file) {
+                Ok(text) => text,
+                Err(e) => {
+                    self.notify_error(format!("Failed to show the file: {}", e));
+                    self.mode = Mode::Normal;
+                    return;
+                },
+            };
+        match e {
+            Event::Char('r') => {
+                self.replace_text(&text);
+                self.buf_op.format = disk_file.format;
+                self.buf_op.disk_file = Some(disk_file);
+                self.buf_op.modified = false;
+            },
+            Event::Char('k') | Event::Esc => {
+                // The buffer is what gets written next time, on purpose now
+                let modified = self.buf_op.modified || self.buffer().to_string() != text;
+                self.buf_op.disk_file = Some(disk_file);
+                self.buf_op.modified = modified;
+            },
+            Event::Char('m') if self.is_modified() && !self.buf_op.is_hex() => {
+                let base = self.buf_op.disk_file.as_ref().map_or(String::new(), |f| f.text.clone());
+                let merged = merge::merge3(&base, &self.buffer().to_string(), &disk_file.text);
+                self.replace_text(&merged.text);
+                self.buf_op.disk_file = Some(disk_file);
+                if merged.conflicts > 0 {
+                    self.notify_error(format!("Merged with {} conflict(s)", merged.conflicts));
+                } else {
+                    self.notify("Merged".to_string());
+                }
+            },
+            _ => {
+                self.disk_change = Some(disk_file);
+                return;
+            },
+        }
+        self.mode = Mode::Normal;
+        self.dirty = true;
+    }
+
+    /// Turns the buffer into text by changing only the lines that differ, as one undo step
+    fn replace_text(&mut self, text: &str) {
+        let changes = merge::changes(&self.buffer().to_string(), text);
+        self.join_undo_step = false;
+        for change in changes.into_iter().rev() {
+            if change.to > change.from {
+                let op = self.buf_op.remove(change.from as u64, (change.to - 1) as u64);
+                self.cursor.op_adjust_cursor(self.buf_op.buffer(), &op);
+                self.log_joined(op);
+            }
+            if !change.text.is_empty() {
+                let op = self.buf_op.insert(change.from as u64, change.text);
+                self.cursor.op_adjust_cursor(self.buf_op.buffer(), &op);
+                self.log_joined(op);
+            }
+        }
+        self.join_undo_step = false;
+        let index = cmp::min(self.cursor.buf_index as usize, self.buffer().len());
+        self.move_cursor_to(index);
+    }
+
+    pub fn save<P: AsRef<Path>>(&mut self, path: &P) -> io::Result<()> {
+        try!(self.buf_op.write_file(path.as_ref(), self.config.backup));
+
+        if self.persist_undo {
+            try!(self.save_undo_file(path.as_ref()));
+        }
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Turns storing the undo history next to the file on or off. Turning it on picks up the
+    /// history from the last session, if the file hasn't changed since and hasn't been edited yet.
+    pub fn set_persist_undo(&mut self, persist: bool) {
+        self.persist_undo = persist;
+        if persist && !self.buf_op.modified && self.undo_tree.len() == 1 {
+            self.read_undo_file();
+        }
+    }
+
+    fn read_undo_file(&mut self) {
+        let undo_file =
+            match self.path {
+                Some(ref path) => UndoFile::read(Path::new(path), self.buffer().hash()),
+                None => { return; },
+            };
+        if let Ok(Some(undo_file)) = undo_file {
+            self.undo_tree = undo_file.tree;
+        }
+    }
+
+    fn save_undo_file(&self, path: &Path) -> io::Result<()> {
+        let undo_file = UndoFile {
+            path: undo_file::canonical_path(path),
+            hash: self.buffer().hash(),
+            tree: self.undo_tree.clone(),
+        };
+        undo_file.write(path)
+    }
+
+    /// Logs an operation as part of the current undo step
+    fn log_joined(&mut self, operation: Operation) {
+        self.log(operation);
+        self.join_undo_step = true;
+    }
+
+    pub fn log(&mut self, operation: Operation) {
+        // Everything typed in one insert mode session is undone together
+        self.undo_tree.push(operation.clone(), self.join_undo_step);
+        self.join_undo_step = self.mode == Mode::Insert;
+
+        self.adjust_windows(&operation);
+        self.net_log.push(operation);
+    }
+
+    /// Reverts the current undo step
+    pub fn undo(&mut self) {
+        if let Some(ops) = self.undo_tree.undo() {
+            self.do_history_operations(ops);
+        }
+    }
+
+    /// Reapplies the most recently undone undo step
+    pub fn redo(&mut self) {
+        if let Some(ops) = self.undo_tree.redo() {
+            self.do_history_operations(ops);
+        }
+    }
+
+    /// Moves through the undo history in the order changes were made, across every branch
+    pub fn time_travel(&mut self, amount: TimeTravel, forward: bool) {
+        let ops =
+            if forward {
+                self.undo_tree.later(amount)
+            } else {
+                self.undo_tree.earlier(amount)
+            };
+        self.do_history_operations(ops);
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn buffer(&self) -> &Buffer {
+        self.buf_op.buffer()
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// The newest message
+    pub fn message(&self) -> Option<&str> {
+        self.messages.back().map(|m| m.text.as_str())
+    }
+
+    /// Messages to show, oldest first
+    pub fn messages(&self) -> &VecDeque<Message> {
+        &self.messages
+    }
+
+    /// Shows a message at the bottom of the screen until the next key
+    pub fn notify(&mut self, text: String) {
+        self.push_message(text, MessageKind::Info);
+    }
+
+    /// Shows an error at the bottom of the screen until the next key
+    pub fn notify_error(&mut self, text: String) {
+        self.push_message(text, MessageKind::Error);
+    }
+
+    fn push_message(&mut self, text: String, kind: MessageKind) {
+        let message = Message { text: text, kind: kind };
+        self.messages.push_back(message.clone());
+        if self.messages.len() > MAX_MESSAGES {
+            self.messages.pop_front();
+        }
+        self.message_log.push(message);
+        if self.message_log.len() > MAX_MESSAGE_LOG {
+            self.message_log.remove(0);
+        }
+        self.dirty = true;
+    }
+
+    pub fn command_buffer(&self) -> &Buffer {
+        self.cmd_buffer.buffer()
+    }
+
+    pub fn running(&self) -> bool {
+        self.running
+    }
+
+    /// Whether the buffer has changes that haven't been written to its file
+    pub fn is_modified(&self) -> bool {
+        self.buf_op.modified
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.buf_op.is_read_only()
+    }
+
+    /// Whether a window shows its buffer as a hex dump
+    pub fn window_is_hex(&self, window: &Window) -> bool {
+        self.window_buf_op(window).is_hex()
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty || self.buf_op.dirty
+    }
+
+    pub fn clean(&mut self) {
+        self.dirty = false;
+        self.buf_op.dirty = false;
+    }
+
+    ////////////////////////////////////////////////////////////////////////////////////////////////
+    // Buffers
+
+    /// Opens a file in a new buffer, or shows its buffer if it's open already. The shown buffer is
+    /// hidden with its changes, unless it's an empty buffer nothing was done in.
+    pub fn open(&mut self, path: String) -> io::Result<()> {
+        if self.is_current_file(&path) {
+            return Ok(());
+        }
+        if let Some(index) = self.hidden_buffers.iter().position(|b| {
+            b.path.as_ref().map_or(false, |p| same_file(p, &path))
+        }) {
+            self.show_buffer(index);
+            return Ok(());
+        }
+        if self.single_buffer {
+            return Err(io::Error::new(io::ErrorKind::Other,
+                                      "Only one buffer can be open while connected to a server"));
+        }
+
+        let buf_op = try!(BufferOperator::from_file(path.clone()));
+        if self.path.is_some() || self.is_modified() || self.buffer().len() > 0 {
+            let hidden = self.hide_buffer();
+            self.add_hidden_buffer(hidden);
+            self.buffer_number = self.next_buffer_number;
+            self.next_buffer_number += 1;
+        }
+        self.load(Some(path), buf_op);
+        Ok(())
+    }
+
+    pub fn buffer_count(&self) -> usize {
+        self.hidden_buffers.len() + 1
+    }
+
+    fn is_current_file(&self, path: &str) -> bool {
+        self.path.as_ref().map_or(false, |p| same_file(p, path))
+    }
+
+    /// Shows the buffer numbered after the shown one, or before it, wrapping around
+    fn cycle_buffer(&mut self, forward: bool) {
+        if self.hidden_buffers.is_empty() {
+            return;
+        }
+        let number = self.buffer_number;
+        let index =
+            if forward {
+                self.hidden_buffers.iter().position(|b| b.number > number).unwrap_or(0)
+            } else {
+                self.hidden_buffers.iter().rposition(|b| b.number < number)
+                                   .unwrap_or(self.hidden_buffers.len() - 1)
+            };
+        self.show_buffer(index);
+    }
+
+    /// The number of the buffer a :b or :bd argument names
+    fn find_buffer(&self, name: &str) -> Result<usize, String> {
+        if let Ok(number) = name.parse::<usize>() {
+            if number == self.buffer_number || self.hidden_buffers.iter().any(|b| b.number == number) {
+                return Ok(number);
+            }
+            return Err(format!("Buffer {} does not exist", number));
+        }
+
+        let mut matches: Vec<usize> =
+            self.hidden_buffers.iter().filter(|b| b.path.as_ref().map_or(false, |p| p.contains(name)))
+                                      .map(|b| b.number)
+                                      .collect();
+        if self.path.as_ref().map_or(false, |p| p.contains(name)) {
+            matches.push(self.buffer_number);
+        }
+        match matches.len() {
+            0 => Err(format!("No matching buffer for {}", name)),
+            1 => Ok(matches[0]),
+            _ => Err(format!("More than one match for {}", name)),
+        }
+    }
+
+    /// Shows a hidden buffer in the window, hiding the shown one
+    fn show_buffer(&mut self, index: usize) {
+        self.swap_buffer(index);
+        self.announce_buffer();
+    }
+
+    fn swap_buffer(&mut self, index: usize) {
+        let buffer = self.hidden_buffers.remove(index);
+        let hidden = self.hide_buffer();
+        self.add_hidden_buffer(hidden);
+        self.restore_buffer(buffer);
+    }
+
+    /// Hides the shown buffer for a new empty one
+    fn new_buffer(&mut self) {
+        let hidden = self.hide_buffer();
+        self.add_hidden_buffer(hidden);
+        self.buffer_number = self.next_buffer_number;
+        self.next_buffer_number += 1;
+        self.load(None, BufferOperator::new());
+    }
+
+    fn announce_buffer(&mut self) {
+        let text = format!("\"{}\" {}L", buffer_name(&self.path), self.buffer().line_count());
+        self.notify(text);
+    }
+
+    /// Takes the shown buffer's state out of ted, to be replaced by another buffer's
+    fn hide_buffer(&mut self) -> HiddenBuffer {
+        HiddenBuffer {
+            number: self.buffer_number,
+            path: self.path.take(),
+            buf_op: mem::replace(&mut self.buf_op, BufferOperator::new()),
+            cursor: self.cursor,
+            scroll: self.scroll,
+            left: self.left,
+            undo_tree: mem::replace(&mut self.undo_tree, UndoTree::new()),
+            visual_lines: self.visual_lines.take(),
+        }
+    }
+
+    fn add_hidden_buffer(&mut self, buffer: HiddenBuffer) {
+        let index = self.hidden_buffers.iter().position(|b| b.number > buffer.number)
+                                              .unwrap_or(self.hidden_buffers.len());
+        self.hidden_buffers.insert(index, buffer);
+    }
+
+    fn restore_buffer(&mut self, buffer: HiddenBuffer) {
+        self.buffer_number = buffer.number;
+        self.path = buffer.path;
+        self.buf_op = buffer.buf_op;
+        self.cursor = buffer.cursor;
+        self.scroll = buffer.scroll;
+        self.left = buffer.left;
+        self.undo_tree = buffer.undo_tree;
+        self.visual_lines = buffer.visual_lines;
+        self.join_undo_step = false;
+        self.disk_change = None;
+        self.file_checked_at = None;
+        self.buf_op.dirty = true;
+    }
+
+    ////////////////////////////////////////////////////////////////////////////////////////////////
+    // Windows
+
+    /// Sets the size of the screen, without the status and command lines
+    pub fn resize(&mut self, width: u64, height: u64) {
+        self.screen_width = width as usize;
+        self.screen_height = height as usize;
+        self.arrange_windows();
+    }
+
+    /// Works out where each window of the shown tab page goes
+    fn arrange_windows(&mut self) {
+        let tab_line = if self.tabs.len() > 1 { 1 } else { 0 };
+        let area = Rect {
+            x: 0,
+            y: tab_line,
+            width: self.screen_width,
+            height: self.screen_height.saturating_sub(tab_line),
+        };
+        let mut rects = Vec::new();
+        self.separators.clear();
+        {
+            let layout = &self.tabs[self.tab].layout;
+            layout.arrange(area, layout.windows().len() > 1, &mut rects, &mut self.separators);
+        }
+        // Windows that got shorter scroll to keep their cursors in view
+        for (id, rect) in rects {
+            if let Some(window) = self.windows.iter_mut().find(|w| w.id == id) {
+                window.rect = rect;
+                let height = cmp::max(rect.height, 1) as u64;
+                if window.scroll + height <= window.cursor.line {
+                    window.scroll = window.cursor.line - (height - 1);
+                }
+            }
+        }
+
+        let height = self.windows.iter().find(|w| w.id == self.window).map_or(1, |w| w.rect.height);
+        self.height = cmp::max(height, 1) as u64;
+        self.scroll_to_cursor();
+    }
+
+    /// The windows of the shown tab page
+    pub fn windows(&self) -> Vec<Window> {
+        self.tabs[self.tab].layout.windows().iter().map(|&id| {
+            let mut window = self.windows[self.window_index(id)];
+            if id == self.window {
+                window.buffer = self.buffer_number;
+                window.cursor = self.cursor;
+                window.scroll = self.scroll;
+                window.left = self.left;
+            }
+            window
+        }).collect()
+    }
+
+    /// Size of the room for the tab line and the windows
+    pub fn screen_size(&self) -> (u64, u64) {
+        (self.screen_width as u64, self.screen_height as u64)
+    }
+
+    /// Id of the window being typed in
+    pub fn active_window(&self) -> usize {
+        self.window
+    }
+
+    /// Columns between side by side windows
+    pub fn separators(&self) -> &[Rect] {
+        &self.separators
+    }
+
+    /// The text a window shows
+    pub fn window_buffer(&self, window: &Window) -> &Buffer {
+        self.window_buf_op(window).buffer()
+    }
+
+    /// The buffer a window shows, along with how it's shown and highlighted
+    pub fn window_buf_op(&self, window: &Window) -> &BufferOperator {
+        match self.hidden_buffers.iter().find(|b| b.number == window.buffer) {
+            Some(buffer) if window.buffer != self.buffer_number => &buffer.buf_op,
+            _ => &self.buf_op,
+        }
+    }
+
+    /// Columns of a window the text goes in, right of the left column
+    pub fn text_width(&self, window: &Window) -> u64 {
+        cmp::max(window.rect.width.saturating_sub(self.config.left_column), 1) as u64
+    }
+
+    /// What a window's status line says
+    pub fn window_title(&self, window: &Window) -> String {
+        let (path, modified) =
+            match self.hidden_buffers.iter().find(|b| b.number == window.buffer) {
+                Some(buffer) if window.buffer != self.buffer_number => (&buffer.path, buffer.buf_op.modified),
+                _ => (&self.path, self.is_modified()),
+            };
+        format!("{}{}", buffer_name(path), if modified { " [+]" } else { "" })
+    }
+
+    /// The name of each tab page's active buffer, and which tab page is shown
+    pub fn tab_titles(&self) -> (Vec<String>, usize) {
+        let titles =
+            self.tabs.iter().map(|tab| {
+                let mut window = self.windows[self.window_index(tab.window)];
+                if tab.window == self.window {
+                    window.buffer = self.buffer_number;
+                }
+                self.window_title(&window)
+            }).collect();
+        (titles, self.tab)
+    }
+
+    /// Search matches to highlight in a line of a window other than the active one
+    pub fn text_matches(&self, text: &str) -> Vec<(usize, usize)> {
+        match self.search {
+            Some(ref search) if self.highlight_search => search.line_matches(text),
+            _ => Vec::new(),
+        }
+    }
+
+    fn window_index(&self, id: usize) -> usize {
+        self.windows.iter().position(|w| w.id == id).unwrap()
+    }
+
+    /// Splits the active window in two, the new window shows the same buffer and is made active
+    fn split_window(&mut self, vertical: bool) -> Result<(), String> {
+        self.save_window();
+        let id = self.next_window_id;
+        let window = Window { id: id, ..self.windows[self.window_index(self.window)] };
+        self.tabs[self.tab].layout.split(self.window, id, vertical);
+        self.windows.push(window);
+        self.arrange_windows();
+
+        let min_width = self.config.left_column + self.config.right_column + 1;
+        if self.windows().iter().any(|w| w.rect.height == 0 || w.rect.width < min_width) {
+            self.tabs[self.tab].layout.remove(id);
+            self.windows.pop();
+            self.arrange_windows();
+            return Err("Not enough room".to_string());
+        }
+        self.next_window_id += 1;
+        self.focus_window(id);
+        Ok(())
+    }
+
+    /// Closes the active window, and its tab page if it's the last one in it
+    fn close_window(&mut self) -> Result<(), String> {
+        if self.windows.len() == 1 {
+            return Err("Cannot close last window".to_string());
+        }
+        let id = self.window;
+        let ids = self.tabs[self.tab].layout.windows();
+        if ids.len() == 1 {
+            self.tabs.remove(self.tab);
+            if self.tab == self.tabs.len() {
+                self.tab -= 1;
+            }
+        } else {
+            let i = ids.iter().position(|&w| w == id).unwrap();
+            self.tabs[self.tab].layout.remove(id);
+            self.tabs[self.tab].window = if i > 0 { ids[i - 1] } else { ids[1] };
+        }
+        let index = self.window_index(id);
+        self.windows.remove(index);
+
+        let next = self.tabs[self.tab].window;
+        self.enter_window(next);
+        Ok(())
+    }
+
+    /// Closes every other window of the tab page
+    fn only_window(&mut self) {
+        let ids = self.tabs[self.tab].layout.windows();
+        let window = self.window;
+        self.windows.retain(|w| w.id == window || !ids.contains(&w.id));
+        self.tabs[self.tab].layout = Layout::Window(window);
+        self.arrange_windows();
+    }
+
+    /// Opens a tab page showing the active window's buffer
+    fn new_tab(&mut self) {
+        self.save_window();
+        let id = self.next_window_id;
+        self.next_window_id += 1;
+        let window = Window { id: id, ..self.windows[self.window_index(self.window)] };
+        self.windows.push(window);
+        self.tab += 1;
+        self.tabs.insert(self.tab, TabPage { layout: Layout::Window(id), window: id });
+        self.enter_window(id);
+    }
+
+    fn cycle_tab(&mut self, forward: bool) {
+        if self.tabs.len() == 1 {
+            return;
+        }
+        self.save_window();
+        let count = self.tabs.len();
+        self.tab = if forward { (self.tab + 1) % count } else { (self.tab + count - 1) % count };
+        let id = self.tabs[self.tab].window;
+        self.enter_window(id);
+    }
+
+    /// Makes another window active
+    fn focus_window(&mut self, id: usize) {
+        if id != self.window {
+            self.save_window();
+            self.enter_window(id);
+        }
+    }
+
+    /// Keeps the active window's state for when it's active again
+    fn save_window(&mut self) {
+        let index = self.window_index(self.window);
+        self.windows[index].buffer = self.buffer_number;
+        self.windows[index].cursor = self.cursor;
+        self.windows[index].scroll = self.scroll;
+        self.windows[index].left = self.left;
+    }
+
+    /// Makes a window active without saving the state of the one that was
+    fn enter_window(&mut self, id: usize) {
+        let window = self.windows[self.window_index(id)];
+        if window.buffer != self.buffer_number {
+            if let Some(index) = self.hidden_buffers.iter().position(|b| b.number == window.buffer) {
+                self.swap_buffer(index);
+            }
+        }
+        self.window = id;
+        self.tabs[self.tab].window = id;
+        self.cursor = window.cursor;
+        self.scroll = window.scroll;
+        self.left = window.left;
+        self.visual_lines = None;
+        match self.mode {
+            Mode::VisualChar { .. } | Mode::VisualLine { .. } | Mode::VisualBlock { .. } => { self.mode = Mode::Normal; },
+            _ => { },
+        }
+        self.arrange_windows();
+    }
+
+    /// Moves the cursors of other windows on the shown buffer along with a change to it
+    fn adjust_windows(&mut self, operation: &Operation) {
+        let (window, buffer_number) = (self.window, self.buffer_number);
+        for w in self.windows.iter_mut().filter(|w| w.id != window && w.buffer == buffer_number) {
+            w.cursor.op_adjust_cursor(self.buf_op.buffer(), operation);
+        }
+    }
+
+    ////////////////////////////////////////////////////////////////////////////////////////////////
+    // Operation stuff
+
+    pub fn do_operation(&mut self, operation: &Operation) {
+        self.buf_op.do_operation(operation);
+        self.cursor.op_adjust_cursor(self.buf_op.buffer(), operation);
+        self.adjust_windows(operation);
+    }
+
+    /// Applies operations from the undo history. They still need to go to the server.
+    fn do_history_operations(&mut self, ops: Vec<Operation>) {
+        for op in ops {
+            self.do_operation(&op);
+            self.net_log.push(op);
+        }
+        self.join_undo_step = false;
+        self.scroll_to_cursor();
+    }
+
+
+    ////////////////////////////////////////////////////////////////////////////////////////////////
+    // Cursor movement
+
+    /// Puts the cursor at the start of the grapheme cluster buf_index is in. Edits can join
+    /// clusters, e.g. deleting a line break before a combining character.
+    fn move_cursor_to(&mut self, buf_index: usize) {
+        self.cursor.buf_index = self.buffer().grapheme_start(buf_index) as u64;
+        self.cursor.calculate_pos(self.buf_op.buffer());
+        self.scroll_to_cursor();
+    }
+
+    fn scroll_to_cursor(&mut self) {
+        if self.scroll > self.cursor.line {
+            self.scroll = self.cursor.line;
+        } else if self.scroll+self.height <= self.cursor.line {
+            self.scroll = self.cursor.line - (self.height-1);
+        }
+
+        let width = self.text_width(&self.windows[self.window_index(self.window)]);
+        if self.config.wrap {
+            // Long lines above the cursor can still push it off the bottom
+            self.left = 0;
+            while self.scroll < self.cursor.line &&
+                  self.cursor.get_display_xy(self.buf_op.buffer(), self.scroll, 0, Some(width)).1 >= self.height {
+                self.scroll += 1;
+            }
+        } else {
+            let (column, _) = self.cursor.get_display_xy(self.buf_op.buffer(), 0, 0, None);
+            if self.left > column {
+                self.left = column;
+            } else if self.left+width <= column {
+                self.left = column - (width-1);
+            }
+        }
+        self.dirty = true;
+    }
+
+    fn cursor_up(&mut self) {
+        self.cursor.move_up(self.buf_op.buffer());
+        self.scroll_to_cursor();
+    }
+
+    fn cursor_down(&mut self) {
+        self.cursor.move_down(self.buf_op.buffer());
+        self.scroll_to_cursor();
+    }
+
+    fn cursor_left(&mut self) {
+        self.cursor.move_left(self.buf_op.buffer());
+        self.scroll_to_cursor();
+    }
+
+    fn cursor_right(&mut self) {
+        self.cursor.move_right(self.buf_op.buffer());
+        self.scroll_to_cursor();
+    }
+
+    /// Moves the cursor up a row of the screen. Without wrapping that's a line, like k.
+    fn cursor_display_up(&mut self) {
+        if self.config.wrap {
+            let width = self.text_width(&self.windows[self.window_index(self.window)]);
+            self.cursor.move_display_up(self.buf_op.buffer(), width);
+            self.scroll_to_cursor();
+        } else {
+            self.cursor_up();
+        }
+    }
+
+    /// Moves the cursor down a row of the screen. Without wrapping that's a line, like j.
+    fn cursor_display_down(&mut self) {
+        if self.config.wrap {
+            let width = self.text_width(&self.windows[self.window_index(self.window)]);
+            self.cursor.move_display_down(self.buf_op.buffer(), width);
+            self.scroll_to_cursor();
+        } else {
+            self.cursor_down();
+        }
+    }
+}
+
+/// The part [from, to) of a line inside a block's display columns, including any wide character
+/// the block only partly covers. None if the line doesn't reach the block.
+fn block_range(line: &str, left: usize, right: usize) -> Option<(usize, usize)> {
+    let from = unicode::index_at_column(line, left);
+    if from >= line.len() {
+        return None;
+    }
+    Some((from, unicode::next_boundary(line, unicode::index_at_column(line, right))))
+}
+
+/// How a buffer is shown in messages
+fn buffer_name(path: &Option<String>) -> String {
+    path.clone().unwrap_or("[No Name]".to_string())
+}
+
+fn same_file(a: &str, b: &str) -> bool {
+    undo_file::canonical_path(Path::new(a)) == undo_file::canonical_path(Path::new(b))
+}
+
+/// Whether a normal mode command changes the buffer's text, or starts typing some
+fn changes_text(cmd: &NormalCommand) -> bool {
+    match *cmd {
+        NormalCommand::Move(_, _) => false,
+        NormalCommand::Operate(operator, _, _) | NormalCommand::OperateObject(operator, _, _) |
+        NormalCommand::OperateLines(operator, _) => operator != Operator::Yank,
+        NormalCommand::Action(ref action, _) => {
+            match *action {
+                Action::Operator(operator) => operator != Operator::Yank,
+                Action::InsertMode | Action::Undo | Action::Redo | Action::PutAfter | Action::PutBefore => true,
+                _ => false,
+            }
+        },
+    }
+}
+
+/// Whether a command that changes the text only types over it or goes through the undo history,
+/// which keeps a hex dump's layout
+fn keeps_layout(cmd: &NormalCommand) -> bool {
+    match *cmd {
+        NormalCommand::Action(Action::InsertMode, _) | NormalCommand::Action(Action::Undo, _) |
+        NormalCommand::Action(Action::Redo, _) => true,
+        _ => false,
+    }
+}
+
+
+#[test]
+fn ted_log_empty() {
+    let mut ted = Ted::new(0);
+    ted.log(Operation::Insert(0, "asdf".to_string()));
+
+    assert!(ted.undo_tree.len() == 2);
+    assert!(ted.undo_tree.node(1).ops == vec![Operation::Insert(0, "asdf".to_string())]);
+    assert!(ted.undo_tree.current() == 1);
+}
+
+#[test]
+fn ted_log_beginning() {
+    let mut ted = Ted::new(0);
+    ted.log(Operation::Insert(0, "asdf".to_string()));
+    ted.undo_tree.undo();
+    ted.log(Operation::Insert(0, "hi".to_string()));
+
+    // Logging after undoing to the beginning branches off the root
+    assert!(ted.undo_tree.node(0).children == vec![1, 2]);
+    assert!(ted.undo_tree.node(2).ops == vec![Operation::Insert(0, "hi".to_string())]);
+    assert!(ted.undo_tree.current() == 2);
+}
+
+#[test]
+fn ted_log_middle() {
+    let mut ted = Ted::new(0);
+    ted.log(Operation::Insert(0, "asdf".to_string()));
+    ted.log(Operation::Insert(0, "asdf".to_string()));
+    ted.log(Operation::Insert(0, "asdf".to_string()));
+    ted.undo_tree.undo();
+    ted.log(Operation::Insert(0, "hi".to_string()));
+
+    // The undone step is kept on its own branch
+    assert!(ted.undo_tree.node(2).children == vec![3, 4]);
+    assert!(ted.undo_tree.branches() == vec![3, 4]);
+    assert!(ted.undo_tree.current() == 4);
+}
+
+#[test]
+fn ted_log_end() {
+    let mut ted = Ted::new(0);
+    ted.log(Operation::Insert(0, "asdf".to_string()));
+    ted.log(Operation::Insert(0, "hi".to_string()));
+
+    assert!(ted.undo_tree.node(1).children == vec![2]);
+    assert!(ted.undo_tree.branches() == vec![2]);
+    assert!(ted.undo_tree.current() == 2);
+}
+
+#[test]
+fn ted_undo_insert_session() {
+    let mut ted = Ted::from_string(10, "hello".to_string());
+    ted.handle_event(Event::Char('i'));
+    ted.handle_event(Event::Char('a'));
+    ted.handle_event(Event::Char('b'));
+    ted.handle_event(Event::Enter);
+    ted.handle_event(Event::Esc);
+    assert!(ted.buffer().to_string() == "ab\nhello");
+
+    ted.handle_event(Event::Char('u'));
+    assert!(ted.buffer().to_string() == "hello");
+    assert!(ted.cursor.buf_index == 0 && ted.cursor.line == 0);
+
+    ted.handle_event(Event::Ctrl('r'));
+    assert!(ted.buffer().to_string() == "ab\nhello");
+    assert!(ted.cursor.line == 1);
+}
+
+#[test]
+fn ted_undo_separate_sessions() {
+    let mut ted = Ted::new(10);
+    for c in "iab".chars() { ted.handle_event(Event::Char(c)); }
+    ted.handle_event(Event::Esc);
+    for c in "icd".chars() { ted.handle_event(Event::Char(c)); }
+    ted.handle_event(Event::Esc);
+
+    ted.undo();
+    assert!(ted.buffer().to_string() == "ab");
+    ted.undo();
+    assert!(ted.buffer().to_string() == "");
+    ted.undo();
+    assert!(ted.buffer().to_string() == "");
+    ted.redo();
+    assert!(ted.buffer().to_string() == "ab");
+}
 
 #[test]
-fn ted_log_end() {
-    let mut ted = Ted::new(0);
-    ted.log(Operation::Insert(0, "asdf".to_string()));
-    ted.log(Operation::Insert(0, "hi".to_string()));
+fn ted_earlier_later_commands() {
+    let mut ted = Ted::new(10);
+    for c in "iab".chars() { ted.handle_event(Event::Char(c)); }
+    ted.handle_event(Event::Esc);
+    ted.undo();
+    for c in "icd".chars() { ted.handle_event(Event::Char(c)); }
+    ted.handle_event(Event::Esc);
+
+    ted.execute_command("earlier".to_string());
+    assert!(ted.buffer().to_string() == "ab");
+    ted.execute_command("later 1".to_string());
+    assert!(ted.buffer().to_string() == "cd");
+    ted.execute_command("earlier 10m".to_string());
+    assert!(ted.buffer().to_string() == "");
+    ted.execute_command("undo 1".to_string());
+    assert!(ted.buffer().to_string() == "ab");
+
+    // Too far to count is refused, as far as can be counted goes to either end
+    ted.execute_command("earlier 999999999999999d".to_string());
+    assert!(ted.message() == Some("Invalid time: 999999999999999d"));
+    ted.execute_command("later 9223372036854775807s".to_string());
+    assert!(ted.buffer().to_string() == "cd");
+    ted.execute_command("earlier 9223372036854775807s".to_string());
+    assert!(ted.buffer().to_string() == "");
+    ted.execute_command("later 9223372036854775807".to_string());
+    assert!(ted.buffer().to_string() == "cd");
+}
+
+#[cfg(test)]
+fn type_keys(ted: &mut Ted, keys: &str) {
+    for c in keys.chars() {
+        ted.handle_event(Event::Char(c));
+    }
+}
+
+#[test]
+fn ted_delete_word() {
+    let mut ted = Ted::from_string(10, "foo bar baz\nqux".to_string());
+    type_keys(&mut ted, "wdw");
+    assert!(ted.buffer().to_string() == "foo baz\nqux");
+
+    // dw on the last word of a line leaves the line break alone
+    type_keys(&mut ted, "dw");
+    assert!(ted.buffer().to_string() == "foo \nqux");
+
+    ted.undo();
+    assert!(ted.buffer().to_string() == "foo baz\nqux");
+}
+
+#[test]
+fn ted_delete_till_and_count() {
+    let mut ted = Ted::from_string(10, "a(b, c), d".to_string());
+    type_keys(&mut ted, "dt,");
+    assert!(ted.buffer().to_string() == ", c), d");
+
+    type_keys(&mut ted, "2dl");
+    assert!(ted.buffer().to_string() == "c), d");
+
+    type_keys(&mut ted, "d$");
+    assert!(ted.buffer().to_string() == "");
+}
+
+#[test]
+fn ted_delete_lines() {
+    let mut ted = Ted::from_string(10, "one\ntwo\nthree\nfour".to_string());
+    type_keys(&mut ted, "j2dd");
+    assert!(ted.buffer().to_string() == "one\nfour");
+    assert!(ted.cursor.line == 1);
+
+    type_keys(&mut ted, "dgg");
+    assert!(ted.buffer().to_string() == "");
+}
+
+#[test]
+fn ted_change_is_one_undo_step() {
+    let mut ted = Ted::from_string(10, "foo bar".to_string());
+    type_keys(&mut ted, "cwbaz");
+    ted.handle_event(Event::Esc);
+    assert!(ted.buffer().to_string() == "baz bar");
+
+    ted.undo();
+    assert!(ted.buffer().to_string() == "foo bar");
+}
+
+#[test]
+fn ted_indent_lines() {
+    let mut ted = Ted::from_string(10, "a\n\nb\n  c".to_string());
+    type_keys(&mut ted, ">G");
+    assert!(ted.buffer().to_string() == "    a\n\n    b\n      c");
+
+    type_keys(&mut ted, "3<<");
+    assert!(ted.buffer().to_string() == "a\n\nb\n      c");
+
+    // Indenting every line is a single undo step
+    ted.undo();
+    ted.undo();
+    assert!(ted.buffer().to_string() == "a\n\nb\n  c");
+}
+
+#[test]
+fn ted_yank_back_moves_to_start() {
+    let mut ted = Ted::from_string(10, "foo bar".to_string());
+    type_keys(&mut ted, "wyb");
+    assert!(ted.registers.get(None).unwrap().text == "foo ");
+    assert!(ted.cursor.buf_index == 0);
+    assert!(ted.buffer().to_string() == "foo bar");
+}
+
+#[test]
+fn ted_change_inside_quotes() {
+    let mut ted = Ted::from_string(10, "say(\"hello\", x)".to_string());
+    type_keys(&mut ted, "ci\"bye");
+    ted.handle_event(Event::Esc);
+    assert!(ted.buffer().to_string() == "say(\"bye\", x)");
+
+    type_keys(&mut ted, "da(");
+    assert!(ted.buffer().to_string() == "say");
+}
+
+#[test]
+fn ted_nmap_rebinds_keys() {
+    let mut ted = Ted::from_string(10, "one\ntwo\nthree".to_string());
+    ted.execute_command("nmap Q dd".to_string());
+    type_keys(&mut ted, "2Q");
+    assert!(ted.buffer().to_string() == "three");
+
+    // Mapping a key to itself doesn't hang
+    ted.execute_command("nmap x x".to_string());
+    type_keys(&mut ted, "x");
+    assert!(ted.message() == Some("Recursive mapping"));
+
+    ted.execute_command("nmap <foo> x".to_string());
+    assert!(ted.message() == Some("Unknown key: <foo>"));
+}
+
+#[test]
+fn ted_imap_waits_for_longer_mapping() {
+    let mut ted = Ted::new(10);
+    ted.execute_command("imap jk <Esc>".to_string());
+    type_keys(&mut ted, "iajbjk");
+    assert!(ted.buffer().to_string() == "ajb");
+    assert!(ted.mode() == Mode::Normal);
+
+    // A lone j is typed once the mapping times out
+    type_keys(&mut ted, "ij");
+    assert!(ted.buffer().to_string() == "ajb");
+    ted.handle_pending_keys(true);
+    assert!(ted.buffer().to_string() == "ajbj");
+}
+
+#[test]
+fn ted_source_config() {
+    use std::env;
+    use std::io::Write;
+
+    let path = env::temp_dir().join("ted_source_config_test.toml");
+    {
+        let mut file = File::create(&path).unwrap();
+        file.write_all(b"tab_width = 2\n[keys.normal]\nQ = \"dd\"\n").unwrap();
+    }
+
+    let mut ted = Ted::from_string(10, "a\nb".to_string());
+    ted.execute_command(format!("source {}", path.display()));
+    assert!(ted.config().tab_width == 2);
+    type_keys(&mut ted, ">>Q");
+    assert!(ted.buffer().to_string() == "b");
+
+    // Errors go to the status line and leave the old settings alone
+    File::create(&path).unwrap().write_all(b"tab_width = -1\n").unwrap();
+    ted.execute_command(format!("source {}", path.display()));
+    assert!(ted.message() == Some(format!("{}: tab_width must be a positive number", path.display()).as_str()));
+    assert!(ted.config().tab_width == 2);
+
+    ted.execute_command("source /nonexistent/ted/config.toml".to_string());
+    assert!(ted.message().unwrap().starts_with("Failed to read /nonexistent/ted/config.toml"));
+}
+
+#[test]
+fn ted_put_lines() {
+    let mut ted = Ted::from_string(10, "one\ntwo\nthree".to_string());
+    type_keys(&mut ted, "ddp");
+    assert!(ted.buffer().to_string() == "two\none\nthree");
+    assert!(ted.cursor.line == 1);
+
+    type_keys(&mut ted, "yyGp");
+    assert!(ted.buffer().to_string() == "two\none\nthree\none");
+
+    type_keys(&mut ted, "gg2P");
+    assert!(ted.buffer().to_string() == "one\none\ntwo\none\nthree\none");
+}
+
+#[test]
+fn ted_put_chars() {
+    let mut ted = Ted::from_string(10, "foo bar".to_string());
+    type_keys(&mut ted, "dwp");
+    assert!(ted.buffer().to_string() == "bfoo ar");
+    assert!(ted.cursor.buf_index == 4);
+
+    type_keys(&mut ted, "0P");
+    assert!(ted.buffer().to_string() == "foo bfoo ar");
+
+    // Putting is undone in one step
+    ted.undo();
+    assert!(ted.buffer().to_string() == "bfoo ar");
+}
+
+#[test]
+fn ted_named_registers() {
+    let mut ted = Ted::from_string(10, "one\ntwo\nthree".to_string());
+    type_keys(&mut ted, "\"ayyj\"Ayyjdd");
+    assert!(ted.registers.get(Some('a')).unwrap().text == "one\ntwo\n");
+
+    // The black hole register leaves the unnamed register alone
+    type_keys(&mut ted, "gg\"_dd");
+    assert!(ted.registers.get(None).unwrap().text == "three\n");
+
+    type_keys(&mut ted, "\"ap");
+    assert!(ted.buffer().to_string() == "two\none\ntwo");
+    type_keys(&mut ted, "\"1P");
+    assert!(ted.buffer().to_string() == "two\nthree\none\ntwo");
+}
+
+#[test]
+fn ted_visual_char_delete() {
+    let mut ted = Ted::from_string(10, "foo bar\nbaz".to_string());
+    type_keys(&mut ted, "lvw");
+    assert!(ted.selection() == Some(Selection::Chars(1, 5)));
+    assert!(ted.line_selection(0) == Some((1, 5)));
+    assert!(ted.line_selection(1) == None);
+
+    type_keys(&mut ted, "d");
+    assert!(ted.buffer().to_string() == "far\nbaz");
+    assert!(ted.mode() == Mode::Normal);
+    assert!(ted.registers.get(None).unwrap().text == "oo b");
+}
+
+#[test]
+fn ted_visual_line_yank_and_switch() {
+    let mut ted = Ted::from_string(10, "one\ntwo\nthree".to_string());
+    type_keys(&mut ted, "vjV");
+    assert!(ted.selection() == Some(Selection::Lines(0, 1)));
+    assert!(ted.line_selection(1) == Some((0, 4)));
+
+    type_keys(&mut ted, "yGp");
+    assert!(ted.buffer().to_string() == "one\ntwo\nthree\none\ntwo");
+
+    // Pressing v in visual character mode leaves visual mode
+    type_keys(&mut ted, "vv");
+    assert!(ted.mode() == Mode::Normal);
+}
+
+#[test]
+fn ted_visual_toggle_case_and_swap_ends() {
+    let mut ted = Ted::from_string(10, "Hello world".to_string());
+    type_keys(&mut ted, "wvlo");
+    assert!(ted.cursor.buf_index == 6);
+    type_keys(&mut ted, "h~");
+    assert!(ted.buffer().to_string() == "Hello WOrld");
+
+    type_keys(&mut ted, "g~~");
+    assert!(ted.buffer().to_string() == "hELLO woRLD");
+
+    let mut ted = Ted::from_string(10, "Straße İ".to_string());
+    type_keys(&mut ted, "g~~");
+    assert!(ted.buffer().to_string() == "sTRASSE i\u{307}");
+}
+
+#[test]
+fn ted_visual_block_delete() {
+    let mut ted = Ted::from_string(10, "abcd\nef\nghij".to_string());
+    ted.handle_event(Event::Char('l'));
+    ted.handle_event(Event::Ctrl('v'));
+    type_keys(&mut ted, "jjl");
+    assert!(ted.selection() == Some(Selection::Block { first: 0, last: 2, left: 1, right: 2 }));
+    assert!(ted.line_selection(1) == Some((1, 2)));
+
+    type_keys(&mut ted, "d");
+    assert!(ted.buffer().to_string() == "ad\ne\ngj");
+    assert!(ted.registers.get(None).unwrap().text == "bc\nf\nhi");
+    assert!(ted.cursor.buf_index == 1);
+
+    // The whole block is one undo step
+    ted.undo();
+    assert!(ted.buffer().to_string() == "abcd\nef\nghij");
+}
+
+#[test]
+fn ted_visual_block_delete_wide() {
+    let mut ted = Ted::from_string(10, "abcd\n日本語".to_string());
+    ted.handle_event(Event::Char('l'));
+    ted.handle_event(Event::Ctrl('v'));
+    type_keys(&mut ted, "jl");
+    // Columns 1 to 3 take in the second half of 日 and all of 本
+    assert!(ted.selection() == Some(Selection::Block { first: 0, last: 1, left: 1, right: 3 }));
+    assert!(ted.line_selection(0) == Some((1, 4)));
+    assert!(ted.line_selection(1) == Some((0, 6)));
+
+    type_keys(&mut ted, "d");
+    assert!(ted.buffer().to_string() == "a\n語");
+    assert!(ted.registers.get(None).unwrap().text == "bcd\n日本");
+
+    ted.undo();
+    assert!(ted.buffer().to_string() == "abcd\n日本語");
+}
+
+#[test]
+fn ted_visual_block_change() {
+    let mut ted = Ted::from_string(10, "let a\nlet b\n\nlet c".to_string());
+    ted.handle_event(Event::Ctrl('v'));
+    type_keys(&mut ted, "jjjllcvar");
+    ted.handle_event(Event::Esc);
+    // Lines too short to reach the block are left alone
+    assert!(ted.buffer().to_string() == "var a\nvar b\n\nvar c");
+
+    ted.undo();
+    assert!(ted.buffer().to_string() == "let a\nlet b\n\nlet c");
+}
+
+#[test]
+fn ted_search() {
+    let mut ted = Ted::from_string(10, "foo bar\nbar foo\nbaz".to_string());
+    type_keys(&mut ted, "/ba");
+    // The cursor follows the pattern as it's typed, and matches are highlighted
+    assert!(ted.cursor.buf_index == 4);
+    assert!(ted.line_matches(1) == vec![(0, 2)]);
+    type_keys(&mut ted, "z");
+    assert!(ted.cursor.buf_index == 16);
+    ted.handle_event(Event::Backspace);
+    ted.handle_event(Event::Enter);
+    assert!(ted.cursor.buf_index == 4);
+
+    type_keys(&mut ted, "nn");
+    assert!(ted.cursor.buf_index == 16);
+    type_keys(&mut ted, "n");
+    assert!(ted.cursor.buf_index == 4);
+    assert!(ted.message() == Some("Search hit BOTTOM, continuing at TOP"));
+    type_keys(&mut ted, "N");
+    assert!(ted.cursor.buf_index == 16);
+
+    type_keys(&mut ted, ":noh");
+    ted.handle_event(Event::Enter);
+    assert!(ted.line_matches(1).is_empty());
+}
+
+#[test]
+fn ted_search_backward_and_cancel() {
+    let mut ted = Ted::from_string(10, "a1 b2 c3".to_string());
+    type_keys(&mut ted, "$?[a-z]");
+    ted.handle_event(Event::Enter);
+    assert!(ted.cursor.buf_index == 6);
+
+    // n keeps going backward after ?, N goes forward
+    type_keys(&mut ted, "n");
+    assert!(ted.cursor.buf_index == 3);
+    type_keys(&mut ted, "N");
+    assert!(ted.cursor.buf_index == 6);
+
+    // Esc puts the cursor back
+    type_keys(&mut ted, "/a");
+    assert!(ted.cursor.buf_index == 0);
+    ted.handle_event(Event::Esc);
+    assert!(ted.cursor.buf_index == 6);
+    assert!(ted.mode() == Mode::Normal);
+
+    type_keys(&mut ted, "/x");
+    ted.handle_event(Event::Enter);
+    assert!(ted.message() == Some("Pattern not found: x"));
+    assert!(ted.cursor.buf_index == 6);
+}
+
+#[test]
+fn ted_search_history() {
+    let mut ted = Ted::from_string(10, "one two one two".to_string());
+    type_keys(&mut ted, "/two");
+    ted.handle_event(Event::Enter);
+    type_keys(&mut ted, "/one");
+    ted.handle_event(Event::Enter);
+    assert!(ted.cursor.buf_index == 8);
+
+    // An empty pattern repeats the last search
+    type_keys(&mut ted, "/");
+    ted.handle_event(Event::Enter);
+    assert!(ted.cursor.buf_index == 0);
+
+    type_keys(&mut ted, "/");
+    ted.handle_event(Event::Up);
+    ted.handle_event(Event::Up);
+    assert!(ted.command_buffer().to_string() == "two");
+    ted.handle_event(Event::Down);
+    assert!(ted.command_buffer().to_string() == "one");
+    ted.handle_event(Event::Up);
+    ted.handle_event(Event::Enter);
+    assert!(ted.cursor.buf_index == 4);
+}
+
+#[test]
+fn ted_substitute() {
+    let mut ted = Ted::from_string(10, "foo = 1\nbar = 2\nfoo = foo".to_string());
+    ted.execute_command("s/foo/baz/".to_string());
+    assert!(ted.buffer().to_string() == "baz = 1\nbar = 2\nfoo = foo");
+
+    ted.execute_command("%s/(\\w+) = (\\w+)/\\2 = \\1/g".to_string());
+    assert!(ted.buffer().to_string() == "1 = baz\n2 = bar\nfoo = foo");
+    assert!(ted.message() == Some("3 substitutions on 3 lines"));
+    assert!(ted.cursor.line == 2);
+
+    // The whole substitution is one undo step
+    ted.undo();
+    assert!(ted.buffer().to_string() == "baz = 1\nbar = 2\nfoo = foo");
+
+    ted.execute_command("2,$s/o/0/g".to_string());
+    assert!(ted.buffer().to_string() == "baz = 1\nbar = 2\nf00 = f00");
+    ted.execute_command("1s/=/\\r/".to_string());
+    assert!(ted.buffer().to_string() == "baz \n 1\nbar = 2\nf00 = f00");
+
+    ted.execute_command("%s/nope/x/".to_string());
+    assert!(ted.message() == Some("Pattern not found: nope"));
+}
+
+#[test]
+fn ted_substitute_confirm() {
+    let mut ted = Ted::from_string(10, "a a\na".to_string());
+    type_keys(&mut ted, ":%s/a/b/gc");
+    ted.handle_event(Event::Enter);
+    assert!(ted.mode() == Mode::Confirm);
+    assert!(ted.line_matches(0) == vec![(0, 1)]);
+
+    type_keys(&mut ted, "n");
+    assert!(ted.cursor.buf_index == 2);
+    type_keys(&mut ted, "y");
+    assert!(ted.buffer().to_string() == "a b\na");
+    assert!(ted.line_matches(1) == vec![(0, 1)]);
+    type_keys(&mut ted, "q");
+    assert!(ted.mode() == Mode::Normal);
+    assert!(ted.message() == Some("1 substitution on 1 line"));
+
+    type_keys(&mut ted, ":%s/a/c/c");
+    ted.handle_event(Event::Enter);
+    type_keys(&mut ted, "a");
+    assert!(ted.buffer().to_string() == "c b\nc");
+}
+
+#[test]
+fn ted_substitute_visual_range() {
+    let mut ted = Ted::from_string(10, "x\nx\nx\nx".to_string());
+    type_keys(&mut ted, "jVj:");
+    assert!(ted.command_buffer().to_string() == "'<,'>");
+    type_keys(&mut ted, "s/x/y/");
+    ted.handle_event(Event::Enter);
+    assert!(ted.buffer().to_string() == "x\ny\ny\nx");
+}
+
+#[test]
+fn ted_ex_unknown_command() {
+    let mut ted = Ted::from_string(10, "a".to_string());
+    ted.execute_command("frobnicate".to_string());
+    assert!(ted.message() == Some("Not an editor command: frobnicate"));
+    ted.execute_command("3,1000d".to_string());
+    assert!(ted.message() == Some("Invalid range"));
+    assert!(ted.buffer().to_string() == "a");
+}
+
+#[test]
+fn ted_ex_write_quit() {
+    use std::env;
+
+    let path = env::temp_dir().join("ted_ex_write_test.txt");
+    let mut ted = Ted::from_string(10, "hello\nworld".to_string());
+    ted.execute_command("w".to_string());
+    assert!(ted.message() == Some("No file name"));
+
+    ted.execute_command(format!("w \"{}\"", path.display()));
+    let mut text = String::new();
+    File::open(&path).unwrap().read_to_string(&mut text).unwrap();
+    assert!(text == "hello\nworld");
+    assert!(ted.running);
+
+    ted.execute_command("q".to_string());
+    assert!(!ted.running);
+}
+
+#[test]
+fn ted_ex_write_and_quit() {
+    use std::env;
+
+    let path = env::temp_dir().join("ted_ex_wq_test.txt");
+    let mut ted = Ted::from_string(10, "x".to_string());
+    ted.execute_command(format!("wq {}", path.display()));
+    assert!(path.exists());
+    assert!(!ted.running);
+}
+
+#[test]
+fn ted_ex_edit_and_read() {
+    use std::env;
+    use std::io::Write;
+
+    let path = env::temp_dir().join("ted_ex_edit_test.txt");
+    {
+        let mut file = File::create(&path).unwrap();
+        file.write_all(b"one\ntwo\n").unwrap();
+    }
+
+    let mut ted = Ted::from_string(10, "a\nb".to_string());
+    ted.execute_command(format!("r {}", path.display()));
+    assert!(ted.buffer().to_string() == "a\none\ntwo\nb");
+    assert!(ted.cursor.line == 1);
+    ted.execute_command(format!("0r {}", path.display()));
+    assert!(ted.buffer().to_string() == "one\ntwo\na\none\ntwo\nb");
+    ted.execute_command(format!("$r {}", path.display()));
+    assert!(ted.buffer().to_string() == "one\ntwo\na\none\ntwo\nb\none\ntwo");
+
+    // The changed buffer is kept while the file is edited
+    ted.execute_command(format!("e {}", path.display()));
+    assert!(ted.buffer().to_string() == "one\ntwo\n");
+    assert!(ted.path == Some(path.display().to_string()));
+    assert!(ted.buffer_count() == 2);
+    ted.execute_command("1d".to_string());
+    ted.execute_command("e".to_string());
+    assert!(ted.message() == Some("No write since last change (add ! to override)"));
+    ted.execute_command("e!".to_string());
+    assert!(ted.buffer().to_string() == "one\ntwo\n");
+    assert!(!ted.is_modified());
+    ted.execute_command("e /nonexistent/ted/file".to_string());
+    assert!(ted.message().unwrap().starts_with("Failed to open /nonexistent/ted/file"));
+}
+
+#[test]
+fn ted_buffers() {
+    use std::env;
+    use std::io::Write;
+
+    let paths: Vec<String> = ["ted_buffers_test_a.txt", "ted_buffers_test_b.txt"].iter().map(|name| {
+        let path = env::temp_dir().join(name);
+        File::create(&path).unwrap().write_all(name.as_bytes()).unwrap();
+        path.display().to_string()
+    }).collect();
+
+    // An empty buffer is replaced by the first file
+    let mut ted = Ted::new(10);
+    ted.execute_command(format!("e {}", paths[0]));
+    ted.execute_command(format!("e {}", paths[1]));
+    assert!(ted.buffer_count() == 2);
+    assert!(ted.buffer().to_string() == "ted_buffers_test_b.txt");
+
+    // Each buffer keeps its cursor and changes
+    ted.execute_command("s/b\\./B./".to_string());
+    type_keys(&mut ted, "$");
+    ted.execute_command("bn".to_string());
+    assert!(ted.buffer().to_string() == "ted_buffers_test_a.txt");
+    assert!(ted.message() == Some(format!("\"{}\" 1L", paths[0]).as_str()));
+    ted.execute_command("ls".to_string());
+    assert!(ted.messages().iter().map(|m| m.text.clone()).collect::<Vec<String>>() ==
+            vec![format!("  1 %  \"{}\" line 1", paths[0]), format!("  2 h+ \"{}\" line 1", paths[1])]);
+    ted.execute_command("b test_b".to_string());
+    assert!(ted.buffer().to_string() == "ted_buffers_test_B.txt");
+    assert!(ted.cursor.buf_index == 21);
+    ted.execute_command("u".to_string());
+    ted.execute_command("bp".to_string());
+    ted.execute_command(format!("e {}", paths[1]));
+    assert!(ted.buffer().to_string() == "ted_buffers_test_b.txt");
+    ted.execute_command("b test".to_string());
+    assert!(ted.message() == Some("More than one match for test"));
+
+    // Changes in hidden buffers stop :q and :bd
+    ted.execute_command("s/b\\./B./".to_string());
+    ted.execute_command("b1".to_string());
+    ted.execute_command("q".to_string());
+    assert!(ted.message() == Some("No write since last change for buffer 2 (add ! to override)"));
+    ted.execute_command("bd 2".to_string());
+    assert!(ted.message() == Some("No write since last change for buffer 2 (add ! to override)"));
+    ted.execute_command("bd! 2".to_string());
+    assert!(ted.buffer_count() == 1);
+    ted.execute_command("bd".to_string());
+    assert!(ted.path == None && ted.buffer().to_string() == "");
+    ted.execute_command("q".to_string());
+    assert!(!ted.running);
+}
+
+#[test]
+fn ted_windows() {
+    let mut ted = Ted::from_string(21, "one\ntwo\nthree".to_string());
+    ted.resize(81, 21);
+    ted.execute_command("vs".to_string());
+    let windows = ted.windows();
+    assert!(windows.len() == 2);
+    assert!(windows[1].id == ted.active_window() && windows[1].rect.x == 41);
+    assert!(ted.height == 20);
+
+    // Each window has its own cursor, and changes move the other windows' cursors along
+    type_keys(&mut ted, "j");
+    ted.handle_event(Event::Ctrl('w'));
+    ted.handle_event(Event::Char('h'));
+    assert!(ted.active_window() == windows[0].id);
+    assert!(ted.cursor.line == 0);
+    type_keys(&mut ted, "dd");
+    assert!(ted.windows()[1].cursor.line == 0);
+    assert!(ted.windows()[1].cursor.buf_index == 0);
+
+    ted.execute_command("sp".to_string());
+    assert!(ted.windows().iter().map(|w| w.rect.height).collect::<Vec<usize>>() == vec![10, 9, 20]);
+    assert!(ted.height == 9);
+    ted.handle_event(Event::Ctrl('w'));
+    ted.handle_event(Event::Char('o'));
+    assert!(ted.windows().len() == 1);
+    assert!(ted.height == 21);
+
+    // :q closes windows until the last one
+    ted.execute_command("vs".to_string());
+    ted.execute_command("q".to_string());
+    assert!(ted.windows().len() == 1 && ted.running);
+    ted.execute_command("close".to_string());
+    assert!(ted.message() == Some("Cannot close last window"));
+}
+
+#[test]
+fn ted_resize_keeps_cursors_in_view() {
+    let text: Vec<String> = (0..30).map(|i| i.to_string()).collect();
+    let mut ted = Ted::from_string(20, text.join("\n"));
+    ted.resize(80, 20);
+    ted.execute_command("sp".to_string());
+    type_keys(&mut ted, "8j");
+    ted.handle_event(Event::Ctrl('w'));
+    ted.handle_event(Event::Char('k'));
+    type_keys(&mut ted, "5j");
+    assert!(ted.scroll == 0);
+
+    ted.resize(80, 8);
+    let windows = ted.windows();
+    assert!(windows.iter().map(|w| w.rect.height).collect::<Vec<usize>>() == vec![3, 3]);
+    assert!(ted.height == 3 && ted.scroll == 3);
+    assert!(windows[1].scroll == 6);
+
+    ted.resize(40, 40);
+    assert!(ted.height == 19 && ted.scroll == 3);
+}
+
+#[test]
+fn ted_horizontal_scroll_and_wrap() {
+    let mut ted = Ted::from_string(3, "short\n0123456789abcdefghij\nend".to_string());
+    ted.resize(13, 3); // Room for 10 columns of text
+
+    // The view follows the cursor sideways
+    type_keys(&mut ted, "j$");
+    assert!(ted.left == 10);
+    assert!(ted.cursor.get_display_xy(ted.buffer(), ted.scroll, ted.left, None) == (9, 1));
+    type_keys(&mut ted, "0");
+    assert!(ted.left == 0);
+
+    // With wrapping, gj and gk go by rows of the screen
+    ted.config.wrap = true;
+    type_keys(&mut ted, "gj");
+    assert!(ted.cursor.line == 1 && ted.cursor.column == 10);
+    assert!(ted.cursor.get_display_xy(ted.buffer(), ted.scroll, ted.left, Some(10)) == (0, 2));
+    type_keys(&mut ted, "gj");
+    assert!(ted.cursor.line == 2 && ted.cursor.column == 0);
+    assert!(ted.scroll == 1); // The long line takes two of the three rows
+    type_keys(&mut ted, "gk");
+    assert!(ted.cursor.line == 1 && ted.cursor.column == 10);
+    type_keys(&mut ted, "gkgk");
+    assert!(ted.cursor.line == 0 && ted.scroll == 0);
+
+    // Without wrapping they go by lines
+    ted.config.wrap = false;
+    type_keys(&mut ted, "lgj");
+    assert!(ted.cursor.line == 1 && ted.cursor.column == 1);
+}
+
+#[test]
+fn ted_random_unicode_editing() {
+    use unicode::{self, Random};
+
+    const KEYS: &'static [&'static str] = &["h", "l", "j", "k", "gj", "gk", "0", "$", "w", "e", "b", "x", "X",
+                                             "dl", "i", "a", "A", "o"];
+    let mut random = Random(0x9e3779b97f4a7c15);
+    let text: String = (0..200).map(|_| random.sample()).collect();
+    let mut ted = Ted::from_string(5, text);
+    ted.resize(13, 5);
+
+    for step in 0..3000 {
+        if step % 500 == 0 {
+            ted.config.wrap = !ted.config.wrap;
+            ted.scroll_to_cursor();
+        }
+        type_keys(&mut ted, KEYS[random.below(KEYS.len())]);
+        if ted.mode == Mode::Insert {
+            for _ in 0..random.below(4) {
+                match random.sample() {
+                    "\n" => { ted.handle_event(Event::Enter); },
+                    sample => { type_keys(&mut ted, sample); },
+                }
+            }
+            if random.below(2) == 0 {
+                ted.handle_event(Event::Backspace);
+            }
+            ted.handle_event(Event::Esc);
+        }
+
+        // The cursor stays on a grapheme cluster, with its line and column to match
+        let index = ted.cursor.buf_index as usize;
+        let line = ted.buffer().line_at_index(index);
+        let info = ted.buffer().line_info(line);
+        assert!(ted.buffer().grapheme_start(index) == index);
+        assert!(ted.cursor.line as usize == line);
+        assert!(unicode::index_at_column(&ted.buffer().line(line), ted.cursor.column as usize) == index - info.buf_index);
+
+        // And in view, at the width of the text before it. Scrolling goes by whole lines, so a
+        // line that wraps onto more rows than the window has can't be shown all at once.
+        let wrap = if ted.config.wrap { Some(10) } else { None };
+        let (x, y) = ted.cursor.get_display_xy(ted.buffer(), ted.scroll, ted.left, wrap);
+        let rows = unicode::wrap_rows(&ted.buffer().line(line), 10).len() as u64;
+        assert!(x <= 10 && (y < ted.height || rows > ted.height));
+        if !ted.config.wrap {
+            let width = unicode::width(&ted.buffer().line(line)[..index - info.buf_index]);
+            assert!(x as usize + ted.left as usize == width);
+        }
+    }
+}
+
+#[test]
+fn ted_tab_pages() {
+    let mut ted = Ted::from_string(21, "one\ntwo".to_string());
+    ted.resize(80, 21);
+    ted.execute_command("tabnew".to_string());
+    assert!(ted.buffer().to_string() == "" && ted.buffer_count() == 2);
+    assert!(ted.tab_titles() == (vec!["[No Name]".to_string(), "[No Name]".to_string()], 1));
+    assert!(ted.windows()[0].rect == Rect { x: 0, y: 1, width: 80, height: 20 });
+
+    type_keys(&mut ted, "gt");
+    assert!(ted.buffer().to_string() == "one\ntwo");
+    assert!(ted.tab_titles().1 == 0);
+    ted.execute_command("tabn".to_string());
+    ted.execute_command("tabclose".to_string());
+    assert!(ted.tab_titles().0.len() == 1);
+    assert!(ted.buffer().to_string() == "one\ntwo");
+    assert!(ted.height == 21);
+    ted.execute_command("tabc".to_string());
+    assert!(ted.message() == Some("Cannot close last tab page"));
+}
+
+#[test]
+fn ted_quit_with_unsaved_changes() {
+    use std::env;
+
+    let path = env::temp_dir().join("ted_quit_modified_test.txt");
+    let mut ted = Ted::from_string(10, "a".to_string());
+    assert!(!ted.is_modified());
+    ted.execute_command("d".to_string());
+    assert!(ted.is_modified());
+
+    ted.execute_command("q".to_string());
+    assert!(ted.message() == Some("No write since last change (add ! to override)"));
+    assert!(ted.running);
+
+    ted.execute_command(format!("w {}", path.display()));
+    assert!(ted.message() == Some(format!("\"{}\" 1L written", path.display()).as_str()));
+    assert!(!ted.is_modified());
+    ted.execute_command("u".to_string());
+    assert!(ted.is_modified());
+    ted.execute_command("q!".to_string());
+    assert!(!ted.running);
+}
+
+#[test]
+fn ted_file_changed_on_disk() {
+    use std::env;
+    use std::io::Write;
+
+    let path = env::temp_dir().join("ted_file_changed_test.txt");
+    let write = |text: &str| File::create(&path).unwrap().write_all(text.as_bytes()).unwrap();
+    write("one\ntwo\nthree\n");
+    let mut ted = Ted::from_file(10, path.display().to_string()).unwrap();
+    ted.check_file_now();
+    assert!(ted.mode() == Mode::Normal);
+
+    // Reloading takes the file's text
+    write("one\n2\nthree\n");
+    ted.check_file_now();
+    assert!(ted.mode() == Mode::FileChanged);
+    assert!(ted.message().unwrap().ends_with("changed on disk: (r)eload or (k)eep?"));
+    ted.handle_event(Event::Char('r'));
+    assert!(ted.mode() == Mode::Normal);
+    assert!(ted.buffer().to_string() == "one\n2\nthree\n");
+    assert!(!ted.is_modified());
+
+    // Changes on both sides are merged, and :w doesn't clobber the file's without a !
+    ted.execute_command("1d".to_string());
+    write("one\n2\nthree\nfour\n");
+    ted.execute_command("w".to_string());
+    assert!(ted.message() == Some("File changed since reading it (add ! to override)"));
+    ted.check_file_now();
+    assert!(ted.mode() == Mode::FileChanged);
+    ted.handle_event(Event::Char('m'));
+    assert!(ted.buffer().to_string() == "2\nthree\nfour\n");
+    assert!(ted.message() == Some("Merged"));
+    assert!(ted.is_modified());
+    ted.execute_command("w".to_string());
+    assert!(!ted.is_modified());
+
+    // Keeping the buffer makes the next :w overwrite the file
+    write("x\n");
+    ted.check_file_now();
+    ted.handle_event(Event::Char('k'));
+    assert!(ted.is_modified());
+    ted.execute_command("w".to_string());
+    let mut text = String::new();
+    File::open(&path).unwrap().read_to_string(&mut text).unwrap();
+    assert!(text == "2\nthree\nfour\n");
+
+    // Changes are still noticed in hex view and after it, and reloading there shows the bytes
+    ted.execute_command("hex".to_string());
+    write("2\nthree\nfour\nfive\n");
+    ted.check_file_now();
+    assert!(ted.message().unwrap().ends_with("changed on disk: (r)eload or (k)eep?"));
+    ted.handle_event(Event::Char('r'));
+    assert!(ted.buffer().to_string() == hex::dump(b"2\nthree\nfour\nfive\n"));
+    ted.execute_command("w".to_string());
+    ted.check_file_now();
+    assert!(ted.mode() == Mode::Normal);
+    ted.execute_command("hex".to_string());
+    assert!(ted.buffer().to_string() == "2\nthree\nfour\nfive\n");
+    write("x\n");
+    ted.check_file_now();
+    assert!(ted.mode() == Mode::FileChanged);
+    ted.handle_event(Event::Char('r'));
+    assert!(ted.buffer().to_string() == "x\n");
+}
+
+#[test]
+fn ted_set_file_format() {
+    use std::env;
+    use std::io::Write;
+
+    let path = env::temp_dir().join("ted_set_file_format_test.txt");
+    File::create(&path).unwrap().write_all(b"one\r\ntwo\r\n").unwrap();
+    let mut ted = Ted::from_file(10, path.display().to_string()).unwrap();
+    assert!(ted.buffer().to_string() == "one\ntwo\n");
+
+    ted.execute_command("set ff? fenc".to_string());
+    assert!(ted.message() == Some("fileformat=dos fileencoding=utf-8"));
+    ted.execute_command("w".to_string());
+    assert!(ted.message() == Some(&format!("\"{}\" [dos] 3L written", path.display()) as &str));
+
+    ted.execute_command("se ff=unix fenc=utf-16le".to_string());
+    assert!(ted.is_modified());
+    ted.execute_command("w".to_string());
+    let mut bytes = Vec::new();
+    File::open(&path).unwrap().read_to_end(&mut bytes).unwrap();
+    assert!(bytes == b"\xFF\xFEo\x00n\x00e\x00\n\x00t\x00w\x00o\x00\n\x00");
+
+    ted.execute_command("set ff=vms".to_string());
+    assert!(ted.message() == Some("Invalid fileformat: vms"));
+    ted.execute_command("set wrapscan".to_string());
+    assert!(ted.message() == Some("Unknown option: wrapscan"));
+}
+
+#[test]
+fn ted_binary_and_large_files() {
+    use std::env;
+    use std::io::Write;
+
+    let path = env::temp_dir().join("ted_binary_file_test.bin");
+    File::create(&path).unwrap().write_all(b"\x7fELF\x00\x01").unwrap();
+    let mut ted = Ted::from_file(10, path.display().to_string()).unwrap();
+    assert!(ted.buffer().to_string() ==
+            "00000000: 7f45 4c46 0001                           .ELF..\n");
+    assert!(ted.message() == Some(&format!("\"{}\" is binary, shown as hex", path.display()) as &str));
+
+    // A file above the size limit is read a page at a time as the cursor moves
+    let path = env::temp_dir().join("ted_large_file_test.txt");
+    let text: String = (0..100).map(|i| format!("line {:02}\n", i)).collect();
+    File::create(&path).unwrap().write_all(text.as_bytes()).unwrap();
+    let buf_op = BufferOperator::open(path.display().to_string(), 60).unwrap();
+    ted.load(Some(path.display().to_string()), buf_op);
+    assert!(ted.is_read_only());
+    assert!(ted.buffer().to_string() == "line 00\nline 01\nline 02\n");
+
+    type_keys(&mut ted, "jjj");
+    assert!(ted.buffer().line(ted.cursor.line as usize) == "line 03");
+    // G, gg and :N go by lines of the whole file
+    type_keys(&mut ted, "G");
+    assert!(ted.buffer().line(ted.cursor.line as usize) == "line 99");
+    type_keys(&mut ted, "gg");
+    assert!(ted.buffer().line(ted.cursor.line as usize) == "line 00");
+    type_keys(&mut ted, "51G");
+    assert!(ted.buffer().line(ted.cursor.line as usize) == "line 50");
+    ted.execute_command(":81".to_string());
+    assert!(ted.buffer().line(ted.cursor.line as usize) == "line 80");
+    type_keys(&mut ted, "3gg");
+    assert!(ted.buffer().line(ted.cursor.line as usize) == "line 02");
+    type_keys(&mut ted, "i");
+    assert!(ted.mode == Mode::Normal);
+
+    // Binary files that are too big aren't opened at all
+    let path = env::temp_dir().join("ted_binary_file_test.bin");
+    assert!(BufferOperator::open(path.display().to_string(), 4).is_err());
+}
+
+#[test]
+fn ted_hex_editing() {
+    use std::env;
+    use std::io::Write;
+
+    let path = env::temp_dir().join("ted_hex_editing_test.bin");
+    File::create(&path).unwrap().write_all(b"\x7fELF\x00\x01").unwrap();
+    let mut ted = Ted::from_file(10, path.display().to_string()).unwrap();
+
+    // Digits go over digits, and text over text, each changing the other column too
+    ted.move_cursor_to(hex::hex_column(1));
+    type_keys(&mut ted, "i4ag");
+    ted.handle_event(Event::Esc);
+    assert!(ted.buffer().line(0) == "00000000: 7f4a 4c46 0001                           .JLF..");
+    assert!(ted.cursor.column as usize == hex::hex_column(2));
+    ted.move_cursor_to(hex::text_column(2));
+    type_keys(&mut ted, "iZ");
+    ted.handle_event(Event::Esc);
+    assert!(ted.buffer().line(0) == "00000000: 7f4a 5a46 0001                           .JZF..");
+
+    // Anything that would move the bytes around is refused
+    type_keys(&mut ted, "dd");
+    assert!(ted.message() == Some("Bytes can only be typed over in hex view"));
+    ted.execute_command("s/4a/4b/".to_string());
+    assert!(ted.message() == Some("Bytes can only be typed over in hex view"));
+
+    type_keys(&mut ted, "u");
+    ted.execute_command("w".to_string());
+    let mut bytes = Vec::new();
+    File::open(&path).unwrap().read_to_end(&mut bytes).unwrap();
+    assert!(bytes == b"\x7fJLF\x00\x01");
+
+    ted.execute_command("hex".to_string());
+    assert!(ted.buffer().to_string() == "\u{7f}JLF\u{0}\u{1}");
+    ted.execute_command("hex".to_string());
+    assert!(ted.buffer().line(0) == "00000000: 7f4a 4c46 0001                           .JLF..");
+}
+
+#[test]
+fn ted_ex_delete() {
+    let mut ted = Ted::from_string(10, "1\n2\n3\n4\n5".to_string());
+    ted.execute_command("2,3d".to_string());
+    assert!(ted.buffer().to_string() == "1\n4\n5");
+    assert!(ted.registers.get(None).unwrap().text == "2\n3\n");
+
+    // The cursor is left on the line after the deleted ones
+    ted.execute_command("d a 2".to_string());
+    assert!(ted.buffer().to_string() == "1");
+    assert!(ted.registers.get(Some('a')).unwrap().text == "4\n5\n");
+}
+
+#[test]
+fn ted_ex_move() {
+    let mut ted = Ted::from_string(10, "1\n2\n3\n4".to_string());
+    ted.execute_command("1,2m$".to_string());
+    assert!(ted.buffer().to_string() == "3\n4\n1\n2");
+    assert!(ted.cursor.line == 3);
+
+    ted.execute_command("m0".to_string());
+    assert!(ted.buffer().to_string() == "2\n3\n4\n1");
+    ted.execute_command("2,4m3".to_string());
+    assert!(ted.message() == Some("Can't move lines into themselves"));
+
+    // The move is one undo step
+    ted.undo();
+    assert!(ted.buffer().to_string() == "3\n4\n1\n2");
+}
+
+#[test]
+fn ted_ex_copy() {
+    let mut ted = Ted::from_string(10, "1\n2\n3".to_string());
+    ted.execute_command("1,2t.".to_string());
+    assert!(ted.buffer().to_string() == "1\n1\n2\n2\n3");
+    assert!(ted.cursor.line == 2);
+    ted.execute_command("$co0".to_string());
+    assert!(ted.buffer().to_string() == "3\n1\n1\n2\n2\n3");
+}
+
+#[test]
+fn ted_ex_normal() {
+    let mut ted = Ted::from_string(10, "a\nb\nc".to_string());
+    ted.execute_command("%norm ix;".to_string());
+    assert!(ted.buffer().to_string() == "x;a\nx;b\nx;c");
+    assert!(ted.mode() == Mode::Normal);
+
+    // Lines the keys add or remove don't throw off the following lines
+    ted.execute_command("1,2normal dd".to_string());
+    assert!(ted.buffer().to_string() == "x;c");
+
+    // ! ignores mappings
+    ted.execute_command("nmap l dd".to_string());
+    ted.execute_command("norm! l".to_string());
+    assert!(ted.buffer().to_string() == "x;c");
+    ted.execute_command("norm l".to_string());
+    assert!(ted.buffer().to_string() == "");
+}
+
+#[test]
+fn ted_messages() {
+    let mut ted = Ted::from_string(10, "a".to_string());
+    ted.execute_command("w".to_string());
+    ted.notify("Something else".to_string());
+    assert!(ted.messages().len() == 2);
+    assert!(ted.messages()[0] == Message { text: "No file name".to_string(), kind: MessageKind::Error });
+    assert!(ted.message() == Some("Something else"));
+
+    // Messages go away on the next key, and :messages brings them back
+    type_keys(&mut ted, "l");
+    assert!(ted.messages().is_empty());
+    type_keys(&mut ted, ":messages");
+    ted.handle_event(Event::Enter);
+    assert!(ted.messages().len() == 2);
+    assert!(ted.message() == Some("Something else"));
+}
+
+#[test]
+fn ted_undo_file_option() {
+    use std::env;
+    use std::io::Write;
+
+    let path = env::temp_dir().join("ted_undo_file_option_test.txt");
+    File::create(&path).unwrap().write_all(b"one").unwrap();
+    let mut ted = Ted::from_file(10, path.display().to_string()).unwrap();
+    ted.set_persist_undo(true);
+    type_keys(&mut ted, "dl");
+    ted.save(&path).unwrap();
 
-    assert!(ted.log == vec![Operation::Insert(0, "asdf".to_string()),
-                            Operation::Insert(0, "hi".to_string())]);
-    assert!(ted.log_index == 1);
+    // The history is only picked up with the option on
+    let mut ted = Ted::from_file(10, path.display().to_string()).unwrap();
+    assert!(ted.undo_tree.len() == 1);
+    ted.set_persist_undo(true);
+    assert!(ted.undo_tree.len() == 2);
+    type_keys(&mut ted, "u");
+    assert!(ted.buffer().to_string() == "one");
 }
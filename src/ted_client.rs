@@ -1,4 +1,5 @@
 use std::borrow::Cow;
+use std::io;
 
 use cursor::Cursor;
 use net;
@@ -14,7 +15,7 @@ pub struct TedClient {
 
     pending_queue: usize,
 
-    op_queue: usize, // Start index in ted.log of ops that need to be sent to the server
+    op_queue: usize, // Start index in ted.net_log of ops that need to be sent to the server
     cmd_queue: usize,
 }
 
@@ -31,7 +32,7 @@ impl TedClient {
     }
     
     pub fn download_buffer(&mut self) -> Result<Ted, String> {
-        let mut packet = self.client.receive();
+        let mut packet = try!(self.client.receive().map_err(|e| e.to_string()));
         let buffer: String = try!(packet.read().map_err(|e| e.to_string()));
         let timeline: Vec<(net::ClientId, Operation)> =
             try!(packet.read().map_err(|e| e.to_string()));
@@ -42,63 +43,70 @@ impl TedClient {
         Ok(Ted::from_string(1, buffer))
     }
 
-    pub fn send_operations(&mut self, ted: &mut Ted) {
-        for op in &ted.log[self.op_queue..] {
-            self.send_operation(op);
+    pub fn send_operations(&mut self, ted: &mut Ted) -> io::Result<()> {
+        for op in &ted.net_log[self.op_queue..] {
+            try!(self.send_operation(op));
+            self.op_queue += 1;
         }
-        self.op_queue = ted.log.len();
+        Ok(())
     }
 
-    pub fn send_commands(&mut self, ted: &mut Ted) {
+    pub fn send_commands(&mut self, ted: &mut Ted) -> io::Result<()> {
         for cmd in &ted.cmd_log[self.cmd_queue..] {
-            self.send_command(cmd);
+            try!(self.send_command(cmd));
+            self.cmd_queue += 1;
         }
-        self.cmd_queue = ted.cmd_log.len();
+        Ok(())
     }
 
-    pub fn handle_packet(&mut self, ted: &mut Ted, packet: &mut net::InPacket) {
-        let packet_id = packet.read().unwrap();
+    /// Handles a packet from the server. Fails if the packet doesn't make sense.
+    pub fn handle_packet(&mut self, ted: &mut Ted, packet: &mut net::InPacket) -> Result<(), String> {
+        let packet_id = try!(packet.read().map_err(|e| format!("Bad packet from server: {}", e)));
 
         match packet_id {
-            PacketId::Response => { self.handle_response_packet(ted, packet); },
-            PacketId::Sync => { self.handle_sync_packet(ted, packet); },
+            PacketId::Response => self.handle_response_packet(ted, packet),
+            PacketId::Sync => self.handle_sync_packet(ted, packet),
         }
     }
 
-    fn handle_response_packet(&mut self, ted: &mut Ted, packet: &mut net::InPacket) {
-        let response: Response = packet.read().unwrap();
+    fn handle_response_packet(&mut self, ted: &mut Ted, packet: &mut net::InPacket) -> Result<(), String> {
+        let response: Response = try!(packet.read().map_err(|e| format!("Bad response from server: {}", e)));
         match response {
             Response::Op => {
                 let op_index = self.pending_queue;
-                self.timeline.push(ted.log[op_index].clone());
+                self.timeline.push(ted.net_log[op_index].clone());
                 self.pending_queue += 1;
                 self.last_sync += 1;
             }
         }
+        Ok(())
     }
 
-    fn handle_sync_packet(&mut self, ted: &mut Ted, packet: &mut net::InPacket) {
-        let num_ops: u64 = packet.read().unwrap();
+    fn handle_sync_packet(&mut self, ted: &mut Ted, packet: &mut net::InPacket) -> Result<(), String> {
+        let num_ops: u64 = try!(packet.read().map_err(|e| format!("Bad sync from server: {}", e)));
         for _ in 0..num_ops {
-            let op = packet.read().unwrap();
+            let op = try!(packet.read().map_err(|e| format!("Bad sync from server: {}", e)));
             self.timeline.push(op);
         }
 
         self.merge_synced_ops(ted);
 
         self.last_sync = self.timeline.len();
+        Ok(())
     }
 
-    fn send_operation(&mut self, op: &Operation) {
+    fn send_operation(&mut self, op: &Operation) -> io::Result<()> {
         let mut packet = net::OutPacket::new();
-        packet.write(&Request::Op(self.timeline.len() as u64, Cow::Borrowed(op))).unwrap();
-        self.client.send(&packet);
+        try!(packet.write(&Request::Op(self.timeline.len() as u64, Cow::Borrowed(op)))
+                   .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string())));
+        self.client.send(&packet)
     }
 
-    fn send_command(&mut self, cmd: &String) {
+    fn send_command(&mut self, cmd: &String) -> io::Result<()> {
         let mut packet = net::OutPacket::new();
-        packet.write(&Request::Command(self.timeline.len() as u64, Cow::Borrowed(cmd))).unwrap();
-        self.client.send(&packet);
+        try!(packet.write(&Request::Command(self.timeline.len() as u64, Cow::Borrowed(cmd)))
+                   .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string())));
+        self.client.send(&packet)
     }
 
     fn cursor_moved(&mut self, cursor: &Cursor) {
@@ -115,9 +123,9 @@ impl TedClient {
         if ops.is_empty() { return; }
 
         // Undo operations that are still pending
-        for i in (self.pending_queue..ted.log.len()).rev() {
+        for i in (self.pending_queue..ted.net_log.len()).rev() {
             // TODO: I shouldn't have to clone here
-            let inverse = ted.log[i].clone().inverse();
+            let inverse = ted.net_log[i].clone().inverse();
             ted.do_operation(&inverse);
         }
         // Apply operations to merge before the pending operations, adjusting pending operations as
@@ -125,19 +133,19 @@ impl TedClient {
         for op in ops {
             // Iterate backwards so we don't do any unnecessary adjustments if operations are
             // cancelled.
-            for i in (self.pending_queue..ted.log.len()).rev() {
-                if !op.do_before(&mut ted.log[i]) {
+            for i in (self.pending_queue..ted.net_log.len()).rev() {
+                if !op.do_before(&mut ted.net_log[i]) {
                     // Pending operation was cancelled. Remove it and adjust later pending
                     // operations.
-                    for j in i..ted.log.len() {
+                    for j in i..ted.net_log.len() {
                     }
                 }
             }
             ted.do_operation(op);
         }
         // Redo operations that are still pending
-        for i in self.pending_queue..ted.log.len() {
-            let op = ted.log[i].clone();
+        for i in self.pending_queue..ted.net_log.len() {
+            let op = ted.net_log[i].clone();
             ted.do_operation(&op);
         }
     }
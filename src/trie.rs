@@ -40,6 +40,25 @@ impl<K: Clone+Eq+Hash, T> Trie<K, T> {
         Ok(())
     }
 
+    /// Stores data at chars, replacing whatever was there
+    pub fn set(&mut self, chars: &[K], data: T) {
+        if chars.len() == 0 {
+            self.data = Some(data);
+            return;
+        }
+
+        self.children.entry(chars[0].clone()).or_insert_with(Trie::new).set(&chars[1..], data);
+    }
+
+    /// Whether there are any longer sequences in the trie that start with chars
+    pub fn has_children(&self, chars: &[K]) -> bool {
+        if chars.len() == 0 {
+            return !self.children.is_empty();
+        }
+
+        self.children.get(&chars[0]).map_or(false, |c| c.has_children(&chars[1..]))
+    }
+
     pub fn get(&self, chars: &[K]) -> Option<&T> {
         if chars.len() == 0 {
             // Made it to a leaf node
@@ -115,3 +134,26 @@ pub fn trie_multiple_root() {
     assert!(trie.get(b"abc") == Some(&7));
     assert!(trie.get(b"bbc") == Some(&42));
 }
+
+#[test]
+pub fn trie_set_replaces() {
+    let mut trie = Trie::new();
+    trie.insert(b"ab", Some(7u32)).unwrap();
+    trie.set(b"ab", 8u32);
+    trie.set(b"b", 9u32);
+
+    assert!(trie.get(b"ab") == Some(&8));
+    assert!(trie.get(b"b") == Some(&9));
+}
+
+#[test]
+pub fn trie_has_children() {
+    let mut trie = Trie::new();
+    trie.insert(b"a", Some(5u32)).unwrap();
+    trie.insert(b"abc", Some(7u32)).unwrap();
+
+    assert!(trie.has_children(b"a"));
+    assert!(trie.has_children(b"ab"));
+    assert!(!trie.has_children(b"abc"));
+    assert!(!trie.has_children(b"b"));
+}
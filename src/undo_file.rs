@@ -0,0 +1,118 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use bincode::{self, SizeLimit};
+
+use undo_tree::UndoTree;
+
+/// Undo history as it is stored on disk, next to the file it belongs to
+#[derive(RustcEncodable, RustcDecodable)]
+pub struct UndoFile {
+    pub path: String, // Canonical path of the edited file
+    pub hash: u64,    // Hash of the file's contents when the history was written
+    pub tree: UndoTree,
+}
+
+impl UndoFile {
+    /// Writes the history for file_path. Like saving a file, it goes to a temporary file first
+    /// which then replaces the old history, so a crash can't leave half of it behind.
+    pub fn write(&self, file_path: &Path) -> io::Result<()> {
+        let undo_path = undo_file_path(file_path);
+        let temp_path = temp_file_path(&undo_path);
+        let result = self.write_temp_file(&temp_path).and_then(|_| fs::rename(&temp_path, &undo_path));
+        if result.is_err() {
+            let _ = fs::remove_file(&temp_path);
+        }
+        result
+    }
+
+    fn write_temp_file(&self, temp_path: &Path) -> io::Result<()> {
+        let mut writer = BufWriter::new(try!(File::create(temp_path)));
+        try!(bincode::encode_into(self, &mut writer, SizeLimit::Infinite)
+                 .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string())));
+        try!(writer.flush());
+        writer.get_ref().sync_all()
+    }
+
+    /// Reads the undo history stored for file_path. Returns None if there isn't one, if it was
+    /// written for a different file or for different contents, or if it isn't a history ted
+    /// could have written.
+    pub fn read(file_path: &Path, hash: u64) -> io::Result<Option<UndoFile>> {
+        let undo_path = undo_file_path(file_path);
+        if !undo_path.exists() {
+            return Ok(None);
+        }
+
+        let mut file = BufReader::new(try!(File::open(undo_path)));
+        let undo_file: UndoFile =
+            try!(bincode::decode_from(&mut file, SizeLimit::Infinite)
+                     .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string())));
+
+        if undo_file.path != canonical_path(file_path) || undo_file.hash != hash || !undo_file.tree.is_valid() {
+            return Ok(None);
+        }
+        Ok(Some(undo_file))
+    }
+}
+
+/// The path a file's undo history is kept at, e.g. `dir/.foo.rs.ted-undo` for `dir/foo.rs`
+pub fn undo_file_path(file_path: &Path) -> PathBuf {
+    let file_name = file_path.file_name().map_or("".into(), |n| n.to_string_lossy());
+    file_path.with_file_name(format!(".{}.ted-undo", file_name))
+}
+
+/// Where the history is written before it replaces the old one
+fn temp_file_path(undo_path: &Path) -> PathBuf {
+    let file_name = undo_path.file_name().map_or("".into(), |n| n.to_string_lossy());
+    undo_path.with_file_name(format!("{}.ted-save", file_name))
+}
+
+pub fn canonical_path(file_path: &Path) -> String {
+    fs::canonicalize(file_path).unwrap_or(file_path.to_path_buf())
+                               .to_string_lossy()
+                               .into_owned()
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+
+#[test]
+fn undo_file_path_is_hidden_sibling() {
+    assert!(undo_file_path(Path::new("dir/foo.rs")) == Path::new("dir/.foo.rs.ted-undo"));
+    assert!(undo_file_path(Path::new("foo")) == Path::new(".foo.ted-undo"));
+}
+
+#[test]
+fn undo_file_read_missing() {
+    let path = Path::new("/nonexistent/ted/file.txt");
+
+    assert!(UndoFile::read(path, 0).unwrap().is_none());
+}
+
+#[test]
+fn undo_file_round_trip() {
+    use std::env;
+    use std::io::Write;
+    use operation::Operation;
+
+    let path = env::temp_dir().join("ted_undo_file_test.txt");
+    File::create(&path).unwrap().write_all(b"text").unwrap();
+    let mut tree = UndoTree::new();
+    tree.push(Operation::Insert(0, "text".to_string()), false);
+    let undo_file = UndoFile { path: canonical_path(&path), hash: 42, tree: tree };
+    undo_file.write(&path).unwrap();
+
+    let read = UndoFile::read(&path, 42).unwrap().unwrap();
+    assert!(read.path == undo_file.path && read.hash == 42);
+    assert!(read.tree.len() == 2 && read.tree.current() == 1);
+    assert!(!temp_file_path(&undo_file_path(&path)).exists());
+
+    // Different contents
+    assert!(UndoFile::read(&path, 43).unwrap().is_none());
+
+    // Written for a different file, e.g. one that was moved here
+    let moved = UndoFile { path: "/elsewhere/file.txt".to_string(), hash: 42, tree: UndoTree::new() };
+    moved.write(&path).unwrap();
+    assert!(UndoFile::read(&path, 42).unwrap().is_none());
+}
@@ -0,0 +1,321 @@
+use std::cmp;
+
+use time;
+
+use operation::Operation;
+
+/// One undo step: the operations that take the parent node's state to this node's state
+#[derive(Clone, RustcEncodable, RustcDecodable)]
+pub struct UndoNode {
+    pub ops: Vec<Operation>,
+    pub parent: usize,
+    pub children: Vec<usize>,
+    pub time: i64, // When the step was made, in seconds since the epoch
+    redo_child: Option<usize>, // Child that redo moves to, the one made or visited most recently
+}
+
+/// Undo history that keeps every branch. Node 0 is the state before any edits, and nodes are
+/// numbered in the order they were made.
+#[derive(Clone, RustcEncodable, RustcDecodable)]
+pub struct UndoTree {
+    nodes: Vec<UndoNode>,
+    current: usize, // Node whose state the buffer is currently in
+}
+
+/// How far to move through the history with :earlier and :later
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TimeTravel {
+    Steps(usize),
+    Seconds(i64),
+}
+
+impl TimeTravel {
+    /// Parses a count ("3"), or a time with an s, m, h or d suffix ("5m"). Times too long to count
+    /// in seconds are refused.
+    pub fn parse(arg: &str) -> Option<TimeTravel> {
+        if arg.is_empty() {
+            return Some(TimeTravel::Steps(1));
+        }
+
+        let (number, unit) =
+            match arg.char_indices().last() {
+                Some((i, c)) if c.is_alphabetic() => (&arg[..i], Some(c)),
+                _ => (arg, None),
+            };
+        let number: i64 = match number.parse() { Ok(n) if n >= 0 => n, _ => { return None; } };
+
+        let seconds_per_unit =
+            match unit {
+                None => { return Some(TimeTravel::Steps(number as usize)); },
+                Some('s') => 1,
+                Some('m') => 60,
+                Some('h') => 60 * 60,
+                Some('d') => 60 * 60 * 24,
+                Some(_) => { return None; },
+            };
+        number.checked_mul(seconds_per_unit).map(TimeTravel::Seconds)
+    }
+}
+
+impl UndoTree {
+    pub fn new() -> UndoTree {
+        UndoTree {
+            nodes: vec![UndoNode {
+                ops: Vec::new(),
+                parent: 0,
+                children: Vec::new(),
+                time: time::get_time().sec,
+                redo_child: None,
+            }],
+            current: 0,
+        }
+    }
+
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    pub fn node(&self, index: usize) -> &UndoNode {
+        &self.nodes[index]
+    }
+
+    /// Number of nodes in the tree, including the root
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Records an operation that was just applied. If `join` is set and nothing has been branched
+    /// off the current step yet, the operation becomes part of the current step.
+    pub fn push(&mut self, op: Operation, join: bool) {
+        let current = self.current;
+        if join && current != 0 && self.nodes[current].children.is_empty() {
+            self.nodes[current].ops.push(op);
+            return;
+        }
+
+        let index = self.nodes.len();
+        self.nodes.push(UndoNode {
+            ops: vec![op],
+            parent: current,
+            children: Vec::new(),
+            time: time::get_time().sec,
+            redo_child: None,
+        });
+        self.nodes[current].children.push(index);
+        self.nodes[current].redo_child = Some(index);
+        self.current = index;
+    }
+
+    /// Moves to the parent step, returning the operations that revert the current one
+    pub fn undo(&mut self) -> Option<Vec<Operation>> {
+        if self.current == 0 {
+            return None;
+        }
+
+        let node = self.current;
+        self.current = self.nodes[node].parent;
+        self.nodes[self.current].redo_child = Some(node);
+        Some(inverse_ops(&self.nodes[node].ops))
+    }
+
+    /// Moves to the most recently used child step, returning its operations
+    pub fn redo(&mut self) -> Option<Vec<Operation>> {
+        let child = match self.nodes[self.current].redo_child { Some(c) => c, None => { return None; } };
+        self.current = child;
+        Some(self.nodes[child].ops.clone())
+    }
+
+    /// Moves to any node in the tree, returning the operations that take the current state there
+    pub fn jump(&mut self, target: usize) -> Vec<Operation> {
+        // Nodes from the target up to the root
+        let mut target_path = vec![target];
+        while *target_path.last().unwrap() != 0 {
+            let parent = self.nodes[*target_path.last().unwrap()].parent;
+            target_path.push(parent);
+        }
+
+        // Undo up to the closest common ancestor...
+        let mut ops = Vec::new();
+        let mut node = self.current;
+        while !target_path.contains(&node) {
+            ops.extend(inverse_ops(&self.nodes[node].ops));
+            node = self.nodes[node].parent;
+        }
+
+        // ...then redo down to the target
+        let ancestor = target_path.iter().position(|&n| n == node).unwrap();
+        for &n in target_path[..ancestor].iter().rev() {
+            ops.extend(self.nodes[n].ops.iter().cloned());
+            let parent = self.nodes[n].parent;
+            self.nodes[parent].redo_child = Some(n);
+        }
+
+        self.current = target;
+        ops
+    }
+
+    /// Goes back in time through every branch, in the order the steps were made
+    pub fn earlier(&mut self, amount: TimeTravel) -> Vec<Operation> {
+        let target =
+            match amount {
+                TimeTravel::Steps(count) => self.current.saturating_sub(count),
+                TimeTravel::Seconds(secs) => self.newest_made_by(self.nodes[self.current].time.saturating_sub(secs)),
+            };
+        self.jump(target)
+    }
+
+    /// Goes forward in time through every branch, in the order the steps were made
+    pub fn later(&mut self, amount: TimeTravel) -> Vec<Operation> {
+        let target =
+            match amount {
+                TimeTravel::Steps(count) => cmp::min(self.current.saturating_add(count), self.nodes.len() - 1),
+                TimeTravel::Seconds(secs) => {
+                    cmp::max(self.current, self.newest_made_by(self.nodes[self.current].time.saturating_add(secs)))
+                },
+            };
+        self.jump(target)
+    }
+
+    /// The tip of every branch in the tree
+    pub fn branches(&self) -> Vec<usize> {
+        (1..self.nodes.len()).filter(|&i| self.nodes[i].children.is_empty()).collect()
+    }
+
+    /// Number of steps between the root and the node
+    pub fn depth(&self, mut node: usize) -> usize {
+        let mut depth = 0;
+        while node != 0 {
+            node = self.nodes[node].parent;
+            depth += 1;
+        }
+        depth
+    }
+
+    /// Whether the tree is one ted could have made: every node points at nodes that exist, made
+    /// before their children, and parents and children agree. Checked on trees read from disk.
+    pub fn is_valid(&self) -> bool {
+        let len = self.nodes.len();
+        if self.current >= len {
+            return false;
+        }
+        self.nodes.iter().enumerate().all(|(i, node)| {
+            (i == 0 || node.parent < i) &&
+            node.children.iter().all(|&c| c > i && c < len && self.nodes[c].parent == i) &&
+            node.redo_child.map_or(true, |c| node.children.contains(&c))
+        })
+    }
+
+    /// Newest node made at or before the specified time
+    fn newest_made_by(&self, time: i64) -> usize {
+        self.nodes.iter().rposition(|n| n.time <= time).unwrap_or(0)
+    }
+}
+
+fn inverse_ops(ops: &[Operation]) -> Vec<Operation> {
+    ops.iter().rev().map(|op| op.clone().inverse()).collect()
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+
+#[test]
+fn undo_tree_push_after_undo_branches() {
+    let mut tree = UndoTree::new();
+    tree.push(Operation::Insert(0, "asdf".to_string()), false);
+    tree.push(Operation::Insert(0, "asdf".to_string()), false);
+    tree.undo();
+    tree.push(Operation::Insert(0, "hi".to_string()), false);
+
+    // Nothing is thrown away, node 2 is still there next to the new node
+    assert!(tree.len() == 4);
+    assert!(tree.node(1).children == vec![2, 3]);
+    assert!(tree.branches() == vec![2, 3]);
+    assert!(tree.current() == 3);
+}
+
+#[test]
+fn undo_tree_join() {
+    let mut tree = UndoTree::new();
+    tree.push(Operation::InsertChar(0, 'a'), false);
+    tree.push(Operation::InsertChar(1, 'b'), true);
+
+    assert!(tree.len() == 2);
+    assert!(tree.undo() == Some(vec![Operation::RemoveChar(1, 'b'), Operation::RemoveChar(0, 'a')]));
+    assert!(tree.undo() == None);
+}
+
+#[test]
+fn undo_tree_redo_follows_last_branch() {
+    let mut tree = UndoTree::new();
+    tree.push(Operation::InsertChar(0, 'a'), false);
+    tree.undo();
+    tree.push(Operation::InsertChar(0, 'b'), false);
+    tree.undo();
+
+    assert!(tree.redo() == Some(vec![Operation::InsertChar(0, 'b')]));
+    assert!(tree.redo() == None);
+}
+
+#[test]
+fn undo_tree_jump_across_branches() {
+    let mut tree = UndoTree::new();
+    tree.push(Operation::InsertChar(0, 'a'), false);
+    tree.push(Operation::InsertChar(1, 'b'), false);
+    tree.undo();
+    tree.push(Operation::InsertChar(1, 'c'), false);
+
+    assert!(tree.jump(2) == vec![Operation::RemoveChar(1, 'c'), Operation::InsertChar(1, 'b')]);
+    assert!(tree.current() == 2);
+    assert!(tree.depth(2) == 2);
+}
+
+#[test]
+fn undo_tree_earlier_later_steps() {
+    let mut tree = UndoTree::new();
+    tree.push(Operation::InsertChar(0, 'a'), false);
+    tree.undo();
+    tree.push(Operation::InsertChar(0, 'b'), false);
+
+    // Going back one step in time crosses over to the other branch
+    assert!(tree.earlier(TimeTravel::Steps(1)) == vec![Operation::RemoveChar(0, 'b'),
+                                                       Operation::InsertChar(0, 'a')]);
+    assert!(tree.later(TimeTravel::Steps(5)) == vec![Operation::RemoveChar(0, 'a'),
+                                                     Operation::InsertChar(0, 'b')]);
+    assert!(tree.earlier(TimeTravel::Seconds(60)).len() == 1);
+    assert!(tree.current() == 0);
+}
+
+#[test]
+fn time_travel_parse() {
+    assert!(TimeTravel::parse("") == Some(TimeTravel::Steps(1)));
+    assert!(TimeTravel::parse("3") == Some(TimeTravel::Steps(3)));
+    assert!(TimeTravel::parse("5m") == Some(TimeTravel::Seconds(300)));
+    assert!(TimeTravel::parse("2h") == Some(TimeTravel::Seconds(7200)));
+    assert!(TimeTravel::parse("5x") == None);
+    assert!(TimeTravel::parse("m") == None);
+    assert!(TimeTravel::parse("999999999999999d") == None);
+    assert!(TimeTravel::parse("9223372036854775807s") == Some(TimeTravel::Seconds(i64::max_value())));
+}
+
+#[test]
+fn undo_tree_is_valid() {
+    let mut tree = UndoTree::new();
+    tree.push(Operation::InsertChar(0, 'a'), false);
+    tree.undo();
+    tree.push(Operation::InsertChar(0, 'b'), false);
+    assert!(tree.is_valid());
+
+    // Indices out of range, e.g. from a corrupt undo file
+    let mut bad = tree.clone();
+    bad.nodes[0].children.push(7);
+    assert!(!bad.is_valid());
+    let mut bad = tree.clone();
+    bad.nodes[2].parent = 5;
+    assert!(!bad.is_valid());
+    let mut bad = tree.clone();
+    bad.nodes[1].redo_child = Some(2);
+    assert!(!bad.is_valid());
+    let mut bad = tree.clone();
+    bad.current = 3;
+    assert!(!bad.is_valid());
+}
@@ -0,0 +1,169 @@
+use std::cmp;
+
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthChar;
+
+/// Columns a grapheme cluster takes on the screen. Control characters are drawn as a space.
+pub fn grapheme_width(grapheme: &str) -> usize {
+    match grapheme.chars().next() {
+        Some(c) if !c.is_control() => cmp::min(cmp::max(c.width().unwrap_or(1), 1), 2),
+        _ => 1,
+    }
+}
+
+/// Columns text takes on the screen
+pub fn width(text: &str) -> usize {
+    text.graphemes(true).map(grapheme_width).sum()
+}
+
+/// Start of the grapheme cluster index is in, or the end of text
+pub fn boundary_at(text: &str, index: usize) -> usize {
+    if index >= text.len() {
+        return text.len();
+    }
+    text.grapheme_indices(true).map(|(i, _)| i).take_while(|&i| i <= index).last().unwrap_or(0)
+}
+
+/// Start of the grapheme cluster after the one index is in, or the end of text
+pub fn next_boundary(text: &str, index: usize) -> usize {
+    text.grapheme_indices(true).map(|(i, g)| i + g.len()).find(|&end| end > index).unwrap_or(text.len())
+}
+
+/// Start of the grapheme cluster before index, or 0
+pub fn prev_boundary(text: &str, index: usize) -> usize {
+    text.grapheme_indices(true).map(|(i, _)| i).take_while(|&i| i < index).last().unwrap_or(0)
+}
+
+/// Start of the grapheme cluster that covers a display column, or the end of text if it's
+/// shorter than that
+pub fn index_at_column(text: &str, column: usize) -> usize {
+    let mut x = 0;
+    for (i, grapheme) in text.grapheme_indices(true) {
+        x += grapheme_width(grapheme);
+        if x > column {
+            return i;
+        }
+    }
+    text.len()
+}
+
+/// End of the grapheme clusters from from that fit in width columns. At least one is taken, so
+/// text too wide for the room still makes progress.
+pub fn fit(text: &str, from: usize, width: usize) -> usize {
+    let mut x = 0;
+    let mut end = from;
+    for grapheme in text[from..].graphemes(true) {
+        x += grapheme_width(grapheme);
+        if x > width && end > from {
+            break;
+        }
+        end += grapheme.len();
+    }
+    end
+}
+
+/// The byte ranges of the rows a line takes when wrapped at width columns. Even an empty line
+/// takes a row.
+pub fn wrap_rows(line: &str, width: usize) -> Vec<(usize, usize)> {
+    let mut rows = Vec::new();
+    let mut start = 0;
+    loop {
+        let end = fit(line, start, width);
+        rows.push((start, end));
+        if end >= line.len() {
+            break;
+        }
+        start = end;
+    }
+    rows
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+
+/// Pieces of text that are easy to get wrong: multi-byte characters, combining accents, wide
+/// characters, flags, emoji and line breaks
+#[cfg(test)]
+pub const SAMPLES: &'static [&'static str] = &[
+    "a", "Z", " ", "\n", "é", "e\u{301}", "ß", "日本", "🇯🇵", "😀", "\u{200d}", "\t",
+];
+
+/// A xorshift generator, so random tests do the same thing every run
+#[cfg(test)]
+pub struct Random(pub u64);
+
+#[cfg(test)]
+impl Random {
+    /// A number below n
+    pub fn below(&mut self, n: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % n as u64) as usize
+    }
+
+    pub fn sample(&mut self) -> &'static str {
+        SAMPLES[self.below(SAMPLES.len())]
+    }
+}
+
+#[test]
+fn unicode_boundaries() {
+    let text = "ae\u{301}日🇯🇵x";
+    assert!(next_boundary(text, 0) == 1);
+    assert!(next_boundary(text, 1) == 4); // e with a combining accent
+    assert!(next_boundary(text, 4) == 7);
+    assert!(next_boundary(text, 7) == 15); // A flag is two regional indicators
+    assert!(prev_boundary(text, 15) == 7);
+    assert!(prev_boundary(text, 4) == 1);
+    assert!(boundary_at(text, 2) == 1);
+    assert!(boundary_at(text, 100) == text.len());
+}
+
+#[test]
+fn unicode_widths() {
+    assert!(width("ae\u{301}") == 2);
+    assert!(width("日本") == 4);
+    assert!(width("a\tb") == 3);
+    assert!(index_at_column("a日b", 1) == 1);
+    assert!(index_at_column("a日b", 2) == 1);
+    assert!(index_at_column("a日b", 3) == 4);
+    assert!(index_at_column("a日b", 9) == 5);
+    assert!(wrap_rows("ab日本c", 3) == vec![(0, 2), (2, 5), (5, 9)]);
+    assert!(wrap_rows("", 3) == vec![(0, 0)]);
+    assert!(wrap_rows("日本", 1) == vec![(0, 3), (3, 6)]);
+}
+
+#[test]
+fn unicode_vertical_movement() {
+    use buffer::Buffer;
+    use cursor::Cursor;
+
+    // j and k keep the display column, whatever the widths of the characters on the way
+    let buffer = Buffer::from_string("abcdef\n日本語\n😀x\nab".to_string());
+    let mut cursor = Cursor { line: 0, column: 0, buf_index: 0 };
+    cursor.move_right(&buffer);
+    cursor.move_right(&buffer);
+    assert!(cursor.column == 2);
+    cursor.move_down(&buffer);
+    assert!(cursor.buf_index == 7 + 3 && cursor.column == 2); // 本
+    cursor.move_down(&buffer);
+    assert!(cursor.buf_index == 17 + 4 && cursor.column == 2); // x
+    cursor.move_up(&buffer);
+    cursor.move_up(&buffer);
+    assert!(cursor.buf_index == 2);
+
+    // A column in the middle of a wide character lands on it, and is kept for the next line
+    cursor.move_right(&buffer);
+    cursor.move_down(&buffer);
+    assert!(cursor.buf_index == 7 + 3 && cursor.column == 3); // 本
+    cursor.move_up(&buffer);
+    assert!(cursor.buf_index == 3);
+
+    // Moving sideways sets the column to where the cursor is on the screen
+    cursor.move_down(&buffer);
+    cursor.move_right(&buffer);
+    assert!(cursor.buf_index == 7 + 6 && cursor.column == 4); // 語
+    cursor.move_up(&buffer);
+    assert!(cursor.buf_index == 4);
+}
@@ -0,0 +1,209 @@
+use cursor::Cursor;
+
+/// A view onto a buffer. The window being typed in keeps its cursor and scroll in Ted while it's
+/// active, the others keep theirs here.
+#[derive(Copy, Clone, PartialEq)]
+pub struct Window {
+    pub id: usize,
+    pub buffer: usize, // Number of the buffer shown
+    pub cursor: Cursor,
+    pub scroll: u64,
+    pub left: u64, // Columns scrolled off the left, when lines don't wrap
+    pub rect: Rect, // Where the window's text goes on the screen
+}
+
+/// A part of the screen, in cells from the top left corner
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Direction {
+    Left,
+    Down,
+    Up,
+    Right,
+}
+
+/// How a tab page's screen is divided between its windows
+#[derive(Clone, Debug, PartialEq)]
+pub enum Layout {
+    Window(usize), // A window's id
+    Split { vertical: bool, children: Vec<Layout> }, // Vertical splits are side by side
+}
+
+/// A tab page, a layout of windows of its own
+#[derive(Clone, Debug, PartialEq)]
+pub struct TabPage {
+    pub layout: Layout,
+    pub window: usize, // The window last typed in
+}
+
+impl Layout {
+    /// Puts a new window after an existing one: below it, or right of it if vertical. Returns
+    /// whether the existing window was found.
+    pub fn split(&mut self, id: usize, new_id: usize, vertical: bool) -> bool {
+        match *self {
+            Layout::Window(window) => {
+                if window != id {
+                    return false;
+                }
+                *self = Layout::Split {
+                    vertical: vertical,
+                    children: vec![Layout::Window(id), Layout::Window(new_id)],
+                };
+                true
+            },
+            Layout::Split { vertical: split_vertical, ref mut children } => {
+                if split_vertical == vertical {
+                    if let Some(i) = children.iter().position(|c| *c == Layout::Window(id)) {
+                        children.insert(i + 1, Layout::Window(new_id));
+                        return true;
+                    }
+                }
+                children.iter_mut().any(|c| c.split(id, new_id, vertical))
+            },
+        }
+    }
+
+    /// Takes a window out of the layout, the windows next to it get its room. The last window
+    /// can't be removed. Returns whether the window was found.
+    pub fn remove(&mut self, id: usize) -> bool {
+        let (found, only_child) =
+            match *self {
+                Layout::Window(_) => { return false; },
+                Layout::Split { ref mut children, .. } => {
+                    let found =
+                        match children.iter().position(|c| *c == Layout::Window(id)) {
+                            Some(i) => { children.remove(i); true },
+                            None => children.iter_mut().any(|c| c.remove(id)),
+                        };
+                    (found, if children.len() == 1 { children.pop() } else { None })
+                },
+            };
+        if let Some(child) = only_child {
+            *self = child;
+        }
+        found
+    }
+
+    /// Ids of the windows, from the top left
+    pub fn windows(&self) -> Vec<usize> {
+        match *self {
+            Layout::Window(id) => vec![id],
+            Layout::Split { ref children, .. } => children.iter().flat_map(|c| c.windows()).collect(),
+        }
+    }
+
+    /// Divides area between the windows. With status_lines, each window's last row is left for
+    /// its status line. Side by side windows have a column between them for a separator.
+    pub fn arrange(&self, area: Rect, status_lines: bool, windows: &mut Vec<(usize, Rect)>,
+                   separators: &mut Vec<Rect>) {
+        match *self {
+            Layout::Window(id) => {
+                let mut rect = area;
+                if status_lines {
+                    rect.height = rect.height.saturating_sub(1);
+                }
+                windows.push((id, rect));
+            },
+            Layout::Split { vertical: true, ref children } => {
+                let count = children.len();
+                let total = area.width.saturating_sub(count - 1);
+                let mut x = area.x;
+                for (i, child) in children.iter().enumerate() {
+                    let width = share(total, count, i);
+                    child.arrange(Rect { x: x, y: area.y, width: width, height: area.height },
+                                  status_lines, windows, separators);
+                    x += width;
+                    if i + 1 < count {
+                        separators.push(Rect { x: x, y: area.y, width: 1, height: area.height });
+                        x += 1;
+                    }
+                }
+            },
+            Layout::Split { vertical: false, ref children } => {
+                let count = children.len();
+                let mut y = area.y;
+                for (i, child) in children.iter().enumerate() {
+                    let height = share(area.height, count, i);
+                    child.arrange(Rect { x: area.x, y: y, width: area.width, height: height },
+                                  status_lines, windows, separators);
+                    y += height;
+                }
+            },
+        }
+    }
+}
+
+/// Part i of total split into count parts, the first ones get the leftover
+fn share(total: usize, count: usize, i: usize) -> usize {
+    total / count + if i < total % count { 1 } else { 0 }
+}
+
+/// The closest window in a direction from a window, out of the windows that are level with it
+pub fn neighbor(windows: &[(usize, Rect)], id: usize, direction: Direction) -> Option<usize> {
+    let from = match windows.iter().find(|w| w.0 == id) { Some(w) => w.1, None => { return None; } };
+    windows.iter().filter(|&&(_, rect)| {
+        let overlaps_rows = rect.y < from.y + from.height + 1 && from.y < rect.y + rect.height + 1;
+        let overlaps_columns = rect.x < from.x + from.width && from.x < rect.x + rect.width;
+        match direction {
+            Direction::Left => rect.x + rect.width <= from.x && overlaps_rows,
+            Direction::Right => rect.x >= from.x + from.width && overlaps_rows,
+            Direction::Up => rect.y + rect.height <= from.y && overlaps_columns,
+            Direction::Down => rect.y >= from.y + from.height && overlaps_columns,
+        }
+    }).min_by_key(|&&(_, rect)| {
+        match direction {
+            Direction::Left => from.x - rect.x,
+            Direction::Right => rect.x - from.x,
+            Direction::Up => from.y - rect.y,
+            Direction::Down => rect.y - from.y,
+        }
+    }).map(|w| w.0)
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+
+#[test]
+fn layout_split_and_remove() {
+    let mut layout = Layout::Window(1);
+    assert!(layout.split(1, 2, false));
+    assert!(layout.split(1, 3, false));
+    assert!(layout.split(3, 4, true));
+    assert!(!layout.split(5, 6, true));
+    assert!(layout.windows() == vec![1, 3, 4, 2]);
+
+    assert!(layout.remove(3));
+    assert!(layout == Layout::Split { vertical: false, children: vec![Layout::Window(1), Layout::Window(4),
+                                                                      Layout::Window(2)] });
+    assert!(layout.remove(1) && layout.remove(2));
+    assert!(layout == Layout::Window(4));
+    assert!(!layout.remove(4));
+}
+
+#[test]
+fn layout_arrange() {
+    let mut layout = Layout::Window(1);
+    layout.split(1, 2, true);
+    layout.split(2, 3, false);
+
+    let mut windows = Vec::new();
+    let mut separators = Vec::new();
+    layout.arrange(Rect { x: 0, y: 0, width: 81, height: 21 }, true, &mut windows, &mut separators);
+    assert!(windows == vec![(1, Rect { x: 0, y: 0, width: 40, height: 20 }),
+                            (2, Rect { x: 41, y: 0, width: 40, height: 10 }),
+                            (3, Rect { x: 41, y: 11, width: 40, height: 9 })]);
+    assert!(separators == vec![Rect { x: 40, y: 0, width: 1, height: 21 }]);
+
+    assert!(neighbor(&windows, 1, Direction::Right) == Some(2));
+    assert!(neighbor(&windows, 3, Direction::Left) == Some(1));
+    assert!(neighbor(&windows, 2, Direction::Down) == Some(3));
+    assert!(neighbor(&windows, 3, Direction::Up) == Some(2));
+    assert!(neighbor(&windows, 1, Direction::Up) == None);
+}